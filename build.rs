@@ -0,0 +1,6 @@
+// The `defmt` log needs its own linker script on top of `link.x`.
+fn main() {
+    if std::env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+}
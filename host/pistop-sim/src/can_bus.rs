@@ -0,0 +1,95 @@
+/*
+ * A CAN bus in memory, for simulated controllers that talk to each other over
+ * the CAN link. Like on a real bus, a frame that one port sends arrives at all
+ * of the other ports, and not at the port that sent it. A port can be
+ * unplugged, to see what the link does when a cable comes out.
+ *
+ * The bus keeps every frame that went over it, so that a test can look at the
+ * traffic.
+ */
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Instant;
+use pistop_core::can_link::{CanBus, CanFrame};
+
+pub struct SimCanBus {
+    ports: RefCell<Vec<&'static SimCanPort>>,
+    frames: RefCell<Vec<(Instant, CanFrame)>>,
+}
+
+impl SimCanBus {
+    pub fn new() -> Self {
+        SimCanBus {
+            ports: RefCell::new(Vec::new()),
+            frames: RefCell::new(Vec::new()),
+        }
+    }
+
+    // A new port on the bus, for a controller to plug in.
+    pub fn port(&'static self) -> &'static SimCanPort {
+        let port: &'static SimCanPort = Box::leak(Box::new(SimCanPort {
+            bus: self,
+            plugged: Cell::new(true),
+            received: RefCell::new(VecDeque::new()),
+            arrived: Signal::new(),
+        }));
+        self.ports.borrow_mut().push(port);
+        port
+    }
+
+    pub fn frames(&self) -> Vec<(Instant, CanFrame)> {
+        self.frames.borrow().clone()
+    }
+
+    fn send(&self, from: &SimCanPort, frame: &CanFrame) {
+        self.frames
+            .borrow_mut()
+            .push((Instant::now(), frame.clone()));
+        for port in self.ports.borrow().iter() {
+            if !std::ptr::eq(*port, from) && port.plugged.get() {
+                port.received.borrow_mut().push_back(frame.clone());
+                port.arrived.signal(());
+            }
+        }
+    }
+}
+
+impl Default for SimCanBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SimCanPort {
+    bus: &'static SimCanBus,
+    plugged: Cell<bool>,
+    received: RefCell<VecDeque<CanFrame>>,
+    arrived: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl SimCanPort {
+    // An unplugged port neither sends nor receives.
+    pub fn set_plugged(&self, plugged: bool) {
+        self.plugged.set(plugged);
+    }
+}
+
+impl CanBus for &SimCanPort {
+    fn send(&mut self, frame: &CanFrame) {
+        if self.plugged.get() {
+            self.bus.send(self, frame);
+        }
+    }
+
+    async fn receive(&mut self) -> CanFrame {
+        loop {
+            if let Some(frame) = self.received.borrow_mut().pop_front() {
+                return frame;
+            }
+            self.arrived.wait().await;
+        }
+    }
+}
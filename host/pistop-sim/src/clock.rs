@@ -0,0 +1,80 @@
+/*
+ * The simulated clock, which stands in for the hardware timer behind
+ * `embassy_time`. Time only moves when the simulation moves it, so a
+ * simulation can run through an hour of traffic in a fraction of a second,
+ * or keep pace with the wall clock when a tool is talking to it.
+ *
+ * Every thread has a clock of its own. Tests run on threads of their own, so
+ * this keeps tests that run side by side from moving each other's time.
+ */
+
+use std::cell::RefCell;
+use std::task::Waker;
+
+use embassy_time_driver::Driver;
+
+struct Clock {
+    now: u64,
+    alarms: Vec<(u64, Waker)>,
+}
+
+thread_local! {
+    static CLOCK: RefCell<Clock> = const {
+        RefCell::new(Clock {
+            now: 0,
+            alarms: Vec::new(),
+        })
+    };
+}
+
+struct SimDriver;
+
+impl Driver for SimDriver {
+    fn now(&self) -> u64 {
+        CLOCK.with(|clock| clock.borrow().now)
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        CLOCK.with(|clock| {
+            let mut clock = clock.borrow_mut();
+            // A task that waits on more than one timer hands in the same waker
+            // more than once. Waking it at the earliest of those is enough, it
+            // will ask again for the others.
+            match clock.alarms.iter_mut().find(|(_, w)| w.will_wake(waker)) {
+                Some(alarm) => alarm.0 = alarm.0.min(at),
+                None => clock.alarms.push((at, waker.clone())),
+            }
+        });
+    }
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: SimDriver = SimDriver);
+
+// Start the clock of this thread over, for a new simulation.
+pub(crate) fn reset() {
+    CLOCK.with(|clock| {
+        let mut clock = clock.borrow_mut();
+        clock.now = 0;
+        clock.alarms.clear();
+    });
+}
+
+pub(crate) fn next_alarm() -> Option<u64> {
+    CLOCK.with(|clock| clock.borrow().alarms.iter().map(|(at, _)| *at).min())
+}
+
+// Move the clock forward and wake everyone whose alarm went off on the way.
+pub(crate) fn advance_to(at: u64) {
+    let due: Vec<Waker> = CLOCK.with(|clock| {
+        let mut clock = clock.borrow_mut();
+        clock.now = clock.now.max(at);
+        let now: u64 = clock.now;
+        let (due, pending): (Vec<_>, Vec<_>) =
+            clock.alarms.drain(..).partition(|(at, _)| *at <= now);
+        clock.alarms = pending;
+        due.into_iter().map(|(_, waker)| waker).collect()
+    });
+    for waker in due {
+        waker.wake();
+    }
+}
@@ -0,0 +1,717 @@
+/*
+ * A simulated controller: the logic of `pistop-core`, wired up the same way
+ * the firmware wires it up, but to simulated inputs and outputs. The pieces
+ * are leaked, since the controller logic expects them to live forever, just
+ * like the statics of the firmware do.
+ */
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+
+use embassy_futures::select::select;
+use embassy_sync::{mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use enum_ordinalize::Ordinalize;
+use pistop_core::{
+    ThreadModeRawMutex, battery_monitor,
+    calendar::Calendar,
+    can_link::{self, CanLink},
+    event_log::EventLog,
+    faults::Faults,
+    green_wave::{self, GreenWave},
+    host_link::{self, HostLink},
+    lights::{self, PedestrianLights, PedestrianPins, TrafficLights, TrafficPins},
+    log::{self, Log},
+    manual,
+    modbus::{self, FrameTiming},
+    modes::{self, CrossingSemaphore, SystemModeHandler},
+    pelican,
+    preemption::{self, Preemption},
+    railway,
+    schedule::{self, Schedule},
+    schedule_storage,
+    serial::Serial,
+    settings::Settings,
+    statistics::Statistics,
+    timed_output_masker::{Pins, TimedOutputMasker},
+    wall_clock::{self, WallClock},
+};
+use pistop_protocol::{Approach, SystemMode};
+
+use crate::Simulation;
+use crate::can_bus::{SimCanBus, SimCanPort};
+use crate::inputs::{SimBattery, SimInput, SimRtc, SimStorage};
+
+// The controller starts in flashing mode, like the firmware does.
+pub const START_MODE: SystemMode = SystemMode::Flash;
+
+// The output loop of the firmware runs at 100Hz.
+pub const TICK: Duration = Duration::from_millis(10);
+
+// The cycles of the masker start this long after the whole second, like they do
+// in the firmware, see `low_power.rs` there.
+pub const CYCLE_OFFSET: Duration = Duration::from_millis(20);
+
+// What the output loop sees at a tick: the outputs and the state of the
+// controller that they follow from.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sample {
+    pub outputs: [bool; Pins::VARIANT_COUNT],
+    // The slow cycle, the fast cycle and the pip timer of the masker.
+    pub cycles: (bool, bool, bool),
+    pub lockout: bool,
+    pub mode: SystemMode,
+    // A bit per `SystemMode` ordinal, see `modes::system_mode()`.
+    pub permits_handed_out: u16,
+}
+
+// The samples with the moment they changed to those values.
+pub type Samples = Vec<(Instant, Sample)>;
+
+fn leak<T>(value: T) -> &'static T {
+    Box::leak(Box::new(value))
+}
+
+pub struct Controller {
+    pub serial: &'static Serial,
+    pub log: &'static Log,
+    pub lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    outputs_changed: &'static Signal<ThreadModeRawMutex, ()>,
+    pub events: &'static EventLog,
+    pub statistics: &'static Statistics,
+    pub faults: &'static Faults,
+    pub preemption: &'static Preemption,
+    pub settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    pub lockout: &'static AtomicBool,
+    pub permits_handed_out: &'static AtomicU16,
+    pub system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
+    pub manual_advance: &'static Signal<ThreadModeRawMutex, ()>,
+    pub kerbside_detected: &'static AtomicBool,
+    pub battery_millivolts: &'static AtomicU32,
+    pub traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pub pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    pub host_link: &'static HostLink,
+    pub wall_clock: &'static WallClock,
+    pub schedule: &'static Mutex<ThreadModeRawMutex, Schedule>,
+    pub calendar: &'static Mutex<ThreadModeRawMutex, Calendar>,
+    pub can_link: &'static CanLink,
+
+    pub mode_inputs: [&'static SimInput; 3],
+    pub buttons: [&'static SimInput; Approach::VARIANT_COUNT],
+    pub preemption_inputs: [&'static SimInput; Approach::VARIANT_COUNT],
+    pub track_circuit: &'static SimInput,
+    pub advance_input: &'static SimInput,
+    pub kerbside_input: &'static SimInput,
+    pub battery: &'static SimBattery,
+    pub rtc: &'static SimRtc,
+    pub storage: &'static SimStorage,
+    // The sync pulses of the green wave. Hand the output of one controller to
+    // the input of the next before spawning it, that is all the wiring there is.
+    pub green_wave: &'static GreenWave,
+    pub sync_input: &'static SimInput,
+    pub sync_output: &'static SimInput,
+    // On a bus of its own, until it is plugged into a port of a shared one
+    // before spawning.
+    pub can_port: &'static SimCanPort,
+    sample: &'static Cell<Sample>,
+    // Only kept while someone is recording, see `record_samples()`.
+    samples: &'static RefCell<Option<Samples>>,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        // All outputs are active-high in the simulation, so that the outputs
+        // are simply the lamps that are lit.
+        let outputs_changed: &'static Signal<ThreadModeRawMutex, ()> = leak(Signal::new());
+        let lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker> = leak(Mutex::new(
+            TimedOutputMasker::new([false; Pins::VARIANT_COUNT], outputs_changed),
+        ));
+        let events: &'static EventLog = leak(EventLog::new(START_MODE));
+        let statistics: &'static Statistics = leak(Statistics::new());
+        let faults: &'static Faults = leak(Faults::new(events));
+        let settings: &'static Mutex<ThreadModeRawMutex, Settings> =
+            leak(Mutex::new(Settings::new()));
+        let serial: &'static Serial = leak(Serial::new());
+        let log: &'static Log = leak(Log::new());
+        let lockout: &'static AtomicBool = leak(AtomicBool::new(true));
+        let permits_handed_out: &'static AtomicU16 = leak(AtomicU16::new(0));
+        let system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode> =
+            leak(Signal::new());
+        let manual_advance: &'static Signal<ThreadModeRawMutex, ()> = leak(Signal::new());
+        let battery_millivolts: &'static AtomicU32 = leak(AtomicU32::new(0));
+        let preemption: &'static Preemption = leak(Preemption::new(events, settings));
+        let wall_clock: &'static WallClock = leak(WallClock::new());
+        let schedule: &'static Mutex<ThreadModeRawMutex, Schedule> =
+            leak(Mutex::new(Schedule::new()));
+        let calendar: &'static Mutex<ThreadModeRawMutex, Calendar> =
+            leak(Mutex::new(Calendar::new()));
+
+        let traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT] = [
+            leak(TrafficLights::new(
+                lights,
+                events,
+                Approach::A,
+                TrafficPins {
+                    red: Pins::ARed,
+                    amber: Pins::AAmber,
+                    green: Pins::AGreen,
+                },
+            )),
+            leak(TrafficLights::new(
+                lights,
+                events,
+                Approach::B,
+                TrafficPins {
+                    red: Pins::BRed,
+                    amber: Pins::BAmber,
+                    green: Pins::BGreen,
+                },
+            )),
+        ];
+        let pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT] = [
+            leak(PedestrianLights::new(
+                lights,
+                events,
+                statistics,
+                Approach::A,
+                PedestrianPins {
+                    red: Pins::APedestrianRed,
+                    green: Pins::APedestrianGreen,
+                    beeper: Pins::ABeeper,
+                    promise: Pins::APromise,
+                },
+            )),
+            leak(PedestrianLights::new(
+                lights,
+                events,
+                statistics,
+                Approach::B,
+                PedestrianPins {
+                    red: Pins::BPedestrianRed,
+                    green: Pins::BPedestrianGreen,
+                    beeper: Pins::BBeeper,
+                    promise: Pins::BPromise,
+                },
+            )),
+        ];
+
+        let host_link: &'static HostLink = leak(HostLink {
+            serial: serial,
+            log: log,
+            events: events,
+            statistics: statistics,
+            faults: faults,
+            preemption: preemption,
+            settings: settings,
+            lights: lights,
+            lockout: lockout,
+            battery_millivolts: battery_millivolts,
+            system_mode_signal: system_mode_signal,
+            manual_advance: manual_advance,
+            pedestrian_lights: pedestrian_lights,
+            wall_clock: wall_clock,
+            schedule: schedule,
+            calendar: calendar,
+            schedule_written: leak(Signal::new()),
+        });
+
+        let green_wave: &'static GreenWave = leak(GreenWave::new());
+        let can_link: &'static CanLink = leak(CanLink {
+            log: log,
+            settings: settings,
+            events: events,
+            system_mode_signal: system_mode_signal,
+            pedestrian_lights: pedestrian_lights,
+            green_wave: green_wave,
+        });
+
+        let controller: Controller = Controller {
+            serial: serial,
+            log: log,
+            lights: lights,
+            outputs_changed: outputs_changed,
+            events: events,
+            statistics: statistics,
+            faults: faults,
+            preemption: preemption,
+            settings: settings,
+            lockout: lockout,
+            permits_handed_out: permits_handed_out,
+            system_mode_signal: system_mode_signal,
+            manual_advance: manual_advance,
+            kerbside_detected: leak(AtomicBool::new(false)),
+            battery_millivolts: battery_millivolts,
+            traffic_lights: traffic_lights,
+            pedestrian_lights: pedestrian_lights,
+            host_link: host_link,
+            wall_clock: wall_clock,
+            schedule: schedule,
+            calendar: calendar,
+            can_link: can_link,
+            mode_inputs: [
+                leak(SimInput::new()),
+                leak(SimInput::new()),
+                leak(SimInput::new()),
+            ],
+            buttons: [leak(SimInput::new()), leak(SimInput::new())],
+            preemption_inputs: [leak(SimInput::new()), leak(SimInput::new())],
+            track_circuit: leak(SimInput::new()),
+            advance_input: leak(SimInput::new()),
+            kerbside_input: leak(SimInput::new()),
+            battery: leak(SimBattery::new(settings)),
+            rtc: leak(SimRtc::new()),
+            storage: leak(SimStorage::new()),
+            green_wave: green_wave,
+            sync_input: leak(SimInput::new()),
+            sync_output: leak(SimInput::new()),
+            can_port: leak(SimCanBus::new()).port(),
+            sample: leak(Cell::new(Sample {
+                outputs: [false; Pins::VARIANT_COUNT],
+                cycles: (false, false, false),
+                lockout: true,
+                mode: START_MODE,
+                permits_handed_out: 0,
+            })),
+            samples: leak(RefCell::new(None)),
+        };
+        controller.set_mode_switch(START_MODE);
+        controller
+    }
+
+    // Start all of the tasks of the controller, as `main()` does on the board.
+    pub fn spawn(&self, simulation: &mut Simulation) {
+        self.spawn_logic(simulation);
+        let host_link: &'static HostLink = self.host_link;
+        simulation.spawn(async move {
+            host_link::host_link(host_link).await;
+        });
+        simulation.spawn(async move {
+            host_link::host_notifications(host_link).await;
+        });
+    }
+
+    // Start the controller with the Modbus slave on the serial port, as
+    // `main()` does on the board when built with the `modbus` feature.
+    pub fn spawn_modbus(&self, simulation: &mut Simulation, timing: FrameTiming) {
+        // The Modbus slave has the serial port to itself, there is no console.
+        self.serial.set_binary_mode(true);
+        self.spawn_logic(simulation);
+        let host_link: &'static HostLink = self.host_link;
+        simulation.spawn(async move {
+            modbus::modbus_slave(host_link, timing).await;
+        });
+    }
+
+    // Everything but what runs on the serial port.
+    fn spawn_logic(&self, simulation: &mut Simulation) {
+        let semaphores: &'static [CrossingSemaphore; SystemMode::VARIANT_COUNT] =
+            leak([const { CrossingSemaphore::new(0) }; SystemMode::VARIANT_COUNT]);
+        let [
+            normal,
+            flash,
+            priority_a,
+            priority_b,
+            railway,
+            manual,
+            all_red,
+            dark,
+            pelican,
+        ] = semaphores.each_ref();
+        let [traffic_a, traffic_b] = self.traffic_lights;
+        let [pedestrian_a, pedestrian_b] = self.pedestrian_lights;
+
+        let settings: &'static Mutex<ThreadModeRawMutex, Settings> = self.settings;
+        let statistics: &'static Statistics = self.statistics;
+        let green_wave: &'static GreenWave = self.green_wave;
+        let preemption: &'static Preemption = self.preemption;
+        simulation.spawn(async move {
+            modes::normal_mode(
+                normal,
+                traffic_a,
+                pedestrian_a,
+                settings,
+                statistics,
+                Some(green_wave),
+                preemption,
+            )
+            .await;
+        });
+        simulation.spawn(async move {
+            modes::normal_mode(
+                normal,
+                traffic_b,
+                pedestrian_b,
+                settings,
+                statistics,
+                None,
+                preemption,
+            )
+            .await;
+        });
+        let lockout: &'static AtomicBool = self.lockout;
+        simulation.spawn(async move {
+            modes::flash_mode(
+                flash,
+                traffic_a,
+                traffic_b,
+                pedestrian_a,
+                pedestrian_b,
+                lockout,
+                statistics,
+            )
+            .await;
+        });
+        simulation.spawn(async move {
+            modes::priority_mode(
+                SystemMode::PriorityA,
+                priority_a,
+                traffic_a,
+                pedestrian_a,
+                lockout,
+                statistics,
+                preemption,
+            )
+            .await;
+        });
+        simulation.spawn(async move {
+            modes::priority_mode(
+                SystemMode::PriorityB,
+                priority_b,
+                traffic_b,
+                pedestrian_b,
+                lockout,
+                statistics,
+                preemption,
+            )
+            .await;
+        });
+
+        let traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT] = self.traffic_lights;
+        let pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT] =
+            self.pedestrian_lights;
+        simulation.spawn(async move {
+            modes::railway_mode(
+                railway,
+                traffic_lights,
+                pedestrian_lights,
+                settings,
+                lockout,
+                statistics,
+            )
+            .await;
+        });
+        let manual_advance: &'static Signal<ThreadModeRawMutex, ()> = self.manual_advance;
+        simulation.spawn(async move {
+            modes::manual_mode(
+                manual,
+                traffic_lights,
+                pedestrian_lights,
+                settings,
+                lockout,
+                statistics,
+                manual_advance,
+            )
+            .await;
+        });
+        let kerbside_detected: &'static AtomicBool = self.kerbside_detected;
+        simulation.spawn(async move {
+            modes::pelican_mode(
+                pelican,
+                traffic_lights,
+                pedestrian_lights,
+                settings,
+                lockout,
+                statistics,
+                kerbside_detected,
+            )
+            .await;
+        });
+        simulation.spawn(async move {
+            modes::all_red_mode(
+                all_red,
+                traffic_lights,
+                pedestrian_lights,
+                lockout,
+                statistics,
+                preemption,
+            )
+            .await;
+        });
+        let lights = self.lights;
+        simulation.spawn(async move {
+            modes::dark_mode(
+                dark,
+                traffic_lights,
+                pedestrian_lights,
+                lights,
+                lockout,
+                statistics,
+            )
+            .await;
+        });
+
+        let log: &'static Log = self.log;
+        let system_mode_signal = self.system_mode_signal;
+        let faults: &'static Faults = self.faults;
+        let events: &'static EventLog = self.events;
+        let permits_handed_out: &'static AtomicU16 = self.permits_handed_out;
+        let handler: &'static SystemModeHandler = leak(SystemModeHandler {
+            log: log,
+            system_mode_signal: system_mode_signal,
+            faults: faults,
+            preemption: preemption,
+            events: events,
+            semaphores: semaphores,
+            lockout: lockout,
+            lights: lights,
+            statistics: statistics,
+            handed_out: permits_handed_out,
+        });
+        simulation.spawn(async move {
+            modes::system_mode(handler, START_MODE).await;
+        });
+        let mut mode_inputs: [&'static SimInput; 3] = self.mode_inputs;
+        simulation.spawn(async move {
+            modes::system_mode_reader(log, &mut mode_inputs, START_MODE, system_mode_signal).await;
+        });
+        let [mut button_a, mut button_b] = self.buttons;
+        simulation.spawn(async move {
+            lights::promise_input(&mut button_a, pedestrian_a).await;
+        });
+        simulation.spawn(async move {
+            lights::promise_input(&mut button_b, pedestrian_b).await;
+        });
+        let mut preemption_inputs: [&'static SimInput; Approach::VARIANT_COUNT] =
+            self.preemption_inputs;
+        simulation.spawn(async move {
+            preemption::preemption_inputs(log, preemption, &mut preemption_inputs).await;
+        });
+        let mut track_circuit: &'static SimInput = self.track_circuit;
+        simulation.spawn(async move {
+            railway::track_circuit(log, preemption, &mut track_circuit).await;
+        });
+        let mut advance_input: &'static SimInput = self.advance_input;
+        simulation.spawn(async move {
+            manual::advance_input(log, &mut advance_input, manual_advance).await;
+        });
+        let mut kerbside_input: &'static SimInput = self.kerbside_input;
+        simulation.spawn(async move {
+            pelican::kerbside_detector(log, &mut kerbside_input, kerbside_detected).await;
+        });
+
+        let mut battery: &'static SimBattery = self.battery;
+        let settings = self.settings;
+        let battery_millivolts: &'static AtomicU32 = self.battery_millivolts;
+        simulation.spawn(async move {
+            battery_monitor::battery_monitor(
+                log,
+                &mut battery,
+                settings,
+                lights,
+                faults,
+                events,
+                battery_millivolts,
+            )
+            .await;
+        });
+
+        let mut rtc: &'static SimRtc = self.rtc;
+        let wall_clock: &'static WallClock = self.wall_clock;
+        simulation.spawn(async move {
+            wall_clock::clock_keeper(log, &mut rtc, wall_clock).await;
+        });
+        let schedule: &'static Mutex<ThreadModeRawMutex, Schedule> = self.schedule;
+        let calendar: &'static Mutex<ThreadModeRawMutex, Calendar> = self.calendar;
+        let mut storage: &'static SimStorage = self.storage;
+        let schedule_written: &'static Signal<ThreadModeRawMutex, ()> =
+            self.host_link.schedule_written;
+        simulation.spawn(async move {
+            schedule_storage::schedule_keeper(
+                log,
+                &mut storage,
+                schedule,
+                calendar,
+                schedule_written,
+            )
+            .await;
+        });
+        simulation.spawn(async move {
+            schedule::scheduler(
+                log,
+                wall_clock,
+                schedule,
+                calendar,
+                settings,
+                system_mode_signal,
+            )
+            .await;
+        });
+
+        let mut sync_input: &'static SimInput = self.sync_input;
+        let mut sync_output: &'static SimInput = self.sync_output;
+        simulation.spawn(async move {
+            green_wave::sync_pulses(log, settings, green_wave, &mut sync_input, &mut sync_output)
+                .await;
+        });
+
+        let can_link: &'static CanLink = self.can_link;
+        let mut can_port: &'static SimCanPort = self.can_port;
+        simulation.spawn(async move {
+            can_link::can_link(can_link, &mut can_port).await;
+        });
+
+        let serial: &'static Serial = self.serial;
+        simulation.spawn(async move {
+            log::log_writer(log, serial).await;
+        });
+
+        // The output loop of the firmware: it moves the masker on by the ticks
+        // that passed and sleeps until the next output changes, or until the
+        // control logic changes a pin.
+        let outputs_changed: &'static Signal<ThreadModeRawMutex, ()> = self.outputs_changed;
+        let pins: &'static Cell<[bool; Pins::VARIANT_COUNT]> =
+            leak(Cell::new([false; Pins::VARIANT_COUNT]));
+        // The masker starts at the last tick of its cycle.
+        let last_tick: &'static Cell<Instant> =
+            leak(Cell::new(Instant::from_ticks(0) + CYCLE_OFFSET - TICK));
+        simulation.spawn(async move {
+            loop {
+                let elapsed_ticks: u64 = Instant::now()
+                    .saturating_duration_since(last_tick.get())
+                    .as_ticks()
+                    / TICK.as_ticks();
+                last_tick.set(last_tick.get() + TICK * elapsed_ticks as u32);
+
+                let ticks_until_change: u32 = {
+                    // scope for the mutex guard...
+                    let mut lights = lights.lock().await;
+                    pins.set(lights.call_after_ticks(elapsed_ticks as u32));
+                    lights.ticks_until_change()
+                };
+
+                select(
+                    Timer::at(last_tick.get() + TICK * ticks_until_change),
+                    outputs_changed.wait(),
+                )
+                .await;
+            }
+        });
+
+        // Samples the pins as the output loop left them, on every tick, like a
+        // logic analyser would. The cycles of the masker are worked out for
+        // the tick, since the output loop does not move the masker on while
+        // it sleeps.
+        let sample: &'static Cell<Sample> = self.sample;
+        let samples = self.samples;
+        simulation.spawn(async move {
+            let mut ticker: Ticker = Ticker::every(TICK);
+            loop {
+                ticker.next().await;
+                let cycles: (bool, bool, bool) = {
+                    // scope for the mutex guard...
+                    let lights = lights.lock().await;
+                    let ticks: u64 = Instant::now()
+                        .saturating_duration_since(last_tick.get())
+                        .as_ticks()
+                        / TICK.as_ticks();
+                    lights.cycles_in(ticks as u32)
+                };
+                let values: Sample = Sample {
+                    outputs: pins.get(),
+                    cycles: cycles,
+                    lockout: lockout.load(Ordering::Relaxed),
+                    mode: events.latest().mode,
+                    permits_handed_out: permits_handed_out.load(Ordering::Relaxed),
+                };
+                if values != sample.get()
+                    && let Some(samples) = samples.borrow_mut().as_mut()
+                {
+                    samples.push((Instant::now(), values));
+                }
+                sample.set(values);
+            }
+        });
+    }
+
+    // Turn the rotary switch. The switch pulls one of its contacts low for
+    // every mode but normal. It has no position for the railway mode, nor for
+    // the manual, all red, dark and pelican modes, which the host asks for.
+    pub fn set_mode_switch(&self, mode: SystemMode) {
+        let contact: Option<usize> = match mode {
+            SystemMode::Normal => None,
+            SystemMode::Flash => Some(0),
+            SystemMode::PriorityA => Some(1),
+            SystemMode::PriorityB => Some(2),
+            SystemMode::Railway
+            | SystemMode::Manual
+            | SystemMode::AllRed
+            | SystemMode::Dark
+            | SystemMode::Pelican => {
+                panic!("the rotary switch has no {mode:?} mode")
+            }
+        };
+        for (i, input) in self.mode_inputs.iter().enumerate() {
+            input.set_high(contact != Some(i));
+        }
+    }
+
+    pub fn set_button(&self, approach: Approach, pressed: bool) {
+        self.buttons[approach.ordinal() as usize].set_high(!pressed);
+    }
+
+    // The officer presses the button of the manual mode, or lets go of it.
+    pub fn set_advance_button(&self, pressed: bool) {
+        self.advance_input.set_high(!pressed);
+    }
+
+    // The kerbside detector of a puffin sees someone at the kerb, or no longer
+    // does.
+    pub fn set_kerbside_detector(&self, detected: bool) {
+        self.kerbside_input.set_high(!detected);
+    }
+
+    // A train comes near the railway crossing, or has passed.
+    pub fn set_track_circuit(&self, occupied: bool) {
+        self.track_circuit.set_high(!occupied);
+    }
+
+    // An emergency vehicle calls for the green of its approach, or no longer
+    // does.
+    pub fn set_preemption_input(&self, approach: Approach, calling: bool) {
+        self.preemption_inputs[approach.ordinal() as usize].set_high(!calling);
+    }
+
+    // The outputs as of the most recent tick.
+    pub fn outputs(&self) -> [bool; Pins::VARIANT_COUNT] {
+        self.sample.get().outputs
+    }
+
+    pub fn sample(&self) -> Sample {
+        self.sample.get()
+    }
+
+    pub fn is_lit(&self, pin: Pins) -> bool {
+        self.outputs()[pin.ordinal()]
+    }
+
+    // Keep every change of the samples from now on, with the tick at which it
+    // happened.
+    pub fn record_samples(&self) {
+        *self.samples.borrow_mut() = Some(Vec::new());
+    }
+
+    // The changes of the samples since the previous call.
+    pub fn take_samples(&self) -> Samples {
+        self.samples
+            .borrow_mut()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
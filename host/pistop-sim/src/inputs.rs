@@ -0,0 +1,242 @@
+/*
+ * Simulated inputs. The controller logic takes its inputs through the
+ * `embedded-hal` traits and through `BatterySensor`, `Rtc` and
+ * `ScheduleStorage`, so the simulated inputs implement those, and the
+ * simulation changes them from the outside.
+ *
+ * The one output that is not a lamp, the sync pulse of the green wave, goes
+ * to the input of another controller. So a `SimInput` doubles as the wire in
+ * between, which one controller drives and the other reads.
+ */
+
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::Instant;
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
+use pistop_core::{
+    ThreadModeRawMutex,
+    battery_monitor::BatterySensor,
+    schedule_storage::{STORED_SIZE, ScheduleStorage},
+    settings::Settings,
+    wall_clock::Rtc,
+};
+use pistop_protocol::WallTime;
+
+// A digital input, such as a button or one of the contacts of the rotary
+// switch. Like on the board, the inputs are pulled up, so they are high until
+// something pulls them low.
+pub struct SimInput {
+    high: Cell<bool>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl SimInput {
+    pub fn new() -> Self {
+        SimInput {
+            high: Cell::new(true),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.high.get()
+    }
+
+    pub fn set_high(&self, high: bool) {
+        if self.high.replace(high) != high {
+            self.changed.signal(());
+        }
+    }
+
+    async fn wait_for_level(&self, high: bool) {
+        while self.high.get() != high {
+            self.changed.wait().await;
+        }
+    }
+
+    async fn wait_for_edge(&self, to_high: Option<bool>) {
+        // Only changes from here on count, not one that happened before we
+        // started to wait.
+        self.changed.reset();
+        loop {
+            self.changed.wait().await;
+            if to_high.is_none_or(|to_high| self.high.get() == to_high) {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for SimInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ErrorType for &SimInput {
+    type Error = Infallible;
+}
+
+impl InputPin for &SimInput {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(self.high.get())
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(!self.high.get())
+    }
+}
+
+impl OutputPin for &SimInput {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        SimInput::set_high(self, false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        SimInput::set_high(self, true);
+        Ok(())
+    }
+}
+
+impl Wait for &SimInput {
+    async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+        self.wait_for_level(true).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+        self.wait_for_level(false).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+        self.wait_for_edge(Some(true)).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+        self.wait_for_edge(Some(false)).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+        self.wait_for_edge(None).await;
+        Ok(())
+    }
+}
+
+// The nominal reading of the internal reference, with the ADC running from
+// 3.3V. The battery monitor only cares about the ratio between the readings.
+const VREF_RAW: u32 = 1_200 * 4_095 / 3_300;
+
+// The battery, which the simulation can run down. The readings follow the
+// divider ratio in the settings, so that the controller sees the voltage that
+// was set here.
+pub struct SimBattery {
+    millivolts: Cell<u32>,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+}
+
+impl SimBattery {
+    pub fn new(settings: &'static Mutex<ThreadModeRawMutex, Settings>) -> Self {
+        SimBattery {
+            // a healthy 2S pack
+            millivolts: Cell::new(7_800),
+            settings: settings,
+        }
+    }
+
+    pub fn set_millivolts(&self, millivolts: u32) {
+        self.millivolts.set(millivolts);
+    }
+}
+
+impl BatterySensor for &SimBattery {
+    async fn read(&mut self) -> (u16, u16) {
+        let ratio_milli: u32 = self.settings.lock().await.battery_divider_ratio_milli;
+        let pin_millivolts: u32 = self.millivolts.get() * 1_000 / ratio_milli.max(1);
+        let raw: u32 = (pin_millivolts * VREF_RAW / 1_200).min(4_095);
+        (raw as u16, VREF_RAW as u16)
+    }
+}
+
+// The RTC, which counts the seconds from the time that it was set to. It
+// starts out not set, like the RTC of a board without a backup battery.
+pub struct SimRtc {
+    set: Cell<Option<(WallTime, Instant)>>,
+}
+
+impl SimRtc {
+    pub fn new() -> Self {
+        SimRtc {
+            set: Cell::new(None),
+        }
+    }
+
+    // Set the RTC behind the back of the controller, as if it had been set
+    // before the controller started.
+    pub fn set(&self, time: WallTime) {
+        self.set.set(Some((time, Instant::now())));
+    }
+}
+
+impl Default for SimRtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rtc for &SimRtc {
+    fn read(&mut self) -> Option<WallTime> {
+        self.set
+            .get()
+            .map(|(time, at)| WallTime(time.0 + (Instant::now() - at).as_secs() as u32))
+    }
+
+    fn write(&mut self, time: WallTime) {
+        SimRtc::set(self, time);
+    }
+}
+
+// The flash that the schedule and the calendar are kept in. It starts out
+// erased, like the flash of a new board. Hand it to another controller before
+// spawning that one to have it restart with what the first one stored.
+pub struct SimStorage {
+    bytes: RefCell<[u8; STORED_SIZE]>,
+    writes: Cell<u32>,
+}
+
+impl SimStorage {
+    pub fn new() -> Self {
+        SimStorage {
+            bytes: RefCell::new([0xff; STORED_SIZE]),
+            writes: Cell::new(0),
+        }
+    }
+
+    // How often the flash was written, which wears it out.
+    pub fn writes(&self) -> u32 {
+        self.writes.get()
+    }
+}
+
+impl Default for SimStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScheduleStorage for &SimStorage {
+    fn read(&mut self, bytes: &mut [u8; STORED_SIZE]) {
+        *bytes = *self.bytes.borrow();
+    }
+
+    fn write(&mut self, bytes: &[u8; STORED_SIZE]) {
+        *self.bytes.borrow_mut() = *bytes;
+        self.writes.set(self.writes.get() + 1);
+    }
+}
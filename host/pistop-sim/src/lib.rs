@@ -0,0 +1,118 @@
+/*
+ * The simulator runs the controller logic of `pistop-core` on a desktop
+ * machine. Instead of the embassy executor and the hardware timer, it has a
+ * small executor of its own that runs on a simulated clock, see `clock.rs`.
+ *
+ * A simulation can run as fast as it likes, which is what tests want, or at
+ * the pace of the wall clock, which is what a tool on the other end of a
+ * serial port wants.
+ */
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Wake, Waker};
+
+use embassy_time::{Duration, Instant};
+
+pub mod can_bus;
+mod clock;
+pub mod controller;
+pub mod inputs;
+pub mod pty;
+pub mod scenario;
+pub mod trace;
+pub mod vcd;
+
+pub use controller::Controller;
+
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// There is one simulation per thread at a time, since it owns the clock of the
+// thread.
+pub struct Simulation {
+    tasks: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+    woken: Arc<Woken>,
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        clock::reset();
+        Simulation {
+            tasks: Vec::new(),
+            woken: Arc::new(Woken(AtomicBool::new(true))),
+        }
+    }
+
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.tasks.push(Box::pin(task));
+        self.woken.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    // Run the tasks until none of them can make progress without time
+    // moving on.
+    pub fn settle(&mut self) {
+        let waker: Waker = Waker::from(self.woken.clone());
+        let mut context: Context = Context::from_waker(&waker);
+        while self.woken.0.swap(false, Ordering::Relaxed) {
+            // Tasks that are done are dropped, they must not be polled again.
+            self.tasks
+                .retain_mut(|task| task.as_mut().poll(&mut context).is_pending());
+        }
+    }
+
+    // Run the simulation up to the given moment, as fast as possible.
+    pub fn run_until(&mut self, until: Instant) {
+        loop {
+            self.settle();
+            match clock::next_alarm() {
+                Some(at) if at <= until.as_ticks() => clock::advance_to(at),
+                _ => break,
+            }
+        }
+        clock::advance_to(until.as_ticks());
+        self.settle();
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(Instant::now() + duration);
+    }
+
+    // Run the simulation forever, `speed` times as fast as the wall clock.
+    pub fn run_realtime(&mut self, speed: f64) -> ! {
+        let start: std::time::Instant = std::time::Instant::now();
+        let sim_start: Instant = Instant::now();
+        loop {
+            let elapsed: std::time::Duration = start.elapsed().mul_f64(speed);
+            let target: Instant = sim_start + Duration::from_micros(elapsed.as_micros() as u64);
+            self.run_until(target);
+
+            // Sleep until the next alarm, but not for so long that the tasks
+            // that poll the outside world fall behind.
+            let until_alarm: u64 = clock::next_alarm()
+                .map(|at| at.saturating_sub(Instant::now().as_ticks()))
+                .unwrap_or(u64::MAX);
+            let sleep: std::time::Duration = Duration::from_ticks(until_alarm)
+                .min(Duration::from_millis(10))
+                .into();
+            std::thread::sleep(sleep.div_f64(speed));
+        }
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
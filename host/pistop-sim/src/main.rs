@@ -0,0 +1,170 @@
+/*
+ * Runs a simulated controller behind a pseudo-terminal, so that `pistopctl`
+ * and other tools can be tried out without a board. Prints the path of the
+ * terminal to open and then runs until it is stopped. With `--vcd`, it also
+ * writes the waveforms of the run to a file, see `vcd.rs`.
+ *
+ * With `--scenario`, it runs a scenario instead, see `scenario.rs`, as fast
+ * as it can and without a terminal. It tells which expectations failed and
+ * exits with a failure when any did.
+ */
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::process::ExitCode;
+
+use embassy_time::{Duration, Instant, Timer};
+use pistop_sim::scenario::Scenario;
+use pistop_sim::vcd::Vcd;
+use pistop_sim::{Controller, Simulation, pty::Pty};
+
+const USAGE: &str = "usage: pistop-sim [--vcd FILE] [--scenario FILE | speed]\n\n\
+    Runs the controller logic behind a pseudo-terminal. The optional speed\n\
+    makes the simulated clock run that many times as fast as the wall clock.\n\
+    With --vcd, the waveforms of the outputs and the modes go to FILE.\n\
+    With --scenario, runs the scenario in FILE and checks its expectations.";
+
+// How often the waveforms are written out, so that a run that is stopped
+// leaves a file that is complete up to a moment ago.
+const VCD_INTERVAL: Duration = Duration::from_secs(1);
+
+// Takes an option with a value out of the arguments.
+fn option(arguments: &mut Vec<String>, name: &str) -> Result<Option<String>, ()> {
+    match arguments.iter().position(|argument| argument == name) {
+        Some(i) if i + 1 < arguments.len() => {
+            let value: String = arguments.remove(i + 1);
+            arguments.remove(i);
+            Ok(Some(value))
+        }
+        Some(_) => Err(()),
+        None => Ok(None),
+    }
+}
+
+fn main() -> ExitCode {
+    let mut arguments: Vec<String> = env::args().skip(1).collect();
+    let (Ok(vcd_path), Ok(scenario_path)) = (
+        option(&mut arguments, "--vcd"),
+        option(&mut arguments, "--scenario"),
+    ) else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    if let Some(scenario_path) = scenario_path {
+        if !arguments.is_empty() {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+        return run_scenario(&scenario_path, vcd_path);
+    }
+    let speed: f64 = match arguments.as_slice() {
+        [] => 1.0,
+        [speed] => match speed.parse::<f64>() {
+            Ok(speed) if speed > 0.0 => speed,
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let pty: Pty = match Pty::open() {
+        Ok(pty) => pty,
+        Err(error) => {
+            eprintln!("pistop-sim: cannot open a pseudo-terminal: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("{}", pty.path());
+    let _ = io::stdout().flush();
+
+    let mut simulation: Simulation = Simulation::new();
+    // Leaked, since the task that writes the waveforms holds on to it.
+    let controller: &'static Controller = Box::leak(Box::new(Controller::new()));
+    controller.spawn(&mut simulation);
+    if let Some(path) = vcd_path {
+        let vcd: Vcd<BufWriter<File>> = match File::create(&path)
+            .and_then(|file| Vcd::new(BufWriter::new(file), "pistop-sim", Instant::now()))
+        {
+            Ok(vcd) => vcd,
+            Err(error) => {
+                eprintln!("pistop-sim: cannot write {path}: {error}");
+                return ExitCode::FAILURE;
+            }
+        };
+        simulation.spawn(write_vcd(controller, vcd, path));
+    }
+    let (receive, transmit) = pty.bridge(controller.serial);
+    simulation.spawn(receive);
+    simulation.spawn(transmit);
+    simulation.run_realtime(speed)
+}
+
+async fn write_vcd(controller: &'static Controller, mut vcd: Vcd<BufWriter<File>>, path: String) {
+    controller.record_samples();
+    let mut written: io::Result<()> = vcd.sample(Instant::now(), &controller.sample());
+    while written.is_ok() {
+        Timer::after(VCD_INTERVAL).await;
+        written = controller
+            .take_samples()
+            .iter()
+            .try_for_each(|(at, sample)| vcd.sample(*at, sample))
+            .and_then(|_| vcd.flush());
+    }
+    if let Err(error) = written {
+        eprintln!("pistop-sim: cannot write {path}: {error}");
+    }
+}
+
+fn run_scenario(path: &str, vcd_path: Option<String>) -> ExitCode {
+    let scenario: Scenario = match fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| Scenario::parse(&text))
+    {
+        Ok(scenario) => scenario,
+        Err(error) => {
+            eprintln!("pistop-sim: cannot read {path}: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.spawn(&mut simulation);
+    let start: Instant = Instant::now();
+    controller.record_samples();
+    let result: Result<(), String> =
+        scenario.run(&mut simulation, &controller, scenario.end(), |_| {});
+
+    if let Some(vcd_path) = vcd_path {
+        let written: io::Result<()> = File::create(&vcd_path).and_then(|file| {
+            let mut vcd: Vcd<BufWriter<File>> = Vcd::new(BufWriter::new(file), path, start)?;
+            controller
+                .take_samples()
+                .iter()
+                .try_for_each(|(at, sample)| vcd.sample(*at, sample))?;
+            vcd.finish(Instant::now())?;
+            vcd.flush()
+        });
+        if let Err(error) = written {
+            eprintln!("pistop-sim: cannot write {vcd_path}: {error}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    match result {
+        Ok(()) => {
+            println!("{path}: passed");
+            ExitCode::SUCCESS
+        }
+        Err(failures) => {
+            println!("{path}: failed\n{failures}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,71 @@
+/*
+ * A pseudo-terminal that stands in for the serial port of the board. Tools
+ * open the terminal end, just like they would open the USB serial adapter,
+ * and the simulation moves the bytes between the other end and the serial
+ * pipes of the controller.
+ */
+
+use std::io::{Read, Write};
+
+use embassy_time::Timer;
+use pistop_core::serial::Serial;
+use serialport::{SerialPort, TTYPort};
+
+pub struct Pty {
+    controller_end: TTYPort,
+    // The terminal end has to stay open, or the controller end reports an
+    // error every time a tool closes the terminal.
+    terminal_end: TTYPort,
+}
+
+impl Pty {
+    pub fn open() -> serialport::Result<Self> {
+        let (controller_end, terminal_end) = TTYPort::pair()?;
+        Ok(Pty {
+            controller_end: controller_end,
+            terminal_end: terminal_end,
+        })
+    }
+
+    // The path for tools to open.
+    pub fn path(&self) -> String {
+        self.terminal_end.name().unwrap_or_default()
+    }
+
+    // Move bytes between the pseudo-terminal and the serial pipes, for as long
+    // as the simulation runs.
+    pub fn bridge(
+        self,
+        serial: &'static Serial,
+    ) -> (impl Future<Output = ()>, impl Future<Output = ()>) {
+        let mut reader: TTYPort = self.controller_end;
+        let mut writer: TTYPort = reader
+            .try_clone_native()
+            .expect("cannot clone the pseudo-terminal");
+        let terminal_end: TTYPort = self.terminal_end;
+
+        let receive = async move {
+            let _terminal_end: TTYPort = terminal_end;
+            let mut buffer: [u8; 64] = [0; 64];
+            loop {
+                Timer::after_millis(1).await;
+                let available: usize = reader.bytes_to_read().unwrap_or(0) as usize;
+                if available == 0 {
+                    continue;
+                }
+                let count: usize = reader.read(&mut buffer[..available.min(64)]).unwrap_or(0);
+                serial.received(&buffer[..count]).await;
+            }
+        };
+        let transmit = async move {
+            let mut buffer: [u8; 64] = [0; 64];
+            loop {
+                let count: usize = serial.transmit(&mut buffer).await;
+                // Nobody may be listening, in which case the bytes are lost,
+                // just like on the real serial port.
+                let _ = writer.write_all(&buffer[..count]);
+            }
+        };
+        (receive, transmit)
+    }
+}
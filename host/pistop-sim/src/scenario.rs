@@ -0,0 +1,491 @@
+/*
+ * Scenarios: what someone does to a controller and what they expect to see,
+ * each at a moment since the start, in a text that people can read and write
+ * without knowing any Rust. For example
+ *
+ *     # A pedestrian at A presses the button while A has green.
+ *     at 0s mode normal
+ *     at 20s press A; at 20.150s release A
+ *     expect at 22s APromise on
+ *     expect at 62s APedestrianGreen on
+ *     expect at 70s served A = 1
+ *
+ * Statements go on lines of their own or are separated by semicolons, and a
+ * `#` starts a comment. There are
+ *
+ *     at <time> mode <normal|flash|priority-a|priority-b>
+ *     at <time> host mode <normal|flash|priority-a|priority-b|manual|all-red|dark|pelican>
+ *     at <time> press <A|B>
+ *     at <time> release <A|B>
+ *     at <time> call <A|B> <on|off>
+ *     at <time> track <on|off>
+ *     at <time> advance <on|off>
+ *     at <time> kerbside <on|off>
+ *     expect at <time> <pin> <on|off>
+ *     expect at <time> mode <mode>
+ *     expect at <time> <counter> <=|>=|= <number>
+ *     at <time> repeat <count> every <time>
+ *     end
+ *
+ * Times are in seconds with up to three decimals, such as `2s` or `2.150s`.
+ * Pins go by their names, such as `AGreen`, and are checked as they were lit
+ * at the latest tick of the output loop. The counters are those of the
+ * statistics: `cycles <mode>`, `mode-switches`, and `requests`, `served` and
+ * `dropped` of an approach.
+ *
+ * The statements between `repeat` and `end` go round the given number of
+ * times, the first round at the time of the `repeat`. The times in the block
+ * count from the start of each round. Within a block, time only goes forward.
+ *
+ * Inputs are raw, like the contacts of the switch and the buttons are. A
+ * bouncing button is a quick run of presses and releases, and a rotary switch
+ * that is turned past a few modes goes through each of them on the way. A
+ * `call` is the preemption input of an approach, which an emergency vehicle
+ * holds on for as long as it calls, and `track` is the track circuit of the
+ * railway, which is on while a train is near. The rotary switch has no
+ * position for the railway mode, so `mode railway` only goes with `expect`.
+ * Nor has it one for the manual, all red, dark and pelican modes, which
+ * `host mode` asks for as the host link does. `advance` is the button that the
+ * officer presses in the manual mode, and `kerbside` is the detector of a
+ * puffin, which is on while it sees someone waiting at the kerb.
+ */
+
+use std::fmt::Write;
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, Pins, Statistics, SystemMode};
+
+use crate::controller::TICK;
+use crate::{Controller, Simulation};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Input {
+    Mode(SystemMode),
+    Press(Approach),
+    Release(Approach),
+    Call(Approach, bool),
+    Track(bool),
+    HostMode(SystemMode),
+    Advance(bool),
+    Kerbside(bool),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Counter {
+    Cycles(SystemMode),
+    ModeSwitches,
+    Requests(Approach),
+    Served(Approach),
+    Dropped(Approach),
+}
+
+#[derive(Ordinalize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    Equal,
+    AtLeast,
+    AtMost,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Expectation {
+    Lit(Pins, bool),
+    Mode(SystemMode),
+    Counter(Counter, Comparison, u32),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Scenario {
+    // The inputs and the expectations, each in the order they happen.
+    pub inputs: Vec<(Duration, Input)>,
+    pub expectations: Vec<(Duration, Expectation)>,
+}
+
+const MODE_NAMES: [(SystemMode, &str); SystemMode::VARIANT_COUNT] = [
+    (SystemMode::Normal, "normal"),
+    (SystemMode::Flash, "flash"),
+    (SystemMode::PriorityA, "priority-a"),
+    (SystemMode::PriorityB, "priority-b"),
+    (SystemMode::Railway, "railway"),
+    (SystemMode::Manual, "manual"),
+    (SystemMode::AllRed, "all-red"),
+    (SystemMode::Dark, "dark"),
+    (SystemMode::Pelican, "pelican"),
+];
+
+const APPROACH_NAMES: [(Approach, &str); Approach::VARIANT_COUNT] =
+    [(Approach::A, "A"), (Approach::B, "B")];
+
+const COMPARISON_NAMES: [(Comparison, &str); Comparison::VARIANT_COUNT] = [
+    (Comparison::Equal, "="),
+    (Comparison::AtLeast, ">="),
+    (Comparison::AtMost, "<="),
+];
+
+fn mode_name(mode: SystemMode) -> &'static str {
+    MODE_NAMES[mode.ordinal() as usize].1
+}
+
+fn approach_name(approach: Approach) -> &'static str {
+    APPROACH_NAMES[approach.ordinal() as usize].1
+}
+
+fn find<T: Copy>(names: &[(T, &str)], text: &str) -> Option<T> {
+    names
+        .iter()
+        .find(|(_, name)| *name == text)
+        .map(|(value, _)| *value)
+}
+
+fn parse_pin(text: &str) -> Option<Pins> {
+    Pins::VARIANTS
+        .iter()
+        .copied()
+        .find(|pin| pin.name() == text)
+}
+
+// Seconds with up to three decimals, such as "2s" or "2.150s".
+fn parse_time(text: &str) -> Option<Duration> {
+    let seconds: &str = text.strip_suffix('s')?;
+    let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+    if fraction.len() > 3 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let milliseconds: u64 = format!("{fraction:0<3}").parse().ok()?;
+    Some(Duration::from_millis(
+        whole.parse::<u64>().ok()? * 1_000 + milliseconds,
+    ))
+}
+
+fn time_text(at: Duration) -> String {
+    let milliseconds: u64 = at.as_millis();
+    format!("{}.{:03}s", milliseconds / 1000, milliseconds % 1000)
+}
+
+impl Counter {
+    fn parse(words: &[&str]) -> Option<Counter> {
+        match words {
+            ["cycles", mode] => find(&MODE_NAMES, mode).map(Counter::Cycles),
+            ["mode-switches"] => Some(Counter::ModeSwitches),
+            ["requests", approach] => find(&APPROACH_NAMES, approach).map(Counter::Requests),
+            ["served", approach] => find(&APPROACH_NAMES, approach).map(Counter::Served),
+            ["dropped", approach] => find(&APPROACH_NAMES, approach).map(Counter::Dropped),
+            _ => None,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Counter::Cycles(mode) => format!("cycles {}", mode_name(*mode)),
+            Counter::ModeSwitches => "mode-switches".to_string(),
+            Counter::Requests(approach) => format!("requests {}", approach_name(*approach)),
+            Counter::Served(approach) => format!("served {}", approach_name(*approach)),
+            Counter::Dropped(approach) => format!("dropped {}", approach_name(*approach)),
+        }
+    }
+
+    fn value(&self, statistics: &Statistics) -> u32 {
+        let approach = |approach: &Approach| &statistics.approaches[approach.ordinal() as usize];
+        match self {
+            Counter::Cycles(mode) => statistics.cycles[mode.ordinal() as usize],
+            Counter::ModeSwitches => statistics.mode_switches,
+            Counter::Requests(a) => approach(a).requests,
+            Counter::Served(a) => approach(a).served,
+            Counter::Dropped(a) => approach(a).dropped,
+        }
+    }
+}
+
+impl Expectation {
+    fn parse(words: &[&str]) -> Option<Expectation> {
+        match words {
+            ["mode", mode] => find(&MODE_NAMES, mode).map(Expectation::Mode),
+            [pin, "on"] => parse_pin(pin).map(|pin| Expectation::Lit(pin, true)),
+            [pin, "off"] => parse_pin(pin).map(|pin| Expectation::Lit(pin, false)),
+            [counter @ .., comparison, value] => Some(Expectation::Counter(
+                Counter::parse(counter)?,
+                find(&COMPARISON_NAMES, comparison)?,
+                value.parse().ok()?,
+            )),
+            _ => None,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Expectation::Lit(pin, on) => {
+                format!("{} {}", pin.name(), if *on { "on" } else { "off" })
+            }
+            Expectation::Mode(mode) => format!("mode {}", mode_name(*mode)),
+            Expectation::Counter(counter, comparison, value) => format!(
+                "{} {} {value}",
+                counter.text(),
+                COMPARISON_NAMES[comparison.ordinal() as usize].1
+            ),
+        }
+    }
+
+    // What the controller shows instead, when it is not what we expect.
+    fn check(&self, controller: &Controller) -> Option<String> {
+        match self {
+            Expectation::Lit(pin, on) => (controller.is_lit(*pin) != *on)
+                .then(|| format!("{} is {}", pin.name(), if *on { "off" } else { "on" })),
+            Expectation::Mode(mode) => {
+                let actual: SystemMode = controller.events.latest().mode;
+                (actual != *mode).then(|| format!("the mode is {}", mode_name(actual)))
+            }
+            Expectation::Counter(counter, comparison, value) => {
+                let actual: u32 = counter.value(&controller.statistics.snapshot());
+                let holds: bool = match comparison {
+                    Comparison::Equal => actual == *value,
+                    Comparison::AtLeast => actual >= *value,
+                    Comparison::AtMost => actual <= *value,
+                };
+                (!holds).then(|| format!("{} is {actual}", counter.text()))
+            }
+        }
+    }
+}
+
+// The words of a statement, with the number of the line it is on.
+type Statement<'a> = (usize, Vec<&'a str>);
+
+fn statements(text: &str) -> Vec<Statement<'_>> {
+    let mut statements: Vec<Statement> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line: &str = line.split('#').next().unwrap_or_default();
+        for statement in line.split(';') {
+            let words: Vec<&str> = statement.split_whitespace().collect();
+            if !words.is_empty() {
+                statements.push((number + 1, words));
+            }
+        }
+    }
+    statements
+}
+
+// How many statements there are up to the `end` of a block, not counting
+// that `end` itself.
+fn block_length(statements: &[Statement]) -> Option<usize> {
+    let mut depth: usize = 1;
+    statements.iter().position(|(_, words)| {
+        match words.as_slice() {
+            ["at", _, "repeat", ..] => depth += 1,
+            ["end"] => depth -= 1,
+            _ => {}
+        }
+        depth == 0
+    })
+}
+
+impl Scenario {
+    pub fn parse(text: &str) -> Result<Scenario, String> {
+        let mut scenario: Scenario = Scenario::default();
+        scenario.parse_block(&statements(text), Duration::from_ticks(0))?;
+        // Rounds of a repeat can overlap what comes after it.
+        scenario.inputs.sort_by_key(|(at, _)| *at);
+        scenario.expectations.sort_by_key(|(at, _)| *at);
+        Ok(scenario)
+    }
+
+    // Adds the statements, with their times counted from `start`.
+    fn parse_block(&mut self, statements: &[Statement], start: Duration) -> Result<(), String> {
+        let mut latest: Duration = Duration::from_ticks(0);
+        let mut i: usize = 0;
+        while i < statements.len() {
+            let (number, words) = &statements[i];
+            let error = || format!("line {number}: cannot make sense of {:?}", words.join(" "));
+            i += 1;
+            let (expect, at, rest): (bool, &str, &[&str]) = match words.as_slice() {
+                ["at", at, rest @ ..] => (false, at, rest),
+                ["expect", "at", at, rest @ ..] => (true, at, rest),
+                ["end"] => return Err(format!("line {number}: end without a repeat")),
+                _ => return Err(error()),
+            };
+            let at: Duration = parse_time(at).ok_or_else(error)?;
+            if at < latest {
+                return Err(format!("line {number}: goes back in time"));
+            }
+            latest = at;
+
+            if expect {
+                let expectation: Expectation = Expectation::parse(rest).ok_or_else(error)?;
+                self.expectations.push((start + at, expectation));
+                continue;
+            }
+            match rest {
+                ["mode", mode] => {
+                    let mode: SystemMode = find(&MODE_NAMES, mode)
+                        .filter(|mode| {
+                            !matches!(
+                                mode,
+                                SystemMode::Railway
+                                    | SystemMode::Manual
+                                    | SystemMode::AllRed
+                                    | SystemMode::Dark
+                                    | SystemMode::Pelican
+                            )
+                        })
+                        .ok_or_else(error)?;
+                    self.inputs.push((start + at, Input::Mode(mode)));
+                }
+                ["host", "mode", mode] => {
+                    let mode: SystemMode = find(&MODE_NAMES, mode)
+                        .filter(|mode| *mode != SystemMode::Railway)
+                        .ok_or_else(error)?;
+                    self.inputs.push((start + at, Input::HostMode(mode)));
+                }
+                [action @ ("press" | "release"), approach] => {
+                    let approach: Approach = find(&APPROACH_NAMES, approach).ok_or_else(error)?;
+                    let input: Input = match *action {
+                        "press" => Input::Press(approach),
+                        _ => Input::Release(approach),
+                    };
+                    self.inputs.push((start + at, input));
+                }
+                ["track", on @ ("on" | "off")] => {
+                    self.inputs.push((start + at, Input::Track(*on == "on")));
+                }
+                ["advance", on @ ("on" | "off")] => {
+                    self.inputs.push((start + at, Input::Advance(*on == "on")));
+                }
+                ["kerbside", on @ ("on" | "off")] => {
+                    self.inputs.push((start + at, Input::Kerbside(*on == "on")));
+                }
+                ["call", approach, on @ ("on" | "off")] => {
+                    let approach: Approach = find(&APPROACH_NAMES, approach).ok_or_else(error)?;
+                    self.inputs
+                        .push((start + at, Input::Call(approach, *on == "on")));
+                }
+                ["repeat", count, "every", every] => {
+                    let count: u32 = count.parse().map_err(|_| error())?;
+                    let every: Duration = parse_time(every).ok_or_else(error)?;
+                    let length: usize = block_length(&statements[i..])
+                        .ok_or_else(|| format!("line {number}: repeat without an end"))?;
+                    for round in 0..count {
+                        self.parse_block(&statements[i..i + length], start + at + every * round)?;
+                    }
+                    i += length + 1;
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(())
+    }
+
+    // The scenario as a text that reads back as the same scenario. Repeats come
+    // out as the rounds they make.
+    pub fn text(&self) -> String {
+        let mut lines: Vec<(Duration, String)> = Vec::new();
+        for (at, input) in self.inputs.iter() {
+            let action: String = match input {
+                Input::Mode(mode) => format!("mode {}", mode_name(*mode)),
+                Input::Press(approach) => format!("press {}", approach_name(*approach)),
+                Input::Release(approach) => format!("release {}", approach_name(*approach)),
+                Input::Call(approach, on) => format!(
+                    "call {} {}",
+                    approach_name(*approach),
+                    if *on { "on" } else { "off" }
+                ),
+                Input::Track(on) => format!("track {}", if *on { "on" } else { "off" }),
+                Input::HostMode(mode) => format!("host mode {}", mode_name(*mode)),
+                Input::Advance(on) => format!("advance {}", if *on { "on" } else { "off" }),
+                Input::Kerbside(on) => format!("kerbside {}", if *on { "on" } else { "off" }),
+            };
+            lines.push((*at, format!("at {} {action}", time_text(*at))));
+        }
+        for (at, expectation) in self.expectations.iter() {
+            lines.push((
+                *at,
+                format!("expect at {} {}", time_text(*at), expectation.text()),
+            ));
+        }
+        lines.sort_by_key(|(at, _)| *at);
+        let mut text: String = String::new();
+        for (_, line) in lines {
+            let _ = writeln!(text, "{line}");
+        }
+        text
+    }
+
+    // When the last input or expectation happens.
+    pub fn end(&self) -> Duration {
+        let inputs = self.inputs.iter().map(|(at, _)| *at);
+        let expectations = self.expectations.iter().map(|(at, _)| *at);
+        inputs
+            .chain(expectations)
+            .max()
+            .unwrap_or(Duration::from_ticks(0))
+    }
+
+    // Give the inputs to the controller as they come, until the given time
+    // since the start, and call `each_tick` after every tick of the output
+    // loop. The expectations are checked before the inputs of the same moment
+    // go in, and the ones that fail come back a line each.
+    pub fn run(
+        &self,
+        simulation: &mut Simulation,
+        controller: &Controller,
+        until: Duration,
+        mut each_tick: impl FnMut(&Controller),
+    ) -> Result<(), String> {
+        let start: Instant = Instant::now();
+        let end: Instant = start + until;
+        let mut inputs = self.inputs.iter().peekable();
+        let mut expectations = self.expectations.iter().peekable();
+        let mut failures: Vec<String> = Vec::new();
+        loop {
+            while let Some((at, expectation)) =
+                expectations.next_if(|(at, _)| start + *at <= Instant::now())
+            {
+                if let Some(actual) = expectation.check(controller) {
+                    failures.push(format!(
+                        "expected at {} {}, but {actual}",
+                        time_text(*at),
+                        expectation.text()
+                    ));
+                }
+            }
+            while let Some((_, input)) = inputs.next_if(|(at, _)| start + *at <= Instant::now()) {
+                apply(controller, *input);
+            }
+            if Instant::now() >= end {
+                break;
+            }
+            // The output loop ticks on whole multiples of `TICK`.
+            let next_tick: Instant = Instant::from_ticks(
+                (Instant::now().as_ticks() / TICK.as_ticks() + 1) * TICK.as_ticks(),
+            );
+            let mut step: Instant = next_tick.min(end);
+            if let Some((at, _)) = inputs.peek() {
+                step = step.min(start + *at);
+            }
+            if let Some((at, _)) = expectations.peek() {
+                step = step.min(start + *at);
+            }
+            simulation.run_until(step);
+            if Instant::now() == next_tick {
+                each_tick(controller);
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("\n"))
+        }
+    }
+}
+
+pub fn apply(controller: &Controller, input: Input) {
+    match input {
+        Input::Mode(mode) => controller.set_mode_switch(mode),
+        Input::Press(approach) => controller.set_button(approach, true),
+        Input::Release(approach) => controller.set_button(approach, false),
+        Input::Call(approach, on) => controller.set_preemption_input(approach, on),
+        Input::Track(on) => controller.set_track_circuit(on),
+        // Just as the host link does, see `host_link.rs`.
+        Input::HostMode(mode) => controller.system_mode_signal.signal(mode),
+        Input::Advance(on) => controller.set_advance_button(on),
+        Input::Kerbside(on) => controller.set_kerbside_detector(on),
+    }
+}
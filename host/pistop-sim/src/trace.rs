@@ -0,0 +1,236 @@
+/*
+ * Traces of a simulated controller at work: every change of the samples that
+ * the output loop takes, such as the outputs as it writes them to the pins,
+ * and every event that the controller records, each with the moment it
+ * happened.
+ *
+ * The text of a trace is meant for people. Tests compare it to golden traces
+ * that are checked in, so that a change to the way the crossing steps through
+ * its phases shows up as a diff in review. A trace has a line for every change
+ * of the outputs rather than for every tick, since the ticks in between add
+ * nothing but length. The rest of the samples goes into the waveforms, see
+ * `vcd.rs`.
+ */
+
+use std::fmt::Write;
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{EventKind, Pins};
+
+use crate::controller::Sample;
+use crate::vcd::Vcd;
+use crate::{Controller, Simulation};
+
+// How far a recording runs before it collects the events. The event log only
+// keeps the most recent events, so this has to be well within the time it
+// takes the controller to fill the log.
+const COLLECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Entry {
+    Sample(Sample),
+    Event(EventKind),
+}
+
+pub struct Trace {
+    pub start: Instant,
+    pub end: Instant,
+    pub entries: Vec<(Instant, Entry)>,
+}
+
+// The groups of outputs in the text of a trace, with a letter for every
+// output in the group.
+const GROUPS: [(&str, &[(Pins, char)]); 3] = [
+    (
+        "A",
+        &[
+            (Pins::ARed, 'R'),
+            (Pins::AAmber, 'A'),
+            (Pins::AGreen, 'G'),
+            (Pins::APedestrianRed, 'r'),
+            (Pins::APedestrianGreen, 'g'),
+            (Pins::APromise, 'P'),
+            (Pins::ABeeper, 'B'),
+        ],
+    ),
+    (
+        "B",
+        &[
+            (Pins::BRed, 'R'),
+            (Pins::BAmber, 'A'),
+            (Pins::BGreen, 'G'),
+            (Pins::BPedestrianRed, 'r'),
+            (Pins::BPedestrianGreen, 'g'),
+            (Pins::BPromise, 'P'),
+            (Pins::BBeeper, 'B'),
+        ],
+    ),
+    (
+        "common",
+        &[
+            (Pins::OnBoardPower, 'O'),
+            (Pins::Power, 'P'),
+            (Pins::SwitchingMode, 'S'),
+        ],
+    ),
+];
+
+impl Trace {
+    // A line per entry, with the time since the start of the trace and a
+    // header that explains the letters, such as
+    //
+    //     #           A        B        common
+    //     #           RAGrgPB  RAGrgPB  OPS
+    //          5.000  event    PhaseChanged(A, Go)
+    //          5.010  outputs  ..G.g..  R..r...  .P.
+    pub fn text(&self, title: &str) -> String {
+        let mut text: String = String::new();
+        let _ = writeln!(text, "# {title}");
+        let _ = writeln!(text, "#");
+        let mut names: String = String::new();
+        let mut letters: String = String::new();
+        for (name, pins) in GROUPS {
+            let _ = write!(names, "  {name:<w$}", w = pins.len());
+            letters.push_str("  ");
+            letters.extend(pins.iter().map(|(_, letter)| *letter));
+        }
+        let _ = writeln!(text, "#{:9}{}", "", names.trim_end());
+        let _ = writeln!(text, "#{:9}{}", "", letters);
+
+        let mut previous: Option<[bool; Pins::VARIANT_COUNT]> = None;
+        for (at, entry) in self.entries.iter() {
+            if let Entry::Sample(sample) = entry {
+                if previous == Some(sample.outputs) {
+                    continue;
+                }
+                previous = Some(sample.outputs);
+            }
+            let milliseconds: u64 = (*at - self.start).as_millis();
+            let _ = write!(
+                text,
+                "{:>6}.{:03}  ",
+                milliseconds / 1000,
+                milliseconds % 1000
+            );
+            match entry {
+                Entry::Sample(sample) => {
+                    text.push_str("outputs");
+                    for (_, pins) in GROUPS {
+                        text.push_str("  ");
+                        text.extend(pins.iter().map(|(pin, letter)| {
+                            if sample.outputs[pin.ordinal()] {
+                                *letter
+                            } else {
+                                '.'
+                            }
+                        }));
+                    }
+                }
+                Entry::Event(kind) => {
+                    let _ = write!(text, "event    {kind:?}");
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    // The waveforms of the samples, see `vcd.rs`. The events are left out,
+    // the mode and the lockout tell the same story.
+    pub fn vcd(&self, title: &str) -> String {
+        // Writing to memory cannot fail.
+        let mut vcd: Vcd<Vec<u8>> = Vcd::new(Vec::new(), title, self.start).unwrap();
+        for (at, entry) in self.entries.iter() {
+            if let Entry::Sample(sample) = entry {
+                vcd.sample(*at, sample).unwrap();
+            }
+        }
+        vcd.finish(self.end).unwrap();
+        String::from_utf8(vcd.into_inner()).unwrap()
+    }
+}
+
+// Records a trace while the simulation runs. The controller is only borrowed,
+// so that the scenario can keep pressing its buttons and turning its switch.
+pub struct Recording<'a> {
+    simulation: &'a mut Simulation,
+    controller: &'a Controller,
+    start: Instant,
+    next_event: u32,
+    entries: Vec<(Instant, Entry)>,
+}
+
+impl<'a> Recording<'a> {
+    // The trace starts with the sample as it is now. Events from before the
+    // start are left out.
+    pub fn start(simulation: &'a mut Simulation, controller: &'a Controller) -> Self {
+        controller.record_samples();
+        let mut next_event: u32 = 0;
+        loop {
+            let (events, next) = controller.events.read_from(next_event);
+            if events.is_empty() {
+                break;
+            }
+            next_event = next;
+        }
+        Recording {
+            simulation: simulation,
+            controller: controller,
+            start: Instant::now(),
+            next_event: next_event,
+            entries: vec![(Instant::now(), Entry::Sample(controller.sample()))],
+        }
+    }
+
+    // Run until the given time since the start of the recording.
+    pub fn run_until(&mut self, since_start: Duration) {
+        let until: Instant = self.start + since_start;
+        while Instant::now() < until {
+            let step: Instant = until.min(Instant::now() + COLLECT_INTERVAL);
+            self.simulation.run_until(step);
+            self.collect_events();
+        }
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(Instant::now() + duration - self.start);
+    }
+
+    pub fn finish(mut self) -> Trace {
+        self.collect_events();
+        for (at, sample) in self.controller.take_samples() {
+            self.entries.push((at, Entry::Sample(sample)));
+        }
+        // Events come before the samples that follow from them, since the
+        // output loop only takes a sample on the next tick. The sort is stable, so
+        // everything else stays in the order it happened.
+        self.entries
+            .sort_by_key(|(at, entry)| (at.as_ticks(), matches!(entry, Entry::Sample(_))));
+        Trace {
+            start: self.start,
+            end: Instant::now(),
+            entries: self.entries,
+        }
+    }
+
+    fn collect_events(&mut self) {
+        loop {
+            let (events, _) = self.controller.events.read_from(self.next_event);
+            if events.is_empty() {
+                break;
+            }
+            for event in events.iter() {
+                assert_eq!(
+                    event.sequence, self.next_event,
+                    "events dropped out of the log before they were collected"
+                );
+                self.entries.push((
+                    Instant::from_millis(event.timestamp_ms),
+                    Entry::Event(event.kind),
+                ));
+                self.next_event = event.sequence + 1;
+            }
+        }
+    }
+}
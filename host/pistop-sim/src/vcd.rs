@@ -0,0 +1,191 @@
+/*
+ * Waveforms of a simulated controller, written as a Value Change Dump that
+ * GTKWave and other waveform viewers open. There is a signal for every output
+ * pin, for the slow cycle, the fast cycle and the pip timer of the masker, for
+ * the lockout, for the system mode and for every permit that the system mode
+ * handler has handed out. Seeing the cycles of the masker next to the outputs
+ * makes it obvious which blink follows which cycle.
+ *
+ * The writer takes the samples as they come, so that the simulator can write
+ * the waveforms of a run that does not end. The time unit is a millisecond,
+ * counted from the start of the waveforms.
+ */
+
+use std::io::{self, Write};
+
+use embassy_time::Instant;
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Pins, SystemMode};
+
+use crate::controller::Sample;
+
+struct Variable {
+    scope: &'static str,
+    name: String,
+    width: u32,
+}
+
+// The signals in the order of `values()`.
+fn variables() -> Vec<Variable> {
+    let mut variables: Vec<Variable> = Vec::new();
+    for pin in Pins::VARIANTS {
+        variables.push(Variable {
+            scope: "outputs",
+            name: format!("{pin:?}"),
+            width: 1,
+        });
+    }
+    for name in ["slow_cycle", "fast_cycle", "pip_timer"] {
+        variables.push(Variable {
+            scope: "masker",
+            name: name.to_string(),
+            width: 1,
+        });
+    }
+    variables.push(Variable {
+        scope: "modes",
+        name: "lockout".to_string(),
+        width: 1,
+    });
+    variables.push(Variable {
+        scope: "modes",
+        name: "mode".to_string(),
+        width: u32::BITS - (SystemMode::VARIANT_COUNT as u32 - 1).leading_zeros(),
+    });
+    for mode in SystemMode::VARIANTS {
+        variables.push(Variable {
+            scope: "modes",
+            name: format!("permit_{mode:?}"),
+            width: 1,
+        });
+    }
+    variables
+}
+
+fn values(sample: &Sample) -> Vec<u32> {
+    let mut values: Vec<u32> = Vec::new();
+    values.extend(sample.outputs.iter().map(|on| *on as u32));
+    let (slow_cycle, fast_cycle, pip_timer) = sample.cycles;
+    values.extend([slow_cycle as u32, fast_cycle as u32, pip_timer as u32]);
+    values.push(sample.lockout as u32);
+    values.push(sample.mode.ordinal() as u32);
+    for mode in SystemMode::VARIANTS {
+        values.push(((sample.permits_handed_out >> mode.ordinal()) & 1) as u32);
+    }
+    values
+}
+
+// The short code that stands for a signal in the value changes. There are
+// fewer signals than printable characters, so one character will do.
+fn identifier(index: usize) -> char {
+    char::from(b'!' + index as u8)
+}
+
+pub struct Vcd<W: Write> {
+    out: W,
+    start: Instant,
+    variables: Vec<Variable>,
+    // The time and the values of the signals as last written.
+    written: Option<(u64, Vec<u32>)>,
+}
+
+impl<W: Write> Vcd<W> {
+    // Writes the header. The samples that follow must not be from before
+    // `start`.
+    pub fn new(mut out: W, title: &str, start: Instant) -> io::Result<Self> {
+        let variables: Vec<Variable> = variables();
+        writeln!(out, "$comment {title} $end")?;
+        let modes: Vec<String> = SystemMode::VARIANTS
+            .iter()
+            .map(|mode| format!("{} {mode:?}", mode.ordinal()))
+            .collect();
+        writeln!(out, "$comment mode is {} $end", modes.join(", "))?;
+        writeln!(out, "$version pistop-sim $end")?;
+        writeln!(out, "$timescale 1ms $end")?;
+        writeln!(out, "$scope module pistop $end")?;
+        let mut scope: Option<&str> = None;
+        for (i, variable) in variables.iter().enumerate() {
+            if scope != Some(variable.scope) {
+                if scope.is_some() {
+                    writeln!(out, "$upscope $end")?;
+                }
+                writeln!(out, "$scope module {} $end", variable.scope)?;
+                scope = Some(variable.scope);
+            }
+            let kind: &str = if variable.width == 1 { "wire" } else { "reg" };
+            writeln!(
+                out,
+                "$var {kind} {} {} {} $end",
+                variable.width,
+                identifier(i),
+                variable.name
+            )?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        Ok(Vcd {
+            out: out,
+            start: start,
+            variables: variables,
+            written: None,
+        })
+    }
+
+    // Writes the signals that changed since the previous sample.
+    pub fn sample(&mut self, at: Instant, sample: &Sample) -> io::Result<()> {
+        let time: u64 = (at - self.start).as_millis();
+        let values: Vec<u32> = values(sample);
+        match self.written.take() {
+            None => {
+                writeln!(self.out, "#{time}")?;
+                writeln!(self.out, "$dumpvars")?;
+                for (i, value) in values.iter().enumerate() {
+                    self.write_value(i, *value)?;
+                }
+                writeln!(self.out, "$end")?;
+            }
+            Some((written_at, written_values)) => {
+                if written_at != time {
+                    writeln!(self.out, "#{time}")?;
+                }
+                for (i, (value, written_value)) in values.iter().zip(written_values).enumerate() {
+                    if *value != written_value {
+                        self.write_value(i, *value)?;
+                    }
+                }
+            }
+        }
+        self.written = Some((time, values));
+        Ok(())
+    }
+
+    // Writes the end time, so that viewers show how long the last values
+    // lasted, and flushes.
+    pub fn finish(&mut self, at: Instant) -> io::Result<()> {
+        let time: u64 = (at - self.start).as_millis();
+        if let Some((written_at, _)) = &mut self.written
+            && *written_at < time
+        {
+            writeln!(self.out, "#{time}")?;
+            *written_at = time;
+        }
+        self.out.flush()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_value(&mut self, index: usize, value: u32) -> io::Result<()> {
+        if self.variables[index].width == 1 {
+            writeln!(self.out, "{value}{}", identifier(index))
+        } else {
+            writeln!(self.out, "b{value:b} {}", identifier(index))
+        }
+    }
+}
@@ -0,0 +1,117 @@
+/*
+ * The crossing closed with every head at steady red. It closes the way that
+ * any mode clears the crossing, and opens again with a start-up: the heads
+ * flash for a while and then clear as they do after flash mode.
+ */
+
+use embassy_time::{Duration, Instant};
+use pistop_protocol::{Approach, Pins, SystemMode};
+use pistop_sim::Controller;
+use pistop_sim::controller::TICK;
+
+mod common;
+use common::{run_until, start};
+
+const REDS: [Pins; 4] = [
+    Pins::ARed,
+    Pins::BRed,
+    Pins::APedestrianRed,
+    Pins::BPedestrianRed,
+];
+
+const OTHERS: [Pins; 6] = [
+    Pins::AAmber,
+    Pins::AGreen,
+    Pins::APedestrianGreen,
+    Pins::BAmber,
+    Pins::BGreen,
+    Pins::BPedestrianGreen,
+];
+
+fn all_red(controller: &Controller) -> bool {
+    REDS.iter().all(|pin| controller.is_lit(*pin))
+        && !OTHERS.iter().any(|pin| controller.is_lit(*pin))
+}
+
+#[test]
+fn closes_through_the_amber_and_the_clearance() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    controller.system_mode_signal.signal(SystemMode::AllRed);
+
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::ARed, true);
+    // The outputs only change on a tick of the output loop.
+    assert!(red_at - amber_at >= Duration::from_millis(6_000) - TICK);
+
+    simulation.run_for(Duration::from_secs(30));
+    assert_eq!(controller.sample().mode, SystemMode::AllRed);
+    let deadline: Instant = Instant::now() + Duration::from_secs(120);
+    while Instant::now() < deadline {
+        assert!(all_red(&controller), "{:?}", controller.outputs());
+        simulation.run_for(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn pedestrians_wait_while_it_is_closed() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.system_mode_signal.signal(SystemMode::AllRed);
+    simulation.run_for(Duration::from_secs(15));
+    assert_eq!(controller.sample().mode, SystemMode::AllRed);
+
+    controller.set_button(Approach::A, true);
+    simulation.run_for(Duration::from_millis(200));
+    controller.set_button(Approach::A, false);
+    simulation.run_for(Duration::from_secs(60));
+    assert!(controller.is_lit(Pins::APedestrianRed));
+    assert!(!controller.is_lit(Pins::APedestrianGreen));
+}
+
+#[test]
+fn opens_with_a_start_up() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller.system_mode_signal.signal(SystemMode::AllRed);
+    simulation.run_for(Duration::from_secs(30));
+    assert!(all_red(&controller));
+
+    let opened_at: Instant = Instant::now();
+    controller.system_mode_signal.signal(SystemMode::Normal);
+    // The heads flash, without any red, ...
+    let flash_at: Instant = run_until(&mut simulation, &controller, Pins::ARed, false);
+    assert!(flash_at - opened_at <= Duration::from_millis(600));
+    assert!(!controller.is_lit(Pins::BRed));
+    let mut amber_blinked: bool = false;
+    while !controller.is_lit(Pins::ARed) {
+        assert!(!controller.is_lit(Pins::AGreen));
+        assert!(!controller.is_lit(Pins::APedestrianGreen));
+        amber_blinked |= !controller.is_lit(Pins::AAmber);
+        simulation.run_for(Duration::from_millis(10));
+    }
+    assert!(amber_blinked);
+    // ... then clear, and only then does the first green come.
+    let red_at: Instant = Instant::now();
+    assert!(red_at - flash_at >= Duration::from_millis(8_000) - TICK);
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::AGreen, true);
+    assert!(green_at - red_at >= Duration::from_millis(4_000) - TICK);
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+}
+
+#[test]
+fn an_emergency_vehicle_skips_the_start_up() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller.system_mode_signal.signal(SystemMode::AllRed);
+    simulation.run_for(Duration::from_secs(30));
+
+    let called_at: Instant = Instant::now();
+    controller.set_preemption_input(Approach::B, true);
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::BGreen, true);
+    // The priority mode has an attention phase of its own.
+    assert!(green_at - called_at <= Duration::from_secs(3));
+
+    // Once the vehicle has passed, the crossing closes again.
+    controller.set_preemption_input(Approach::B, false);
+    simulation.run_for(Duration::from_secs(30));
+    assert_eq!(controller.sample().mode, SystemMode::AllRed);
+    assert!(all_red(&controller));
+}
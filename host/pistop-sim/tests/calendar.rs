@@ -0,0 +1,309 @@
+/*
+ * Which entry of the calendar wins on a day, and what that does to the
+ * schedule. The simulation only comes in at the end, the rest is plain logic.
+ */
+
+use embassy_time::Duration;
+use pistop_core::calendar::Calendar;
+use pistop_core::schedule::Schedule;
+use pistop_protocol::{
+    CalendarAction, CalendarEntry, Date, EVERY_DAY, SECONDS_PER_DAY, ScheduleAction, ScheduleEntry,
+    SystemMode, TimingPlan, WEEKEND, WORKDAYS, WallTime,
+};
+use pistop_sim::{Controller, Simulation};
+
+const FLASH: CalendarAction = CalendarAction::Forced(ScheduleAction::Mode(SystemMode::Flash));
+const NORMAL: CalendarAction = CalendarAction::Forced(ScheduleAction::Mode(SystemMode::Normal));
+const LIKE_SUNDAY: CalendarAction = CalendarAction::AsWeekday(6);
+
+fn date(year: u16, month: u8, day: u8) -> Date {
+    Date {
+        year: year,
+        month: month,
+        day: day,
+    }
+}
+
+fn time(year: u16, month: u8, day: u8, hour: u8, minute: u8) -> WallTime {
+    WallTime::from_date_time(year, month, day, hour, minute, 0).unwrap()
+}
+
+fn day(year: u16, month: u8, day: u8) -> u32 {
+    time(year, month, day, 0, 0).0 / SECONDS_PER_DAY
+}
+
+fn entry(first: Date, last: Date, every_year: bool, action: CalendarAction) -> CalendarEntry {
+    CalendarEntry {
+        first: first,
+        last: last,
+        every_year: every_year,
+        action: action,
+    }
+}
+
+fn calendar(entries: &[CalendarEntry]) -> Calendar {
+    let mut calendar: Calendar = Calendar::new();
+    for (index, entry) in entries.iter().enumerate() {
+        calendar.set(index as u8, Some(*entry)).unwrap();
+    }
+    calendar
+}
+
+// Normal mode on workdays, flashing in the evening and all weekend.
+fn schedule() -> Schedule {
+    let mut schedule: Schedule = Schedule::new();
+    let entries: [ScheduleEntry; 3] = [
+        ScheduleEntry {
+            weekdays: WORKDAYS,
+            start_minute: 7 * 60,
+            action: ScheduleAction::Mode(SystemMode::Normal),
+        },
+        ScheduleEntry {
+            weekdays: EVERY_DAY,
+            start_minute: 22 * 60,
+            action: ScheduleAction::Mode(SystemMode::Flash),
+        },
+        ScheduleEntry {
+            weekdays: WEEKEND,
+            start_minute: 10 * 60,
+            action: ScheduleAction::Mode(SystemMode::PriorityA),
+        },
+    ];
+    for (index, entry) in entries.iter().enumerate() {
+        schedule.set(index as u8, Some(*entry)).unwrap();
+    }
+    schedule
+}
+
+#[test]
+fn holidays_come_back_every_year() {
+    let christmas: CalendarEntry = entry(date(2000, 12, 25), date(2000, 12, 26), true, FLASH);
+    let calendar: Calendar = calendar(&[christmas]);
+    assert_eq!(calendar.action_on(day(2024, 12, 25)), Some(FLASH));
+    assert_eq!(calendar.action_on(day(2031, 12, 26)), Some(FLASH));
+    assert_eq!(calendar.action_on(day(2031, 12, 27)), None);
+    assert_eq!(calendar.action_on(day(2031, 12, 24)), None);
+}
+
+#[test]
+fn holidays_may_run_over_new_year() {
+    let winter: CalendarEntry = entry(date(2000, 12, 30), date(2000, 1, 2), true, LIKE_SUNDAY);
+    let calendar: Calendar = calendar(&[winter]);
+    for (year, month, day_of_month) in [(2024, 12, 30), (2024, 12, 31), (2025, 1, 1), (2025, 1, 2)]
+    {
+        assert_eq!(
+            calendar.action_on(day(year, month, day_of_month)),
+            Some(LIKE_SUNDAY)
+        );
+    }
+    assert_eq!(calendar.action_on(day(2025, 1, 3)), None);
+    assert_eq!(calendar.action_on(day(2024, 12, 29)), None);
+}
+
+#[test]
+fn one_off_days_come_once() {
+    let open_day: CalendarEntry = entry(date(2024, 5, 18), date(2024, 5, 18), false, NORMAL);
+    let calendar: Calendar = calendar(&[open_day]);
+    assert_eq!(calendar.action_on(day(2024, 5, 18)), Some(NORMAL));
+    assert_eq!(calendar.action_on(day(2025, 5, 18)), None);
+}
+
+#[test]
+fn the_most_specific_entry_wins() {
+    let summer: CalendarEntry = entry(date(2000, 7, 1), date(2000, 8, 31), true, LIKE_SUNDAY);
+    let fair: CalendarEntry = entry(date(2000, 7, 10), date(2000, 7, 20), true, FLASH);
+    let open_day: CalendarEntry = entry(date(2024, 7, 1), date(2024, 7, 31), false, NORMAL);
+    // Whichever order they go in.
+    for entries in [[summer, fair, open_day], [open_day, fair, summer]] {
+        let calendar: Calendar = calendar(&entries);
+        // Dates beat every year, however long they last.
+        assert_eq!(calendar.action_on(day(2024, 7, 15)), Some(NORMAL));
+        // Short beats long.
+        assert_eq!(calendar.action_on(day(2025, 7, 15)), Some(FLASH));
+        assert_eq!(calendar.action_on(day(2025, 7, 25)), Some(LIKE_SUNDAY));
+    }
+}
+
+#[test]
+fn a_tie_goes_to_the_last_entry() {
+    let first: CalendarEntry = entry(date(2000, 5, 1), date(2000, 5, 1), true, FLASH);
+    let second: CalendarEntry = entry(date(2000, 5, 1), date(2000, 5, 1), true, NORMAL);
+    assert_eq!(
+        calendar(&[first, second]).action_on(day(2024, 5, 1)),
+        Some(NORMAL)
+    );
+    assert_eq!(
+        calendar(&[second, first]).action_on(day(2024, 5, 1)),
+        Some(FLASH)
+    );
+}
+
+#[test]
+fn leap_days_only_come_in_leap_years() {
+    let leap_day: CalendarEntry = entry(date(2000, 2, 29), date(2000, 2, 29), true, FLASH);
+    let calendar: Calendar = calendar(&[leap_day]);
+    assert_eq!(calendar.action_on(day(2028, 2, 29)), Some(FLASH));
+    assert_eq!(calendar.action_on(day(2027, 2, 28)), None);
+    assert_eq!(calendar.action_on(day(2027, 3, 1)), None);
+}
+
+#[test]
+fn refuses_entries_that_make_no_sense() {
+    let mut calendar: Calendar = Calendar::new();
+    let backwards: CalendarEntry = entry(date(2024, 5, 2), date(2024, 5, 1), false, FLASH);
+    assert!(calendar.set(0, Some(backwards)).is_err());
+    let no_such_day: CalendarEntry = entry(date(2024, 2, 30), date(2024, 3, 1), false, FLASH);
+    assert!(calendar.set(0, Some(no_such_day)).is_err());
+    let no_such_weekday: CalendarEntry = entry(
+        date(2000, 1, 1),
+        date(2000, 1, 1),
+        true,
+        CalendarAction::AsWeekday(7),
+    );
+    assert!(calendar.set(0, Some(no_such_weekday)).is_err());
+    let holiday: CalendarEntry = entry(date(2000, 1, 1), date(2000, 1, 1), true, FLASH);
+    assert!(calendar.set(16, Some(holiday)).is_err());
+    assert!(calendar.set(15, Some(holiday)).is_ok());
+    assert_eq!(calendar.get(15), Ok(Some(holiday)));
+}
+
+#[test]
+fn a_forced_mode_holds_from_midnight_to_midnight() {
+    // 2024-05-01 is a Wednesday.
+    let labour_day: CalendarEntry = entry(date(2000, 5, 1), date(2000, 5, 1), true, FLASH);
+    let calendar: Calendar = calendar(&[labour_day]);
+    let schedule: Schedule = schedule();
+    assert_eq!(
+        schedule.mode_at(&calendar, time(2024, 5, 1, 12, 0)),
+        Some((time(2024, 5, 1, 0, 0), SystemMode::Flash))
+    );
+    // Neither the morning nor the evening entry starts on the day itself.
+    assert_eq!(
+        schedule.mode_at(&calendar, time(2024, 5, 1, 23, 0)),
+        Some((time(2024, 5, 1, 0, 0), SystemMode::Flash))
+    );
+    // The next day goes on from the evening entry of the day before.
+    assert_eq!(
+        schedule.mode_at(&calendar, time(2024, 5, 2, 6, 0)),
+        Some((time(2024, 4, 30, 22, 0), SystemMode::Flash))
+    );
+    assert_eq!(
+        schedule.mode_at(&calendar, time(2024, 5, 2, 7, 0)),
+        Some((time(2024, 5, 2, 7, 0), SystemMode::Normal))
+    );
+}
+
+#[test]
+fn a_day_like_another_takes_its_entries() {
+    let open_day: CalendarEntry = entry(date(2024, 5, 1), date(2024, 5, 1), false, LIKE_SUNDAY);
+    let calendar: Calendar = calendar(&[open_day]);
+    let schedule: Schedule = schedule();
+    assert_eq!(
+        schedule.mode_at(&calendar, time(2024, 5, 1, 8, 0)),
+        Some((time(2024, 4, 30, 22, 0), SystemMode::Flash))
+    );
+    assert_eq!(
+        schedule.mode_at(&calendar, time(2024, 5, 1, 11, 0)),
+        Some((time(2024, 5, 1, 10, 0), SystemMode::PriorityA))
+    );
+}
+
+const RUSH: TimingPlan = TimingPlan {
+    attention_ms: 2_000,
+    go_ms: 25_000,
+    yield_ms: 4_000,
+    clear_ms: 3_000,
+};
+
+const QUIET: TimingPlan = TimingPlan {
+    attention_ms: 2_000,
+    go_ms: 10_000,
+    yield_ms: 4_000,
+    clear_ms: 3_000,
+};
+
+// The market is held on a single day, with the timings of the rush hour.
+fn market() -> Calendar {
+    calendar(&[entry(
+        date(2024, 5, 1),
+        date(2024, 5, 1),
+        false,
+        CalendarAction::Forced(ScheduleAction::Timings(RUSH)),
+    )])
+}
+
+// The schedule above, with quiet timings from the morning on workdays.
+fn schedule_with_timings() -> Schedule {
+    let mut schedule: Schedule = schedule();
+    let quiet: ScheduleEntry = ScheduleEntry {
+        weekdays: WORKDAYS,
+        start_minute: 9 * 60,
+        action: ScheduleAction::Timings(QUIET),
+    };
+    schedule.set(3, Some(quiet)).unwrap();
+    schedule
+}
+
+#[test]
+fn forced_timings_leave_the_modes_alone() {
+    let calendar: Calendar = market();
+    let schedule: Schedule = schedule();
+    assert_eq!(
+        schedule.mode_at(&calendar, time(2024, 5, 1, 8, 0)),
+        Some((time(2024, 5, 1, 7, 0), SystemMode::Normal))
+    );
+    assert_eq!(
+        schedule.timings_at(&calendar, time(2024, 5, 1, 8, 0)),
+        Some((time(2024, 5, 1, 0, 0), RUSH))
+    );
+    // Without timing entries of its own, the next day has none at all.
+    assert_eq!(schedule.timings_at(&calendar, time(2024, 5, 2, 0, 0)), None);
+    assert_eq!(schedule.timings_at(&calendar, time(2024, 5, 2, 8, 0)), None);
+}
+
+#[test]
+fn the_day_after_a_forced_day_goes_by_the_schedule() {
+    let calendar: Calendar = market();
+    let schedule: Schedule = schedule_with_timings();
+    assert_eq!(
+        schedule.timings_at(&calendar, time(2024, 5, 1, 10, 0)),
+        Some((time(2024, 5, 1, 0, 0), RUSH))
+    );
+    // The quiet timings from the Tuesday go on, until they start again.
+    assert_eq!(
+        schedule.timings_at(&calendar, time(2024, 5, 2, 0, 0)),
+        Some((time(2024, 4, 30, 9, 0), QUIET))
+    );
+    assert_eq!(
+        schedule.timings_at(&calendar, time(2024, 5, 2, 10, 0)),
+        Some((time(2024, 5, 2, 9, 0), QUIET))
+    );
+}
+
+#[test]
+fn the_controller_drops_the_forced_timings_at_midnight() {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    *controller.schedule.try_lock().unwrap() = schedule_with_timings();
+    *controller.calendar.try_lock().unwrap() = market();
+    controller.rtc.set(time(2024, 5, 1, 23, 55));
+    controller.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(120));
+    assert_eq!(controller.settings.try_lock().unwrap().normal_go_ms, 25_000);
+    simulation.run_for(Duration::from_secs(5 * 60));
+    assert_eq!(controller.settings.try_lock().unwrap().normal_go_ms, 10_000);
+}
+
+#[test]
+fn the_controller_flashes_on_a_holiday() {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    *controller.schedule.try_lock().unwrap() = schedule();
+    let labour_day: CalendarEntry = entry(date(2000, 5, 1), date(2000, 5, 1), true, FLASH);
+    *controller.calendar.try_lock().unwrap() = calendar(&[labour_day]);
+    controller.rtc.set(time(2024, 5, 1, 9, 0));
+    controller.set_mode_switch(SystemMode::Normal);
+    controller.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(120));
+    assert_eq!(controller.sample().mode, SystemMode::Flash);
+}
@@ -0,0 +1,279 @@
+/*
+ * Simulated controllers on a CAN bus in memory. The messages are checked
+ * frame by frame first, then the controllers run together to see that they
+ * change modes as one, pass on presses of the buttons and keep a green wave
+ * without the sync wire. Unplugging a port shows how a box gets by on its own.
+ */
+
+use embassy_time::{Duration, Instant};
+use pistop_core::can_link::{CanFrame, Message};
+use pistop_core::green_wave::Role;
+use pistop_core::settings::Settings;
+use pistop_protocol::{Approach, ConfigKey, EventKind, Pins, SystemMode};
+use pistop_sim::can_bus::SimCanBus;
+use pistop_sim::{Controller, Simulation};
+
+mod common;
+use common::set;
+
+// Long enough for the mode reader to see the switch and for normal mode to
+// finish its turns. The turn that waits for the permit goes before the mode
+// switch, so that can take two turns.
+const MODE_CHANGE_TIME: Duration = Duration::from_secs(50);
+
+// A box goes to a mode first, and only then do the others hear of it.
+const LINE_MODE_CHANGE_TIME: Duration = Duration::from_secs(100);
+
+fn frame(id: u16, data: &[u8]) -> CanFrame {
+    CanFrame {
+        id: id,
+        data: data.try_into().unwrap(),
+    }
+}
+
+// Controllers on one bus, numbered from node 1, all with their switch on
+// normal mode and out of the startup.
+fn line(count: usize) -> (Simulation, &'static SimCanBus, Vec<Controller>) {
+    let mut simulation: Simulation = Simulation::new();
+    let bus: &'static SimCanBus = Box::leak(Box::new(SimCanBus::new()));
+    let mut line: Vec<Controller> = Vec::new();
+    for node in 1..=count {
+        let mut controller: Controller = Controller::new();
+        controller.can_port = bus.port();
+        set(&controller, ConfigKey::CanNodeId, node as u32);
+        controller.set_mode_switch(SystemMode::Normal);
+        controller.spawn(&mut simulation);
+        line.push(controller);
+    }
+    simulation.run_for(Duration::from_secs(30));
+    (simulation, bus, line)
+}
+
+fn modes(line: &[Controller]) -> Vec<SystemMode> {
+    line.iter()
+        .map(|controller| controller.sample().mode)
+        .collect()
+}
+
+// What is still in the event log, which only keeps the most recent events.
+fn events(controller: &Controller) -> Vec<EventKind> {
+    let mut kinds: Vec<EventKind> = Vec::new();
+    let mut next: u32 = 0;
+    loop {
+        let (events, after) = controller.events.read_from(next);
+        if events.is_empty() {
+            return kinds;
+        }
+        kinds.extend(events.iter().map(|event| event.kind));
+        next = after;
+    }
+}
+
+fn messages_since(bus: &SimCanBus, since: Instant) -> Vec<(u8, Message)> {
+    bus.frames()
+        .iter()
+        .filter(|(at, _)| *at >= since)
+        .filter_map(|(_, frame)| Message::decode(frame))
+        .collect()
+}
+
+#[test]
+fn messages_go_both_ways() {
+    let messages: [Message; 4] = [
+        Message::CycleSync,
+        Message::ModeRequest(SystemMode::PriorityB),
+        Message::Demand(Approach::B),
+        Message::Heartbeat,
+    ];
+    for message in messages {
+        assert_eq!(Message::decode(&message.encode(5)), Some((5, message)));
+    }
+    assert_eq!(Message::CycleSync.encode(5), frame(0x015, &[]));
+    assert_eq!(
+        Message::ModeRequest(SystemMode::Flash).encode(15),
+        frame(0x02f, &[1])
+    );
+    assert_eq!(Message::Heartbeat.encode(1), frame(0x071, &[]));
+}
+
+#[test]
+fn ignores_frames_it_does_not_know() {
+    // A kind that we don't have.
+    assert_eq!(Message::decode(&frame(0x051, &[])), None);
+    // The wrong length.
+    assert_eq!(Message::decode(&frame(0x021, &[])), None);
+    assert_eq!(Message::decode(&frame(0x011, &[0])), None);
+    // No such mode or approach.
+    assert_eq!(Message::decode(&frame(0x021, &[9])), None);
+    assert_eq!(Message::decode(&frame(0x031, &[2])), None);
+    // Node 0 is not on the bus.
+    assert_eq!(Message::decode(&frame(0x070, &[])), None);
+}
+
+#[test]
+fn refuses_node_numbers_that_do_not_fit() {
+    let mut settings: Settings = Settings::new();
+    assert!(settings.set(ConfigKey::CanNodeId, 15).is_ok());
+    assert!(settings.set(ConfigKey::CanNodeId, 16).is_err());
+    assert!(settings.set(ConfigKey::CanNodeId, 0).is_ok());
+}
+
+#[test]
+fn every_node_sends_heartbeats() {
+    let (mut simulation, bus, line) = line(2);
+    assert!(events(&line[0]).contains(&EventKind::NodeFound(2)));
+    assert!(events(&line[1]).contains(&EventKind::NodeFound(1)));
+
+    let since: Instant = Instant::now();
+    simulation.run_for(Duration::from_secs(10));
+    let messages: Vec<(u8, Message)> = messages_since(bus, since);
+    for node in [1, 2] {
+        let heartbeats: usize = messages
+            .iter()
+            .filter(|message| **message == (node, Message::Heartbeat))
+            .count();
+        assert!((19..=21).contains(&heartbeats), "{heartbeats}");
+    }
+}
+
+#[test]
+fn a_box_off_the_bus_stays_quiet() {
+    let (mut simulation, bus, line) = line(2);
+    set(&line[1], ConfigKey::CanNodeId, 0);
+    simulation.run_for(Duration::from_secs(5));
+
+    let since: Instant = Instant::now();
+    line[1].set_mode_switch(SystemMode::Flash);
+    simulation.run_for(MODE_CHANGE_TIME);
+    assert!(
+        messages_since(bus, since)
+            .iter()
+            .all(|(node, _)| *node == 1)
+    );
+    assert_eq!(modes(&line), [SystemMode::Normal, SystemMode::Flash]);
+    assert!(events(&line[0]).contains(&EventKind::NodeLost(2)));
+}
+
+#[test]
+fn the_line_changes_modes_as_one() {
+    let (mut simulation, _bus, line) = line(3);
+    assert_eq!(modes(&line), [SystemMode::Normal; 3]);
+
+    line[1].set_mode_switch(SystemMode::Flash);
+    simulation.run_for(LINE_MODE_CHANGE_TIME);
+    assert_eq!(modes(&line), [SystemMode::Flash; 3]);
+
+    // Whoever changes the mode last, wins.
+    line[2].set_mode_switch(SystemMode::PriorityB);
+    simulation.run_for(LINE_MODE_CHANGE_TIME);
+    assert_eq!(modes(&line), [SystemMode::PriorityB; 3]);
+}
+
+#[test]
+fn a_press_goes_to_every_box_once() {
+    let (mut simulation, bus, line) = line(3);
+    let since: Instant = Instant::now();
+    line[0].set_button(Approach::B, true);
+    simulation.run_for(Duration::from_millis(150));
+    line[0].set_button(Approach::B, false);
+    simulation.run_for(Duration::from_secs(1));
+
+    for controller in line.iter() {
+        let presses: usize = events(controller)
+            .iter()
+            .filter(|kind| **kind == EventKind::ButtonPressed(Approach::B))
+            .count();
+        assert_eq!(presses, 1);
+    }
+    // The others don't pass it on again.
+    let demands: Vec<(u8, Message)> = messages_since(bus, since)
+        .into_iter()
+        .filter(|(_, message)| *message != Message::Heartbeat)
+        .collect();
+    assert_eq!(demands, [(1, Message::Demand(Approach::B))]);
+}
+
+#[test]
+fn the_green_wave_runs_over_the_bus() {
+    const CYCLE_MS: u64 = 60_000;
+    const OFFSET_MS: u64 = 15_000;
+    let mut simulation: Simulation = Simulation::new();
+    let bus: &'static SimCanBus = Box::leak(Box::new(SimCanBus::new()));
+    // No sync wire in between, and the follower has a slow crystal.
+    let roles: [(Role, u64, u64); 2] = [
+        (Role::Master, 0, CYCLE_MS),
+        (Role::Follower, OFFSET_MS, CYCLE_MS + 60),
+    ];
+    let mut line: Vec<Controller> = Vec::new();
+    for (node, (role, offset_ms, cycle_ms)) in roles.iter().enumerate() {
+        let mut controller: Controller = Controller::new();
+        controller.can_port = bus.port();
+        {
+            // scope for the mutex guard...
+            let mut settings = controller.settings.try_lock().unwrap();
+            settings.can_node_id = node as u8 + 1;
+            settings.green_wave = *role;
+            settings.green_wave_cycle_ms = *cycle_ms;
+            settings.green_wave_offset_ms = *offset_ms;
+        }
+        controller.set_mode_switch(SystemMode::Normal);
+        controller.spawn(&mut simulation);
+        line.push(controller);
+    }
+    simulation.run_for(Duration::from_secs(120));
+
+    let mut greens: Vec<u64> = Vec::new();
+    let mut lit: bool = line[1].is_lit(Pins::AGreen);
+    let until: Instant = Instant::now() + Duration::from_secs(600);
+    while Instant::now() < until {
+        simulation.run_for(Duration::from_millis(10));
+        let now_lit: bool = line[1].is_lit(Pins::AGreen);
+        if now_lit && !lit {
+            greens.push(Instant::now().as_millis());
+        }
+        lit = now_lit;
+    }
+    assert_eq!(greens.len(), 10, "{greens:?}");
+    for green_ms in greens {
+        // The slow crystal loses 60ms in a cycle, and the outputs change on
+        // a tick of 10ms.
+        let into_cycle_ms: u64 = (green_ms + CYCLE_MS - OFFSET_MS) % CYCLE_MS;
+        assert!(
+            into_cycle_ms.min(CYCLE_MS - into_cycle_ms) <= 80,
+            "{green_ms}"
+        );
+    }
+}
+
+#[test]
+fn a_box_on_its_own_goes_back_to_its_own_mode() {
+    let (mut simulation, _bus, line) = line(2);
+    line[0].set_mode_switch(SystemMode::Flash);
+    simulation.run_for(LINE_MODE_CHANGE_TIME);
+    assert_eq!(modes(&line), [SystemMode::Flash; 2]);
+
+    // The cable of the second box comes out. Both lose the other, but only
+    // the second box was in a mode that it did not pick itself.
+    line[1].can_port.set_plugged(false);
+    simulation.run_for(MODE_CHANGE_TIME);
+    assert_eq!(modes(&line), [SystemMode::Flash, SystemMode::Normal]);
+    assert!(events(&line[0]).contains(&EventKind::NodeLost(2)));
+    assert!(events(&line[1]).contains(&EventKind::NodeLost(1)));
+
+    // Back on the bus, it hears the others again, but nothing is sent again.
+    line[1].can_port.set_plugged(true);
+    simulation.run_for(Duration::from_secs(1));
+    let kinds: Vec<EventKind> = events(&line[1]);
+    let lost: usize = kinds
+        .iter()
+        .rposition(|kind| *kind == EventKind::NodeLost(1))
+        .unwrap();
+    assert!(kinds[lost..].contains(&EventKind::NodeFound(1)));
+    simulation.run_for(MODE_CHANGE_TIME);
+    assert_eq!(modes(&line), [SystemMode::Flash, SystemMode::Normal]);
+
+    // From then on they go as one again.
+    line[1].set_mode_switch(SystemMode::PriorityA);
+    simulation.run_for(LINE_MODE_CHANGE_TIME);
+    assert_eq!(modes(&line), [SystemMode::PriorityA; 2]);
+}
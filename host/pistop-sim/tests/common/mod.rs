@@ -0,0 +1,83 @@
+/*
+ * What the tests of a simulated controller have in common: starting it in a
+ * mode, changing its settings, pressing its buttons and running it until a
+ * lamp changes.
+ */
+
+// Not every test uses every one of these.
+#![allow(dead_code)]
+
+use std::sync::atomic::Ordering;
+
+use embassy_time::{Duration, Instant};
+use pistop_protocol::{Approach, ConfigKey, Pins, SystemMode};
+use pistop_sim::{Controller, Simulation};
+
+// Starts with the rotary switch in the mode and runs long enough for the
+// controller to have left the flashing that it starts with.
+pub fn start(mode: SystemMode) -> (Simulation, Controller) {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.set_mode_switch(mode);
+    controller.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(30));
+    (simulation, controller)
+}
+
+// Starts in flash mode and has the host ask for the mode, for the modes that
+// the rotary switch has no position for.
+pub fn start_from_host(mode: SystemMode) -> (Simulation, Controller) {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.set_mode_switch(SystemMode::Flash);
+    controller.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(5));
+    controller.system_mode_signal.signal(mode);
+    (simulation, controller)
+}
+
+// Starts the controller and runs it until it has settled into the mode, so
+// that the recordings start from there.
+pub fn start_in(mode: SystemMode) -> (Simulation, Controller) {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.spawn(&mut simulation);
+    controller.set_mode_switch(mode);
+    simulation.settle();
+    let deadline: Instant = Instant::now() + Duration::from_secs(60);
+    while controller.events.latest().mode != mode || controller.lockout.load(Ordering::Relaxed) {
+        assert!(Instant::now() < deadline, "never got to {mode:?}");
+        simulation.run_for(Duration::from_millis(10));
+    }
+    (simulation, controller)
+}
+
+pub fn set(controller: &Controller, key: ConfigKey, value: u32) {
+    controller
+        .settings
+        .try_lock()
+        .unwrap()
+        .set(key, value)
+        .unwrap();
+}
+
+pub fn press(simulation: &mut Simulation, controller: &Controller, approach: Approach) {
+    controller.set_button(approach, true);
+    simulation.run_for(Duration::from_millis(100));
+    controller.set_button(approach, false);
+}
+
+// Runs until the pin is lit, or no longer is, and returns when it happened.
+pub fn run_until(
+    simulation: &mut Simulation,
+    controller: &Controller,
+    pin: Pins,
+    lit: bool,
+) -> Instant {
+    let deadline: Instant = Instant::now() + Duration::from_secs(120);
+    while controller.is_lit(pin) != lit {
+        assert!(Instant::now() < deadline, "{pin:?} never went {lit}");
+        simulation.run_for(Duration::from_millis(10));
+    }
+    Instant::now()
+}
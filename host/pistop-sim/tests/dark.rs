@@ -0,0 +1,155 @@
+/*
+ * The crossing switched off, with every lamp dark. As with a real controller
+ * that is switched off and on again, the heads flash for a while before they
+ * go dark, and they come back with a flash, an amber and an all red before
+ * anyone gets a green.
+ */
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, Pins, SystemMode};
+use pistop_sim::Controller;
+use pistop_sim::controller::TICK;
+
+mod common;
+use common::{press, run_until, start};
+
+const HEADS: [Pins; 10] = [
+    Pins::ARed,
+    Pins::AAmber,
+    Pins::AGreen,
+    Pins::APedestrianRed,
+    Pins::APedestrianGreen,
+    Pins::BRed,
+    Pins::BAmber,
+    Pins::BGreen,
+    Pins::BPedestrianRed,
+    Pins::BPedestrianGreen,
+];
+
+fn dark(controller: &Controller) -> bool {
+    !HEADS.iter().any(|pin| controller.is_lit(*pin))
+}
+
+#[test]
+fn goes_dark_after_a_flash() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.system_mode_signal.signal(SystemMode::Dark);
+
+    // The heads flash first, with the ambers going on and off.
+    let flash_at: Instant = run_until(&mut simulation, &controller, Pins::ARed, false);
+    // The heads are dark between the blinks of the ambers too, so they have
+    // gone dark for good once nothing has been lit for a few seconds.
+    let mut lit_at: Instant = flash_at;
+    let mut amber_blinked: bool = false;
+    while Instant::now() - lit_at < Duration::from_secs(3) {
+        if !dark(&controller) {
+            lit_at = Instant::now();
+        }
+        amber_blinked |= !controller.is_lit(Pins::AAmber);
+        assert!(!controller.is_lit(Pins::AGreen));
+        assert!(!controller.is_lit(Pins::BGreen));
+        assert!(Instant::now() - flash_at < Duration::from_secs(60));
+        simulation.run_for(Duration::from_millis(10));
+    }
+    assert!(amber_blinked);
+    assert!(lit_at - flash_at >= Duration::from_millis(10_000) - TICK);
+    assert_eq!(controller.sample().mode, SystemMode::Dark);
+
+    let deadline: Instant = Instant::now() + Duration::from_secs(120);
+    while Instant::now() < deadline {
+        assert!(dark(&controller), "{:?}", controller.outputs());
+        simulation.run_for(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn a_pip_shows_that_it_still_runs() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.system_mode_signal.signal(SystemMode::Dark);
+    simulation.run_for(Duration::from_secs(30));
+    assert!(dark(&controller));
+
+    let (mut on, mut off): (u32, u32) = (0, 0);
+    for _ in 0..1_000 {
+        if controller.is_lit(Pins::SwitchingMode) {
+            on += 1;
+        } else {
+            off += 1;
+        }
+        simulation.run_for(Duration::from_millis(10));
+    }
+    // A pip is short, the LED is off most of the time.
+    assert!(on > 0);
+    assert!(off > 4 * on);
+}
+
+#[test]
+fn closes_through_the_amber_and_the_clearance() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    controller.system_mode_signal.signal(SystemMode::Dark);
+
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::ARed, true);
+    // The outputs only change on a tick of the output loop.
+    assert!(red_at - amber_at >= Duration::from_millis(6_000) - TICK);
+    simulation.run_for(Duration::from_secs(60));
+    assert!(dark(&controller));
+}
+
+#[test]
+fn comes_back_through_flash_and_all_red() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.system_mode_signal.signal(SystemMode::Dark);
+    simulation.run_for(Duration::from_secs(30));
+    assert!(dark(&controller));
+
+    controller.system_mode_signal.signal(SystemMode::Normal);
+    let flash_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    while !controller.is_lit(Pins::ARed) {
+        assert!(!controller.is_lit(Pins::AGreen));
+        assert!(!controller.is_lit(Pins::BGreen));
+        simulation.run_for(Duration::from_millis(10));
+    }
+    let red_at: Instant = Instant::now();
+    assert!(red_at - flash_at >= Duration::from_millis(8_000) - TICK);
+    assert!(controller.is_lit(Pins::BRed));
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::AGreen, true);
+    assert!(green_at - red_at >= Duration::from_millis(4_000) - TICK);
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+    let statistics = controller.statistics.snapshot();
+    assert_eq!(statistics.cycles[SystemMode::Dark.ordinal() as usize], 1);
+}
+
+#[test]
+fn the_button_makes_no_promise_while_dark() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.system_mode_signal.signal(SystemMode::Dark);
+    simulation.run_for(Duration::from_secs(30));
+    assert!(dark(&controller));
+
+    press(&mut simulation, &controller, Approach::A);
+    for _ in 0..500 {
+        assert!(!controller.is_lit(Pins::APromise));
+        simulation.run_for(Duration::from_millis(10));
+    }
+    let statistics = controller.statistics.snapshot();
+    assert_eq!(
+        statistics.approaches[Approach::A.ordinal() as usize].requests,
+        0
+    );
+}
+
+#[test]
+fn an_emergency_vehicle_waits_for_the_start_up() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.system_mode_signal.signal(SystemMode::Dark);
+    simulation.run_for(Duration::from_secs(30));
+
+    let called_at: Instant = Instant::now();
+    controller.set_preemption_input(Approach::B, true);
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::BGreen, true);
+    assert!(green_at - called_at >= Duration::from_millis(12_000) - TICK);
+    assert_eq!(controller.sample().mode, SystemMode::PriorityB);
+}
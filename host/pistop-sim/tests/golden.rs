@@ -0,0 +1,156 @@
+/*
+ * Runs the controller through scenarios and compares the traces with the
+ * golden traces in `tests/golden`. When a change to the controller is meant
+ * to change a trace, run the tests with `UPDATE_GOLDEN=1` to write the new
+ * traces and check the diff before committing them. With
+ * `GOLDEN_VCD=<directory>`, the waveforms of the scenarios are written there
+ * as well, to look at in GTKWave.
+ */
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, Phase, SystemMode};
+use pistop_sim::Controller;
+use pistop_sim::trace::{Recording, Trace};
+
+mod common;
+use common::start_in;
+
+// Lines of context around the first difference.
+const CONTEXT: usize = 5;
+
+fn check_golden(name: &str, title: &str, trace: Trace) {
+    let path: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.trace"));
+    let actual: String = trace.text(title);
+    if let Some(directory) = env::var_os("GOLDEN_VCD") {
+        let directory: PathBuf = PathBuf::from(directory);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join(format!("{name}.vcd")), trace.vcd(title)).unwrap();
+    }
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+
+    let expected: String = fs::read_to_string(&path).unwrap_or_default();
+    if actual == expected {
+        return;
+    }
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let first: usize = actual_lines
+        .iter()
+        .zip(expected_lines.iter())
+        .take_while(|(actual, expected)| actual == expected)
+        .count();
+    let from: usize = first.saturating_sub(CONTEXT);
+    let mut diff: String = String::new();
+    for line in &expected_lines[from..expected_lines.len().min(first + CONTEXT)] {
+        diff.push_str(&format!("- {line}\n"));
+    }
+    for line in &actual_lines[from..actual_lines.len().min(first + CONTEXT)] {
+        diff.push_str(&format!("+ {line}\n"));
+    }
+    panic!(
+        "{} differs from line {}, rerun with UPDATE_GOLDEN=1 if that is intended:\n{diff}",
+        path.display(),
+        first + 1
+    );
+}
+
+fn press(recording: &mut Recording, controller: &Controller, approach: Approach) {
+    controller.set_button(approach, true);
+    recording.run_for(Duration::from_millis(100));
+    controller.set_button(approach, false);
+}
+
+fn run_until_phase(
+    recording: &mut Recording,
+    controller: &Controller,
+    approach: Approach,
+    phase: Phase,
+) {
+    let deadline: Instant = Instant::now() + Duration::from_secs(120);
+    while controller.events.latest().phases[approach.ordinal() as usize] != phase {
+        assert!(
+            Instant::now() < deadline,
+            "{approach:?} never got to {phase:?}"
+        );
+        recording.run_for(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn flash_mode() {
+    let (mut simulation, controller) = start_in(SystemMode::Flash);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    recording.run_until(Duration::from_secs(10));
+    check_golden("flash_mode", "flash mode", recording.finish());
+}
+
+#[test]
+fn normal_mode_button_a_at_5s() {
+    let (mut simulation, controller) = start_in(SystemMode::Normal);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    recording.run_until(Duration::from_secs(5));
+    press(&mut recording, &controller, Approach::A);
+    recording.run_until(Duration::from_secs(50));
+    check_golden(
+        "normal_mode_button_a_at_5s",
+        "normal mode, button A pressed at t=5s",
+        recording.finish(),
+    );
+}
+
+// B is pressed while A has green, and A while B has green.
+#[test]
+fn normal_mode_both_buttons() {
+    let (mut simulation, controller) = start_in(SystemMode::Normal);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    recording.run_until(Duration::from_secs(5));
+    press(&mut recording, &controller, Approach::B);
+    recording.run_until(Duration::from_secs(26));
+    press(&mut recording, &controller, Approach::A);
+    recording.run_until(Duration::from_secs(65));
+    check_golden(
+        "normal_mode_both_buttons",
+        "normal mode, button B pressed at t=5s and button A at t=26s",
+        recording.finish(),
+    );
+}
+
+#[test]
+fn normal_to_priority_b_mid_go_phase() {
+    let (mut simulation, controller) = start_in(SystemMode::Normal);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    run_until_phase(&mut recording, &controller, Approach::A, Phase::Go);
+    recording.run_for(Duration::from_secs(4));
+    controller.set_mode_switch(SystemMode::PriorityB);
+    recording.run_for(Duration::from_secs(30));
+    check_golden(
+        "normal_to_priority_b_mid_go_phase",
+        "switch Normal to PriorityB halfway through the go phase of A",
+        recording.finish(),
+    );
+}
+
+#[test]
+fn priority_a_to_flash() {
+    let (mut simulation, controller) = start_in(SystemMode::PriorityA);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    recording.run_until(Duration::from_secs(10));
+    controller.set_mode_switch(SystemMode::Flash);
+    recording.run_until(Duration::from_secs(30));
+    check_golden(
+        "priority_a_to_flash",
+        "switch PriorityA to Flash at t=10s",
+        recording.finish(),
+    );
+}
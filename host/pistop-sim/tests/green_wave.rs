@@ -0,0 +1,164 @@
+/*
+ * A line of simulated controllers in a green wave, all in one simulation. The
+ * sync output of each controller goes to the sync input of the next, and the
+ * first one is the master. The simulated controllers share a clock, so the
+ * drift of a crystal is played by a follower that runs a slightly longer
+ * cycle than the master does.
+ */
+
+use embassy_time::{Duration, Instant};
+use pistop_core::green_wave::Role;
+use pistop_core::settings::Settings;
+use pistop_protocol::{ConfigKey, Pins, SystemMode};
+use pistop_sim::{Controller, Simulation};
+
+mod common;
+use common::set;
+
+const CYCLE_MS: u64 = 60_000;
+
+// The outputs change on a tick of the output loop, every 10ms.
+const TOLERANCE_MS: u64 = 20;
+
+// The follower with the slow crystal, which loses this much every cycle. Its
+// greens may be late by as much, as it waits for its turn before the pulse
+// comes in.
+const SLOW_MS: u64 = 60;
+
+// The line of controllers, each with its offset and cycle length, running
+// normal mode.
+fn line(controllers: &[(u64, u64)]) -> (Simulation, Vec<Controller>) {
+    let mut simulation: Simulation = Simulation::new();
+    let mut line: Vec<Controller> = Vec::new();
+    for (index, (offset_ms, cycle_ms)) in controllers.iter().enumerate() {
+        let mut controller: Controller = Controller::new();
+        if let Some(previous) = line.last() {
+            controller.sync_input = previous.sync_output;
+        }
+        {
+            // scope for the mutex guard...
+            let mut settings = controller.settings.try_lock().unwrap();
+            let role: Role = if index == 0 {
+                Role::Master
+            } else {
+                Role::Follower
+            };
+            settings.green_wave = role;
+            settings.green_wave_cycle_ms = *cycle_ms;
+            settings.green_wave_offset_ms = *offset_ms;
+        }
+        controller.set_mode_switch(SystemMode::Normal);
+        controller.spawn(&mut simulation);
+        line.push(controller);
+    }
+    (simulation, line)
+}
+
+// When approach A went green on each controller.
+fn greens(simulation: &mut Simulation, line: &[Controller], duration: Duration) -> Vec<Vec<u64>> {
+    let mut greens: Vec<Vec<u64>> = vec![Vec::new(); line.len()];
+    let mut lit: Vec<bool> = line
+        .iter()
+        .map(|controller| controller.is_lit(Pins::AGreen))
+        .collect();
+    let until: Instant = Instant::now() + duration;
+    while Instant::now() < until {
+        simulation.run_for(Duration::from_millis(10));
+        for (index, controller) in line.iter().enumerate() {
+            let now_lit: bool = controller.is_lit(Pins::AGreen);
+            if now_lit && !lit[index] {
+                greens[index].push(Instant::now().as_millis());
+            }
+            lit[index] = now_lit;
+        }
+    }
+    greens
+}
+
+// How far a green is from where it should be in the cycle of the master,
+// which started counting at zero.
+fn off_by_ms(green_ms: u64, offset_ms: u64) -> u64 {
+    let into_cycle_ms: u64 = (green_ms + CYCLE_MS - offset_ms) % CYCLE_MS;
+    into_cycle_ms.min(CYCLE_MS - into_cycle_ms)
+}
+
+#[test]
+fn greens_follow_each_other_down_the_line() {
+    let offsets: [u64; 3] = [0, 10_000, 20_000];
+    let (mut simulation, line) = line(&offsets.map(|offset_ms| (offset_ms, CYCLE_MS)));
+    // Out of flashing mode and into step.
+    simulation.run_for(Duration::from_secs(120));
+
+    let greens: Vec<Vec<u64>> = greens(&mut simulation, &line, Duration::from_secs(300));
+    for (offset_ms, greens) in offsets.iter().zip(greens) {
+        assert_eq!(greens.len(), 5, "{greens:?}");
+        for green_ms in greens {
+            assert!(
+                off_by_ms(green_ms, *offset_ms) <= TOLERANCE_MS,
+                "{green_ms}"
+            );
+        }
+    }
+}
+
+#[test]
+fn followers_with_a_slow_crystal_stay_in_step() {
+    let (mut simulation, line) = line(&[(0, CYCLE_MS), (15_000, CYCLE_MS + SLOW_MS)]);
+    simulation.run_for(Duration::from_secs(120));
+
+    let greens: Vec<Vec<u64>> = greens(&mut simulation, &line, Duration::from_secs(600));
+    assert_eq!(greens[1].len(), 10, "{greens:?}");
+    for green_ms in greens[1].iter() {
+        assert!(
+            off_by_ms(*green_ms, 15_000) <= SLOW_MS + TOLERANCE_MS,
+            "{green_ms}"
+        );
+    }
+}
+
+#[test]
+fn followers_run_free_without_pulses_and_catch_up_when_they_return() {
+    let (mut simulation, line) = line(&[
+        (0, CYCLE_MS),
+        (10_000, CYCLE_MS),
+        (20_000, CYCLE_MS + SLOW_MS),
+    ]);
+    simulation.run_for(Duration::from_secs(120));
+
+    // The middle one stops passing the pulses on, so the last one goes by its
+    // own crystal, which is slow.
+    set(&line[1], ConfigKey::GreenWave, Role::Off as u32);
+    let drifting: Vec<Vec<u64>> = greens(&mut simulation, &line, Duration::from_secs(600));
+    let drifted_ms: u64 = off_by_ms(*drifting[2].last().unwrap(), 20_000);
+    assert!(drifted_ms >= 400, "{drifting:?}");
+
+    set(&line[1], ConfigKey::GreenWave, Role::Follower as u32);
+    simulation.run_for(Duration::from_secs(120));
+    let caught_up: Vec<Vec<u64>> = greens(&mut simulation, &line, Duration::from_secs(180));
+    for green_ms in caught_up[2].iter() {
+        assert!(
+            off_by_ms(*green_ms, 20_000) <= SLOW_MS + TOLERANCE_MS,
+            "{green_ms}"
+        );
+    }
+}
+
+#[test]
+fn refuses_a_cycle_that_normal_mode_does_not_fit_in() {
+    let mut settings: Settings = Settings::new();
+    assert!(settings.set(ConfigKey::GreenWaveCycleMs, 40_000).is_ok());
+    assert!(
+        settings
+            .set(ConfigKey::GreenWave, Role::Master as u32)
+            .is_err()
+    );
+    assert!(settings.set(ConfigKey::GreenWaveCycleMs, 42_000).is_ok());
+    assert!(
+        settings
+            .set(ConfigKey::GreenWave, Role::Master as u32)
+            .is_ok()
+    );
+    assert!(settings.set(ConfigKey::NormalGoMs, 9_000).is_err());
+    assert!(settings.set(ConfigKey::GreenWaveOffsetMs, 42_000).is_err());
+    assert!(settings.set(ConfigKey::GreenWave, 3).is_err());
+}
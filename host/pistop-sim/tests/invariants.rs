@@ -0,0 +1,360 @@
+/*
+ * Safety rules that have to hold on every tick, whatever the user does:
+ *
+ * - the greens of the two approaches are never lit together,
+ * - a head goes from green through amber to red, and the other approach only
+ *   gets green once it is red,
+ * - pedestrians only get green while the stream they cross is stopped, but
+ *   those that cross already may keep it while the stream flashes amber, as
+ *   in the pelican mode,
+ * - the promise led is off by the time the pedestrians get green.
+ *
+ * Proptest throws random turns of the rotary switch, presses of the buttons,
+ * calls of emergency vehicles and trains, requests from the host and presses
+ * of the officer at the controller, bouncing contacts included. When it finds inputs that
+ * break a rule, it shrinks them as far as it can and the smallest inputs end up
+ * in `tests/scenarios/shrunk.scenario`, see `scenario.rs`. Give the file a name
+ * of its own to keep it: every scenario in that directory is replayed, and
+ * the expectations that a scenario has are checked along with the rules.
+ */
+
+use std::fs;
+use std::path::PathBuf;
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, Pins, SystemMode};
+use pistop_sim::scenario::{Input, Scenario};
+use pistop_sim::{Controller, Simulation};
+use proptest::prelude::*;
+
+// Long enough after the last input for the controller to get to the mode on
+// the switch and to serve the pedestrians that are still waiting.
+const TAIL: Duration = Duration::from_secs(100);
+
+#[derive(Copy, Clone)]
+struct Head {
+    red: Pins,
+    amber: Pins,
+    green: Pins,
+    pedestrian_green: Pins,
+    promise: Pins,
+}
+
+const HEADS: [Head; Approach::VARIANT_COUNT] = [
+    Head {
+        red: Pins::ARed,
+        amber: Pins::AAmber,
+        green: Pins::AGreen,
+        pedestrian_green: Pins::APedestrianGreen,
+        promise: Pins::APromise,
+    },
+    Head {
+        red: Pins::BRed,
+        amber: Pins::BAmber,
+        green: Pins::BGreen,
+        pedestrian_green: Pins::BPedestrianGreen,
+        promise: Pins::BPromise,
+    },
+];
+
+// Where a head is on its way from green to red.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Clearance {
+    Green,
+    Yielding,
+    Cleared,
+}
+
+struct Rules {
+    clearance: [Clearance; Approach::VARIANT_COUNT],
+    green: [bool; Approach::VARIANT_COUNT],
+    // The pedestrians that got green while the stream they cross was stopped.
+    crossing: [bool; Approach::VARIANT_COUNT],
+}
+
+impl Rules {
+    fn new() -> Self {
+        Rules {
+            clearance: [Clearance::Cleared; Approach::VARIANT_COUNT],
+            green: [false; Approach::VARIANT_COUNT],
+            crossing: [false; Approach::VARIANT_COUNT],
+        }
+    }
+
+    fn check(&mut self, controller: &Controller) -> Result<(), String> {
+        let at: u64 = Instant::now().as_millis();
+        let lit = |pin: Pins| controller.is_lit(pin);
+        // Red, and not about to move off.
+        let stopped = |head: &Head| lit(head.red) && !lit(head.green);
+
+        if lit(Pins::AGreen) && lit(Pins::BGreen) {
+            return Err(format!("both greens lit at {at}ms"));
+        }
+        for approach in Approach::VARIANTS {
+            let i: usize = approach.ordinal() as usize;
+            let head: &Head = &HEADS[i];
+            let other: &Head = &HEADS[1 - i];
+            let (red, amber, green) = (lit(head.red), lit(head.amber), lit(head.green));
+
+            self.clearance[i] = match (self.clearance[i], red, amber, green) {
+                (Clearance::Yielding, _, _, true) => {
+                    return Err(format!(
+                        "{approach:?} back to green while yielding at {at}ms"
+                    ));
+                }
+                (_, _, _, true) => Clearance::Green,
+                (Clearance::Green | Clearance::Yielding, false, true, false) => Clearance::Yielding,
+                (Clearance::Green, ..) => {
+                    return Err(format!(
+                        "{approach:?} left green other than to amber at {at}ms"
+                    ));
+                }
+                (Clearance::Yielding, true, false, false) => Clearance::Cleared,
+                (Clearance::Yielding, ..) => {
+                    return Err(format!(
+                        "{approach:?} left amber other than to red at {at}ms"
+                    ));
+                }
+                (Clearance::Cleared, ..) => Clearance::Cleared,
+            };
+
+            if green
+                && !self.green[i]
+                && (self.clearance[1 - i] != Clearance::Cleared || !stopped(other))
+            {
+                return Err(format!(
+                    "{approach:?} went green at {at}ms before the other approach cleared"
+                ));
+            }
+            self.green[i] = green;
+
+            if lit(other.green) || (stopped(other) && !lit(head.pedestrian_green)) {
+                self.crossing[i] = false;
+            }
+            if lit(head.pedestrian_green) {
+                if stopped(other) {
+                    self.crossing[i] = true;
+                } else if !self.crossing[i] {
+                    return Err(format!(
+                        "pedestrians of {approach:?} got green at {at}ms while traffic moves"
+                    ));
+                }
+                if lit(head.promise) {
+                    return Err(format!(
+                        "the promise of {approach:?} still lit at {at}ms while pedestrians cross"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs the scenario on a fresh controller and checks the rules on every tick,
+// as well as the expectations of the scenario.
+fn check_scenario(scenario: &Scenario) -> Result<(), String> {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.spawn(&mut simulation);
+    let mut rules: Rules = Rules::new();
+    let mut broken: Option<String> = None;
+    let expected: Result<(), String> = scenario.run(
+        &mut simulation,
+        &controller,
+        scenario.end() + TAIL,
+        |controller| {
+            if broken.is_none() {
+                broken = rules.check(controller).err();
+            }
+        },
+    );
+    broken.map_or(expected, Err)
+}
+
+fn scenarios_directory() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/scenarios")
+}
+
+// What the user does, with the time to wait before doing it.
+#[derive(Clone, Debug)]
+enum Action {
+    // Turn the switch, past a few other modes on the way, each for a moment.
+    Turn {
+        past: Vec<(SystemMode, u64)>,
+        to: SystemMode,
+    },
+    // Press the button and hold it, while the contacts bounce at first.
+    Press {
+        approach: Approach,
+        bounces: Vec<u64>,
+        hold_ms: u64,
+    },
+    // An emergency vehicle calls for the green and holds the call.
+    Call {
+        approach: Approach,
+        hold_ms: u64,
+    },
+    // A train passes the track circuit.
+    Train {
+        hold_ms: u64,
+    },
+    // The host asks for a mode.
+    Host {
+        mode: SystemMode,
+    },
+    // The officer presses the button of the manual mode.
+    Advance {
+        hold_ms: u64,
+    },
+}
+
+// The modes on the rotary switch, which has no position for the railway mode
+// nor for the modes that the host asks for.
+fn mode() -> impl Strategy<Value = SystemMode> {
+    (0..SystemMode::VARIANT_COUNT as u8)
+        .prop_map(|i| SystemMode::from_ordinal(i).unwrap())
+        .prop_filter("not on the switch", |mode| {
+            !matches!(
+                mode,
+                SystemMode::Railway
+                    | SystemMode::Manual
+                    | SystemMode::AllRed
+                    | SystemMode::Dark
+                    | SystemMode::Pelican
+            )
+        })
+}
+
+// The modes that the host may ask for.
+fn host_mode() -> impl Strategy<Value = SystemMode> {
+    (0..SystemMode::VARIANT_COUNT as u8)
+        .prop_map(|i| SystemMode::from_ordinal(i).unwrap())
+        .prop_filter("refused by the host link", |mode| {
+            *mode != SystemMode::Railway
+        })
+}
+
+fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        (prop::collection::vec((mode(), 1..300u64), 0..4), mode())
+            .prop_map(|(past, to)| Action::Turn { past: past, to: to }),
+        (
+            prop_oneof![Just(Approach::A), Just(Approach::B)],
+            prop::collection::vec(1..20u64, 0..6),
+            20..2_000u64,
+        )
+            .prop_map(|(approach, bounces, hold_ms)| Action::Press {
+                approach: approach,
+                bounces: bounces,
+                hold_ms: hold_ms,
+            }),
+        (
+            prop_oneof![Just(Approach::A), Just(Approach::B)],
+            20..30_000u64
+        )
+            .prop_map(|(approach, hold_ms)| Action::Call {
+                approach: approach,
+                hold_ms: hold_ms,
+            }),
+        (20..60_000u64).prop_map(|hold_ms| Action::Train { hold_ms: hold_ms }),
+        host_mode().prop_map(|mode| Action::Host { mode: mode }),
+        (20..2_000u64).prop_map(|hold_ms| Action::Advance { hold_ms: hold_ms }),
+    ]
+}
+
+fn scenario() -> impl Strategy<Value = Scenario> {
+    prop::collection::vec((0..15_000u64, action()), 1..10).prop_map(|actions| {
+        let mut scenario: Scenario = Scenario::default();
+        let mut at: u64 = 0;
+        let mut input = |at: u64, input: Input| {
+            scenario.inputs.push((Duration::from_millis(at), input));
+        };
+        for (wait_ms, action) in actions {
+            at += wait_ms;
+            match action {
+                Action::Turn { past, to } => {
+                    for (mode, ms) in past {
+                        input(at, Input::Mode(mode));
+                        at += ms;
+                    }
+                    input(at, Input::Mode(to));
+                }
+                Action::Press {
+                    approach,
+                    bounces,
+                    hold_ms,
+                } => {
+                    input(at, Input::Press(approach));
+                    for ms in bounces {
+                        at += ms;
+                        input(at, Input::Release(approach));
+                        at += ms;
+                        input(at, Input::Press(approach));
+                    }
+                    at += hold_ms;
+                    input(at, Input::Release(approach));
+                }
+                Action::Call { approach, hold_ms } => {
+                    input(at, Input::Call(approach, true));
+                    at += hold_ms;
+                    input(at, Input::Call(approach, false));
+                }
+                Action::Train { hold_ms } => {
+                    input(at, Input::Track(true));
+                    at += hold_ms;
+                    input(at, Input::Track(false));
+                }
+                Action::Host { mode } => input(at, Input::HostMode(mode)),
+                Action::Advance { hold_ms } => {
+                    input(at, Input::Advance(true));
+                    at += hold_ms;
+                    input(at, Input::Advance(false));
+                }
+            }
+        }
+        scenario
+    })
+}
+
+proptest! {
+    // Every case simulates a few minutes, so a few dozen cases will do for a
+    // test run. Failures are kept as scenarios rather than by proptest.
+    #![proptest_config(ProptestConfig {
+        cases: 32,
+        failure_persistence: None,
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn random_inputs_keep_the_rules(scenario in scenario()) {
+        if let Err(broken) = check_scenario(&scenario) {
+            // Proptest runs every smaller case that still fails, so the last
+            // one written is the smallest.
+            fs::create_dir_all(scenarios_directory()).unwrap();
+            fs::write(scenarios_directory().join("shrunk.scenario"), scenario.text()).unwrap();
+            prop_assert!(false, "{broken}, with inputs\n{}", scenario.text());
+        }
+    }
+}
+
+#[test]
+fn saved_scenarios_keep_the_rules() {
+    let mut count: usize = 0;
+    for entry in fs::read_dir(scenarios_directory()).unwrap() {
+        let path: PathBuf = entry.unwrap().path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != "scenario")
+        {
+            continue;
+        }
+        let scenario: Scenario = Scenario::parse(&fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|error| panic!("{}: {error}", path.display()));
+        if let Err(broken) = check_scenario(&scenario) {
+            panic!("{}: {broken}", path.display());
+        }
+        count += 1;
+    }
+    assert!(count > 0);
+}
@@ -0,0 +1,145 @@
+/*
+ * The log, on its own and inside a simulated controller.
+ */
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embassy_time::Duration;
+use pistop_core::log::{self, Level, Log, Record};
+use pistop_core::serial::Serial;
+use pistop_core::{debug, info, warn};
+use pistop_protocol::{EventKind, Phase, SystemMode};
+use pistop_sim::{Controller, Simulation};
+
+fn leak<T>(value: T) -> &'static T {
+    Box::leak(Box::new(value))
+}
+
+// Take the next record from the log, which has to be in the queue already.
+fn next_record(simulation: &mut Simulation, log: &'static Log) -> Record {
+    let slot: Rc<RefCell<Option<Record>>> = Rc::new(RefCell::new(None));
+    let filler: Rc<RefCell<Option<Record>>> = slot.clone();
+    simulation.spawn(async move {
+        *filler.borrow_mut() = Some(log.next().await);
+    });
+    simulation.settle();
+    slot.take().expect("no record")
+}
+
+// Collect everything that goes out over the serial port.
+fn drain(simulation: &mut Simulation, serial: &'static Serial) -> Rc<RefCell<String>> {
+    let text: Rc<RefCell<String>> = Rc::new(RefCell::new(String::new()));
+    let collector: Rc<RefCell<String>> = text.clone();
+    simulation.spawn(async move {
+        let mut buffer: [u8; 64] = [0; 64];
+        loop {
+            let count: usize = serial.transmit(&mut buffer).await;
+            collector
+                .borrow_mut()
+                .push_str(&String::from_utf8_lossy(&buffer[..count]));
+        }
+    });
+    text
+}
+
+#[test]
+fn formats_records_with_time_level_and_tag() {
+    let mut simulation: Simulation = Simulation::new();
+    simulation.run_for(Duration::from_millis(12_345));
+    let log: &'static Log = leak(Log::new());
+    info!(log, "mode reader", "signalling {:?}.", Level::Warn);
+    let record: Record = next_record(&mut simulation, log);
+    assert_eq!(
+        log::format(&record).as_str(),
+        "    12.345 INFO  mode reader     signalling Warn.\r\n"
+    );
+}
+
+#[test]
+fn filters_by_level() {
+    let log: &'static Log = leak(Log::new());
+    assert_eq!(log.level(), Level::Info);
+    debug!(log, "test", "hidden");
+    warn!(log, "test", "shown");
+    log.set_level(Level::Debug);
+    debug!(log, "test", "shown too");
+
+    let mut simulation: Simulation = Simulation::new();
+    let serial: &'static Serial = leak(Serial::new());
+    let text: Rc<RefCell<String>> = drain(&mut simulation, serial);
+    simulation.spawn(async move {
+        log::log_writer(log, serial).await;
+    });
+    simulation.settle();
+    let text: String = text.take();
+    assert!(!text.contains("hidden"), "{text}");
+    assert!(text.contains("WARN  test            shown\r\n"), "{text}");
+    assert!(
+        text.contains("DEBUG test            shown too\r\n"),
+        "{text}"
+    );
+}
+
+#[test]
+fn drops_and_counts_records_when_the_queue_is_full() {
+    let log: &'static Log = leak(Log::new());
+    for i in 0..20 {
+        info!(log, "test", "record {}", i);
+    }
+    assert_eq!(log.dropped(), 4);
+
+    let mut simulation: Simulation = Simulation::new();
+    let serial: &'static Serial = leak(Serial::new());
+    let text: Rc<RefCell<String>> = drain(&mut simulation, serial);
+    simulation.spawn(async move {
+        log::log_writer(log, serial).await;
+    });
+    simulation.settle();
+    let text: String = text.take();
+    assert!(text.contains("record 15\r\n"), "{text}");
+    assert!(!text.contains("record 16"), "{text}");
+    assert!(text.contains("4 log records dropped.\r\n"), "{text}");
+}
+
+#[test]
+fn cuts_long_messages_short() {
+    let log: &'static Log = leak(Log::new());
+    info!(log, "test", "{}", "x".repeat(100));
+    let mut simulation: Simulation = Simulation::new();
+    let record: Record = next_record(&mut simulation, log);
+    assert_eq!(record.message.len(), 64);
+}
+
+// The controller keeps going when nobody reads the serial port, such as when
+// the USB serial adapter is not connected.
+#[test]
+fn controller_does_not_wait_for_the_serial_port() {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.spawn(&mut simulation);
+    controller.set_mode_switch(SystemMode::Normal);
+
+    // Way more than fits in the queue and the serial port together.
+    for i in 0..100 {
+        info!(controller.log, "test", "filling up the serial port {}", i);
+    }
+    simulation.run_for(Duration::from_secs(120));
+
+    assert!(controller.log.dropped() > 0);
+    let mut goes: usize = 0;
+    let mut next: u32 = 0;
+    loop {
+        let (events, continue_from) = controller.events.read_from(next);
+        if events.is_empty() {
+            break;
+        }
+        goes += events
+            .iter()
+            .filter(|event| matches!(event.kind, EventKind::PhaseChanged(_, Phase::Go)))
+            .count();
+        next = continue_from;
+    }
+    // A cycle of both heads takes 42s.
+    assert!(goes >= 4, "{goes}");
+}
@@ -0,0 +1,133 @@
+/*
+ * An officer who runs the crossing by hand. An approach keeps its green until
+ * the officer presses the button, and however fast the officer presses, the
+ * amber and the clearance take as long as in normal mode.
+ */
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, ConfigKey, Pins, SystemMode};
+use pistop_sim::controller::TICK;
+use pistop_sim::{Controller, Simulation};
+
+mod common;
+use common::{run_until, set, start_from_host};
+
+fn advance(simulation: &mut Simulation, controller: &Controller) {
+    controller.set_advance_button(true);
+    simulation.run_for(Duration::from_millis(200));
+    controller.set_advance_button(false);
+    simulation.run_for(Duration::from_millis(100));
+}
+
+#[test]
+fn the_green_holds_until_the_officer_presses() {
+    let (mut simulation, controller) = start_from_host(SystemMode::Manual);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    assert_eq!(controller.sample().mode, SystemMode::Manual);
+    simulation.run_for(Duration::from_secs(120));
+    assert!(controller.is_lit(Pins::AGreen));
+    assert!(controller.is_lit(Pins::BRed));
+
+    let pressed_at: Instant = Instant::now();
+    controller.set_advance_button(true);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    // The input settles first.
+    assert!(amber_at - pressed_at <= Duration::from_millis(100));
+    controller.set_advance_button(false);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    simulation.run_for(Duration::from_secs(120));
+    assert!(controller.is_lit(Pins::BGreen));
+    assert!(controller.is_lit(Pins::ARed));
+
+    advance(&mut simulation, &controller);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    let statistics = controller.statistics.snapshot();
+    assert_eq!(statistics.cycles[SystemMode::Manual.ordinal() as usize], 1);
+}
+
+#[test]
+fn the_amber_and_the_clearance_run_in_full() {
+    let (mut simulation, controller) = start_from_host(SystemMode::Manual);
+    set(&controller, ConfigKey::NormalYieldMs, 5_000);
+    set(&controller, ConfigKey::NormalClearMs, 3_000);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+
+    // Pressing on and on does not hurry the crossing along.
+    controller.set_advance_button(true);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    controller.set_advance_button(false);
+    for _ in 0..5 {
+        advance(&mut simulation, &controller);
+    }
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::ARed, true);
+    for _ in 0..3 {
+        advance(&mut simulation, &controller);
+    }
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::BGreen, true);
+    // The outputs only change on a tick of the output loop.
+    assert!(red_at - amber_at >= Duration::from_millis(5_000) - TICK);
+    assert!(green_at - red_at >= Duration::from_millis(3_000) - TICK);
+
+    // The presses from before the green of B do not count.
+    simulation.run_for(Duration::from_secs(30));
+    assert!(controller.is_lit(Pins::BGreen));
+}
+
+#[test]
+fn pedestrians_walk_along_with_their_approach() {
+    let (mut simulation, controller) = start_from_host(SystemMode::Manual);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    controller.set_button(Approach::B, true);
+    simulation.run_for(Duration::from_millis(200));
+    controller.set_button(Approach::B, false);
+    simulation.run_for(Duration::from_secs(10));
+    assert!(!controller.is_lit(Pins::BPedestrianGreen));
+
+    advance(&mut simulation, &controller);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    assert!(controller.is_lit(Pins::BPedestrianGreen));
+    assert!(!controller.is_lit(Pins::APedestrianGreen));
+}
+
+#[test]
+fn the_rotary_switch_takes_the_crossing_back() {
+    let (mut simulation, controller) = start_from_host(SystemMode::Manual);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    controller.set_mode_switch(SystemMode::Normal);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::ARed, true);
+    assert!(red_at - amber_at >= Duration::from_millis(6_000) - TICK);
+    simulation.run_for(Duration::from_secs(10));
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+}
+
+#[test]
+fn an_emergency_vehicle_gets_its_way() {
+    let (mut simulation, controller) = start_from_host(SystemMode::Manual);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    controller.set_preemption_input(Approach::B, true);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    assert_eq!(controller.sample().mode, SystemMode::PriorityB);
+
+    // The officer takes over again once the vehicle has passed.
+    controller.set_preemption_input(Approach::B, false);
+    simulation.run_for(Duration::from_secs(60));
+    assert_eq!(controller.sample().mode, SystemMode::Manual);
+    assert!(controller.is_lit(Pins::AGreen));
+}
+
+#[test]
+fn a_press_outside_the_manual_mode_is_forgotten() {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.set_mode_switch(SystemMode::Flash);
+    controller.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(5));
+    advance(&mut simulation, &controller);
+
+    controller.system_mode_signal.signal(SystemMode::Manual);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    simulation.run_for(Duration::from_secs(30));
+    assert!(controller.is_lit(Pins::AGreen));
+}
@@ -0,0 +1,188 @@
+/*
+ * The output masker on its own, without the controller around it. The output
+ * loop of the firmware sleeps for as many ticks as `ticks_until_change()`
+ * says, and then moves the masker on by those ticks at once. That only works
+ * when both agree with stepping the masker at 100Hz, tick by tick.
+ */
+
+use embassy_sync::signal::Signal;
+use enum_ordinalize::Ordinalize;
+use pistop_core::ThreadModeRawMutex;
+use pistop_core::timed_output_masker::{Pins, TimedOutputMasker};
+
+fn masker() -> TimedOutputMasker {
+    let changed: &'static Signal<ThreadModeRawMutex, ()> = Box::leak(Box::new(Signal::new()));
+    TimedOutputMasker::new([false; Pins::VARIANT_COUNT], changed)
+}
+
+// A masker at the start of its cycle, with one pin lit subject to the given
+// timers. All outputs are active-high, so the outputs are the lit pins.
+fn masker_with(slow_cycle: bool, fast_cycle: bool, pip_timer: bool) -> TimedOutputMasker {
+    let mut masker: TimedOutputMasker = masker();
+    masker.set_pin(Pins::AAmber, true, slow_cycle, fast_cycle, pip_timer);
+    masker.call_at_100_hz();
+    masker
+}
+
+// Steps the masker at 100Hz and notes the ticks at which the amber changes.
+fn changes_of_the_amber(masker: &mut TimedOutputMasker, ticks: u32) -> Vec<u32> {
+    let mut lit: bool = masker.lit()[Pins::AAmber.ordinal()];
+    let mut changes: Vec<u32> = Vec::new();
+    for tick in 1..=ticks {
+        let outputs: [bool; Pins::VARIANT_COUNT] = masker.call_at_100_hz();
+        if outputs[Pins::AAmber.ordinal()] != lit {
+            lit = !lit;
+            changes.push(tick);
+        }
+    }
+    changes
+}
+
+#[test]
+fn nothing_blinking_asks_for_a_call_once_per_cycle() {
+    let mut masker: TimedOutputMasker = masker();
+    masker.set_on_off(Pins::ARed, true);
+    masker.call_at_100_hz();
+    assert_eq!(masker.ticks_until_change(), 100);
+    masker.call_after_ticks(37);
+    assert_eq!(masker.ticks_until_change(), 100);
+}
+
+#[test]
+fn the_slow_cycle_changes_every_half_second() {
+    let mut masker: TimedOutputMasker = masker_with(true, false, false);
+    assert!(masker.lit()[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 50);
+    masker.call_after_ticks(50);
+    assert!(!masker.lit()[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 50);
+    masker.call_after_ticks(20);
+    assert_eq!(masker.ticks_until_change(), 30);
+
+    let mut masker: TimedOutputMasker = masker_with(true, false, false);
+    assert_eq!(changes_of_the_amber(&mut masker, 200), [50, 100, 150, 200]);
+}
+
+#[test]
+fn the_fast_cycle_changes_every_tenth_of_a_second() {
+    let mut masker: TimedOutputMasker = masker_with(false, true, false);
+    assert!(!masker.lit()[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 10);
+    masker.call_after_ticks(10);
+    assert!(masker.lit()[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 10);
+    masker.call_after_ticks(85);
+    assert_eq!(masker.ticks_until_change(), 5);
+
+    let mut masker: TimedOutputMasker = masker_with(false, true, false);
+    assert_eq!(
+        changes_of_the_amber(&mut masker, 100),
+        [10, 20, 30, 40, 50, 60, 70, 80, 90, 100]
+    );
+}
+
+#[test]
+fn the_pip_timer_is_on_for_one_tick_per_cycle() {
+    let mut masker: TimedOutputMasker = masker_with(false, false, true);
+    assert!(masker.lit()[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 1);
+    masker.call_after_ticks(1);
+    assert!(!masker.lit()[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 99);
+
+    let mut masker: TimedOutputMasker = masker_with(false, false, true);
+    assert_eq!(changes_of_the_amber(&mut masker, 200), [1, 100, 101, 200]);
+}
+
+#[test]
+fn combined_timers_change_when_the_first_of_them_does() {
+    // On while both the slow and the fast cycle are, that is from tick 10 to
+    // 20 and from 30 to 40, since the slow cycle goes off at 50.
+    let mut masker: TimedOutputMasker = masker_with(true, true, false);
+    assert_eq!(masker.ticks_until_change(), 10);
+    masker.call_after_ticks(30);
+    assert_eq!(masker.ticks_until_change(), 10);
+    masker.call_after_ticks(10);
+    assert_eq!(masker.ticks_until_change(), 70);
+}
+
+#[test]
+fn a_change_part_way_through_a_cycle_takes_effect_straight_away() {
+    let changed: &'static Signal<ThreadModeRawMutex, ()> = Box::leak(Box::new(Signal::new()));
+    let mut masker: TimedOutputMasker =
+        TimedOutputMasker::new([false; Pins::VARIANT_COUNT], changed);
+    masker.set_pin(Pins::AAmber, true, true, false, false);
+    masker.call_at_100_hz();
+    changed.reset();
+
+    masker.call_after_ticks(20);
+    assert_eq!(masker.ticks_until_change(), 30);
+
+    // The output loop is woken up, and moves the masker on by no ticks at all
+    // to write the new outputs.
+    masker.set_pin(Pins::AAmber, true, false, true, false);
+    assert!(changed.signaled());
+    let outputs: [bool; Pins::VARIANT_COUNT] = masker.call_after_ticks(0);
+    assert!(!outputs[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 10);
+
+    // Setting a pin the way it already is does not wake the output loop.
+    changed.reset();
+    masker.set_pin(Pins::AAmber, true, false, true, false);
+    assert!(!changed.signaled());
+
+    masker.set_on_off(Pins::AAmber, true);
+    let outputs: [bool; Pins::VARIANT_COUNT] = masker.call_after_ticks(0);
+    assert!(outputs[Pins::AAmber.ordinal()]);
+    assert_eq!(masker.ticks_until_change(), 100);
+}
+
+#[test]
+fn skipping_ticks_is_the_same_as_stepping_them() {
+    for ticks in 0..=250 {
+        let mut skipping: TimedOutputMasker = masker();
+        let mut stepping: TimedOutputMasker = masker();
+        for masker in [&mut skipping, &mut stepping] {
+            masker.set_pin(Pins::AAmber, true, true, false, false);
+            masker.set_pin(Pins::BAmber, true, false, true, false);
+            masker.set_pin(Pins::SwitchingMode, true, false, false, true);
+            masker.set_pin(Pins::ARed, true, true, true, false);
+            masker.set_on_off(Pins::BRed, true);
+            masker.call_after_ticks(13);
+        }
+
+        let skipped: [bool; Pins::VARIANT_COUNT] = skipping.call_after_ticks(ticks);
+        let mut stepped: [bool; Pins::VARIANT_COUNT] = stepping.lit();
+        for _ in 0..ticks {
+            stepped = stepping.call_at_100_hz();
+        }
+        assert_eq!(skipped, stepped, "after {ticks} ticks");
+        assert_eq!(skipping.cycles(), stepping.cycles(), "after {ticks} ticks");
+        assert_eq!(
+            skipping.ticks_until_change(),
+            stepping.ticks_until_change(),
+            "after {ticks} ticks"
+        );
+    }
+}
+
+#[test]
+fn sleeping_until_the_next_change_misses_no_change() {
+    // The output loop of the firmware, against stepping at 100Hz.
+    let mut sleeping: TimedOutputMasker = masker_with(true, true, false);
+    sleeping.set_pin(Pins::SwitchingMode, true, false, false, true);
+    let mut stepping: TimedOutputMasker = masker_with(true, true, false);
+    stepping.set_pin(Pins::SwitchingMode, true, false, false, true);
+
+    let mut outputs: [bool; Pins::VARIANT_COUNT] = sleeping.call_after_ticks(0);
+    let mut wake_at: u32 = sleeping.ticks_until_change();
+    for tick in 1..=300 {
+        let stepped: [bool; Pins::VARIANT_COUNT] = stepping.call_at_100_hz();
+        if tick == wake_at {
+            let slept: u32 = sleeping.ticks_until_change();
+            outputs = sleeping.call_after_ticks(slept);
+            wake_at = tick + sleeping.ticks_until_change();
+        }
+        assert_eq!(outputs, stepped, "at tick {tick}");
+    }
+}
@@ -0,0 +1,319 @@
+/*
+ * Drives the Modbus slave of a simulated controller from a simulated master,
+ * with the gaps between the bytes under the control of the test.
+ */
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embassy_time::{Duration, Instant};
+use pistop_core::modbus::{self, FrameTiming};
+use pistop_core::serial::Serial;
+use pistop_sim::{Controller, Simulation};
+
+const ADDRESS: u8 = 1;
+const TIMING: FrameTiming = FrameTiming::for_baud_rate(115_200);
+
+// Long enough for any response to have gone out.
+const RESPONSE_TIME: Duration = Duration::from_millis(20);
+
+// Long enough to clear the crossing and change modes.
+const MODE_CHANGE_TIME: Duration = Duration::from_secs(20);
+
+// Worked out bit by bit, rather than with the same code that the slave uses.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in bytes {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn with_crc(frame: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = frame.to_vec();
+    frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+    frame
+}
+
+struct Master {
+    simulation: Simulation,
+    serial: &'static Serial,
+    // Every byte that the slave sent, with the moment it was sent.
+    sent: Rc<RefCell<Vec<(Instant, u8)>>>,
+}
+
+impl Master {
+    fn new() -> Self {
+        let mut simulation: Simulation = Simulation::new();
+        let controller: Controller = Controller::new();
+        controller.spawn_modbus(&mut simulation, TIMING);
+
+        let serial: &'static Serial = controller.serial;
+        let sent: Rc<RefCell<Vec<(Instant, u8)>>> = Rc::new(RefCell::new(Vec::new()));
+        let collector: Rc<RefCell<Vec<(Instant, u8)>>> = sent.clone();
+        simulation.spawn(async move {
+            let mut buffer: [u8; 64] = [0; 64];
+            loop {
+                let count: usize = serial.transmit(&mut buffer).await;
+                let now: Instant = Instant::now();
+                collector
+                    .borrow_mut()
+                    .extend(buffer[..count].iter().map(|byte| (now, *byte)));
+            }
+        });
+        // Give the controller time to start up and read the rotary switch,
+        // which would otherwise override the modes that the tests ask for.
+        simulation.run_for(Duration::from_secs(1));
+
+        Master {
+            simulation: simulation,
+            serial: serial,
+            sent: sent,
+        }
+    }
+
+    // Put bytes on the line, all in one go.
+    fn write(&mut self, bytes: &[u8]) {
+        let serial: &'static Serial = self.serial;
+        let bytes: Vec<u8> = bytes.to_vec();
+        self.simulation.spawn(async move {
+            serial.received(&bytes).await;
+        });
+        self.simulation.settle();
+    }
+
+    fn take_sent(&mut self) -> Vec<(Instant, u8)> {
+        self.sent.take()
+    }
+
+    // Send a request and return the response without its CRC, after checking
+    // the CRC.
+    fn request(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        self.take_sent();
+        self.write(&with_crc(request));
+        self.simulation.run_for(RESPONSE_TIME);
+        let sent: Vec<u8> = self.take_sent().iter().map(|(_, byte)| *byte).collect();
+        if sent.is_empty() {
+            return None;
+        }
+        let (response, crc) = sent.split_at(sent.len() - 2);
+        assert_eq!(crc, crc16(response).to_le_bytes(), "{sent:02X?}");
+        Some(response.to_vec())
+    }
+
+    fn read_registers(&mut self, function: u8, start: u16, quantity: u16) -> Vec<u16> {
+        let [start_high, start_low] = start.to_be_bytes();
+        let [quantity_high, quantity_low] = quantity.to_be_bytes();
+        let response: Vec<u8> = self
+            .request(&[
+                ADDRESS,
+                function,
+                start_high,
+                start_low,
+                quantity_high,
+                quantity_low,
+            ])
+            .expect("no response");
+        assert_eq!(response[..3], [ADDRESS, function, (quantity * 2) as u8]);
+        response[3..]
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect()
+    }
+
+    fn input_register(&mut self, address: u16) -> u16 {
+        self.read_registers(0x04, address, 1)[0]
+    }
+}
+
+#[test]
+fn crc_matches_the_example_from_the_spec() {
+    assert_eq!(
+        with_crc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]),
+        [0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]
+    );
+    assert_eq!(modbus::crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xCDC5);
+}
+
+#[test]
+fn frame_timing_follows_the_spec() {
+    assert_eq!(TIMING.char_gap, Duration::from_micros(750));
+    assert_eq!(TIMING.frame_gap, Duration::from_micros(1_750));
+
+    // 11 bits at 9600 baud take 1145us.
+    let timing: FrameTiming = FrameTiming::for_baud_rate(9_600);
+    assert_eq!(timing.char_gap, Duration::from_micros(1_717));
+    assert_eq!(timing.frame_gap, Duration::from_micros(4_007));
+}
+
+#[test]
+fn reads_the_state_of_the_crossing() {
+    let mut master: Master = Master::new();
+
+    let registers: Vec<u16> = master.read_registers(0x04, 0, 10);
+    // Flash mode, not locked out, both heads flashing.
+    assert_eq!(registers[..4], [1, 0, 4, 4]);
+    // The battery of the simulator is at 7.8V and fine.
+    assert_eq!(registers[8], 0);
+    assert!((7_700..=7_900).contains(&registers[9]), "{registers:?}");
+}
+
+#[test]
+fn answers_only_after_the_frame_gap() {
+    let mut master: Master = Master::new();
+    let request: Vec<u8> = with_crc(&[ADDRESS, 0x04, 0x00, 0x00, 0x00, 0x01]);
+    master.write(&request);
+    let end_of_request: Instant = Instant::now();
+    master.simulation.run_for(RESPONSE_TIME);
+
+    let sent: Vec<(Instant, u8)> = master.take_sent();
+    assert!(!sent.is_empty());
+    assert!(sent[0].0 - end_of_request >= TIMING.frame_gap);
+}
+
+#[test]
+fn drops_frames_with_a_gap_in_the_middle() {
+    let mut master: Master = Master::new();
+    let request: Vec<u8> = with_crc(&[ADDRESS, 0x04, 0x00, 0x00, 0x00, 0x01]);
+
+    // Longer than 1.5 characters, but shorter than 3.5 characters.
+    master.write(&request[..3]);
+    master.simulation.run_for(Duration::from_micros(1_000));
+    master.write(&request[3..]);
+    master.simulation.run_for(RESPONSE_TIME);
+    assert!(master.take_sent().is_empty());
+
+    // Shorter than 1.5 characters is fine.
+    master.write(&request[..3]);
+    master.simulation.run_for(Duration::from_micros(500));
+    master.write(&request[3..]);
+    master.simulation.run_for(RESPONSE_TIME);
+    assert!(!master.take_sent().is_empty());
+
+    // Longer than 3.5 characters makes two frames, neither of which is whole.
+    master.write(&request[..3]);
+    master.simulation.run_for(Duration::from_micros(2_000));
+    master.write(&request[3..]);
+    master.simulation.run_for(RESPONSE_TIME);
+    assert!(master.take_sent().is_empty());
+
+    // One broken frame, one too short and one with a bad CRC.
+    assert_eq!(master.input_register(11), 3);
+}
+
+#[test]
+fn drops_frames_with_a_bad_crc() {
+    let mut master: Master = Master::new();
+    let mut request: Vec<u8> = with_crc(&[ADDRESS, 0x04, 0x00, 0x00, 0x00, 0x01]);
+    request[6] ^= 0x01;
+    master.write(&request);
+    master.simulation.run_for(RESPONSE_TIME);
+    assert!(master.take_sent().is_empty());
+    assert_eq!(master.input_register(11), 1);
+}
+
+#[test]
+fn ignores_other_slaves() {
+    let mut master: Master = Master::new();
+    assert_eq!(
+        master.request(&[ADDRESS + 1, 0x04, 0x00, 0x00, 0x00, 0x01]),
+        None
+    );
+}
+
+#[test]
+fn carries_out_broadcasts_without_answering() {
+    let mut master: Master = Master::new();
+    // Write normal mode to holding register 0.
+    assert_eq!(master.request(&[0, 0x06, 0x00, 0x00, 0x00, 0x00]), None);
+    master.simulation.run_for(MODE_CHANGE_TIME);
+    assert_eq!(master.input_register(0), 0);
+}
+
+#[test]
+fn coils_press_the_buttons() {
+    let mut master: Master = Master::new();
+    assert_eq!(
+        master.request(&[ADDRESS, 0x06, 0x00, 0x00, 0x00, 0x00]),
+        Some(vec![ADDRESS, 0x06, 0x00, 0x00, 0x00, 0x00])
+    );
+    master.simulation.run_for(MODE_CHANGE_TIME);
+    assert_eq!(master.input_register(0), 0);
+
+    assert_eq!(
+        master.request(&[ADDRESS, 0x05, 0x00, 0x01, 0xFF, 0x00]),
+        Some(vec![ADDRESS, 0x05, 0x00, 0x01, 0xFF, 0x00])
+    );
+    master.simulation.run_for(Duration::from_millis(100));
+    assert_eq!(
+        master.request(&[ADDRESS, 0x01, 0x00, 0x00, 0x00, 0x02]),
+        Some(vec![ADDRESS, 0x01, 0x01, 0b10])
+    );
+
+    // Coils are either on or off.
+    assert_eq!(
+        master.request(&[ADDRESS, 0x05, 0x00, 0x00, 0x12, 0x34]),
+        Some(vec![ADDRESS, 0x85, 0x03])
+    );
+}
+
+#[test]
+fn holding_registers_change_the_timings() {
+    let mut master: Master = Master::new();
+    assert_eq!(
+        master.read_registers(0x03, 1, 4),
+        [3_000, 8_000, 6_000, 4_000]
+    );
+
+    assert_eq!(
+        master.request(&[
+            ADDRESS, 0x10, 0x00, 0x02, 0x00, 0x02, 0x04, 0x13, 0x88, 0x0F, 0xA0
+        ]),
+        Some(vec![ADDRESS, 0x10, 0x00, 0x02, 0x00, 0x02])
+    );
+    assert_eq!(
+        master.read_registers(0x03, 1, 4),
+        [3_000, 5_000, 4_000, 4_000]
+    );
+
+    // A phase cannot take no time at all, and a refused write changes
+    // nothing, not even the registers before the bad one.
+    assert_eq!(
+        master.request(&[
+            ADDRESS, 0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x07, 0xD0, 0x00, 0x00
+        ]),
+        Some(vec![ADDRESS, 0x90, 0x03])
+    );
+    assert_eq!(
+        master.read_registers(0x03, 1, 4),
+        [3_000, 5_000, 4_000, 4_000]
+    );
+}
+
+#[test]
+fn answers_with_exceptions() {
+    let mut master: Master = Master::new();
+    // Unknown function.
+    assert_eq!(
+        master.request(&[ADDRESS, 0x2B, 0x0E, 0x01, 0x00]),
+        Some(vec![ADDRESS, 0xAB, 0x01])
+    );
+    // Past the last input register.
+    assert_eq!(
+        master.request(&[ADDRESS, 0x04, 0x00, 0x0E, 0x00, 0x02]),
+        Some(vec![ADDRESS, 0x84, 0x02])
+    );
+    // No such mode.
+    assert_eq!(
+        master.request(&[ADDRESS, 0x06, 0x00, 0x00, 0x00, 0x09]),
+        Some(vec![ADDRESS, 0x86, 0x03])
+    );
+    assert_eq!(master.input_register(12), 3);
+}
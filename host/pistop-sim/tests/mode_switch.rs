@@ -0,0 +1,448 @@
+/*
+ * Explores every order in which the system mode handler, the mode tasks and
+ * the user can take their steps, to show that switching modes is safe: a
+ * permit is never handed out twice, no two mode tasks run at once, and the
+ * handler never gets stuck, however the user turns the switch while the
+ * permits are being collected.
+ *
+ * The handler is the `ModeSwitch` of the controller, driven the way
+ * `modes::system_mode()` drives it. The mode tasks, the semaphores, the signal
+ * from the mode reader, the faults and the preemption are small models, each of which takes
+ * one step at a time. Time is left out: a task can take its next step at any
+ * moment, so every timing of the real tasks is among the orders explored.
+ */
+
+use std::collections::{HashMap, VecDeque};
+
+use enum_ordinalize::Ordinalize;
+use pistop_core::mode_switch::{ModeSwitch, PermitError};
+use pistop_protocol::SystemMode;
+use pistop_sim::controller::START_MODE;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+enum Handler {
+    // About to release the lockout and enter the next mode.
+    Entering,
+    // Waiting for another mode to be asked for.
+    Running,
+    // Locked out, taking the permits back.
+    Collecting,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+enum Task {
+    // Waiting for a permit.
+    Waiting,
+    // Holding its permit, going through a cycle.
+    Cycling,
+    // Out of its inner loop, finishing the cycle.
+    Finishing,
+}
+
+// How the mode tasks with an inner loop learn that they have to let go.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Design {
+    // The handler sets the lockout, as in `modes.rs`.
+    Lockout,
+    // The tasks look at a global variable with the mode on the switch, the
+    // design that the comment in `system_mode()` warns against.
+    GlobalMode,
+}
+
+// The mode tasks of the controller, with whether they have an inner loop.
+const TASKS: [(SystemMode, bool); 10] = [
+    (SystemMode::Normal, false),
+    (SystemMode::Normal, false),
+    (SystemMode::Flash, true),
+    (SystemMode::PriorityA, true),
+    (SystemMode::PriorityB, true),
+    (SystemMode::Railway, true),
+    (SystemMode::Manual, true),
+    (SystemMode::AllRed, true),
+    (SystemMode::Dark, true),
+    (SystemMode::Pelican, true),
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+struct State {
+    handler: Handler,
+    switch: ModeSwitch,
+    lockout: bool,
+    // The mode waiting in the signal from the mode reader, if any.
+    signal: Option<SystemMode>,
+    // The mode on the switch, as far as the mode reader is concerned.
+    switched_to: SystemMode,
+    force_flash: bool,
+    faults_changed: bool,
+    // The priority mode that an emergency vehicle calls for, if any.
+    preempted: Option<SystemMode>,
+    preemption_changed: bool,
+    permits: [u8; SystemMode::VARIANT_COUNT],
+    tasks: [Task; TASKS.len()],
+}
+
+impl State {
+    fn start() -> Self {
+        State {
+            handler: Handler::Entering,
+            switch: ModeSwitch::new(START_MODE),
+            lockout: true,
+            signal: None,
+            switched_to: START_MODE,
+            force_flash: false,
+            faults_changed: false,
+            preempted: None,
+            preemption_changed: false,
+            permits: [0; SystemMode::VARIANT_COUNT],
+            tasks: [Task::Waiting; TASKS.len()],
+        }
+    }
+
+    // Nothing left to do until the user or a fault asks for another mode.
+    fn settled(&self) -> bool {
+        self.handler == Handler::Running
+            && self.signal.is_none()
+            && !self.faults_changed
+            && !self.preemption_changed
+            && !self.switch.must_leave(self.force_flash)
+    }
+
+    // The modes of the tasks that hold a permit.
+    fn active(&self) -> Vec<SystemMode> {
+        TASKS
+            .iter()
+            .zip(self.tasks)
+            .filter(|(_, task)| *task != Task::Waiting)
+            .map(|((mode, _), _)| *mode)
+            .collect()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if let Some(mode) = SystemMode::VARIANTS
+            .iter()
+            .find(|mode| self.permits[mode.ordinal() as usize] > 1)
+        {
+            return Err(format!(
+                "the semaphore of {mode:?} has more than one permit"
+            ));
+        }
+        let active: Vec<SystemMode> = self.active();
+        if active.len() > 1 {
+            return Err(format!("mode tasks of {active:?} are active at once"));
+        }
+        Ok(())
+    }
+}
+
+type Step = (String, Result<State, PermitError>);
+
+// The steps that the controller can take from the state.
+fn controller_steps(state: &State, design: Design) -> Vec<Step> {
+    let mut steps: Vec<Step> = Vec::new();
+    match state.handler {
+        Handler::Entering => {
+            let mut next: State = *state;
+            next.lockout = false;
+            if let Some(mode) = next.signal.take() {
+                next.switch.request(mode);
+            }
+            next.switch.preempt(next.preempted);
+            let entered: Result<State, PermitError> =
+                next.switch.enter(next.force_flash).map(|mode| {
+                    next.permits[mode.ordinal() as usize] += 1;
+                    next.handler = Handler::Running;
+                    next
+                });
+            steps.push(("handler enters a mode".to_string(), entered));
+        }
+        Handler::Running => {
+            // The handler waits for any of them, and takes whichever comes
+            // first.
+            let mut woken: Vec<State> = Vec::new();
+            if let Some(mode) = state.signal {
+                let mut next: State = *state;
+                next.signal = None;
+                next.switch.request(mode);
+                woken.push(next);
+            }
+            if state.faults_changed {
+                let mut next: State = *state;
+                next.faults_changed = false;
+                woken.push(next);
+            }
+            if state.preemption_changed {
+                let mut next: State = *state;
+                next.preemption_changed = false;
+                woken.push(next);
+            }
+            for mut next in woken {
+                next.switch.preempt(next.preempted);
+                if next.switch.must_leave(next.force_flash) {
+                    next.switch.lock_out();
+                    next.lockout = true;
+                    next.handler = Handler::Collecting;
+                }
+                steps.push(("handler wakes up".to_string(), Ok(next)));
+            }
+        }
+        Handler::Collecting => match state.switch.next_to_collect() {
+            None => {
+                let mut next: State = *state;
+                next.handler = Handler::Entering;
+                steps.push(("handler has every permit".to_string(), Ok(next)));
+            }
+            Some(mode) if state.permits[mode.ordinal() as usize] > 0 => {
+                let mut next: State = *state;
+                next.permits[mode.ordinal() as usize] -= 1;
+                let collected: Result<State, PermitError> =
+                    next.switch.collected(mode).map(|_| next);
+                steps.push((format!("handler takes back {mode:?}"), collected));
+            }
+            Some(_) => {}
+        },
+    }
+
+    for (i, ((mode, inner_loop), task)) in TASKS.iter().zip(state.tasks).enumerate() {
+        let permits: usize = mode.ordinal() as usize;
+        let mut next: State = *state;
+        let label: String = format!("task {i} of {mode:?}");
+        match task {
+            Task::Waiting if state.permits[permits] > 0 => {
+                next.permits[permits] -= 1;
+                next.tasks[i] = Task::Cycling;
+                steps.push((format!("{label} takes its permit"), Ok(next)));
+            }
+            Task::Cycling if !*inner_loop => {
+                next.permits[permits] += 1;
+                next.tasks[i] = Task::Waiting;
+                steps.push((format!("{label} completes a cycle"), Ok(next)));
+            }
+            Task::Cycling => {
+                let let_go: bool = match design {
+                    Design::Lockout => state.lockout,
+                    Design::GlobalMode => state.switched_to != *mode,
+                };
+                if let_go {
+                    next.tasks[i] = Task::Finishing;
+                    steps.push((format!("{label} leaves its inner loop"), Ok(next)));
+                }
+            }
+            Task::Finishing => {
+                next.permits[permits] += 1;
+                next.tasks[i] = Task::Waiting;
+                steps.push((format!("{label} completes a cycle"), Ok(next)));
+            }
+            Task::Waiting => {}
+        }
+    }
+    steps
+}
+
+// What the user and the faults can do at any moment.
+fn input_steps(state: &State) -> Vec<Step> {
+    let mut steps: Vec<Step> = Vec::new();
+    // The mode reader only signals a mode that differs from the one before.
+    // The host may still ask for the railway mode, which has to be ignored.
+    for mode in SystemMode::VARIANTS.iter().copied() {
+        if mode != state.switched_to {
+            let mut next: State = *state;
+            next.signal = Some(mode);
+            next.switched_to = mode;
+            steps.push((format!("user switches to {mode:?}"), Ok(next)));
+        }
+    }
+    let mut next: State = *state;
+    next.force_flash = !state.force_flash;
+    next.faults_changed = true;
+    steps.push(("a fault comes or goes".to_string(), Ok(next)));
+    for preempted in [
+        None,
+        Some(SystemMode::PriorityA),
+        Some(SystemMode::PriorityB),
+        Some(SystemMode::Railway),
+    ] {
+        if preempted != state.preempted {
+            let mut next: State = *state;
+            next.preempted = preempted;
+            next.preemption_changed = true;
+            steps.push((
+                format!("an emergency vehicle or a train calls for {preempted:?}"),
+                Ok(next),
+            ));
+        }
+    }
+    steps
+}
+
+struct Exploration {
+    states: Vec<State>,
+    // How every state was first reached, for the counterexamples.
+    parents: Vec<Option<(usize, String)>>,
+    // The states that the controller can step to on its own.
+    controller_edges: Vec<Vec<usize>>,
+}
+
+impl Exploration {
+    // The steps from the start to the state.
+    fn path(&self, mut index: usize) -> String {
+        let mut steps: Vec<String> = Vec::new();
+        while let Some((parent, label)) = &self.parents[index] {
+            steps.push(label.clone());
+            index = *parent;
+        }
+        steps.reverse();
+        steps.join("\n  ")
+    }
+}
+
+// A breadth-first search of every state that can be reached, failing on the
+// first state that breaks the rules.
+fn explore(design: Design) -> Result<Exploration, String> {
+    let mut exploration: Exploration = Exploration {
+        states: vec![State::start()],
+        parents: vec![None],
+        controller_edges: Vec::new(),
+    };
+    let mut index: HashMap<State, usize> = HashMap::from([(State::start(), 0)]);
+    let mut queue: VecDeque<usize> = VecDeque::from([0]);
+    while let Some(current) = queue.pop_front() {
+        let state: State = exploration.states[current];
+        let controller: Vec<Step> = controller_steps(&state, design);
+        let controller_count: usize = controller.len();
+        let mut edges: Vec<usize> = Vec::new();
+        for (n, (label, next)) in controller
+            .into_iter()
+            .chain(input_steps(&state))
+            .enumerate()
+        {
+            let next: State = next.map_err(|error| {
+                format!(
+                    "{error:?} after\n  {}\n  {label}",
+                    exploration.path(current)
+                )
+            })?;
+            let next_index: usize = *index.entry(next).or_insert_with(|| {
+                exploration.states.push(next);
+                exploration.parents.push(Some((current, label)));
+                queue.push_back(exploration.states.len() - 1);
+                exploration.states.len() - 1
+            });
+            if let Err(error) = next.check() {
+                return Err(format!("{error} after\n  {}", exploration.path(next_index)));
+            }
+            if n < controller_count {
+                edges.push(next_index);
+            }
+        }
+        if exploration.controller_edges.len() <= current {
+            exploration.controller_edges.resize(current + 1, Vec::new());
+        }
+        exploration.controller_edges[current] = edges;
+    }
+    Ok(exploration)
+}
+
+// The states from which the controller cannot settle without help from the
+// user, such as when it waits for a permit that never comes back.
+fn stuck(exploration: &Exploration) -> Vec<usize> {
+    let mut reverse: Vec<Vec<usize>> = vec![Vec::new(); exploration.states.len()];
+    for (from, edges) in exploration.controller_edges.iter().enumerate() {
+        for to in edges {
+            reverse[*to].push(from);
+        }
+    }
+    let mut can_settle: Vec<bool> = exploration
+        .states
+        .iter()
+        .map(|state| state.settled())
+        .collect();
+    let mut queue: VecDeque<usize> = (0..exploration.states.len())
+        .filter(|i| can_settle[*i])
+        .collect();
+    while let Some(to) = queue.pop_front() {
+        for from in &reverse[to] {
+            if !can_settle[*from] {
+                can_settle[*from] = true;
+                queue.push_back(*from);
+            }
+        }
+    }
+    (0..exploration.states.len())
+        .filter(|i| !can_settle[*i])
+        .collect()
+}
+
+#[test]
+fn permits_are_never_handed_out_twice_and_one_mode_runs_at_a_time() {
+    let exploration: Exploration =
+        explore(Design::Lockout).unwrap_or_else(|error| panic!("{error}"));
+    // The user switching while the permits are being collected is among the
+    // states, or there would be little to show.
+    assert!(
+        exploration
+            .states
+            .iter()
+            .any(|state| { state.handler == Handler::Collecting && state.signal.is_some() })
+    );
+    assert!(
+        exploration.states.len() > 1_000,
+        "{}",
+        exploration.states.len()
+    );
+}
+
+#[test]
+fn the_handler_always_gets_to_the_mode_asked_for() {
+    let exploration: Exploration =
+        explore(Design::Lockout).unwrap_or_else(|error| panic!("{error}"));
+    if let Some(state) = stuck(&exploration).first() {
+        panic!(
+            "the controller cannot settle after\n  {}",
+            exploration.path(*state)
+        );
+    }
+}
+
+// The explorer finds the deadlock that the comment in `system_mode()` warns
+// about: switching away and back while the permits are being collected.
+#[test]
+fn a_global_mode_instead_of_the_lockout_deadlocks() {
+    let exploration: Exploration =
+        explore(Design::GlobalMode).unwrap_or_else(|error| panic!("{error}"));
+    // Stuck for good: not a single step the controller can take.
+    let deadlock: usize = stuck(&exploration)
+        .into_iter()
+        .find(|i| controller_steps(&exploration.states[*i], Design::GlobalMode).is_empty())
+        .expect("no deadlock found");
+    let state: &State = &exploration.states[deadlock];
+    assert_eq!(
+        state.handler,
+        Handler::Collecting,
+        "{}",
+        exploration.path(deadlock)
+    );
+    // A mode task holds on to its permit, since the switch is back at its mode.
+    assert_eq!(state.active(), vec![state.switched_to]);
+}
+
+#[test]
+fn the_handler_refuses_to_hand_out_a_permit_twice() {
+    let mut switch: ModeSwitch = ModeSwitch::new(SystemMode::Normal);
+    assert_eq!(switch.enter(false), Ok(SystemMode::Normal));
+    assert_eq!(switch.handed_out(), 0b0001);
+    switch.request(SystemMode::Flash);
+    assert_eq!(
+        switch.enter(false),
+        Err(PermitError::HandedOut(SystemMode::Normal))
+    );
+    switch.lock_out();
+    assert_eq!(switch.next_to_collect(), Some(SystemMode::Normal));
+    assert_eq!(switch.collected(SystemMode::Normal), Ok(()));
+    assert_eq!(
+        switch.collected(SystemMode::Normal),
+        Err(PermitError::NotHandedOut(SystemMode::Normal))
+    );
+    assert_eq!(switch.next_to_collect(), None);
+    assert_eq!(switch.enter(true), Ok(SystemMode::Flash));
+    assert_eq!(switch.handed_out(), 0b0010);
+}
@@ -0,0 +1,198 @@
+/*
+ * A crossing in the middle of a block. The road, approach B, rests on green
+ * and only stops for the pedestrians of A once it had its minimum green. A
+ * pelican lets the road go again after a flashing amber, a puffin after a red.
+ * A puffin also forgets a press once its kerbside detector sees nobody
+ * waiting at the kerb.
+ */
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_core::settings::Settings;
+use pistop_protocol::{Approach, ConfigKey, Pins, SystemMode};
+use pistop_sim::controller::TICK;
+use pistop_sim::{Controller, Simulation};
+
+mod common;
+use common::{press, run_until, set, start_from_host};
+
+// Has the host ask for the pelican mode, then runs until the road is green.
+fn start() -> (Simulation, Controller) {
+    let (mut simulation, controller) = start_from_host(SystemMode::Pelican);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    (simulation, controller)
+}
+
+#[test]
+fn the_road_rests_on_green() {
+    let (mut simulation, controller) = start();
+    assert_eq!(controller.sample().mode, SystemMode::Pelican);
+    let deadline: Instant = Instant::now() + Duration::from_secs(120);
+    while Instant::now() < deadline {
+        assert!(controller.is_lit(Pins::BGreen));
+        assert!(controller.is_lit(Pins::ARed));
+        assert!(controller.is_lit(Pins::APedestrianRed));
+        simulation.run_for(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn the_road_gets_its_minimum_green() {
+    let (mut simulation, controller) = start();
+    let green_at: Instant = Instant::now();
+    press(&mut simulation, &controller, Approach::A);
+    assert!(controller.is_lit(Pins::APromise));
+
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::BAmber, true);
+    assert!(amber_at - green_at >= Duration::from_millis(20_000));
+    assert!(amber_at - green_at <= Duration::from_millis(20_000) + 2 * TICK + TICK);
+}
+
+#[test]
+fn the_pedestrians_walk_and_then_flash() {
+    let (mut simulation, controller) = start();
+    simulation.run_for(Duration::from_secs(30));
+
+    // The road had its minimum green already, so it stops right away.
+    controller.set_button(Approach::A, true);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::BAmber, true);
+    controller.set_button(Approach::A, false);
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::BRed, true);
+    // The outputs only change on a tick of the output loop.
+    assert!(red_at - amber_at >= Duration::from_millis(6_000) - TICK);
+    let walk_at: Instant = run_until(&mut simulation, &controller, Pins::APedestrianGreen, true);
+    assert!(walk_at - red_at >= Duration::from_millis(4_000) - TICK);
+    assert!(!controller.is_lit(Pins::APromise));
+
+    // The road flashes amber along with the green of the pedestrians, ...
+    let flash_at: Instant = run_until(&mut simulation, &controller, Pins::BRed, false);
+    assert!(flash_at - walk_at >= Duration::from_millis(6_000) - TICK);
+    let (mut amber_blinked, mut green_blinked): (bool, bool) = (false, false);
+    while !controller.is_lit(Pins::BGreen) {
+        assert!(!controller.is_lit(Pins::BRed));
+        amber_blinked |= !controller.is_lit(Pins::BAmber);
+        green_blinked |= !controller.is_lit(Pins::APedestrianGreen);
+        simulation.run_for(Duration::from_millis(10));
+    }
+    assert!(amber_blinked);
+    assert!(green_blinked);
+    // ... and goes straight back to green.
+    let green_at: Instant = Instant::now();
+    assert!(green_at - flash_at >= Duration::from_millis(8_000) - TICK);
+    assert!(controller.is_lit(Pins::APedestrianRed));
+    assert!(!controller.is_lit(Pins::APedestrianGreen));
+
+    let statistics = controller.statistics.snapshot();
+    assert_eq!(statistics.cycles[SystemMode::Pelican.ordinal() as usize], 1);
+    assert_eq!(
+        statistics.approaches[Approach::A.ordinal() as usize].served,
+        1
+    );
+}
+
+#[test]
+fn a_puffin_holds_the_road_at_red_instead_of_flashing() {
+    let (mut simulation, controller) = start();
+    set(&controller, ConfigKey::Puffin, 1);
+    set(&controller, ConfigKey::PelicanFlashMs, 15_000);
+    simulation.run_for(Duration::from_secs(30));
+    controller.set_kerbside_detector(true);
+    press(&mut simulation, &controller, Approach::A);
+
+    run_until(&mut simulation, &controller, Pins::APedestrianGreen, true);
+    controller.set_kerbside_detector(false);
+    let clear_at: Instant = run_until(&mut simulation, &controller, Pins::APedestrianGreen, false);
+    // No flashing, the pedestrians see red and the road stays red.
+    while !controller.is_lit(Pins::BAmber) {
+        assert!(controller.is_lit(Pins::APedestrianRed));
+        assert!(controller.is_lit(Pins::BRed));
+        simulation.run_for(Duration::from_millis(10));
+    }
+    let attention_at: Instant = Instant::now();
+    assert!(attention_at - clear_at >= Duration::from_millis(15_000) - TICK);
+    assert!(attention_at - clear_at <= Duration::from_millis(15_000) + 3 * TICK);
+    // The road gets its red and amber before the green.
+    assert!(controller.is_lit(Pins::BRed));
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::BGreen, true);
+    assert!(green_at - attention_at >= Duration::from_millis(3_000) - TICK);
+}
+
+#[test]
+fn a_puffin_cancels_a_press_with_nobody_at_the_kerb() {
+    let (mut simulation, controller) = start();
+    set(&controller, ConfigKey::Puffin, 1);
+    simulation.run_for(Duration::from_secs(30));
+    press(&mut simulation, &controller, Approach::A);
+    simulation.run_for(Duration::from_secs(1));
+    assert!(!controller.is_lit(Pins::APromise));
+
+    let deadline: Instant = Instant::now() + Duration::from_secs(60);
+    while Instant::now() < deadline {
+        assert!(controller.is_lit(Pins::BGreen));
+        simulation.run_for(Duration::from_millis(10));
+    }
+    let statistics = controller.statistics.snapshot();
+    let approach = statistics.approaches[Approach::A.ordinal() as usize];
+    assert_eq!(approach.requests, 1);
+    assert_eq!(approach.dropped, 1);
+    assert_eq!(approach.served, 0);
+}
+
+#[test]
+fn a_puffin_cancels_a_press_once_the_kerb_is_empty() {
+    let (mut simulation, controller) = start();
+    set(&controller, ConfigKey::Puffin, 1);
+    // Someone presses during the minimum green, then crosses in a gap in the
+    // traffic.
+    controller.set_kerbside_detector(true);
+    press(&mut simulation, &controller, Approach::A);
+    simulation.run_for(Duration::from_secs(5));
+    assert!(controller.is_lit(Pins::APromise));
+    controller.set_kerbside_detector(false);
+    simulation.run_for(Duration::from_secs(1));
+    assert!(!controller.is_lit(Pins::APromise));
+
+    let deadline: Instant = Instant::now() + Duration::from_secs(60);
+    while Instant::now() < deadline {
+        assert!(controller.is_lit(Pins::BGreen));
+        simulation.run_for(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn the_crossing_clears_on_the_way_out() {
+    let (mut simulation, controller) = start();
+    simulation.run_for(Duration::from_secs(30));
+    press(&mut simulation, &controller, Approach::A);
+    run_until(&mut simulation, &controller, Pins::BRed, true);
+    run_until(&mut simulation, &controller, Pins::BRed, false);
+
+    // During the flashing amber, the road goes through a steady amber.
+    controller.system_mode_signal.signal(SystemMode::Normal);
+    let mut steady_amber: Option<Instant> = None;
+    while !controller.is_lit(Pins::BRed) {
+        assert!(!controller.is_lit(Pins::BGreen));
+        if controller.is_lit(Pins::BAmber) {
+            steady_amber.get_or_insert(Instant::now());
+        } else {
+            steady_amber = None;
+        }
+        simulation.run_for(Duration::from_millis(10));
+    }
+    let red_at: Instant = Instant::now();
+    assert!(red_at - steady_amber.unwrap() >= Duration::from_millis(6_000) - TICK);
+    simulation.run_for(Duration::from_secs(10));
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+}
+
+#[test]
+fn the_pelican_settings_can_be_changed() {
+    let mut settings: Settings = Settings::new();
+    assert!(!settings.puffin);
+    assert!(settings.set(ConfigKey::Puffin, 1).is_ok());
+    assert!(settings.set(ConfigKey::Puffin, 2).is_err());
+    assert!(settings.set(ConfigKey::PelicanWalkMs, 0).is_err());
+    assert!(settings.set(ConfigKey::PelicanMinGreenMs, 30_000).is_ok());
+    assert_eq!(settings.get(ConfigKey::PelicanMinGreenMs), 30_000);
+    assert!(settings.puffin);
+}
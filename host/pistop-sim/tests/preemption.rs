@@ -0,0 +1,249 @@
+/*
+ * Emergency vehicles that call for the green of their approach. The crossing
+ * has to cut normal mode short without cutting the amber or the clearance,
+ * give the green to the caller for at least the dwell and go back to the mode
+ * that it ran before.
+ */
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_core::preemption::Caller;
+use pistop_core::settings::Settings;
+use pistop_protocol::{Approach, ConfigKey, EventKind, Pins, SystemMode};
+use pistop_sim::Controller;
+use pistop_sim::controller::TICK;
+
+mod common;
+use common::{run_until, set, start};
+
+// Long enough for normal mode to clear the crossing and for the priority mode
+// to get to its green.
+const PREEMPTION_TIME: Duration = Duration::from_secs(15);
+
+fn events(controller: &Controller) -> Vec<EventKind> {
+    let mut kinds: Vec<EventKind> = Vec::new();
+    let mut next: u32 = 0;
+    loop {
+        let (events, after) = controller.events.read_from(next);
+        if events.is_empty() {
+            return kinds;
+        }
+        kinds.extend(events.iter().map(|event| event.kind));
+        next = after;
+    }
+}
+
+fn preemptions(controller: &Controller) -> Vec<EventKind> {
+    events(controller)
+        .into_iter()
+        .filter(|kind| {
+            matches!(
+                kind,
+                EventKind::PreemptionStarted(_) | EventKind::PreemptionEnded(_)
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn a_call_cuts_the_green_short_but_not_the_amber() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    simulation.run_for(Duration::from_secs(1));
+
+    let called_at: Instant = Instant::now();
+    controller.set_preemption_input(Approach::B, true);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    // The input settles first.
+    assert!(amber_at - called_at <= Duration::from_millis(100));
+    assert!(!controller.is_lit(Pins::AGreen));
+
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::ARed, true);
+    // The outputs only change on a tick of the output loop.
+    assert!(red_at - amber_at >= Duration::from_millis(6_000) - TICK);
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::BGreen, true);
+    assert!(green_at - red_at >= Duration::from_millis(4_000) - TICK);
+    assert!(green_at - called_at <= PREEMPTION_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::PriorityB);
+    assert_eq!(
+        preemptions(&controller),
+        vec![EventKind::PreemptionStarted(Approach::B)]
+    );
+}
+
+#[test]
+fn a_call_before_the_turn_starts_skips_it() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    // A clears the crossing and B is about to take its turn.
+    run_until(&mut simulation, &controller, Pins::AAmber, true);
+    run_until(&mut simulation, &controller, Pins::AAmber, false);
+    controller.set_preemption_input(Approach::A, true);
+
+    let deadline: Instant = Instant::now() + PREEMPTION_TIME;
+    while !controller.is_lit(Pins::AGreen) {
+        assert!(Instant::now() < deadline, "AGreen never lit");
+        assert!(!controller.is_lit(Pins::BAmber), "B started its turn");
+        assert!(!controller.is_lit(Pins::BGreen), "B started its turn");
+        simulation.run_for(Duration::from_millis(10));
+    }
+    assert_eq!(controller.sample().mode, SystemMode::PriorityA);
+}
+
+#[test]
+fn a_short_call_still_gets_the_dwell() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    set(&controller, ConfigKey::PreemptionDwellMs, 12_000);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+
+    controller.set_preemption_input(Approach::A, true);
+    simulation.run_for(Duration::from_millis(200));
+    controller.set_preemption_input(Approach::A, false);
+
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::AGreen, true);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    // The priority mode looks for the end of the preemption twice a second.
+    assert!(amber_at - green_at >= Duration::from_millis(12_000) - TICK);
+    assert!(amber_at - green_at <= Duration::from_millis(12_600));
+
+    simulation.run_for(Duration::from_secs(10));
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+    assert_eq!(
+        preemptions(&controller),
+        vec![
+            EventKind::PreemptionStarted(Approach::A),
+            EventKind::PreemptionEnded(Approach::A),
+        ]
+    );
+}
+
+#[test]
+fn the_green_holds_for_as_long_as_the_call() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller.set_preemption_input(Approach::B, true);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    simulation.run_for(Duration::from_secs(60));
+    assert!(controller.is_lit(Pins::BGreen));
+
+    controller.set_preemption_input(Approach::B, false);
+    run_until(&mut simulation, &controller, Pins::BAmber, true);
+    simulation.run_for(Duration::from_secs(10));
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+}
+
+#[test]
+fn goes_back_to_the_mode_it_ran_before() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.set_preemption_input(Approach::A, true);
+    simulation.run_for(PREEMPTION_TIME);
+    assert!(controller.is_lit(Pins::AGreen));
+    assert_eq!(controller.sample().mode, SystemMode::PriorityA);
+
+    // The call holds until it had the dwell.
+    controller.set_preemption_input(Approach::A, false);
+    simulation.run_for(Duration::from_secs(15));
+    assert_eq!(controller.sample().mode, SystemMode::Flash);
+}
+
+#[test]
+fn goes_to_a_mode_that_was_asked_for_in_the_meantime() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller.set_preemption_input(Approach::A, true);
+    simulation.run_for(PREEMPTION_TIME);
+    controller.set_mode_switch(SystemMode::Flash);
+    simulation.run_for(Duration::from_secs(5));
+    assert_eq!(controller.sample().mode, SystemMode::PriorityA);
+
+    controller.set_preemption_input(Approach::A, false);
+    simulation.run_for(Duration::from_secs(10));
+    assert_eq!(controller.sample().mode, SystemMode::Flash);
+}
+
+#[test]
+fn normal_mode_starts_again_with_the_exit_approach() {
+    for exit in [Approach::A, Approach::B] {
+        let (mut simulation, controller) = start(SystemMode::Normal);
+        set(&controller, ConfigKey::PreemptionExit, exit as u32);
+        controller.set_preemption_input(Approach::A, true);
+        run_until(&mut simulation, &controller, Pins::AGreen, true);
+        controller.set_preemption_input(Approach::A, false);
+        run_until(&mut simulation, &controller, Pins::AGreen, false);
+
+        let (exit_green, other_green) = match exit {
+            Approach::A => (Pins::AGreen, Pins::BGreen),
+            Approach::B => (Pins::BGreen, Pins::AGreen),
+        };
+        run_until(&mut simulation, &controller, exit_green, true);
+        assert!(!controller.is_lit(other_green));
+        assert_eq!(controller.sample().mode, SystemMode::Normal);
+        let statistics = controller.statistics.snapshot();
+        assert_eq!(
+            statistics.cycles[SystemMode::PriorityA.ordinal() as usize],
+            1
+        );
+    }
+}
+
+#[test]
+fn serves_the_first_call_first() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    set(&controller, ConfigKey::PreemptionDwellMs, 5_000);
+    controller.set_preemption_input(Approach::B, true);
+    simulation.run_for(Duration::from_secs(1));
+    controller.set_preemption_input(Approach::A, true);
+    simulation.run_for(PREEMPTION_TIME);
+    assert!(controller.is_lit(Pins::BGreen));
+
+    controller.set_preemption_input(Approach::B, false);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    assert_eq!(controller.sample().mode, SystemMode::PriorityA);
+    assert_eq!(
+        preemptions(&controller),
+        vec![
+            EventKind::PreemptionStarted(Approach::B),
+            EventKind::PreemptionEnded(Approach::B),
+            EventKind::PreemptionStarted(Approach::A),
+        ]
+    );
+}
+
+#[test]
+fn the_host_and_the_input_call_together() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller
+        .preemption
+        .set_call(Caller::Host, Approach::B, true);
+    controller.set_preemption_input(Approach::B, true);
+    // Long enough for the dwell, after which the call only holds for as long
+    // as someone calls.
+    simulation.run_for(PREEMPTION_TIME * 2);
+    assert_eq!(controller.preemption.called(), Some(Approach::B));
+
+    controller
+        .preemption
+        .set_call(Caller::Host, Approach::B, false);
+    simulation.run_for(Duration::from_secs(1));
+    assert_eq!(controller.preemption.called(), Some(Approach::B));
+    controller.set_preemption_input(Approach::B, false);
+    simulation.run_for(Duration::from_secs(1));
+    assert_eq!(controller.preemption.called(), None);
+}
+
+#[test]
+fn a_fault_beats_the_call() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller.set_preemption_input(Approach::A, true);
+    simulation.run_for(PREEMPTION_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::PriorityA);
+
+    controller.faults.set_lamp_out(Pins::ARed);
+    simulation.run_for(PREEMPTION_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::Flash);
+}
+
+#[test]
+fn the_exit_approach_has_to_exist() {
+    let mut settings: Settings = Settings::new();
+    assert!(settings.set(ConfigKey::PreemptionExit, 1).is_ok());
+    assert!(settings.set(ConfigKey::PreemptionExit, 2).is_err());
+    assert_eq!(settings.preemption_exit, Approach::B);
+}
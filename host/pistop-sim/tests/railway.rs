@@ -0,0 +1,171 @@
+/*
+ * Trains that pass the track circuit. The crossing has to clear the approach
+ * over the tracks first, without cutting the amber or the clearance, hold it
+ * at red while the train passes and let the other approach go in the
+ * meantime. Once the track is clear, it goes back to the mode that it ran
+ * before.
+ */
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_core::settings::Settings;
+use pistop_protocol::{Approach, ConfigKey, Pins, SystemMode};
+use pistop_sim::controller::TICK;
+
+mod common;
+use common::{run_until, set, start};
+
+// Long enough for normal mode to clear the crossing and for the railway mode
+// to get through the track clearance.
+const RAILWAY_TIME: Duration = Duration::from_secs(40);
+
+#[test]
+fn a_train_cuts_the_green_short_but_not_the_amber() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    simulation.run_for(Duration::from_secs(1));
+
+    let train_at: Instant = Instant::now();
+    controller.set_track_circuit(true);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::BAmber, true);
+    // The input settles first.
+    assert!(amber_at - train_at <= Duration::from_millis(100));
+    assert!(!controller.is_lit(Pins::BGreen));
+
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::BRed, true);
+    // The outputs only change on a tick of the output loop.
+    assert!(red_at - amber_at >= Duration::from_millis(6_000) - TICK);
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::AGreen, true);
+    assert!(green_at - red_at >= Duration::from_millis(4_000) - TICK);
+    assert_eq!(controller.sample().mode, SystemMode::Railway);
+}
+
+#[test]
+fn the_tracks_get_cleared_and_then_held() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    set(&controller, ConfigKey::TrackClearanceMs, 7_000);
+    controller.set_track_circuit(true);
+
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::AGreen, true);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::AAmber, true);
+    assert!(amber_at - green_at >= Duration::from_millis(7_000) - TICK);
+    assert!(amber_at - green_at <= Duration::from_millis(7_000) + TICK);
+    run_until(&mut simulation, &controller, Pins::ARed, true);
+
+    // Nobody walks while the tracks are being cleared.
+    assert!(!controller.is_lit(Pins::APedestrianGreen));
+    assert!(!controller.is_lit(Pins::BPedestrianGreen));
+
+    // The other approach goes, with the pedestrians that asked to walk along,
+    // and the tracks stay red for as long as the train is near.
+    controller.set_button(Approach::B, true);
+    simulation.run_for(Duration::from_millis(200));
+    controller.set_button(Approach::B, false);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    assert!(controller.is_lit(Pins::BPedestrianGreen));
+    let deadline: Instant = Instant::now() + Duration::from_secs(120);
+    while Instant::now() < deadline {
+        assert!(controller.is_lit(Pins::ARed), "the tracks were let go");
+        assert!(!controller.is_lit(Pins::APedestrianGreen));
+        simulation.run_for(Duration::from_millis(10));
+    }
+    assert!(controller.is_lit(Pins::BGreen));
+}
+
+#[test]
+fn goes_back_to_the_mode_it_ran_before() {
+    let (mut simulation, controller) = start(SystemMode::Flash);
+    controller.set_track_circuit(true);
+    simulation.run_for(RAILWAY_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::Railway);
+    assert!(controller.is_lit(Pins::ARed));
+    assert!(controller.is_lit(Pins::BGreen));
+
+    controller.set_track_circuit(false);
+    let amber_at: Instant = run_until(&mut simulation, &controller, Pins::BAmber, true);
+    let red_at: Instant = run_until(&mut simulation, &controller, Pins::BRed, true);
+    assert!(red_at - amber_at >= Duration::from_millis(6_000) - TICK);
+    simulation.run_for(Duration::from_secs(10));
+    assert_eq!(controller.sample().mode, SystemMode::Flash);
+    let statistics = controller.statistics.snapshot();
+    assert_eq!(statistics.cycles[SystemMode::Railway.ordinal() as usize], 1);
+}
+
+#[test]
+fn the_track_circuit_may_drop_out_for_a_moment() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller.set_track_circuit(true);
+    simulation.run_for(RAILWAY_TIME);
+    assert!(controller.is_lit(Pins::BGreen));
+
+    for _ in 0..5 {
+        controller.set_track_circuit(false);
+        simulation.run_for(Duration::from_millis(1_500));
+        controller.set_track_circuit(true);
+        simulation.run_for(Duration::from_millis(200));
+    }
+    assert!(controller.preemption.track_occupied());
+    assert!(controller.is_lit(Pins::BGreen));
+    assert_eq!(controller.sample().mode, SystemMode::Railway);
+
+    controller.set_track_circuit(false);
+    simulation.run_for(Duration::from_millis(2_500));
+    assert!(!controller.preemption.track_occupied());
+}
+
+#[test]
+fn the_tracks_may_cross_approach_b() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    set(&controller, ConfigKey::RailwayApproach, Approach::B as u32);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    controller.set_track_circuit(true);
+
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    run_until(&mut simulation, &controller, Pins::BRed, true);
+    run_until(&mut simulation, &controller, Pins::AGreen, true);
+    simulation.run_for(Duration::from_secs(60));
+    assert!(controller.is_lit(Pins::BRed));
+    assert!(controller.is_lit(Pins::AGreen));
+}
+
+#[test]
+fn a_train_goes_before_an_emergency_vehicle() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    controller.set_preemption_input(Approach::B, true);
+    run_until(&mut simulation, &controller, Pins::BGreen, true);
+    assert_eq!(controller.sample().mode, SystemMode::PriorityB);
+
+    controller.set_track_circuit(true);
+    simulation.run_for(RAILWAY_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::Railway);
+    assert!(controller.is_lit(Pins::ARed));
+
+    // The emergency vehicle still calls, so it gets its green back.
+    controller.set_track_circuit(false);
+    simulation.run_for(RAILWAY_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::PriorityB);
+    assert!(controller.is_lit(Pins::BGreen));
+
+    controller.set_preemption_input(Approach::B, false);
+    simulation.run_for(RAILWAY_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+}
+
+#[test]
+fn nobody_asks_for_the_railway_mode() {
+    let (mut simulation, controller) = start(SystemMode::Normal);
+    // Just as the host would, were it not turned away.
+    controller.system_mode_signal.signal(SystemMode::Railway);
+    simulation.run_for(RAILWAY_TIME);
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+}
+
+#[test]
+fn the_railway_approach_has_to_exist() {
+    let mut settings: Settings = Settings::new();
+    assert!(settings.set(ConfigKey::RailwayApproach, 1).is_ok());
+    assert!(settings.set(ConfigKey::RailwayApproach, 2).is_err());
+    assert_eq!(settings.railway_approach, Approach::B);
+    assert!(settings.set(ConfigKey::TrackClearanceMs, 15_000).is_ok());
+    assert_eq!(settings.track_clearance_ms, 15_000);
+}
@@ -0,0 +1,150 @@
+/*
+ * The scenario format: how scenarios read, how they write back and how the
+ * expectations in them come out when they run.
+ */
+
+use embassy_time::Duration;
+use pistop_protocol::{Approach, Pins, SystemMode};
+use pistop_sim::scenario::{Comparison, Counter, Expectation, Input, Scenario};
+use pistop_sim::{Controller, Simulation};
+
+fn run(text: &str) -> Result<(), String> {
+    let scenario: Scenario = Scenario::parse(text).unwrap();
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.spawn(&mut simulation);
+    scenario.run(&mut simulation, &controller, scenario.end(), |_| {})
+}
+
+#[test]
+fn scenarios_read_back_the_way_they_are_written() {
+    let text: &str = "\
+        # the switch bounces past flash\n\
+        at 1s mode flash\n\
+        at 1.05s mode normal\n\
+        at 20.5s press B   # and the button too\n\
+        at 20.512s release B\n";
+    let scenario: Scenario = Scenario::parse(text).unwrap();
+    assert_eq!(
+        scenario.inputs,
+        vec![
+            (Duration::from_secs(1), Input::Mode(SystemMode::Flash)),
+            (
+                Duration::from_millis(1_050),
+                Input::Mode(SystemMode::Normal)
+            ),
+            (Duration::from_millis(20_500), Input::Press(Approach::B)),
+            (Duration::from_millis(20_512), Input::Release(Approach::B)),
+        ]
+    );
+    assert_eq!(Scenario::parse(&scenario.text()), Ok(scenario));
+    assert!(Scenario::parse("at 2s mode sideways").is_err());
+    assert!(Scenario::parse("at 2s press A\nat 1s release A").is_err());
+}
+
+#[test]
+fn expectations_read_back_the_way_they_are_written() {
+    let text: &str = "\
+        at 2s press A; expect at 2s APromise on\n\
+        expect at 3s BPedestrianGreen off; expect at 3s mode priority-b\n\
+        expect at 4s served A >= 1; expect at 4s cycles flash = 2\n\
+        expect at 5s mode-switches <= 3\n";
+    let scenario: Scenario = Scenario::parse(text).unwrap();
+    assert_eq!(
+        scenario.expectations,
+        vec![
+            (
+                Duration::from_secs(2),
+                Expectation::Lit(Pins::APromise, true)
+            ),
+            (
+                Duration::from_secs(3),
+                Expectation::Lit(Pins::BPedestrianGreen, false)
+            ),
+            (
+                Duration::from_secs(3),
+                Expectation::Mode(SystemMode::PriorityB)
+            ),
+            (
+                Duration::from_secs(4),
+                Expectation::Counter(Counter::Served(Approach::A), Comparison::AtLeast, 1)
+            ),
+            (
+                Duration::from_secs(4),
+                Expectation::Counter(Counter::Cycles(SystemMode::Flash), Comparison::Equal, 2)
+            ),
+            (
+                Duration::from_secs(5),
+                Expectation::Counter(Counter::ModeSwitches, Comparison::AtMost, 3)
+            ),
+        ]
+    );
+    assert_eq!(Scenario::parse(&scenario.text()), Ok(scenario));
+    assert!(Scenario::parse("expect at 2s AGreen dim").is_err());
+    assert!(Scenario::parse("expect at 2s served C = 1").is_err());
+    assert!(Scenario::parse("expect at 2s served A > 1").is_err());
+}
+
+#[test]
+fn repeats_go_round_from_where_they_start() {
+    let text: &str = "\
+        at 10s repeat 3 every 5s\n\
+            at 0s press A; at 0.1s release A\n\
+            at 1s repeat 2 every 1s\n\
+                expect at 0s APromise on\n\
+            end\n\
+        end\n\
+        at 12s mode normal\n";
+    let scenario: Scenario = Scenario::parse(text).unwrap();
+    let times = |milliseconds: &[u64]| -> Vec<Duration> {
+        milliseconds
+            .iter()
+            .map(|ms| Duration::from_millis(*ms))
+            .collect()
+    };
+    assert_eq!(
+        scenario
+            .inputs
+            .iter()
+            .map(|(at, _)| *at)
+            .collect::<Vec<_>>(),
+        times(&[10_000, 10_100, 12_000, 15_000, 15_100, 20_000, 20_100])
+    );
+    assert_eq!(scenario.inputs[2].1, Input::Mode(SystemMode::Normal));
+    assert_eq!(
+        scenario
+            .expectations
+            .iter()
+            .map(|(at, _)| *at)
+            .collect::<Vec<_>>(),
+        times(&[11_000, 12_000, 16_000, 17_000, 21_000, 22_000])
+    );
+    assert_eq!(scenario.end(), Duration::from_secs(22));
+
+    assert_eq!(
+        Scenario::parse("at 1s press A\nend"),
+        Err("line 2: end without a repeat".to_string())
+    );
+    assert_eq!(
+        Scenario::parse("at 1s repeat 2 every 1s\nat 0s press A"),
+        Err("line 1: repeat without an end".to_string())
+    );
+    assert_eq!(
+        Scenario::parse("at 1s press A; at 2s hop A"),
+        Err("line 1: cannot make sense of \"at 2s hop A\"".to_string())
+    );
+}
+
+#[test]
+fn failed_expectations_say_what_was_there_instead() {
+    // The controller starts in flash mode and only then follows the switch.
+    assert_eq!(
+        run("at 0s mode normal\n\
+            expect at 1s mode normal; expect at 1s AGreen on\n\
+            expect at 1s mode-switches = 0\n\
+            expect at 20s mode normal; expect at 20s mode-switches >= 1"),
+        Err("expected at 1.000s mode normal, but the mode is flash\n\
+            expected at 1.000s AGreen on, but AGreen is off"
+            .to_string())
+    );
+}
@@ -0,0 +1,299 @@
+/*
+ * The wall clock and the schedule of a simulated controller. The schedule only
+ * acts on whole minutes, so the tests give it a minute or so to catch up.
+ */
+
+use embassy_time::Duration;
+use pistop_core::wall_clock::Rtc;
+use pistop_protocol::{
+    CalendarAction, CalendarEntry, ConfigKey, Date, EVERY_DAY, ScheduleAction, ScheduleEntry,
+    SystemMode, TimingPlan, WORKDAYS, WallTime,
+};
+use pistop_sim::{Controller, Simulation};
+
+fn start() -> (Simulation, Controller) {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.spawn(&mut simulation);
+    (simulation, controller)
+}
+
+fn time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> WallTime {
+    WallTime::from_date_time(year, month, day, hour, minute, second).unwrap()
+}
+
+fn set_entry(
+    controller: &Controller,
+    index: u8,
+    weekdays: u8,
+    start_minute: u16,
+    action: ScheduleAction,
+) {
+    let entry: ScheduleEntry = ScheduleEntry {
+        weekdays: weekdays,
+        start_minute: start_minute,
+        action: action,
+    };
+    controller
+        .schedule
+        .try_lock()
+        .unwrap()
+        .set(index, Some(entry))
+        .unwrap();
+}
+
+#[test]
+fn wall_time_counts_dates_and_weekdays() {
+    assert_eq!(WallTime(0).date_time(), (2000, 1, 1, 0, 0, 0));
+    // 2000-01-01 was a Saturday, 2024-03-04 a Monday.
+    assert_eq!(WallTime(0).weekday(), 5);
+    assert_eq!(time(2024, 3, 4, 8, 0, 0).weekday(), 0);
+    assert_eq!(time(2024, 3, 4, 8, 15, 30).minute_of_day(), 8 * 60 + 15);
+    for (year, month, day) in [(2024, 2, 29), (2000, 12, 31), (2100, 3, 1), (2135, 6, 15)] {
+        let wall_time: WallTime = time(year, month, day, 23, 59, 58);
+        assert_eq!(wall_time.date_time(), (year, month, day, 23, 59, 58));
+    }
+    assert_eq!(WallTime::from_date_time(2023, 2, 29, 0, 0, 0), None);
+    assert_eq!(WallTime::from_date_time(2100, 2, 29, 0, 0, 0), None);
+    assert_eq!(WallTime::from_date_time(2024, 1, 1, 24, 0, 0), None);
+    // The seconds run out early in 2136.
+    assert_eq!(WallTime::from_date_time(2137, 1, 1, 0, 0, 0), None);
+}
+
+#[test]
+fn wall_clock_starts_from_the_rtc() {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.rtc.set(time(2024, 3, 4, 6, 0, 0));
+    assert_eq!(controller.wall_clock.now(), None);
+    controller.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(90));
+    assert_eq!(
+        controller.wall_clock.now(),
+        Some(time(2024, 3, 4, 6, 1, 30))
+    );
+}
+
+#[test]
+fn the_schedule_and_the_calendar_survive_a_restart() {
+    let (mut simulation, controller) = start();
+    simulation.run_for(Duration::from_secs(1));
+    let holiday: CalendarEntry = CalendarEntry {
+        first: Date {
+            year: 2024,
+            month: 12,
+            day: 25,
+        },
+        last: Date {
+            year: 2024,
+            month: 12,
+            day: 26,
+        },
+        every_year: true,
+        action: CalendarAction::AsWeekday(6),
+    };
+    // The host writes a few entries in a row, like a tool does.
+    set_entry(
+        &controller,
+        0,
+        WORKDAYS,
+        7 * 60,
+        ScheduleAction::Mode(SystemMode::Normal),
+    );
+    controller.host_link.schedule_written.signal(());
+    simulation.run_for(Duration::from_secs(1));
+    set_entry(
+        &controller,
+        1,
+        EVERY_DAY,
+        22 * 60,
+        ScheduleAction::Mode(SystemMode::Flash),
+    );
+    controller.host_link.schedule_written.signal(());
+    simulation.run_for(Duration::from_secs(1));
+    controller
+        .calendar
+        .try_lock()
+        .unwrap()
+        .set(3, Some(holiday))
+        .unwrap();
+    controller.host_link.schedule_written.signal(());
+    simulation.run_for(Duration::from_secs(10));
+    // Stored once, after the host was done.
+    assert_eq!(controller.storage.writes(), 1);
+
+    // The box restarts with the flash as it was.
+    drop(simulation);
+    let mut simulation: Simulation = Simulation::new();
+    let mut restarted: Controller = Controller::new();
+    restarted.storage = controller.storage;
+    restarted.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(1));
+    let schedule = restarted.schedule.try_lock().unwrap();
+    assert_eq!(
+        schedule.get(1),
+        Ok(Some(ScheduleEntry {
+            weekdays: EVERY_DAY,
+            start_minute: 22 * 60,
+            action: ScheduleAction::Mode(SystemMode::Flash),
+        }))
+    );
+    assert_eq!(schedule.get(2), Ok(None));
+    let calendar = restarted.calendar.try_lock().unwrap();
+    assert_eq!(calendar.get(3), Ok(Some(holiday)));
+    assert_eq!(calendar.get(0), Ok(None));
+}
+
+#[test]
+fn a_new_box_keeps_the_built_in_schedule() {
+    let (mut simulation, controller) = start();
+    simulation.run_for(Duration::from_secs(60));
+    assert_eq!(controller.storage.writes(), 0);
+    assert_eq!(controller.schedule.try_lock().unwrap().get(0), Ok(None));
+}
+
+#[test]
+fn setting_the_clock_writes_the_rtc() {
+    let (mut simulation, controller) = start();
+    simulation.run_for(Duration::from_secs(1));
+    assert_eq!((&*controller.rtc).read(), None);
+    controller.wall_clock.set(time(2024, 3, 4, 6, 0, 0));
+    simulation.run_for(Duration::from_secs(10));
+    assert_eq!((&*controller.rtc).read(), Some(time(2024, 3, 4, 6, 0, 10)));
+    assert_eq!(
+        controller.wall_clock.now(),
+        Some(time(2024, 3, 4, 6, 0, 10))
+    );
+}
+
+#[test]
+fn switches_modes_when_an_entry_starts() {
+    let (mut simulation, controller) = start();
+    set_entry(
+        &controller,
+        0,
+        WORKDAYS,
+        7 * 60,
+        ScheduleAction::Mode(SystemMode::Normal),
+    );
+    set_entry(
+        &controller,
+        1,
+        EVERY_DAY,
+        22 * 60,
+        ScheduleAction::Mode(SystemMode::Flash),
+    );
+    // Monday, a minute before the entry starts. It flashes from last night on.
+    controller.wall_clock.set(time(2024, 3, 4, 6, 59, 0));
+    simulation.run_for(Duration::from_secs(30));
+    assert_eq!(controller.sample().mode, SystemMode::Flash);
+
+    // Leaving flashing mode goes through the lockout.
+    simulation.run_for(Duration::from_secs(60));
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+
+    // Turning the switch overrides the schedule until the next entry starts.
+    controller.set_mode_switch(SystemMode::PriorityA);
+    simulation.run_for(Duration::from_secs(120));
+    assert_eq!(controller.sample().mode, SystemMode::PriorityA);
+    controller.wall_clock.set(time(2024, 3, 5, 6, 59, 30));
+    simulation.run_for(Duration::from_secs(60));
+    assert_eq!(controller.sample().mode, SystemMode::Normal);
+}
+
+#[test]
+fn leaves_the_weekend_alone() {
+    let (mut simulation, controller) = start();
+    set_entry(
+        &controller,
+        0,
+        WORKDAYS,
+        7 * 60,
+        ScheduleAction::Mode(SystemMode::Normal),
+    );
+    set_entry(
+        &controller,
+        1,
+        EVERY_DAY,
+        22 * 60,
+        ScheduleAction::Mode(SystemMode::Flash),
+    );
+    controller.set_mode_switch(SystemMode::Normal);
+    simulation.run_for(Duration::from_secs(30));
+    // Saturday morning, the flashing from Friday night holds.
+    controller.wall_clock.set(time(2024, 3, 9, 6, 59, 0));
+    simulation.run_for(Duration::from_secs(120));
+    assert_eq!(controller.sample().mode, SystemMode::Flash);
+}
+
+#[test]
+fn changes_the_timings_when_an_entry_starts() {
+    let (mut simulation, controller) = start();
+    let daytime: TimingPlan = TimingPlan {
+        attention_ms: 2_000,
+        go_ms: 15_000,
+        yield_ms: 4_000,
+        clear_ms: 3_000,
+    };
+    let rush_hour: TimingPlan = TimingPlan {
+        attention_ms: 2_000,
+        go_ms: 25_000,
+        yield_ms: 4_000,
+        clear_ms: 3_000,
+    };
+    set_entry(
+        &controller,
+        2,
+        EVERY_DAY,
+        9 * 60,
+        ScheduleAction::Timings(daytime),
+    );
+    set_entry(
+        &controller,
+        3,
+        WORKDAYS,
+        16 * 60 + 30,
+        ScheduleAction::Timings(rush_hour),
+    );
+    controller.wall_clock.set(time(2024, 3, 4, 16, 29, 30));
+    simulation.run_for(Duration::from_secs(10));
+    let go_ms: u32 = controller
+        .settings
+        .try_lock()
+        .unwrap()
+        .get(ConfigKey::NormalGoMs);
+    assert_eq!(go_ms, 15_000);
+
+    simulation.run_for(Duration::from_secs(60));
+    let settings = controller.settings.try_lock().unwrap();
+    assert_eq!(settings.get(ConfigKey::NormalAttentionMs), 2_000);
+    assert_eq!(settings.get(ConfigKey::NormalGoMs), 25_000);
+    assert_eq!(settings.get(ConfigKey::NormalYieldMs), 4_000);
+    assert_eq!(settings.get(ConfigKey::NormalClearMs), 3_000);
+}
+
+#[test]
+fn refuses_entries_that_make_no_sense() {
+    let controller: Controller = Controller::new();
+    let mut schedule = controller.schedule.try_lock().unwrap();
+    let entry: ScheduleEntry = ScheduleEntry {
+        weekdays: 0,
+        start_minute: 0,
+        action: ScheduleAction::Mode(SystemMode::Flash),
+    };
+    assert!(schedule.set(0, Some(entry)).is_err());
+    let entry: ScheduleEntry = ScheduleEntry {
+        weekdays: EVERY_DAY,
+        start_minute: 24 * 60,
+        action: ScheduleAction::Mode(SystemMode::Flash),
+    };
+    assert!(schedule.set(0, Some(entry)).is_err());
+    let entry: ScheduleEntry = ScheduleEntry {
+        weekdays: EVERY_DAY,
+        start_minute: 0,
+        action: ScheduleAction::Mode(SystemMode::Flash),
+    };
+    assert!(schedule.set(8, Some(entry)).is_err());
+    assert!(schedule.set(7, Some(entry)).is_ok());
+    assert_eq!(schedule.get(7), Ok(Some(entry)));
+}
@@ -0,0 +1,127 @@
+/*
+ * The statistics that a simulated controller keeps while it runs.
+ */
+
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, ApproachStatistics, Pins, Statistics, SystemMode};
+use pistop_sim::{Controller, Simulation};
+
+mod common;
+use common::{press, run_until};
+
+fn start() -> (Simulation, Controller) {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.spawn(&mut simulation);
+    (simulation, controller)
+}
+
+fn approach(controller: &Controller, approach: Approach) -> ApproachStatistics {
+    controller.statistics.snapshot().approaches[approach.ordinal() as usize]
+}
+
+#[test]
+fn counts_cycles_mode_switches_and_lockout() {
+    let (mut simulation, controller) = start();
+    controller.set_mode_switch(SystemMode::Normal);
+    simulation.run_for(Duration::from_secs(60));
+
+    let statistics: Statistics = controller.statistics.snapshot();
+    assert_eq!(statistics.uptime_ms, 60_000);
+    assert_eq!(statistics.counted_ms, 60_000);
+    assert_eq!(statistics.mode_switches, 1);
+    // Leaving flashing mode takes a yield and a clear phase.
+    assert!(statistics.lockout_ms >= 7_000, "{statistics:?}");
+    assert_eq!(statistics.cycles[SystemMode::Flash.ordinal() as usize], 1);
+    assert!(
+        statistics.cycles[SystemMode::Normal.ordinal() as usize] >= 2,
+        "{statistics:?}"
+    );
+}
+
+#[test]
+fn measures_how_long_pedestrians_wait() {
+    let (mut simulation, controller) = start();
+    controller.set_mode_switch(SystemMode::Normal);
+    simulation.run_for(Duration::from_secs(15));
+
+    let pressed_at: Instant = Instant::now();
+    press(&mut simulation, &controller, Approach::A);
+    assert!(approach(&controller, Approach::A).pending);
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::APedestrianGreen, true);
+
+    let statistics: ApproachStatistics = approach(&controller, Approach::A);
+    assert_eq!(statistics.requests, 1);
+    assert_eq!(statistics.served, 1);
+    assert!(!statistics.pending);
+    // The outputs only change on a tick of the output loop.
+    let waited: u64 = (green_at - pressed_at).as_millis();
+    assert!(waited - statistics.max_wait_ms <= 10, "{statistics:?}");
+    assert_eq!(statistics.average_wait_ms(), statistics.max_wait_ms);
+    assert_eq!(approach(&controller, Approach::B).requests, 0);
+}
+
+#[test]
+fn counts_a_promise_once_however_often_the_button_is_pressed() {
+    let (mut simulation, controller) = start();
+    controller.set_mode_switch(SystemMode::Normal);
+    simulation.run_for(Duration::from_secs(15));
+
+    press(&mut simulation, &controller, Approach::B);
+    press(&mut simulation, &controller, Approach::B);
+    press(&mut simulation, &controller, Approach::B);
+    assert_eq!(approach(&controller, Approach::B).requests, 1);
+}
+
+#[test]
+fn a_button_held_at_startup_makes_a_promise() {
+    let mut simulation: Simulation = Simulation::new();
+    let controller: Controller = Controller::new();
+    controller.set_button(Approach::A, true);
+    controller.spawn(&mut simulation);
+    simulation.run_for(Duration::from_secs(1));
+    assert_eq!(approach(&controller, Approach::A).requests, 1);
+}
+
+#[test]
+fn flashing_mode_drops_open_promises() {
+    let (mut simulation, controller) = start();
+    simulation.run_for(Duration::from_secs(1));
+    press(&mut simulation, &controller, Approach::A);
+    controller.set_mode_switch(SystemMode::Normal);
+    simulation.run_for(Duration::from_secs(15));
+
+    let statistics: ApproachStatistics = approach(&controller, Approach::A);
+    assert_eq!(statistics.requests, 1);
+    assert_eq!(statistics.dropped, 1);
+    assert_eq!(statistics.served, 0);
+    assert!(!statistics.pending);
+}
+
+#[test]
+fn reset_starts_counting_again_but_keeps_open_promises() {
+    let (mut simulation, controller) = start();
+    controller.set_mode_switch(SystemMode::Normal);
+    simulation.run_for(Duration::from_secs(15));
+    let pressed_at: Instant = Instant::now();
+    press(&mut simulation, &controller, Approach::B);
+
+    controller.statistics.reset();
+    let reset_at: Instant = Instant::now();
+    let statistics: Statistics = controller.statistics.snapshot();
+    assert_eq!(statistics.counted_ms, 0);
+    assert_eq!(statistics.mode_switches, 0);
+    assert_eq!(statistics.lockout_ms, 0);
+    assert_eq!(statistics.cycles, [0; SystemMode::VARIANT_COUNT]);
+    assert_eq!(statistics.approaches[1].requests, 0);
+    assert!(statistics.approaches[1].pending);
+    assert!(statistics.uptime_ms > 15_000);
+
+    let green_at: Instant = run_until(&mut simulation, &controller, Pins::BPedestrianGreen, true);
+    let statistics: ApproachStatistics = approach(&controller, Approach::B);
+    assert_eq!(statistics.served, 1);
+    // The wait counts from the press, not from the reset.
+    assert!(statistics.max_wait_ms > (green_at - reset_at).as_millis());
+    assert!(statistics.max_wait_ms <= (green_at - pressed_at).as_millis());
+}
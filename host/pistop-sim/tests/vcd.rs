@@ -0,0 +1,180 @@
+/*
+ * The waveforms of a simulated controller, read back the way a waveform viewer
+ * reads them.
+ */
+
+use std::collections::HashMap;
+
+use embassy_time::Duration;
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Pins, SystemMode};
+use pistop_sim::controller::CYCLE_OFFSET;
+use pistop_sim::trace::Recording;
+
+mod common;
+use common::start_in;
+
+// The value changes of every signal by name, with the time in milliseconds.
+struct Waveforms {
+    changes: HashMap<String, Vec<(u64, String)>>,
+    end: u64,
+}
+
+impl Waveforms {
+    fn parse(vcd: &str) -> Self {
+        let mut names: HashMap<String, String> = HashMap::new();
+        let mut changes: HashMap<String, Vec<(u64, String)>> = HashMap::new();
+        let mut time: u64 = 0;
+        let mut definitions: bool = true;
+        for line in vcd.lines() {
+            if definitions {
+                let words: Vec<&str> = line.split_whitespace().collect();
+                if let ["$var", _, _, identifier, name, "$end"] = words.as_slice() {
+                    names.insert(identifier.to_string(), name.to_string());
+                }
+                definitions = line != "$enddefinitions $end";
+                continue;
+            }
+            let (value, identifier) = if let Some(at) = line.strip_prefix('#') {
+                time = at.parse().unwrap();
+                continue;
+            } else if let Some((value, identifier)) = line.split_once(' ') {
+                (value, identifier)
+            } else if line.starts_with('$') {
+                continue;
+            } else {
+                line.split_at(1)
+            };
+            changes
+                .entry(names[identifier].clone())
+                .or_default()
+                .push((time, value.to_string()));
+        }
+        Waveforms {
+            changes: changes,
+            end: time,
+        }
+    }
+
+    fn times(&self, name: &str) -> Vec<u64> {
+        self.changes[name].iter().map(|(at, _)| *at).collect()
+    }
+
+    // The value of the signal at the given time.
+    fn at(&self, name: &str, time: u64) -> &str {
+        let changes: &Vec<(u64, String)> = &self.changes[name];
+        let i: usize = changes.partition_point(|(at, _)| *at <= time);
+        &changes[i - 1].1
+    }
+
+    // When the signal first changed to the value after the given time.
+    fn first(&self, name: &str, value: &str, after: u64) -> u64 {
+        self.changes[name]
+            .iter()
+            .find(|(at, changed_to)| *at > after && changed_to == value)
+            .unwrap_or_else(|| panic!("{name} never changed to {value}"))
+            .0
+    }
+}
+
+#[test]
+fn declares_a_signal_for_every_pin_and_the_modes() {
+    let (mut simulation, controller) = start_in(SystemMode::Flash);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    recording.run_until(Duration::from_secs(2));
+    let vcd: String = recording.finish().vcd("flash mode");
+
+    assert!(vcd.starts_with("$comment flash mode $end\n"));
+    assert!(vcd.contains("$timescale 1ms $end\n"));
+    let waveforms: Waveforms = Waveforms::parse(&vcd);
+    for pin in Pins::VARIANTS {
+        assert!(
+            waveforms.changes.contains_key(&format!("{pin:?}")),
+            "{pin:?}"
+        );
+    }
+    for name in [
+        "slow_cycle",
+        "fast_cycle",
+        "pip_timer",
+        "lockout",
+        "mode",
+        "permit_Normal",
+        "permit_Flash",
+        "permit_PriorityA",
+        "permit_PriorityB",
+    ] {
+        assert!(waveforms.changes.contains_key(name), "{name}");
+    }
+    assert_eq!(waveforms.end, 2_000);
+}
+
+// In flashing mode the ambers follow the slow cycle, the pip timer is on for
+// one tick a second and the fast cycle toggles every tenth of a second.
+#[test]
+fn flashing_ambers_follow_the_slow_cycle() {
+    let (mut simulation, controller) = start_in(SystemMode::Flash);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    recording.run_until(Duration::from_secs(5));
+    let waveforms: Waveforms = Waveforms::parse(&recording.finish().vcd("flash mode"));
+
+    let amber: Vec<u64> = waveforms.times("AAmber");
+    assert!(amber.len() >= 9, "{amber:?}");
+    assert_eq!(amber, waveforms.times("BAmber"));
+    for pair in amber[1..].windows(2) {
+        assert_eq!(pair[1] - pair[0], 500, "{amber:?}");
+    }
+    for at in &amber[1..] {
+        assert_eq!(
+            waveforms.at("AAmber", *at),
+            waveforms.at("slow_cycle", *at),
+            "at {at}"
+        );
+    }
+    let pips: Vec<&(u64, String)> = waveforms.changes["pip_timer"]
+        .iter()
+        .filter(|(_, value)| value == "1")
+        .collect();
+    assert!(pips.windows(2).all(|pair| pair[1].0 - pair[0].0 == 1_000));
+    // The masker starts at the last tick of its cycle, just before the first
+    // cycle starts at `CYCLE_OFFSET`.
+    let fast: Vec<u64> = waveforms.times("fast_cycle");
+    assert_eq!(fast[2], CYCLE_OFFSET.as_millis(), "{fast:?}");
+    assert!(
+        fast[2..].windows(2).all(|pair| pair[1] - pair[0] == 100),
+        "{fast:?}"
+    );
+    assert_eq!(waveforms.at("mode", 4_000), "b1");
+    assert_eq!(waveforms.at("permit_Flash", 4_000), "1");
+}
+
+// During the lockout the handler takes the permit of the old mode back before
+// it hands out the permit of the new mode.
+#[test]
+fn shows_the_permits_change_hands_during_the_lockout() {
+    let (mut simulation, controller) = start_in(SystemMode::Normal);
+    let mut recording: Recording = Recording::start(&mut simulation, &controller);
+    recording.run_until(Duration::from_secs(1));
+    controller.set_mode_switch(SystemMode::Flash);
+    // Normal mode only lets go of its permit at the end of a cycle of both
+    // heads, which takes 42s.
+    recording.run_until(Duration::from_secs(60));
+    let waveforms: Waveforms = Waveforms::parse(&recording.finish().vcd("to flash"));
+
+    assert_eq!(waveforms.at("mode", 0), "b0");
+    assert_eq!(waveforms.at("permit_Normal", 0), "1");
+    let locked_out: u64 = waveforms.first("lockout", "1", 0);
+    let normal_collected: u64 = waveforms.first("permit_Normal", "0", 0);
+    let flash_handed_out: u64 = waveforms.first("permit_Flash", "1", 0);
+    let released: u64 = waveforms.first("lockout", "0", locked_out);
+    assert!(
+        locked_out <= normal_collected,
+        "{locked_out} {normal_collected}"
+    );
+    assert!(normal_collected <= flash_handed_out);
+    assert_eq!(released, flash_handed_out);
+    assert_eq!(waveforms.first("mode", "b1", 0), flash_handed_out);
+    // The switching mode led blinks for as long as we are locked out.
+    assert!(waveforms.first("SwitchingMode", "1", locked_out) < released);
+    assert_eq!(waveforms.at("SwitchingMode", released + 10), "0");
+}
@@ -0,0 +1,282 @@
+/*
+ * Turns what the controller reports into text for people.
+ */
+
+use std::fmt::Write;
+
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{
+    Approach, CalendarAction, CalendarEntry, Date, Event, EventKind, Phase, Pins, ScheduleAction,
+    ScheduleEntry, Statistics, Status, SystemMode, WallTime,
+};
+
+// The outputs of one head, in the order they are shown.
+struct Head {
+    approach: Approach,
+    red: Pins,
+    amber: Pins,
+    green: Pins,
+    pedestrian_red: Pins,
+    pedestrian_green: Pins,
+    promise: Pins,
+    beeper: Option<Pins>,
+}
+
+const HEADS: [Head; Approach::VARIANT_COUNT] = [
+    Head {
+        approach: Approach::A,
+        red: Pins::ARed,
+        amber: Pins::AAmber,
+        green: Pins::AGreen,
+        pedestrian_red: Pins::APedestrianRed,
+        pedestrian_green: Pins::APedestrianGreen,
+        promise: Pins::APromise,
+        beeper: Some(Pins::ABeeper),
+    },
+    Head {
+        approach: Approach::B,
+        red: Pins::BRed,
+        amber: Pins::BAmber,
+        green: Pins::BGreen,
+        pedestrian_red: Pins::BPedestrianRed,
+        pedestrian_green: Pins::BPedestrianGreen,
+        promise: Pins::BPromise,
+        // There is no beeper for the up-down lane.
+        beeper: None,
+    },
+];
+
+pub fn status(status: &Status) -> String {
+    let mut text: String = String::new();
+    let _ = writeln!(
+        text,
+        "mode     {:?}{}",
+        status.mode,
+        if status.locked_out {
+            " (locked out)"
+        } else {
+            ""
+        }
+    );
+    let _ = writeln!(
+        text,
+        "battery  {:?}, {} mV",
+        status.battery_state, status.battery_millivolts
+    );
+    let _ = writeln!(text, "uptime   {}", duration(status.uptime_ms));
+    let _ = writeln!(text);
+    text.push_str(&heads(status));
+    text
+}
+
+// A line per head, with each lamp shown as on, off or out.
+pub fn heads(status: &Status) -> String {
+    let mut text: String = String::new();
+    let _ = writeln!(
+        text,
+        "head  phase      red  amber green   walk: red  green   promise beeper"
+    );
+    for (head, phase) in HEADS.iter().zip(status.phases) {
+        let beeper: &str = match head.beeper {
+            Some(pin) => lamp(status, pin),
+            None => "-",
+        };
+        let _ = writeln!(
+            text,
+            "{:<5} {:<10} {:<4} {:<5} {:<5}         {:<4} {:<5}   {:<7} {}",
+            format!("{:?}", head.approach),
+            phase_name(phase),
+            lamp(status, head.red),
+            lamp(status, head.amber),
+            lamp(status, head.green),
+            lamp(status, head.pedestrian_red),
+            lamp(status, head.pedestrian_green),
+            lamp(status, head.promise),
+            beeper,
+        );
+    }
+    text
+}
+
+pub fn statistics(statistics: &Statistics) -> String {
+    let mut text: String = String::new();
+    let _ = writeln!(text, "uptime        {}", duration(statistics.uptime_ms));
+    let _ = writeln!(text, "counting for  {}", duration(statistics.counted_ms));
+    let _ = writeln!(text, "mode switches {}", statistics.mode_switches);
+    let _ = writeln!(text, "locked out    {}", duration(statistics.lockout_ms));
+    let _ = writeln!(text);
+    let _ = writeln!(text, "mode        cycles");
+    for (mode, cycles) in SystemMode::VARIANTS.iter().zip(statistics.cycles) {
+        let _ = writeln!(text, "{:<11} {cycles}", format!("{mode:?}"));
+    }
+    let _ = writeln!(text);
+    let _ = writeln!(
+        text,
+        "button  requests  served  dropped  pending  average wait  longest wait"
+    );
+    for (approach, counts) in Approach::VARIANTS.iter().zip(statistics.approaches) {
+        let _ = writeln!(
+            text,
+            "{:<7} {:>8}  {:>6}  {:>7}  {:<7}  {:>12}  {:>12}",
+            format!("{approach:?}"),
+            counts.requests,
+            counts.served,
+            counts.dropped,
+            if counts.pending { "yes" } else { "no" },
+            duration(counts.average_wait_ms()),
+            duration(counts.max_wait_ms),
+        );
+    }
+    text
+}
+
+// The days of the week in the order of the bits of `ScheduleEntry::weekdays`.
+pub const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+pub fn wall_time(time: Option<WallTime>) -> String {
+    let Some(time) = time else {
+        return "not set".to_string();
+    };
+    let (year, month, day, hour, minute, second) = time.date_time();
+    format!(
+        "{} {year}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}",
+        WEEKDAYS[time.weekday() as usize]
+    )
+}
+
+pub fn schedule_entry(index: u8, entry: Option<ScheduleEntry>) -> String {
+    let Some(entry) = entry else {
+        return format!("{index}  -");
+    };
+    format!(
+        "{index}  {:<27} {:02}:{:02}  {}",
+        weekdays(entry.weekdays),
+        entry.start_minute / 60,
+        entry.start_minute % 60,
+        schedule_action(entry.action)
+    )
+}
+
+fn schedule_action(action: ScheduleAction) -> String {
+    match action {
+        ScheduleAction::Mode(mode) => format!("mode {mode:?}"),
+        ScheduleAction::Timings(timings) => format!(
+            "timings {}/{}/{}/{} ms",
+            timings.attention_ms, timings.go_ms, timings.yield_ms, timings.clear_ms
+        ),
+    }
+}
+
+pub fn calendar_entry(index: u8, entry: Option<CalendarEntry>) -> String {
+    let Some(entry) = entry else {
+        return format!("{index}  -");
+    };
+    let days: String = if entry.first == entry.last {
+        date(entry.first, entry.every_year)
+    } else {
+        format!(
+            "{} to {}",
+            date(entry.first, entry.every_year),
+            date(entry.last, entry.every_year)
+        )
+    };
+    let what: String = match entry.action {
+        CalendarAction::Forced(action) => schedule_action(action),
+        CalendarAction::AsWeekday(weekday) => {
+            format!("like {}", WEEKDAYS.get(weekday as usize).unwrap_or(&"?"))
+        }
+    };
+    format!("{index}  {days:<24}  {what}")
+}
+
+// Days that come back every year go without the year, the way they are typed.
+fn date(date: Date, every_year: bool) -> String {
+    if every_year {
+        format!("{:02}-{:02}", date.month, date.day)
+    } else {
+        format!("{}-{:02}-{:02}", date.year, date.month, date.day)
+    }
+}
+
+// Runs of three days or more are shown as a range, the way they are typed.
+fn weekdays(weekdays: u8) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut day: usize = 0;
+    while day < WEEKDAYS.len() {
+        if weekdays & (1 << day) == 0 {
+            day += 1;
+            continue;
+        }
+        let mut last: usize = day;
+        while last + 1 < WEEKDAYS.len() && weekdays & (1 << (last + 1)) != 0 {
+            last += 1;
+        }
+        if last - day >= 2 {
+            parts.push(format!("{}-{}", WEEKDAYS[day], WEEKDAYS[last]));
+        } else {
+            parts.extend(WEEKDAYS[day..=last].iter().map(|name| name.to_string()));
+        }
+        day = last + 1;
+    }
+    parts.join(",")
+}
+
+fn lamp(status: &Status, pin: Pins) -> &'static str {
+    if status.is_lamp_out(pin) {
+        "OUT"
+    } else if status.is_lit(pin) {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn phase_name(phase: Phase) -> &'static str {
+    match phase {
+        Phase::Attention => "attention",
+        Phase::Go => "go",
+        Phase::Yield => "yield",
+        Phase::Clear => "clear",
+        Phase::Flash => "flash",
+        Phase::Dark => "dark",
+    }
+}
+
+pub fn event(event: &Event) -> String {
+    let what: String = match event.kind {
+        EventKind::ModeChanged(mode) => format!("mode changed to {mode:?}"),
+        EventKind::LockedOut => "locked out to change modes".to_string(),
+        EventKind::PhaseChanged(approach, phase) => {
+            format!("head {approach:?} changed to {}", phase_name(phase))
+        }
+        EventKind::ButtonPressed(approach) => format!("button {approach:?} pressed"),
+        EventKind::BatteryStateChanged(state) => format!("battery changed to {state:?}"),
+        EventKind::LampOut(pin) => format!("lamp {} is out", pin.name()),
+        EventKind::NodeFound(node) => format!("found node {node} on the CAN bus"),
+        EventKind::NodeLost(node) => format!("lost node {node} on the CAN bus"),
+        EventKind::PreemptionStarted(approach) => format!("preemption for head {approach:?}"),
+        EventKind::PreemptionEnded(approach) => {
+            format!("preemption for head {approach:?} ended")
+        }
+        EventKind::ClockCrystalFailed => {
+            "clock crystal failed, the clock runs from the internal oscillator".to_string()
+        }
+    };
+    format!(
+        "{:>6} {:>12}  {}",
+        event.sequence,
+        duration(event.timestamp_ms),
+        what
+    )
+}
+
+fn duration(milliseconds: u64) -> String {
+    let seconds: u64 = milliseconds / 1000;
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        milliseconds % 1000
+    )
+}
@@ -0,0 +1,161 @@
+/*
+ * The host end of the host protocol. Sends requests, waits for the matching
+ * responses and keeps the notifications that come in on the side.
+ */
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use pistop_protocol::{
+    ControllerMessage, Event, FrameReader, HostMessage, MAX_FRAME_SIZE, Request, Response,
+    encode_frame,
+};
+use serialport::{ClearBuffer, SerialPort};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1_000);
+const ATTEMPTS: usize = 3;
+
+// Between events, the controller goes into STOP for up to a second, and does
+// not hear what comes in. It stays awake for ten seconds after the last bytes
+// it received, and listens for a little while after each STOP. So before the
+// first request in a while, we send zero bytes for longer than a STOP lasts.
+const AWAKE_FOR: Duration = Duration::from_secs(5);
+const WAKE_UP_FOR: Duration = Duration::from_millis(1_200);
+
+pub struct Link {
+    port: Box<dyn SerialPort>,
+    reader: FrameReader,
+    sequence: u16,
+    notifications: VecDeque<Event>,
+    baud_rate: u32,
+    last_sent: Option<Instant>,
+}
+
+impl Link {
+    pub fn open(path: &str, baud_rate: u32) -> Result<Self> {
+        let port: Box<dyn SerialPort> = serialport::new(path, baud_rate)
+            .timeout(Duration::from_millis(20))
+            .open()
+            .with_context(|| format!("cannot open {path}"))?;
+        // Whatever the controller printed before we came along is of no
+        // interest to us.
+        let _ = port.clear(ClearBuffer::Input);
+        Ok(Link {
+            port: port,
+            reader: FrameReader::new(),
+            sequence: 0,
+            notifications: VecDeque::new(),
+            baud_rate: baud_rate,
+            last_sent: None,
+        })
+    }
+
+    pub fn request(&mut self, request: Request) -> Result<Response> {
+        for _ in 0..ATTEMPTS {
+            self.sequence = self.sequence.wrapping_add(1);
+            let message: HostMessage = HostMessage {
+                sequence: self.sequence,
+                request: request,
+            };
+            self.send(&message)?;
+
+            let deadline: Instant = Instant::now() + RESPONSE_TIMEOUT;
+            while let Some(message) = self.receive(deadline)? {
+                match message {
+                    ControllerMessage::Response { sequence, response } => {
+                        // Responses to earlier attempts that came in late
+                        // are of no use anymore.
+                        if sequence == self.sequence {
+                            return Ok(response);
+                        }
+                    }
+                    ControllerMessage::Notification(event) => self.notifications.push_back(event),
+                }
+            }
+        }
+        bail!("the controller does not respond")
+    }
+
+    // Waits for the next notification, for at most the given time.
+    pub fn notification(&mut self, timeout: Duration) -> Result<Option<Event>> {
+        if let Some(event) = self.notifications.pop_front() {
+            return Ok(Some(event));
+        }
+        let deadline: Instant = Instant::now() + timeout;
+        while let Some(message) = self.receive(deadline)? {
+            if let ControllerMessage::Notification(event) = message {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    fn send(&mut self, message: &HostMessage) -> Result<()> {
+        let mut buffer: [u8; MAX_FRAME_SIZE] = [0; MAX_FRAME_SIZE];
+        let frame: &[u8] = encode_frame(message, &mut buffer)
+            .map_err(|error| anyhow!("cannot encode request: {error:?}"))?;
+        if self
+            .last_sent
+            .is_none_or(|last_sent| last_sent.elapsed() > AWAKE_FOR)
+        {
+            self.wake_up()?;
+        }
+        // The leading zero ends anything that the controller may have taken
+        // for the start of a frame, such as someone typing at the console.
+        self.port.write_all(&[0])?;
+        self.port.write_all(frame)?;
+        self.port.flush()?;
+        self.last_sent = Some(Instant::now());
+        Ok(())
+    }
+
+    // Zero bytes are no frame at all, so the controller skips them, see
+    // `FrameReader`.
+    fn wake_up(&mut self) -> Result<()> {
+        // Ten bits on the wire for every byte. The port takes them a lot
+        // slower than we write them, and says so with a time-out.
+        let mut count: usize = (self.baud_rate as u128 * WAKE_UP_FOR.as_millis() / 10_000) as usize;
+        let zeros: [u8; 64] = [0; 64];
+        while count > 0 {
+            match self.port.write(&zeros[..count.min(zeros.len())]) {
+                Ok(written) => count -= written,
+                Err(error) if error.kind() == ErrorKind::TimedOut => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        // The writes of the request time out as well, so wait for the zeros
+        // to go out first.
+        self.port.flush()?;
+        Ok(())
+    }
+
+    // Returns the next message that comes in before the deadline. Frames that
+    // don't decode, such as console text from before the controller switched
+    // to binary mode, are skipped.
+    fn receive(&mut self, deadline: Instant) -> Result<Option<ControllerMessage>> {
+        let mut byte: [u8; 1] = [0];
+        while Instant::now() < deadline {
+            match self.port.read(&mut byte) {
+                Ok(0) => {}
+                Ok(_) => {
+                    if let Some(Ok(message)) = self.reader.push(byte[0]) {
+                        return Ok(Some(message));
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::TimedOut => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Link {
+    // Hand the serial port back to the human console, for whoever looks at it
+    // next.
+    fn drop(&mut self) {
+        let _ = self.request(Request::Console);
+    }
+}
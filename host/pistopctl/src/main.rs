@@ -0,0 +1,623 @@
+/*
+ * Controls and monitors the controller over its serial port, by way of the
+ * host protocol. The port defaults to `STM_SERIAL_PORT`, which `cargo run`
+ * takes from `.cargo/config.toml`, so the tool talks to the same board that
+ * gets flashed. For trying it out without a board, point it at the terminal
+ * that `pistop-sim` prints.
+ */
+
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use pistop_protocol::{
+    Approach, CALENDAR_ENTRIES, CalendarAction, CalendarEntry, ConfigKey, Date, ErrorCode, Event,
+    MINUTES_PER_DAY, Request, Response, SCHEDULE_ENTRIES, ScheduleAction, ScheduleEntry, Status,
+    SystemMode, TimingPlan, WallTime,
+};
+
+mod display;
+mod link;
+
+use link::Link;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Control and monitor the pedestrian crossing controller"
+)]
+struct Cli {
+    // The serial port that the controller is on.
+    #[arg(short, long, env = "STM_SERIAL_PORT")]
+    port: String,
+    #[arg(short, long, default_value_t = 115_200)]
+    baud: u32,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the mode, the battery and the state of every head
+    Status,
+    /// Switch to another mode, as if the rotary switch was turned
+    Mode { mode: Mode },
+    /// Press the pedestrian button of a head
+    Press { approach: Head },
+    /// Give the green to a head, as an emergency vehicle would ask for it
+    Preempt {
+        /// Leave this out to end the preemption
+        approach: Option<Head>,
+    },
+    /// Send the manual mode on to the next head, as the officer's button does
+    Advance,
+    /// Show the event log
+    Log {
+        /// Keep showing events as they happen
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Read or change the settings
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Keep showing the state of every head
+    Watch {
+        #[arg(short, long, default_value_t = 500)]
+        interval_ms: u64,
+        /// Stop after this many updates
+        #[arg(short, long)]
+        count: Option<u64>,
+    },
+    /// Show the statistics since startup, or since they were last reset
+    Stats {
+        /// Start counting again afterwards
+        #[arg(short, long)]
+        reset: bool,
+    },
+    /// Show the wall clock of the controller, or set it
+    Clock {
+        /// The local time to set, as "YYYY-MM-DD HH:MM:SS"
+        #[arg(value_parser = parse_wall_time)]
+        set: Option<WallTime>,
+    },
+    /// Show or change the schedule of modes and timings
+    #[command(subcommand)]
+    Schedule(ScheduleCommand),
+    /// Show or change the calendar of holidays and other days out of the
+    /// ordinary
+    #[command(subcommand)]
+    Calendar(CalendarCommand),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Show a setting, or all of them
+    Get { key: Option<Key> },
+    /// Change a setting until the controller restarts
+    Set { key: Key, value: u32 },
+}
+
+#[derive(Subcommand)]
+enum ScheduleCommand {
+    /// Show every entry of the schedule
+    Show,
+    /// Switch modes from a time of day on
+    Mode {
+        index: u8,
+        /// Days of the week, such as mon-fri or sat,sun
+        #[arg(value_parser = parse_weekdays)]
+        days: u8,
+        /// The time of day, as HH:MM
+        #[arg(value_parser = parse_minute_of_day)]
+        start: u16,
+        mode: Mode,
+    },
+    /// Run normal mode with other timings from a time of day on
+    Timings {
+        index: u8,
+        /// Days of the week, such as mon-fri or sat,sun
+        #[arg(value_parser = parse_weekdays)]
+        days: u8,
+        /// The time of day, as HH:MM
+        #[arg(value_parser = parse_minute_of_day)]
+        start: u16,
+        attention_ms: u32,
+        go_ms: u32,
+        yield_ms: u32,
+        clear_ms: u32,
+    },
+    /// Clear an entry of the schedule
+    Clear { index: u8 },
+}
+
+#[derive(Subcommand)]
+enum CalendarCommand {
+    /// Show every entry of the calendar
+    Show,
+    /// Force a mode all day long
+    Mode {
+        index: u8,
+        #[command(flatten)]
+        days: Days,
+        mode: Mode,
+    },
+    /// Run normal mode with other timings all day long
+    Timings {
+        index: u8,
+        #[command(flatten)]
+        days: Days,
+        attention_ms: u32,
+        go_ms: u32,
+        yield_ms: u32,
+        clear_ms: u32,
+    },
+    /// Follow the schedule as on another day of the week
+    Like {
+        index: u8,
+        #[command(flatten)]
+        days: Days,
+        #[arg(value_parser = parse_weekday)]
+        weekday: u8,
+    },
+    /// Clear an entry of the calendar
+    Clear { index: u8 },
+}
+
+#[derive(Args)]
+struct Days {
+    /// The day, as YYYY-MM-DD, or as MM-DD for every year
+    #[arg(value_parser = parse_date)]
+    first: (Date, bool),
+    /// The last day, for more than one day in a row
+    #[arg(short, long, value_parser = parse_date)]
+    until: Option<(Date, bool)>,
+}
+
+#[derive(ValueEnum, Copy, Clone)]
+enum Mode {
+    Normal,
+    Flash,
+    PriorityA,
+    PriorityB,
+    Manual,
+    AllRed,
+    Dark,
+    Pelican,
+}
+
+impl From<Mode> for SystemMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Normal => SystemMode::Normal,
+            Mode::Flash => SystemMode::Flash,
+            Mode::PriorityA => SystemMode::PriorityA,
+            Mode::PriorityB => SystemMode::PriorityB,
+            Mode::Manual => SystemMode::Manual,
+            Mode::AllRed => SystemMode::AllRed,
+            Mode::Dark => SystemMode::Dark,
+            Mode::Pelican => SystemMode::Pelican,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone)]
+enum Head {
+    A,
+    B,
+}
+
+impl From<Head> for Approach {
+    fn from(head: Head) -> Self {
+        match head {
+            Head::A => Approach::A,
+            Head::B => Approach::B,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Eq)]
+enum Key {
+    BatteryDividerRatioMilli,
+    BatteryLowMillivolts,
+    BatteryCriticalMillivolts,
+    BatteryHysteresisMillivolts,
+    BatterySampleIntervalMs,
+    BatteryReportIntervalMs,
+    SelfTest,
+    NormalAttentionMs,
+    NormalGoMs,
+    NormalYieldMs,
+    NormalClearMs,
+    ModbusAddress,
+    LogLevel,
+    GreenWave,
+    GreenWaveCycleMs,
+    GreenWaveOffsetMs,
+    CanNodeId,
+    PreemptionDwellMs,
+    PreemptionExit,
+    RailwayApproach,
+    TrackClearanceMs,
+    PelicanMinGreenMs,
+    PelicanWalkMs,
+    PelicanFlashMs,
+    Puffin,
+}
+
+impl From<Key> for ConfigKey {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::BatteryDividerRatioMilli => ConfigKey::BatteryDividerRatioMilli,
+            Key::BatteryLowMillivolts => ConfigKey::BatteryLowMillivolts,
+            Key::BatteryCriticalMillivolts => ConfigKey::BatteryCriticalMillivolts,
+            Key::BatteryHysteresisMillivolts => ConfigKey::BatteryHysteresisMillivolts,
+            Key::BatterySampleIntervalMs => ConfigKey::BatterySampleIntervalMs,
+            Key::BatteryReportIntervalMs => ConfigKey::BatteryReportIntervalMs,
+            Key::SelfTest => ConfigKey::SelfTest,
+            Key::NormalAttentionMs => ConfigKey::NormalAttentionMs,
+            Key::NormalGoMs => ConfigKey::NormalGoMs,
+            Key::NormalYieldMs => ConfigKey::NormalYieldMs,
+            Key::NormalClearMs => ConfigKey::NormalClearMs,
+            Key::ModbusAddress => ConfigKey::ModbusAddress,
+            Key::LogLevel => ConfigKey::LogLevel,
+            Key::GreenWave => ConfigKey::GreenWave,
+            Key::GreenWaveCycleMs => ConfigKey::GreenWaveCycleMs,
+            Key::GreenWaveOffsetMs => ConfigKey::GreenWaveOffsetMs,
+            Key::CanNodeId => ConfigKey::CanNodeId,
+            Key::PreemptionDwellMs => ConfigKey::PreemptionDwellMs,
+            Key::PreemptionExit => ConfigKey::PreemptionExit,
+            Key::RailwayApproach => ConfigKey::RailwayApproach,
+            Key::TrackClearanceMs => ConfigKey::TrackClearanceMs,
+            Key::PelicanMinGreenMs => ConfigKey::PelicanMinGreenMs,
+            Key::PelicanWalkMs => ConfigKey::PelicanWalkMs,
+            Key::PelicanFlashMs => ConfigKey::PelicanFlashMs,
+            Key::Puffin => ConfigKey::Puffin,
+        }
+    }
+}
+
+impl Key {
+    fn name(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+fn main() -> ExitCode {
+    let cli: Cli = Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("pistopctl: {error:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let mut link: Link = Link::open(&cli.port, cli.baud)?;
+    match cli.command {
+        Command::Status => {
+            print!("{}", display::status(&status(&mut link)?));
+        }
+        Command::Mode { mode } => {
+            expect_ok(link.request(Request::SetMode(mode.into()))?)?;
+        }
+        Command::Press { approach } => {
+            expect_ok(link.request(Request::PressButton(approach.into()))?)?;
+        }
+        Command::Preempt { approach } => {
+            expect_ok(link.request(Request::Preempt(approach.map(Approach::from)))?)?;
+        }
+        Command::Advance => match link.request(Request::Advance)? {
+            Response::Error(ErrorCode::InvalidValue) => {
+                bail!("the controller is not in manual mode")
+            }
+            response => expect_ok(response)?,
+        },
+        Command::Log { follow } => {
+            let mut next: u32 = 0;
+            loop {
+                let (events, continue_from) = events(&mut link, next)?;
+                for event in events.iter() {
+                    println!("{}", display::event(event));
+                }
+                next = continue_from;
+                if events.is_empty() {
+                    break;
+                }
+            }
+            if follow {
+                loop {
+                    if let Some(event) = link.notification(Duration::from_secs(1))? {
+                        // Events that were already shown from the log may come
+                        // in again as notifications.
+                        if event.sequence >= next {
+                            println!("{}", display::event(&event));
+                            next = event.sequence + 1;
+                        }
+                    }
+                }
+            }
+        }
+        Command::Config(ConfigCommand::Get { key }) => {
+            let keys: Vec<Key> = match key {
+                Some(key) => vec![key],
+                None => Key::value_variants().to_vec(),
+            };
+            for key in keys {
+                match link.request(Request::ReadConfig(key.into()))? {
+                    Response::Config(_, value) => println!("{} = {value}", key.name()),
+                    response => unexpected(response)?,
+                }
+            }
+        }
+        Command::Config(ConfigCommand::Set { key, value }) => {
+            match link.request(Request::WriteConfig(key.into(), value))? {
+                Response::Config(_, _) => {}
+                response => unexpected(response)?,
+            }
+        }
+        Command::Watch { interval_ms, count } => {
+            let mut shown: u64 = 0;
+            while count.is_none_or(|count| shown < count) {
+                if shown > 0 {
+                    std::thread::sleep(Duration::from_millis(interval_ms));
+                }
+                let status: Status = status(&mut link)?;
+                println!("{:?}", status.mode);
+                print!("{}", display::heads(&status));
+                println!();
+                shown += 1;
+            }
+        }
+        Command::Stats { reset } => {
+            match link.request(Request::ReadStatistics)? {
+                Response::Statistics(statistics) => print!("{}", display::statistics(&statistics)),
+                response => unexpected(response)?,
+            }
+            if reset {
+                expect_ok(link.request(Request::ResetStatistics)?)?;
+            }
+        }
+        Command::Clock { set: Some(time) } => {
+            expect_ok(link.request(Request::SetClock(time))?)?;
+        }
+        Command::Clock { set: None } => match link.request(Request::ReadClock)? {
+            Response::Clock(time) => println!("{}", display::wall_time(time)),
+            response => unexpected(response)?,
+        },
+        Command::Schedule(ScheduleCommand::Show) => {
+            for index in 0..SCHEDULE_ENTRIES as u8 {
+                match link.request(Request::ReadSchedule(index))? {
+                    Response::Schedule(index, entry) => {
+                        println!("{}", display::schedule_entry(index, entry))
+                    }
+                    response => unexpected(response)?,
+                }
+            }
+        }
+        Command::Schedule(ScheduleCommand::Mode {
+            index,
+            days,
+            start,
+            mode,
+        }) => {
+            let entry: ScheduleEntry = ScheduleEntry {
+                weekdays: days,
+                start_minute: start,
+                action: ScheduleAction::Mode(mode.into()),
+            };
+            write_schedule(&mut link, index, Some(entry))?;
+        }
+        Command::Schedule(ScheduleCommand::Timings {
+            index,
+            days,
+            start,
+            attention_ms,
+            go_ms,
+            yield_ms,
+            clear_ms,
+        }) => {
+            let entry: ScheduleEntry = ScheduleEntry {
+                weekdays: days,
+                start_minute: start,
+                action: ScheduleAction::Timings(TimingPlan {
+                    attention_ms: attention_ms,
+                    go_ms: go_ms,
+                    yield_ms: yield_ms,
+                    clear_ms: clear_ms,
+                }),
+            };
+            write_schedule(&mut link, index, Some(entry))?;
+        }
+        Command::Schedule(ScheduleCommand::Clear { index }) => {
+            write_schedule(&mut link, index, None)?;
+        }
+        Command::Calendar(CalendarCommand::Show) => {
+            for index in 0..CALENDAR_ENTRIES as u8 {
+                match link.request(Request::ReadCalendar(index))? {
+                    Response::Calendar(index, entry) => {
+                        println!("{}", display::calendar_entry(index, entry))
+                    }
+                    response => unexpected(response)?,
+                }
+            }
+        }
+        Command::Calendar(CalendarCommand::Mode { index, days, mode }) => {
+            let action: CalendarAction = CalendarAction::Forced(ScheduleAction::Mode(mode.into()));
+            write_calendar(&mut link, index, Some(calendar_entry(days, action)?))?;
+        }
+        Command::Calendar(CalendarCommand::Timings {
+            index,
+            days,
+            attention_ms,
+            go_ms,
+            yield_ms,
+            clear_ms,
+        }) => {
+            let action: CalendarAction =
+                CalendarAction::Forced(ScheduleAction::Timings(TimingPlan {
+                    attention_ms: attention_ms,
+                    go_ms: go_ms,
+                    yield_ms: yield_ms,
+                    clear_ms: clear_ms,
+                }));
+            write_calendar(&mut link, index, Some(calendar_entry(days, action)?))?;
+        }
+        Command::Calendar(CalendarCommand::Like {
+            index,
+            days,
+            weekday,
+        }) => {
+            let action: CalendarAction = CalendarAction::AsWeekday(weekday);
+            write_calendar(&mut link, index, Some(calendar_entry(days, action)?))?;
+        }
+        Command::Calendar(CalendarCommand::Clear { index }) => {
+            write_calendar(&mut link, index, None)?;
+        }
+    }
+    Ok(())
+}
+
+fn status(link: &mut Link) -> Result<Status> {
+    match link.request(Request::GetStatus)? {
+        Response::Status(status) => Ok(status),
+        response => unexpected(response),
+    }
+}
+
+fn events(link: &mut Link, from: u32) -> Result<(Vec<Event>, u32)> {
+    match link.request(Request::ReadEventLog(from))? {
+        Response::Events(events, next) => Ok((events.to_vec(), next)),
+        response => unexpected(response),
+    }
+}
+
+fn write_schedule(link: &mut Link, index: u8, entry: Option<ScheduleEntry>) -> Result<()> {
+    match link.request(Request::WriteSchedule(index, entry))? {
+        Response::Schedule(_, _) => Ok(()),
+        response => unexpected(response),
+    }
+}
+
+fn write_calendar(link: &mut Link, index: u8, entry: Option<CalendarEntry>) -> Result<()> {
+    match link.request(Request::WriteCalendar(index, entry))? {
+        Response::Calendar(_, _) => Ok(()),
+        response => unexpected(response),
+    }
+}
+
+fn calendar_entry(days: Days, action: CalendarAction) -> Result<CalendarEntry> {
+    let (first, every_year) = days.first;
+    let (last, last_every_year) = days.until.unwrap_or(days.first);
+    if every_year != last_every_year {
+        bail!("either both days come back every year, or neither does");
+    }
+    Ok(CalendarEntry {
+        first: first,
+        last: last,
+        every_year: every_year,
+        action: action,
+    })
+}
+
+// A date, or a month and day for every year, which then goes in as in 2000.
+fn parse_date(text: &str) -> Result<(Date, bool), String> {
+    let invalid =
+        || format!("expected a date like 2024-12-25, or 12-25 for every year, not {text:?}");
+    let numbers: Vec<u16> = text
+        .split('-')
+        .map(|number| number.parse::<u16>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let (year, month, day, every_year) = match numbers[..] {
+        [year, month, day] => (year, month, day, false),
+        [month, day] => (2000, month, day, true),
+        _ => return Err(invalid()),
+    };
+    let month: u8 = u8::try_from(month).map_err(|_| invalid())?;
+    let day: u8 = u8::try_from(day).map_err(|_| invalid())?;
+    WallTime::from_date_time(year, month, day, 0, 0, 0).ok_or_else(invalid)?;
+    let date: Date = Date {
+        year: year,
+        month: month,
+        day: day,
+    };
+    Ok((date, every_year))
+}
+
+fn parse_wall_time(text: &str) -> Result<WallTime, String> {
+    let invalid = || format!("expected a date and time like 2024-03-31 14:05:00, not {text:?}");
+    let numbers: Vec<u16> = text
+        .split(['-', ' ', ':'])
+        .map(|number| number.parse::<u16>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let [year, month, day, hour, minute, second] = numbers[..] else {
+        return Err(invalid());
+    };
+    let narrow = |number: u16| u8::try_from(number).map_err(|_| invalid());
+    WallTime::from_date_time(
+        year,
+        narrow(month)?,
+        narrow(day)?,
+        narrow(hour)?,
+        narrow(minute)?,
+        narrow(second)?,
+    )
+    .ok_or_else(invalid)
+}
+
+// Day names, or ranges of them, separated by commas: mon-fri,sun.
+fn parse_weekdays(text: &str) -> Result<u8, String> {
+    let mut weekdays: u8 = 0;
+    for part in text.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let (first, last) = (parse_weekday(first)?, parse_weekday(last)?);
+        if first > last {
+            return Err(format!("{part:?} runs backwards"));
+        }
+        for day in first..=last {
+            weekdays |= 1 << day;
+        }
+    }
+    Ok(weekdays)
+}
+
+fn parse_weekday(name: &str) -> Result<u8, String> {
+    display::WEEKDAYS
+        .iter()
+        .position(|day| day.eq_ignore_ascii_case(name))
+        .map(|day| day as u8)
+        .ok_or_else(|| {
+            format!("{name:?} is not a day, expected one of mon, tue, wed, thu, fri, sat or sun")
+        })
+}
+
+fn parse_minute_of_day(text: &str) -> Result<u16, String> {
+    let invalid = || format!("expected a time of day like 07:30, not {text:?}");
+    let (hour, minute) = text.split_once(':').ok_or_else(invalid)?;
+    let hour: u16 = hour.parse().map_err(|_| invalid())?;
+    let minute: u16 = minute.parse().map_err(|_| invalid())?;
+    if minute >= 60 || hour * 60 + minute >= MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(hour * 60 + minute)
+}
+
+fn expect_ok(response: Response) -> Result<()> {
+    match response {
+        Response::Ok => Ok(()),
+        response => unexpected(response),
+    }
+}
+
+fn unexpected<T>(response: Response) -> Result<T> {
+    match response {
+        Response::Error(ErrorCode::InvalidValue) => bail!("the controller refused the value"),
+        response => bail!("unexpected response from the controller: {response:?}"),
+    }
+}
@@ -0,0 +1,241 @@
+/*
+ * Runs `pistopctl` against a simulated controller behind a pseudo-terminal,
+ * the same way it would run against the board.
+ */
+
+use std::process::{Command, Output};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pistop_sim::{Controller, Simulation, pty::Pty};
+
+// Fast enough to get through a few phases quickly, slow enough that the
+// simulated controller answers well within the timeout of `pistopctl`.
+const SPEED: f64 = 20.0;
+
+// Starts a simulated controller that runs until the test ends, and returns
+// the path of its terminal.
+fn start_controller() -> String {
+    let (path_sender, path_receiver) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        let pty: Pty = Pty::open().expect("cannot open a pseudo-terminal");
+        path_sender.send(pty.path()).unwrap();
+
+        let mut simulation: Simulation = Simulation::new();
+        let controller: Controller = Controller::new();
+        controller.spawn(&mut simulation);
+        let (receive, transmit) = pty.bridge(controller.serial);
+        simulation.spawn(receive);
+        simulation.spawn(transmit);
+        simulation.run_realtime(SPEED)
+    });
+    path_receiver.recv().unwrap()
+}
+
+fn pistopctl(port: &str, arguments: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pistopctl"))
+        .arg("--port")
+        .arg(port)
+        .args(arguments)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "pistopctl failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// Changing modes takes a while, since the crossing has to be cleared first.
+fn wait_for_mode(port: &str, mode: &str) {
+    let deadline: Instant = Instant::now() + Duration::from_secs(10);
+    loop {
+        let status: String = stdout(&pistopctl(port, &["status"]));
+        if status.starts_with(&format!("mode     {mode}\n")) {
+            return;
+        }
+        assert!(Instant::now() < deadline, "{status}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn status_shows_the_mode_and_every_head() {
+    let port: String = start_controller();
+    let status: String = stdout(&pistopctl(&port, &["status"]));
+    assert!(status.contains("mode     Flash"), "{status}");
+    assert!(status.contains("\nA "), "{status}");
+    assert!(status.contains("\nB "), "{status}");
+}
+
+#[test]
+fn mode_switches_the_controller() {
+    let port: String = start_controller();
+    stdout(&pistopctl(&port, &["mode", "normal"]));
+    wait_for_mode(&port, "Normal");
+}
+
+#[test]
+fn press_shows_up_in_the_log() {
+    let port: String = start_controller();
+    stdout(&pistopctl(&port, &["mode", "normal"]));
+    wait_for_mode(&port, "Normal");
+    stdout(&pistopctl(&port, &["press", "a"]));
+    let log: String = stdout(&pistopctl(&port, &["log"]));
+    assert!(log.contains("mode changed to Normal"), "{log}");
+    assert!(log.contains("button A pressed"), "{log}");
+}
+
+#[test]
+fn advance_only_works_in_manual_mode() {
+    let port: String = start_controller();
+    let output: Output = pistopctl(&port, &["advance"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not in manual mode"));
+
+    stdout(&pistopctl(&port, &["mode", "manual"]));
+    wait_for_mode(&port, "Manual");
+    stdout(&pistopctl(&port, &["advance"]));
+}
+
+#[test]
+fn config_set_changes_the_setting() {
+    let port: String = start_controller();
+    stdout(&pistopctl(
+        &port,
+        &["config", "set", "battery-low-millivolts", "7000"],
+    ));
+    let config: String = stdout(&pistopctl(
+        &port,
+        &["config", "get", "battery-low-millivolts"],
+    ));
+    assert_eq!(config, "battery-low-millivolts = 7000\n");
+}
+
+#[test]
+fn config_set_refuses_invalid_values() {
+    let port: String = start_controller();
+    let output: Output = pistopctl(&port, &["config", "set", "battery-sample-interval-ms", "0"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("refused"));
+}
+
+#[test]
+fn watch_stops_after_the_count() {
+    let port: String = start_controller();
+    let watch: String = stdout(&pistopctl(
+        &port,
+        &["watch", "--interval-ms", "50", "--count", "3"],
+    ));
+    assert_eq!(watch.matches("Flash\n").count(), 3, "{watch}");
+}
+
+#[test]
+fn stats_counts_and_resets() {
+    let port: String = start_controller();
+    stdout(&pistopctl(&port, &["mode", "normal"]));
+    wait_for_mode(&port, "Normal");
+    let stats: String = stdout(&pistopctl(&port, &["stats", "--reset"]));
+    assert!(stats.contains("mode switches 1\n"), "{stats}");
+    let stats: String = stdout(&pistopctl(&port, &["stats"]));
+    assert!(stats.contains("mode switches 0\n"), "{stats}");
+    assert!(stats.contains("\nA "), "{stats}");
+}
+
+#[test]
+fn clock_is_set_and_shown() {
+    let port: String = start_controller();
+    assert_eq!(stdout(&pistopctl(&port, &["clock"])), "not set\n");
+    stdout(&pistopctl(&port, &["clock", "2024-03-04 06:59:00"]));
+    let clock: String = stdout(&pistopctl(&port, &["clock"]));
+    assert!(clock.starts_with("mon 2024-03-04 06:59:"), "{clock}");
+    let output: Output = pistopctl(&port, &["clock", "2024-02-30 12:00:00"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn schedule_is_written_and_cleared() {
+    let port: String = start_controller();
+    stdout(&pistopctl(
+        &port,
+        &["schedule", "mode", "1", "sat,sun", "22:00", "flash"],
+    ));
+    stdout(&pistopctl(
+        &port,
+        &[
+            "schedule", "timings", "2", "mon-fri", "07:30", "2000", "25000", "4000", "3000",
+        ],
+    ));
+    let schedule: String = stdout(&pistopctl(&port, &["schedule", "show"]));
+    assert!(schedule.contains("\n1  sat,sun"), "{schedule}");
+    assert!(schedule.contains(" 22:00  mode Flash\n"), "{schedule}");
+    assert!(
+        schedule
+            .contains("\n2  mon-fri                     07:30  timings 2000/25000/4000/3000 ms\n"),
+        "{schedule}"
+    );
+    stdout(&pistopctl(&port, &["schedule", "clear", "1"]));
+    let schedule: String = stdout(&pistopctl(&port, &["schedule", "show"]));
+    assert!(schedule.starts_with("0  -\n1  -\n2  mon-fri"), "{schedule}");
+    let output: Output = pistopctl(&port, &["schedule", "clear", "8"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("refused"));
+}
+
+#[test]
+fn calendar_is_written_and_shown() {
+    let port: String = start_controller();
+    stdout(&pistopctl(
+        &port,
+        &["calendar", "mode", "0", "12-25", "flash"],
+    ));
+    stdout(&pistopctl(
+        &port,
+        &[
+            "calendar",
+            "like",
+            "3",
+            "2024-07-01",
+            "--until",
+            "2024-07-05",
+            "sun",
+        ],
+    ));
+    let calendar: String = stdout(&pistopctl(&port, &["calendar", "show"]));
+    assert!(calendar.starts_with("0  12-25"), "{calendar}");
+    assert!(calendar.contains("  mode Flash\n"), "{calendar}");
+    assert!(
+        calendar.contains("\n3  2024-07-01 to 2024-07-05  like sun\n"),
+        "{calendar}"
+    );
+    let output: Output = pistopctl(
+        &port,
+        &[
+            "calendar",
+            "mode",
+            "1",
+            "12-25",
+            "--until",
+            "2025-01-01",
+            "flash",
+        ],
+    );
+    assert!(!output.status.success());
+    let output: Output = pistopctl(
+        &port,
+        &[
+            "calendar",
+            "mode",
+            "1",
+            "2024-07-05",
+            "--until",
+            "2024-07-01",
+            "flash",
+        ],
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("refused"));
+}
@@ -0,0 +1,198 @@
+/*
+ * The box runs from a battery pack, which slowly drains while we are showing
+ * off our traffic lights. A traffic light that browns out can end up in any
+ * state, including one that shows green in both directions for a split second.
+ * We would much rather see the battery coming and put the crossing in a safe
+ * state while we still can.
+ *
+ * The battery rail is measured through a resistor divider on one of the ADC
+ * inputs. The ADC measures relative to its supply, which itself sags when the
+ * battery runs low. So we also measure the internal voltage reference and use
+ * that to work out the actual voltage at the pin.
+ *
+ * Single ADC readings are noisy, especially with the beeper and lamps
+ * switching. We smooth the readings using an exponential moving average and
+ * classify the result with some hysteresis, so that the state does not flap
+ * around a threshold.
+ *
+ * Like the output masker, the monitor itself knows nothing about time or
+ * hardware. It is handed raw ADC readings and it returns the battery state.
+ * The task that drives it gets the readings through `BatterySensor`.
+ */
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use pistop_protocol::EventKind;
+
+use crate::ThreadModeRawMutex;
+use crate::coarse_timer;
+use crate::event_log::EventLog;
+use crate::faults::Faults;
+use crate::log::Log;
+use crate::settings::Settings;
+use crate::timed_output_masker::{Pins, TimedOutputMasker};
+use crate::{error, info, warn};
+
+pub use pistop_protocol::BatteryState;
+
+// The STM32F103 has no calibration data for its internal reference, so we use
+// the nominal value from the datasheet.
+const VREF_INT_MILLIVOLTS: u32 = 1_200;
+
+// Each new reading contributes 1/8th to the filtered value.
+const FILTER_WEIGHT: u32 = 8;
+
+pub struct BatteryMonitor {
+    // The filtered value, scaled up by `FILTER_WEIGHT` to avoid losing
+    // precision in integer arithmetic.
+    accumulator: u32,
+    primed: bool,
+    state: BatteryState,
+}
+
+impl BatteryMonitor {
+    pub const fn new() -> Self {
+        BatteryMonitor {
+            accumulator: 0,
+            primed: false,
+            state: BatteryState::Ok,
+        }
+    }
+
+    pub fn update(&mut self, settings: &Settings, raw: u16, vref_raw: u16) -> BatteryState {
+        let millivolts = rail_millivolts(settings, raw, vref_raw);
+
+        // Prime the filter with the first reading, otherwise we would start out
+        // from zero volts and report a critical battery at every boot.
+        if self.primed {
+            self.accumulator = self.accumulator - self.accumulator / FILTER_WEIGHT + millivolts;
+        } else {
+            self.accumulator = millivolts * FILTER_WEIGHT;
+            self.primed = true;
+        }
+
+        let filtered = self.filtered_millivolts();
+        let low = settings.battery_low_millivolts;
+        let critical = settings.battery_critical_millivolts;
+        let hysteresis = settings.battery_hysteresis_millivolts;
+
+        self.state = match self.state {
+            BatteryState::Ok => {
+                if filtered < critical {
+                    BatteryState::Critical
+                } else if filtered < low {
+                    BatteryState::Low
+                } else {
+                    BatteryState::Ok
+                }
+            }
+            BatteryState::Low => {
+                if filtered < critical {
+                    BatteryState::Critical
+                } else if filtered >= low + hysteresis {
+                    BatteryState::Ok
+                } else {
+                    BatteryState::Low
+                }
+            }
+            BatteryState::Critical => {
+                if filtered >= low + hysteresis {
+                    BatteryState::Ok
+                } else if filtered >= critical + hysteresis {
+                    BatteryState::Low
+                } else {
+                    BatteryState::Critical
+                }
+            }
+        };
+
+        self.state
+    }
+
+    pub fn filtered_millivolts(&self) -> u32 {
+        self.accumulator / FILTER_WEIGHT
+    }
+}
+
+impl Default for BatteryMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Convert a raw reading of the battery input into the voltage of the battery
+// rail. Since both readings are relative to the same (unknown) ADC supply, the
+// supply voltage cancels out.
+fn rail_millivolts(settings: &Settings, raw: u16, vref_raw: u16) -> u32 {
+    if vref_raw == 0 {
+        return 0;
+    }
+
+    let pin_millivolts = raw as u32 * VREF_INT_MILLIVOLTS / vref_raw as u32;
+    pin_millivolts * settings.battery_divider_ratio_milli / 1_000
+}
+
+// Where the battery readings come from. On the controller that is the ADC, in
+// the simulator it is whatever the scenario asks for.
+pub trait BatterySensor {
+    // Returns the raw readings of the battery divider and of the internal
+    // reference, in that order.
+    fn read(&mut self) -> impl Future<Output = (u16, u16)>;
+}
+
+pub async fn battery_monitor(
+    log: &'static Log,
+    sensor: &mut impl BatterySensor,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    faults: &'static Faults,
+    events: &'static EventLog,
+    battery_millivolts: &'static AtomicU32,
+) -> ! {
+    let mut monitor: BatteryMonitor = BatteryMonitor::new();
+    let mut old_state: Option<BatteryState> = None;
+    // The first reading is always a change, so it gets reported anyway.
+    let mut since_report_ms: u64 = 0;
+    loop {
+        // The settings may be changed over the host protocol, so we take a
+        // fresh copy every time round.
+        let current: Settings = *settings.lock().await;
+
+        let (raw, vref_raw): (u16, u16) = sensor.read().await;
+        let state: BatteryState = monitor.update(&current, raw, vref_raw);
+        battery_millivolts.store(monitor.filtered_millivolts(), Ordering::Relaxed);
+
+        let changed: bool = old_state != Some(state);
+        if changed {
+            old_state = Some(state);
+            {
+                // scope for the mutex guard...
+                let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+                    lights.lock().await;
+                match state {
+                    BatteryState::Ok => lights.set_on_off(Pins::Power, true),
+                    BatteryState::Low => lights.set_pin(Pins::Power, true, true, false, false),
+                    BatteryState::Critical => lights.set_pin(Pins::Power, true, false, false, true),
+                }
+            }
+            faults.set_battery_critical(state == BatteryState::Critical);
+            events.record(EventKind::BatteryStateChanged(state));
+        }
+
+        if changed || since_report_ms >= current.battery_report_interval_ms {
+            since_report_ms = 0;
+
+            let millivolts: u32 = monitor.filtered_millivolts();
+            match state {
+                BatteryState::Ok => info!(log, "battery monitor", "{} mV, ok.", millivolts),
+                BatteryState::Low => warn!(log, "battery monitor", "{} mV, low.", millivolts),
+                BatteryState::Critical => {
+                    error!(log, "battery monitor", "{} mV, critical.", millivolts)
+                }
+            }
+        }
+
+        coarse_timer::after_millis(current.battery_sample_interval_ms).await;
+        since_report_ms += current.battery_sample_interval_ms;
+    }
+}
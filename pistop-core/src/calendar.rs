@@ -0,0 +1,146 @@
+/*
+ * The calendar holds the days on which the schedule should not simply go by
+ * the day of the week. Think of public holidays, which come back every year,
+ * or of the open day of the school down the road, when we demonstrate the
+ * crossing all day long. On such a day the calendar either forces a mode or
+ * timings from midnight to midnight, or has the schedule run as it would on
+ * another day of the week. See `schedule.rs` for how the two go together.
+ *
+ * When more than one entry covers a day, the most specific one wins. An entry
+ * for particular dates wins over one that comes back every year, and a short
+ * entry wins over a long one. That way an open day that happens to fall in the
+ * summer holidays simply goes in next to them. When that still leaves a tie,
+ * the entry that comes last in the calendar wins, like in the schedule.
+ *
+ * Like the schedule, the calendar starts out as set in `Calendar::new()` and
+ * can be changed over the host protocol. The changes are kept in flash, see
+ * `schedule_storage.rs`.
+ */
+
+use pistop_protocol::{
+    CALENDAR_ENTRIES, CalendarAction, CalendarEntry, Date, ErrorCode, SECONDS_PER_DAY, WallTime,
+};
+
+use crate::schedule;
+
+#[derive(Copy, Clone)]
+pub struct Calendar {
+    entries: [Option<CalendarEntry>; CALENDAR_ENTRIES],
+}
+
+impl Calendar {
+    pub const fn new() -> Self {
+        Calendar {
+            entries: [None; CALENDAR_ENTRIES],
+        }
+    }
+
+    pub fn get(&self, index: u8) -> Result<Option<CalendarEntry>, ErrorCode> {
+        self.entries
+            .get(index as usize)
+            .copied()
+            .ok_or(ErrorCode::InvalidValue)
+    }
+
+    // Entries that make no sense are refused and leave the calendar as it was.
+    pub fn set(&mut self, index: u8, entry: Option<CalendarEntry>) -> Result<(), ErrorCode> {
+        if entry.is_some_and(|entry| !is_valid(&entry)) {
+            return Err(ErrorCode::InvalidValue);
+        }
+        *self
+            .entries
+            .get_mut(index as usize)
+            .ok_or(ErrorCode::InvalidValue)? = entry;
+        Ok(())
+    }
+
+    // What the calendar says about a day, counted in days since 2000-01-01 like
+    // `WallTime` counts seconds.
+    pub fn action_on(&self, day: u32) -> Option<CalendarAction> {
+        let (year, month, day_of_month, _, _, _) = WallTime(day * SECONDS_PER_DAY).date_time();
+        let date: Date = Date {
+            year: year,
+            month: month,
+            day: day_of_month,
+        };
+        // Lower is more specific.
+        let mut best: Option<((bool, u32), CalendarAction)> = None;
+        for entry in self.entries.iter().flatten() {
+            if !covers(entry, date) {
+                continue;
+            }
+            let specificity: (bool, u32) = (entry.every_year, length(entry));
+            if best.is_none_or(|(best, _)| specificity <= best) {
+                best = Some((specificity, entry.action));
+            }
+        }
+        best.map(|(_, action)| action)
+    }
+}
+
+impl Default for Calendar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn covers(entry: &CalendarEntry, date: Date) -> bool {
+    if !entry.every_year {
+        return entry.first <= date && date <= entry.last;
+    }
+    let (first, last, date) = (
+        in_leap_year(entry.first),
+        in_leap_year(entry.last),
+        in_leap_year(date),
+    );
+    if first <= last {
+        first <= date && date <= last
+    } else {
+        // Over new year.
+        first <= date || date <= last
+    }
+}
+
+// The number of days that an entry covers, less one.
+fn length(entry: &CalendarEntry) -> u32 {
+    let (first, last) = if entry.every_year {
+        (in_leap_year(entry.first), in_leap_year(entry.last))
+    } else {
+        (entry.first, entry.last)
+    };
+    let (Some(first), Some(last)) = (day_number(first), day_number(last)) else {
+        return u32::MAX;
+    };
+    if first <= last {
+        last - first
+    } else {
+        last + 366 - first
+    }
+}
+
+// Dates that come back every year are compared as if they were in 2000, so
+// that the 29th of February has a place too.
+fn in_leap_year(date: Date) -> Date {
+    Date { year: 2000, ..date }
+}
+
+fn day_number(date: Date) -> Option<u32> {
+    WallTime::from_date_time(date.year, date.month, date.day, 0, 0, 0)
+        .map(|time| time.0 / SECONDS_PER_DAY)
+}
+
+fn is_valid(entry: &CalendarEntry) -> bool {
+    let dates_valid: bool = if entry.every_year {
+        day_number(in_leap_year(entry.first)).is_some()
+            && day_number(in_leap_year(entry.last)).is_some()
+    } else {
+        day_number(entry.first).is_some()
+            && day_number(entry.last).is_some()
+            && entry.first <= entry.last
+    };
+    dates_valid
+        && match entry.action {
+            CalendarAction::Forced(action) => schedule::is_valid_action(action),
+            CalendarAction::AsWeekday(weekday) => weekday < 7,
+        }
+}
@@ -0,0 +1,346 @@
+/*
+ * The CAN link ties the boxes of a line together over a CAN bus, as an
+ * alternative to the sync wire of the green wave. Every box on the bus is a
+ * node with a number of its own, from 1 to 15, and everything that a node
+ * sends goes to all of the others. A box with node number 0 stays off the bus.
+ *
+ * The message set is small:
+ *
+ *   cycle sync      the master of the green wave sends one at the start of
+ *                   every cycle. A follower takes the start of its cycle from
+ *                   it, just like from a pulse on the sync wire.
+ *   mode request    the mode that a box went to by itself, from its rotary
+ *                   switch, the host link or the schedule. The other boxes go
+ *                   to that mode too, so the line changes modes as one.
+ *   demand          someone pressed a button, which the other boxes take as a
+ *                   press of their own button of the same approach.
+ *   heartbeat       twice a second, to tell the others that we are there.
+ *
+ * The kind of a message goes in the high bits of the identifier and the node
+ * that sent it in the low four bits. Lower identifiers win the arbitration on
+ * the bus, so the kinds go in order of urgency and the cycle sync goes first.
+ *
+ * A box that hears nothing from a node for a while takes it to be gone. When
+ * no node is left, the box is on its own again. It goes back to the mode that
+ * it was in by itself, its green wave runs on its own crystal and its buttons
+ * only serve its own crossing. When the others come back, it simply picks up
+ * their messages again. Nothing is resent after a loss, a box that comes back
+ * does not learn about what it missed.
+ *
+ * An emergency vehicle or a train only concerns the crossing that it passes,
+ * so the modes of a preemption stay off the bus, see `preemption.rs`. So does
+ * the manual mode, since the officer only stands at one of the crossings.
+ *
+ * The link only knows about frames. The firmware hands it the bxCAN controller
+ * of the STM32 and the simulator hands it a bus in memory, both through
+ * `CanBus`.
+ */
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::{mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use enum_ordinalize::Ordinalize;
+use heapless::Vec;
+use pistop_protocol::{Approach, EVENTS_PER_RESPONSE, EventKind, SystemMode};
+
+use crate::ThreadModeRawMutex;
+use crate::event_log::EventLog;
+use crate::green_wave::{GreenWave, Role};
+use crate::lights::PedestrianLights;
+use crate::log::Log;
+use crate::settings::Settings;
+use crate::{info, warn};
+
+// Node numbers take four bits, and 0 is for a box that is not on the bus.
+pub const MAX_NODE: u8 = 15;
+
+const CYCLE_SYNC: u16 = 0x1;
+const MODE_REQUEST: u16 = 0x2;
+const DEMAND: u16 = 0x3;
+const HEARTBEAT: u16 = 0x7;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+// Four heartbeats in a row, so that a frame lost here or there does no harm.
+const NODE_TIMEOUT: Duration = Duration::from_millis(2_000);
+
+// Mode requests and presses of the button reach us through the event log,
+// which has no room for another waiter. So we look at it this often.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Longer than the mode reader takes to look at the switch and debounce it.
+// Until then the box does not know what mode it is in by itself.
+const SWITCH_SETTLE_SECS: u64 = 10;
+
+// A frame with a standard, 11 bit identifier.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CanFrame {
+    pub id: u16,
+    pub data: Vec<u8, 8>,
+}
+
+// Where the frames go. On the controller that is the bxCAN controller, in the
+// simulator it is a bus in memory.
+pub trait CanBus {
+    // Hands a frame to the controller without waiting for it to go out. When
+    // nobody acknowledges our frames, such as when the cable is out, the
+    // mailboxes fill up and frames are dropped. The heartbeats stop getting
+    // through too, so the others know that we are gone.
+    fn send(&mut self, frame: &CanFrame);
+    fn receive(&mut self) -> impl Future<Output = CanFrame>;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Message {
+    CycleSync,
+    ModeRequest(SystemMode),
+    Demand(Approach),
+    Heartbeat,
+}
+
+impl Message {
+    pub fn encode(&self, node: u8) -> CanFrame {
+        let (kind, data): (u16, &[u8]) = match self {
+            Message::CycleSync => (CYCLE_SYNC, &[]),
+            Message::ModeRequest(mode) => (MODE_REQUEST, &[mode.ordinal()]),
+            Message::Demand(approach) => (DEMAND, &[approach.ordinal()]),
+            Message::Heartbeat => (HEARTBEAT, &[]),
+        };
+        CanFrame {
+            id: kind << 4 | (node & MAX_NODE) as u16,
+            data: Vec::from_slice(data).unwrap(),
+        }
+    }
+
+    // Returns the node that sent the message too. Frames that we don't know,
+    // perhaps from a newer firmware, are ignored.
+    pub fn decode(frame: &CanFrame) -> Option<(u8, Message)> {
+        let node: u8 = (frame.id & MAX_NODE as u16) as u8;
+        if node == 0 {
+            return None;
+        }
+        let message: Message = match (frame.id >> 4, frame.data.as_slice()) {
+            (CYCLE_SYNC, []) => Message::CycleSync,
+            (MODE_REQUEST, [mode]) => Message::ModeRequest(SystemMode::from_ordinal(*mode)?),
+            (DEMAND, [approach]) => Message::Demand(Approach::from_ordinal(*approach)?),
+            (HEARTBEAT, []) => Message::Heartbeat,
+            _ => return None,
+        };
+        Some((node, message))
+    }
+}
+
+pub struct CanLink {
+    pub log: &'static Log,
+    pub settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    pub events: &'static EventLog,
+    pub system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
+    pub pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    pub green_wave: &'static GreenWave,
+}
+
+// What the link task keeps track of between frames.
+struct LinkState {
+    node: u8,
+    // When we last heard from each node, by node number.
+    last_heard: [Option<Instant>; MAX_NODE as usize + 1],
+    // The mode that the box went to by itself, which it goes back to when it
+    // is on its own again.
+    own_mode: SystemMode,
+    // The mode that the link asked for, until the box gets there.
+    requested_mode: Option<SystemMode>,
+    // Whether the mode that runs is the one the link asked for.
+    mode_from_link: bool,
+    // The mode that runs, leaving out the modes of a preemption.
+    running_mode: SystemMode,
+    preempted: bool,
+    // A press that the link passed on to the pedestrian lights, which should
+    // not go back out on the bus.
+    demand_from_link: Option<Approach>,
+    next_sequence: u32,
+    next_heartbeat: Instant,
+    warned_twin: bool,
+}
+
+impl CanLink {
+    async fn received(&self, state: &mut LinkState, frame: &CanFrame) {
+        let Some((node, message)) = Message::decode(frame) else {
+            return;
+        };
+        if node == state.node {
+            if !state.warned_twin {
+                state.warned_twin = true;
+                warn!(self.log, "can link", "another box is node {} too.", node);
+            }
+            return;
+        }
+        if state.last_heard[node as usize].is_none() {
+            info!(self.log, "can link", "found node {}.", node);
+            self.events.record(EventKind::NodeFound(node));
+        }
+        state.last_heard[node as usize] = Some(Instant::now());
+
+        match message {
+            Message::CycleSync => {
+                if self.settings.lock().await.green_wave == Role::Follower {
+                    self.green_wave.set_cycle_start(Some(Instant::now()));
+                }
+            }
+            Message::ModeRequest(mode) => {
+                if mode != self.events.latest().mode {
+                    info!(self.log, "can link", "node {} went to {:?}.", node, mode);
+                    state.requested_mode = Some(mode);
+                    self.system_mode_signal.signal(mode);
+                }
+            }
+            Message::Demand(approach) => {
+                state.demand_from_link = Some(approach);
+                self.pedestrian_lights[approach.ordinal() as usize]
+                    .make_promise()
+                    .await;
+            }
+            Message::Heartbeat => {}
+        }
+    }
+
+    // Pass on what happened at this box since we last looked.
+    fn forward_events(&self, state: &mut LinkState, bus: &mut impl CanBus) {
+        loop {
+            let (events, next) = self.events.read_from(state.next_sequence);
+            state.next_sequence = next;
+            for event in events.iter() {
+                let message: Message = match event.kind {
+                    EventKind::PreemptionStarted(_) => {
+                        state.preempted = true;
+                        continue;
+                    }
+                    EventKind::PreemptionEnded(_) => {
+                        state.preempted = false;
+                        continue;
+                    }
+                    EventKind::ModeChanged(_) if state.preempted => continue,
+                    EventKind::ModeChanged(SystemMode::Railway | SystemMode::Manual) => continue,
+                    EventKind::ModeChanged(mode) if state.requested_mode == Some(mode) => {
+                        state.requested_mode = None;
+                        state.mode_from_link = true;
+                        state.running_mode = mode;
+                        continue;
+                    }
+                    // Back from a preemption, to the mode that ran before.
+                    EventKind::ModeChanged(mode) if state.running_mode == mode => continue,
+                    EventKind::ModeChanged(mode) => {
+                        state.running_mode = mode;
+                        state.own_mode = mode;
+                        state.mode_from_link = false;
+                        Message::ModeRequest(mode)
+                    }
+                    EventKind::ButtonPressed(approach)
+                        if state.demand_from_link != Some(approach) =>
+                    {
+                        Message::Demand(approach)
+                    }
+                    _ => continue,
+                };
+                if state.node != 0 {
+                    bus.send(&message.encode(state.node));
+                }
+            }
+            if events.len() < EVENTS_PER_RESPONSE {
+                break;
+            }
+        }
+        state.demand_from_link = None;
+    }
+
+    // Forget the nodes that went quiet, and when none are left, go back to
+    // running on our own.
+    fn check_nodes(&self, state: &mut LinkState) {
+        let was_linked: bool = state.last_heard.iter().any(Option::is_some);
+        for (node, last_heard) in state.last_heard.iter_mut().enumerate() {
+            if last_heard.is_some_and(|at| state.node == 0 || Instant::now() - at > NODE_TIMEOUT) {
+                *last_heard = None;
+                warn!(self.log, "can link", "lost node {}.", node);
+                self.events.record(EventKind::NodeLost(node as u8));
+            }
+        }
+        if !was_linked || state.last_heard.iter().any(Option::is_some) {
+            return;
+        }
+        warn!(self.log, "can link", "no nodes left, running on our own.");
+        state.requested_mode = None;
+        if state.mode_from_link {
+            state.mode_from_link = false;
+            info!(self.log, "can link", "back to {:?}.", state.own_mode);
+            self.system_mode_signal.signal(state.own_mode);
+        }
+    }
+}
+
+// The start of the next cycle, for the master of a green wave.
+fn next_cycle_sync(settings: &Settings, green_wave: &GreenWave) -> Option<Instant> {
+    if settings.green_wave != Role::Master {
+        return None;
+    }
+    let cycle_start: Instant = green_wave.cycle_start()?;
+    let cycle: Duration = Duration::from_millis(settings.green_wave_cycle_ms);
+    let cycles: u64 = (Instant::now() - cycle_start).as_ticks() / cycle.as_ticks();
+    Some(cycle_start + cycle * (cycles as u32 + 1))
+}
+
+pub async fn can_link(can_link: &'static CanLink, bus: &mut impl CanBus) -> ! {
+    Timer::after_secs(SWITCH_SETTLE_SECS).await;
+    let mut state: LinkState = LinkState {
+        node: 0,
+        last_heard: [None; MAX_NODE as usize + 1],
+        own_mode: can_link.events.latest().mode,
+        requested_mode: None,
+        mode_from_link: false,
+        running_mode: can_link.events.latest().mode,
+        preempted: false,
+        demand_from_link: None,
+        next_sequence: 0,
+        next_heartbeat: Instant::now(),
+        warned_twin: false,
+    };
+    // What happened before we got here is old news.
+    loop {
+        let (events, next) = can_link.events.read_from(state.next_sequence);
+        state.next_sequence = next;
+        if events.len() < EVENTS_PER_RESPONSE {
+            break;
+        }
+    }
+    loop {
+        let settings: Settings = *can_link.settings.lock().await;
+        if settings.can_node_id != state.node {
+            state.node = settings.can_node_id;
+            info!(can_link.log, "can link", "running as node {}.", state.node);
+        }
+        if state.node == 0 {
+            // Off the bus. We still keep up with the event log, so that we
+            // don't pass on old news once we get on it.
+            Timer::after_secs(1).await;
+            can_link.forward_events(&mut state, bus);
+            can_link.check_nodes(&mut state);
+            continue;
+        }
+
+        let cycle_sync: Option<Instant> = next_cycle_sync(&settings, can_link.green_wave);
+        let wake_at: Instant = (Instant::now() + EVENT_POLL_INTERVAL)
+            .min(state.next_heartbeat)
+            .min(cycle_sync.unwrap_or(Instant::MAX));
+        if let Either::First(frame) = select(bus.receive(), Timer::at(wake_at)).await {
+            can_link.received(&mut state, &frame).await;
+        }
+
+        if cycle_sync.is_some_and(|at| Instant::now() >= at) {
+            bus.send(&Message::CycleSync.encode(state.node));
+        }
+        can_link.forward_events(&mut state, bus);
+        if Instant::now() >= state.next_heartbeat {
+            bus.send(&Message::Heartbeat.encode(state.node));
+            state.next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
+        }
+        can_link.check_nodes(&mut state);
+    }
+}
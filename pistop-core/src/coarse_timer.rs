@@ -0,0 +1,31 @@
+/*
+ * Some timers only have another look at something every so often, and don't
+ * care about the exact moment: the inputs in case an edge slipped by, the
+ * battery, the settings of the green wave. On the controller, each of those
+ * wakes the core up from STOP, and they would all do so at moments of their
+ * own. So they go off on the whole second instead, all at once, just before
+ * the cycles of the output masker start, see `low_power.rs` in the firmware.
+ */
+
+use embassy_time::{Duration, Instant, Timer};
+
+// A timer that goes off after about the given time, on the whole second. That
+// is up to a second early, unless that would be in the past already, which
+// only happens for times shorter than a second.
+pub fn after(duration: Duration) -> Timer {
+    let at: Instant = Instant::now() + duration;
+    let on_the_second: Instant = Instant::from_secs(at.as_secs());
+    if on_the_second > Instant::now() {
+        Timer::at(on_the_second)
+    } else {
+        Timer::at(at)
+    }
+}
+
+pub fn after_secs(secs: u64) -> Timer {
+    after(Duration::from_secs(secs))
+}
+
+pub fn after_millis(millis: u64) -> Timer {
+    after(Duration::from_millis(millis))
+}
@@ -0,0 +1,120 @@
+/*
+ * The event log keeps the most recent things that happened at the crossing,
+ * such as mode and phase changes, button presses and faults. Tools read it over
+ * the host protocol to find out what the controller has been up to, and the
+ * host link sends each new event as a notification.
+ *
+ * The log is a ring of the most recent events. Events are numbered, so that a
+ * reader can keep track of where it left off and can tell when events dropped
+ * out of the log before it got to them.
+ *
+ * The log also remembers the latest mode, phases and battery state. Those make
+ * up most of the status of the controller, and the events that set them may
+ * have long since dropped out of the log.
+ */
+
+use core::cell::RefCell;
+use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use embassy_time::Instant;
+use enum_ordinalize::Ordinalize;
+use heapless::{Deque, Vec};
+use pistop_protocol::{
+    Approach, BatteryState, EVENTS_PER_RESPONSE, Event, EventKind, Phase, SystemMode,
+};
+
+use crate::ThreadModeRawMutex;
+
+const LOG_SIZE: usize = 32;
+
+#[derive(Copy, Clone)]
+pub struct Latest {
+    pub mode: SystemMode,
+    pub phases: [Phase; Approach::VARIANT_COUNT],
+    pub battery_state: BatteryState,
+}
+
+struct Log {
+    events: Deque<Event, LOG_SIZE>,
+    next_sequence: u32,
+    latest: Latest,
+}
+
+pub struct EventLog {
+    // The log is only ever held for a moment, so a blocking mutex will do and
+    // recording an event does not need to be awaited.
+    log: Mutex<ThreadModeRawMutex, RefCell<Log>>,
+    recorded: Signal<ThreadModeRawMutex, ()>,
+}
+
+impl EventLog {
+    pub const fn new(start_mode: SystemMode) -> Self {
+        EventLog {
+            log: Mutex::new(RefCell::new(Log {
+                events: Deque::new(),
+                next_sequence: 0,
+                latest: Latest {
+                    mode: start_mode,
+                    phases: [Phase::Clear; Approach::VARIANT_COUNT],
+                    battery_state: BatteryState::Ok,
+                },
+            })),
+            recorded: Signal::new(),
+        }
+    }
+
+    pub fn record(&self, kind: EventKind) {
+        self.log.lock(|log| {
+            let mut log = log.borrow_mut();
+            match kind {
+                EventKind::ModeChanged(mode) => log.latest.mode = mode,
+                EventKind::PhaseChanged(approach, phase) => {
+                    log.latest.phases[approach.ordinal() as usize] = phase
+                }
+                EventKind::BatteryStateChanged(state) => log.latest.battery_state = state,
+                _ => {}
+            }
+
+            let event: Event = Event {
+                sequence: log.next_sequence,
+                timestamp_ms: Instant::now().as_millis(),
+                kind: kind,
+            };
+            log.next_sequence = log.next_sequence.wrapping_add(1);
+            if log.events.is_full() {
+                log.events.pop_front();
+            }
+            let _ = log.events.push_back(event);
+        });
+        self.recorded.signal(());
+    }
+
+    pub fn latest(&self) -> Latest {
+        self.log.lock(|log| log.borrow().latest)
+    }
+
+    // Read the events starting at the given sequence number, as many as fit in
+    // a response. Also returns the sequence number to continue reading from.
+    pub fn read_from(&self, sequence: u32) -> (Vec<Event, EVENTS_PER_RESPONSE>, u32) {
+        self.log.lock(|log| {
+            let log = log.borrow();
+            let mut events: Vec<Event, EVENTS_PER_RESPONSE> = Vec::new();
+            let mut next: u32 = sequence;
+            for event in log.events.iter() {
+                // Compare by distance, so that this keeps working when the
+                // sequence numbers wrap around.
+                if event.sequence.wrapping_sub(sequence) > u32::MAX / 2 {
+                    continue;
+                }
+                if events.push(*event).is_err() {
+                    break;
+                }
+                next = event.sequence.wrapping_add(1);
+            }
+            (events, next)
+        })
+    }
+
+    pub async fn wait_recorded(&self) {
+        self.recorded.wait().await
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * Faults are conditions under which we no longer trust ourselves to run the
+ * crossing. The standard reaction to a fault is to put the crossing in flashing
+ * mode, which is what real traffic lights do too. Drivers and pedestrians know
+ * to treat a flashing amber light with care and it needs the fewest lamps to
+ * work.
+ *
+ * Different parts of the system detect different faults. They all report them
+ * here and the system mode handler decides what to do about them.
+ */
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_sync::signal::Signal;
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::EventKind;
+
+use crate::ThreadModeRawMutex;
+use crate::event_log::EventLog;
+use crate::timed_output_masker::Pins;
+
+// Without a red lamp we cannot stop the traffic, so a burnt-out red lamp is a
+// reason to go to flashing mode. A missing amber or green lamp is bad, but it
+// is safe to keep running.
+const RED_LAMPS: [Pins; 4] = [
+    Pins::ARed,
+    Pins::APedestrianRed,
+    Pins::BRed,
+    Pins::BPedestrianRed,
+];
+
+pub struct Faults {
+    battery_critical: AtomicBool,
+    // one bit per `Pins` ordinal
+    lamps_out: AtomicU32,
+    clock_crystal_failed: AtomicBool,
+    changed: Signal<ThreadModeRawMutex, ()>,
+    events: &'static EventLog,
+}
+
+impl Faults {
+    pub const fn new(events: &'static EventLog) -> Self {
+        Faults {
+            battery_critical: AtomicBool::new(false),
+            lamps_out: AtomicU32::new(0),
+            clock_crystal_failed: AtomicBool::new(false),
+            changed: Signal::new(),
+            events: events,
+        }
+    }
+
+    pub fn set_battery_critical(&self, battery_critical: bool) {
+        if self
+            .battery_critical
+            .swap(battery_critical, Ordering::Relaxed)
+            != battery_critical
+        {
+            self.changed.signal(());
+        }
+    }
+
+    // Lamps don't heal, so a lamp that was found to be out stays out until the
+    // box is restarted.
+    pub fn set_lamp_out(&self, pin: Pins) {
+        let bit: u32 = 1 << pin.ordinal();
+        if self.lamps_out.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+            self.events.record(EventKind::LampOut(pin));
+            self.changed.signal(());
+        }
+    }
+
+    // A clock that runs fast or slow only moves the schedule a little, so this
+    // is reported but does not take the crossing to flashing mode.
+    pub fn set_clock_crystal_failed(&self) {
+        if !self.clock_crystal_failed.swap(true, Ordering::Relaxed) {
+            self.events.record(EventKind::ClockCrystalFailed);
+        }
+    }
+
+    // one bit per `Pins` ordinal
+    pub fn lamps_out(&self) -> u32 {
+        self.lamps_out.load(Ordering::Relaxed)
+    }
+
+    pub fn is_lamp_out(&self, pin: Pins) -> bool {
+        self.lamps_out.load(Ordering::Relaxed) & (1 << pin.ordinal()) != 0
+    }
+
+    pub fn force_flash(&self) -> bool {
+        self.battery_critical.load(Ordering::Relaxed)
+            || RED_LAMPS.iter().any(|red| self.is_lamp_out(*red))
+    }
+
+    pub async fn wait_changed(&self) {
+        self.changed.wait().await
+    }
+}
@@ -0,0 +1,174 @@
+/*
+ * A green wave runs down a line of controllers, such as two or three boxes on
+ * a table that show a corridor. All of them run the same cycle, and each one
+ * starts the green of approach A at its own offset into that cycle, so that
+ * the greens follow each other down the line.
+ *
+ * The controllers agree on when a cycle starts by way of a sync pulse. The
+ * sync output of one controller goes to the sync input of the next. The master
+ * pulses its output at the start of every cycle. A follower takes the start of
+ * the cycle from the pulse on its input and passes the pulse on to its own
+ * output, so that the line can be as long as we like. The line idles high and
+ * a pulse pulls it low, like the buttons.
+ *
+ * Every controller goes by its own crystal, which drift apart by a little
+ * every cycle. The followers start counting again on every pulse, so the drift
+ * never adds up to more than a cycle's worth. When the pulses stop, a follower
+ * keeps running on the last one it saw, and picks up the next one when it
+ * comes.
+ *
+ * Boxes on a CAN bus can do without the wire, the master sends the start of
+ * the cycle over the bus as well. See `can_link.rs`.
+ *
+ * The common cycle has to be longer than a cycle of normal mode, which is a
+ * turn of approach A followed by a turn of approach B. The slack goes into a
+ * longer all-red before the turn of A, so keep the cycle just a little longer.
+ */
+
+use core::cell::Cell;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::Mutex, mutex::Mutex as AsyncMutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::digital::Wait;
+use enum_ordinalize::Ordinalize;
+
+use crate::ThreadModeRawMutex;
+use crate::coarse_timer;
+use crate::log::Log;
+use crate::settings::Settings;
+use crate::{info, warn};
+
+#[derive(Ordinalize, PartialEq, Eq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Role {
+    // Normal mode runs at its own pace.
+    Off,
+    // Pulses the start of every cycle.
+    Master,
+    // Takes the start of the cycle from the pulses of the master.
+    Follower,
+}
+
+// Long enough for the inputs of the next controller to see it.
+const SYNC_PULSE_MS: u64 = 100;
+
+// A follower that misses this many pulses in a row says so in the log.
+const MISSED_PULSES: u32 = 3;
+
+pub struct GreenWave {
+    // The start of a cycle, none until the master started counting or the
+    // follower saw its first pulse.
+    cycle_start: Mutex<ThreadModeRawMutex, Cell<Option<Instant>>>,
+}
+
+impl GreenWave {
+    pub const fn new() -> Self {
+        GreenWave {
+            cycle_start: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    // Wait until approach A should start its turn, which starts with the
+    // attention phase, so that the green comes at the offset of this
+    // controller. Without a green wave, or before the cycle is known, there is
+    // no need to wait.
+    pub async fn wait_for_turn(&self, settings: &Settings) {
+        if settings.green_wave == Role::Off {
+            return;
+        }
+        let Some(cycle_start) = self.cycle_start() else {
+            return;
+        };
+        let cycle_ms: u64 = settings.green_wave_cycle_ms;
+        let turn_ms: u64 = (settings.green_wave_offset_ms + cycle_ms
+            - settings.normal_attention_ms % cycle_ms)
+            % cycle_ms;
+        let into_cycle_ms: u64 = (Instant::now() - cycle_start).as_millis() % cycle_ms;
+        Timer::after_millis((turn_ms + cycle_ms - into_cycle_ms) % cycle_ms).await;
+    }
+
+    pub(crate) fn set_cycle_start(&self, at: Option<Instant>) {
+        self.cycle_start.lock(|cycle_start| cycle_start.set(at));
+    }
+
+    pub(crate) fn cycle_start(&self) -> Option<Instant> {
+        self.cycle_start.lock(|cycle_start| cycle_start.get())
+    }
+}
+
+impl Default for GreenWave {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Looks after the sync pulses, as master or as follower, whichever the
+// settings say.
+pub async fn sync_pulses(
+    log: &'static Log,
+    settings: &'static AsyncMutex<ThreadModeRawMutex, Settings>,
+    green_wave: &'static GreenWave,
+    sync_input: &mut impl Wait,
+    sync_output: &mut impl OutputPin,
+) -> ! {
+    let mut role: Role = Role::Off;
+    let mut missed: u32 = 0;
+    loop {
+        let settings: Settings = *settings.lock().await;
+        if settings.green_wave != role {
+            role = settings.green_wave;
+            info!(log, "green wave", "running as {:?}.", role);
+            green_wave.set_cycle_start(match role {
+                Role::Master => Some(Instant::now()),
+                Role::Off | Role::Follower => None,
+            });
+            missed = 0;
+        }
+        let cycle: Duration = Duration::from_millis(settings.green_wave_cycle_ms);
+        match (role, green_wave.cycle_start()) {
+            (Role::Master, Some(cycle_start)) => {
+                let cycles: u64 = (Instant::now() - cycle_start).as_ticks() / cycle.as_ticks();
+                Timer::at(cycle_start + cycle * (cycles as u32 + 1)).await;
+                pulse(sync_output).await;
+            }
+            (Role::Follower, _) => {
+                match select(
+                    sync_input.wait_for_falling_edge(),
+                    Timer::after(cycle * MISSED_PULSES),
+                )
+                .await
+                {
+                    Either::First(_) => {
+                        green_wave.set_cycle_start(Some(Instant::now()));
+                        missed = 0;
+                        pulse(sync_output).await;
+                    }
+                    // Unless the start of the cycle came over the CAN link.
+                    Either::Second(_) if synced_within(green_wave, cycle * MISSED_PULSES) => {
+                        missed = 0;
+                    }
+                    Either::Second(_) => {
+                        missed += MISSED_PULSES;
+                        warn!(log, "green wave", "missed {} sync pulses.", missed);
+                    }
+                }
+            }
+            // Have another look at the settings in a while.
+            _ => coarse_timer::after_secs(1).await,
+        }
+    }
+}
+
+fn synced_within(green_wave: &GreenWave, window: Duration) -> bool {
+    green_wave
+        .cycle_start()
+        .is_some_and(|cycle_start| Instant::now() - cycle_start < window)
+}
+
+async fn pulse(sync_output: &mut impl OutputPin) {
+    let _ = sync_output.set_low();
+    Timer::after_millis(SYNC_PULSE_MS).await;
+    let _ = sync_output.set_high();
+}
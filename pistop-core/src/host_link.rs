@@ -0,0 +1,222 @@
+/*
+ * The host link answers the requests of tools that speak the host protocol,
+ * see the `pistop-protocol` crate, and passes on every event as a
+ * notification. It needs to get at most of the controller, so we hand it
+ * everything in one go.
+ */
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_sync::{
+    mutex::{Mutex, MutexGuard},
+    signal::Signal,
+};
+use embassy_time::Instant;
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{
+    Approach, ControllerMessage, EVENTS_PER_RESPONSE, ErrorCode, FrameReader, HostMessage, Request,
+    Response, Status, SystemMode,
+};
+
+use crate::ThreadModeRawMutex;
+use crate::calendar::Calendar;
+use crate::event_log::{EventLog, Latest};
+use crate::faults::Faults;
+use crate::lights::PedestrianLights;
+use crate::log::Log;
+use crate::preemption::{Caller, Preemption};
+use crate::schedule::Schedule;
+use crate::serial::Serial;
+use crate::settings::Settings;
+use crate::statistics::Statistics;
+use crate::timed_output_masker::{Pins, TimedOutputMasker};
+use crate::wall_clock::WallClock;
+
+pub struct HostLink {
+    pub serial: &'static Serial,
+    pub log: &'static Log,
+    pub events: &'static EventLog,
+    pub statistics: &'static Statistics,
+    pub faults: &'static Faults,
+    pub preemption: &'static Preemption,
+    pub settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    pub lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    pub lockout: &'static AtomicBool,
+    pub battery_millivolts: &'static AtomicU32,
+    pub system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
+    pub manual_advance: &'static Signal<ThreadModeRawMutex, ()>,
+    pub pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    pub wall_clock: &'static WallClock,
+    pub schedule: &'static Mutex<ThreadModeRawMutex, Schedule>,
+    pub calendar: &'static Mutex<ThreadModeRawMutex, Calendar>,
+    // Tells the schedule keeper to store the schedule and the calendar.
+    pub schedule_written: &'static Signal<ThreadModeRawMutex, ()>,
+}
+
+impl HostLink {
+    async fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::GetStatus => Response::Status(self.status().await),
+            // This works just like turning the rotary switch, so the mode
+            // sticks until either is used again. Only a train gets to run the
+            // railway mode.
+            Request::SetMode(SystemMode::Railway) => Response::Error(ErrorCode::InvalidValue),
+            Request::SetMode(mode) => {
+                self.system_mode_signal.signal(mode);
+                Response::Ok
+            }
+            Request::PressButton(approach) => {
+                self.pedestrian_lights[approach.ordinal() as usize]
+                    .make_promise()
+                    .await;
+                Response::Ok
+            }
+            Request::ReadConfig(key) => Response::Config(key, self.settings.lock().await.get(key)),
+            Request::WriteConfig(key, value) => {
+                let mut settings: MutexGuard<'_, ThreadModeRawMutex, Settings> =
+                    self.settings.lock().await;
+                match settings.set(key, value) {
+                    Ok(()) => {
+                        // The log keeps its own copy of the level, since it
+                        // cannot wait for the settings.
+                        self.log.set_level(settings.log_level);
+                        Response::Config(key, value)
+                    }
+                    Err(error) => Response::Error(error),
+                }
+            }
+            Request::ReadEventLog(sequence) => {
+                let (events, next) = self.events.read_from(sequence);
+                Response::Events(events, next)
+            }
+            Request::Console => Response::Ok,
+            Request::ReadStatistics => Response::Statistics(self.statistics.snapshot()),
+            Request::ResetStatistics => {
+                self.statistics.reset();
+                Response::Ok
+            }
+            Request::ReadClock => Response::Clock(self.wall_clock.now()),
+            Request::SetClock(time) => {
+                self.wall_clock.set(time);
+                Response::Ok
+            }
+            Request::ReadSchedule(index) => match self.schedule.lock().await.get(index) {
+                Ok(entry) => Response::Schedule(index, entry),
+                Err(error) => Response::Error(error),
+            },
+            Request::WriteSchedule(index, entry) => {
+                match self.schedule.lock().await.set(index, entry) {
+                    Ok(()) => {
+                        self.schedule_written.signal(());
+                        Response::Schedule(index, entry)
+                    }
+                    Err(error) => Response::Error(error),
+                }
+            }
+            Request::ReadCalendar(index) => match self.calendar.lock().await.get(index) {
+                Ok(entry) => Response::Calendar(index, entry),
+                Err(error) => Response::Error(error),
+            },
+            Request::WriteCalendar(index, entry) => {
+                match self.calendar.lock().await.set(index, entry) {
+                    Ok(()) => {
+                        self.schedule_written.signal(());
+                        Response::Calendar(index, entry)
+                    }
+                    Err(error) => Response::Error(error),
+                }
+            }
+            // The host calls for one approach at a time, a call for the other
+            // one takes the first call back. The new call goes in first, so
+            // that the crossing does not go back to its mode in between.
+            Request::Preempt(called) => {
+                if let Some(approach) = called {
+                    self.preemption.set_call(Caller::Host, approach, true);
+                }
+                for approach in Approach::VARIANTS {
+                    if called != Some(*approach) {
+                        self.preemption.set_call(Caller::Host, *approach, false);
+                    }
+                }
+                Response::Ok
+            }
+            // Only the manual mode waits for the officer. A press at any
+            // other time would only be forgotten.
+            Request::Advance if self.events.latest().mode != SystemMode::Manual => {
+                Response::Error(ErrorCode::InvalidValue)
+            }
+            Request::Advance => {
+                self.manual_advance.signal(());
+                Response::Ok
+            }
+        }
+    }
+
+    pub async fn status(&self) -> Status {
+        let latest: Latest = self.events.latest();
+        let lit: [bool; Pins::VARIANT_COUNT] = self.lights.lock().await.lit();
+        Status {
+            mode: latest.mode,
+            locked_out: self.lockout.load(Ordering::Relaxed),
+            phases: latest.phases,
+            lit: lit
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (i, on)| bits | ((*on as u32) << i)),
+            lamps_out: self.faults.lamps_out(),
+            battery_state: latest.battery_state,
+            battery_millivolts: self.battery_millivolts.load(Ordering::Relaxed),
+            uptime_ms: Instant::now().as_millis(),
+        }
+    }
+}
+
+pub async fn host_link(host_link: &'static HostLink) -> ! {
+    let serial: &'static Serial = host_link.serial;
+    let mut reader: FrameReader = FrameReader::new();
+    let mut buffer: [u8; 32] = [0; 32];
+    loop {
+        let count: usize = serial.receive(&mut buffer).await;
+        for byte in &buffer[..count] {
+            // Frames that don't decode are dropped without an answer, the host
+            // will time out and ask again. In console mode, those are mostly
+            // people typing at the console.
+            let Some(Ok(message)) = reader.push::<HostMessage>(*byte) else {
+                continue;
+            };
+
+            serial.set_binary_mode(true);
+            let response: Response = host_link.handle(message.request).await;
+            serial
+                .send(&ControllerMessage::Response {
+                    sequence: message.sequence,
+                    response: response,
+                })
+                .await;
+            if message.request == Request::Console {
+                serial.set_binary_mode(false);
+            }
+        }
+    }
+}
+
+// Send every event that is recorded as a notification, as long as a tool is
+// listening.
+pub async fn host_notifications(host_link: &'static HostLink) -> ! {
+    let serial: &'static Serial = host_link.serial;
+    let mut next_sequence: u32 = 0;
+    loop {
+        host_link.events.wait_recorded().await;
+        loop {
+            let (events, next) = host_link.events.read_from(next_sequence);
+            next_sequence = next;
+            if serial.binary_mode() {
+                for event in events.iter() {
+                    serial.send(&ControllerMessage::Notification(*event)).await;
+                }
+            }
+            if events.len() < EVENTS_PER_RESPONSE {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+/*
+ * The inputs from the headers of the box, the preemption inputs, the track
+ * circuit, the kerbside detector and the button of the officer, all bounce for
+ * a moment when they change, like any contact. The tasks that read them sleep
+ * until an input changes and then give it time to settle before they read it.
+ */
+
+use embassy_futures::select::{select, select_array};
+use embassy_time::Timer;
+use embedded_hal_async::digital::Wait;
+
+use crate::coarse_timer;
+
+// Long enough for a contact or the output of a detector to stop bouncing.
+pub const INPUT_SETTLE_MS: u64 = 50;
+
+// Sleep until one of the inputs changes, then until it settled. We also have
+// another look every second or so, in case an edge slipped by while the inputs
+// were being read.
+pub async fn wait_for_input_change<I: Wait, const N: usize>(inputs: &mut [I; N]) {
+    select(
+        select_array(inputs.each_mut().map(|input| input.wait_for_any_edge())),
+        coarse_timer::after_secs(1),
+    )
+    .await;
+    Timer::after_millis(INPUT_SETTLE_MS).await;
+}
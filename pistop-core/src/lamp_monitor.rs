@@ -0,0 +1,91 @@
+/*
+ * A traffic light with a burnt-out lamp is dangerous, a traffic light with a
+ * burnt-out red lamp doubly so. The controller cannot see its own lamps, so
+ * boxes that care can fit a current sensor per lamp: a shunt resistor and a
+ * comparator that pulls an input high while current flows through the lamp.
+ *
+ * We can only learn something from a sensor while its lamp is supposed to be
+ * on. When the lamp is off, no current flows whether the lamp is fine or not.
+ * Lamps take a moment to draw current after being switched on, and the sensor
+ * may catch a blinking lamp just as it is being switched off. So we only call
+ * a lamp out when it failed to draw current for a number of consecutive
+ * samples in which it should have.
+ *
+ * Like the output masker, the monitor itself knows nothing about time or
+ * hardware. It is handed what the lamps should do and what the sensors saw.
+ */
+
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use embedded_hal::digital::InputPin;
+use enum_ordinalize::Ordinalize;
+
+use crate::ThreadModeRawMutex;
+use crate::faults::Faults;
+use crate::log::Log;
+use crate::timed_output_masker::{Pins, TimedOutputMasker};
+use crate::warn;
+
+// At the sample rate of the lamp monitor task, this is a fifth of a second.
+const LAMP_OUT_SAMPLES: u8 = 4;
+
+pub struct LampMonitor {
+    dark_samples: [u8; Pins::VARIANT_COUNT],
+}
+
+impl LampMonitor {
+    pub const fn new() -> Self {
+        LampMonitor {
+            dark_samples: [0; Pins::VARIANT_COUNT],
+        }
+    }
+
+    // Feed one sample for a lamp. Returns true exactly once, at the sample
+    // where the lamp is found to be out.
+    pub fn update(&mut self, pin: Pins, commanded_on: bool, current_sensed: bool) -> bool {
+        let dark_samples: &mut u8 = &mut self.dark_samples[pin.ordinal()];
+        if !commanded_on {
+            return false;
+        }
+        if current_sensed {
+            *dark_samples = 0;
+            return false;
+        }
+
+        if *dark_samples < LAMP_OUT_SAMPLES {
+            *dark_samples += 1;
+            return *dark_samples == LAMP_OUT_SAMPLES;
+        }
+        false
+    }
+}
+
+impl Default for LampMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Samples the current sensors, which pull their input high while their lamp
+// draws current.
+pub async fn lamp_monitor(
+    log: &'static Log,
+    sense_inputs: &mut [(Pins, impl InputPin)],
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    faults: &'static Faults,
+) -> ! {
+    let mut monitor: LampMonitor = LampMonitor::new();
+    loop {
+        Timer::after_millis(50).await;
+
+        let lit: [bool; Pins::VARIANT_COUNT] = lights.lock().await.lit();
+        for (pin, input) in sense_inputs.iter_mut() {
+            let current_sensed: bool = matches!(input.is_high(), Ok(true));
+            if monitor.update(*pin, lit[pin.ordinal()], current_sensed) {
+                faults.set_lamp_out(*pin);
+
+                warn!(log, "lamp monitor", "{} is out.", pin.name());
+            }
+        }
+    }
+}
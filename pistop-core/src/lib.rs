@@ -0,0 +1,48 @@
+#![no_std]
+
+/*
+ * The logic of the controller, without any of the hardware. The firmware wires
+ * this up to the pins, the ADC and the serial port of the board. The simulator
+ * in `host/` wires the very same logic up to simulated inputs and outputs, so
+ * that we can run and test the controller on a desktop machine.
+ *
+ * Hardware comes in through the `embedded-hal` traits where there is one,
+ * and through small traits of our own where there isn't. The serial port is a
+ * pair of byte pipes, which the firmware drains into the UART.
+ */
+
+pub mod battery_monitor;
+pub mod calendar;
+pub mod can_link;
+pub mod coarse_timer;
+pub mod event_log;
+pub mod faults;
+pub mod green_wave;
+pub mod host_link;
+pub mod inputs;
+pub mod lamp_monitor;
+pub mod lights;
+pub mod log;
+pub mod manual;
+pub mod modbus;
+pub mod mode_switch;
+pub mod modes;
+pub mod pelican;
+pub mod preemption;
+pub mod railway;
+pub mod schedule;
+pub mod schedule_storage;
+pub mod serial;
+pub mod settings;
+pub mod statistics;
+pub mod timed_output_masker;
+pub mod wall_clock;
+
+// On the controller everything runs in thread mode, which makes the thread
+// mode mutex the cheapest choice. On a desktop machine that mutex insists on
+// running on the main thread, which tests don't, so there we fall back to a
+// critical section.
+#[cfg(not(target_arch = "arm"))]
+pub use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex as ThreadModeRawMutex;
+#[cfg(target_arch = "arm")]
+pub use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
@@ -0,0 +1,299 @@
+/*
+ * The heads of the crossing. Each approach has a set of traffic lights and a
+ * set of pedestrian lights, which the mode tasks step through the phases of
+ * their cycle. The heads record every phase change in the event log.
+ */
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embedded_hal_async::digital::Wait;
+use pistop_protocol::{Approach, EventKind, Phase};
+
+use crate::ThreadModeRawMutex;
+use crate::event_log::EventLog;
+use crate::statistics::Statistics;
+use crate::timed_output_masker::{Pins, TimedOutputMasker};
+
+// The outputs of a set of traffic lights.
+#[derive(Copy, Clone)]
+pub struct TrafficPins {
+    pub red: Pins,
+    pub amber: Pins,
+    pub green: Pins,
+}
+
+pub struct TrafficLights {
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    events: &'static EventLog,
+    approach: Approach,
+    red: Pins,
+    amber: Pins,
+    green: Pins,
+}
+
+impl TrafficLights {
+    pub const fn new(
+        lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+        events: &'static EventLog,
+        approach: Approach,
+        pins: TrafficPins,
+    ) -> Self {
+        TrafficLights {
+            lights: lights,
+            events: events,
+            approach: approach,
+            red: pins.red,
+            amber: pins.amber,
+            green: pins.green,
+        }
+    }
+
+    pub fn approach(&self) -> Approach {
+        self.approach
+    }
+
+    pub async fn go_attention(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        lights.set_on_off3(self.red, true, self.amber, true, self.green, false);
+        self.record_phase(Phase::Attention);
+    }
+    pub async fn go_go(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        lights.set_on_off3(self.red, false, self.amber, false, self.green, true);
+        self.record_phase(Phase::Go);
+    }
+    pub async fn go_flash(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        lights.set_on_off2(self.red, false, self.green, false);
+        lights.set_pin(self.amber, true, true, false, false);
+        self.record_phase(Phase::Flash);
+    }
+    pub async fn go_yield(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        lights.set_on_off3(self.red, false, self.amber, true, self.green, false);
+        self.record_phase(Phase::Yield);
+    }
+    pub async fn go_yield_flash(&self) {
+        self.go_yield().await;
+    }
+    pub async fn go_clear(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        lights.set_on_off3(self.red, true, self.amber, false, self.green, false);
+        self.record_phase(Phase::Clear);
+    }
+    pub async fn go_dark(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        lights.set_on_off3(self.red, false, self.amber, false, self.green, false);
+        self.record_phase(Phase::Dark);
+    }
+
+    fn record_phase(&self, phase: Phase) {
+        self.events
+            .record(EventKind::PhaseChanged(self.approach, phase));
+    }
+}
+
+// The outputs of a set of pedestrian lights, with the beeper and the promise
+// led of its button.
+#[derive(Copy, Clone)]
+pub struct PedestrianPins {
+    pub red: Pins,
+    pub green: Pins,
+    pub beeper: Pins,
+    pub promise: Pins,
+}
+
+pub struct PedestrianLights {
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    events: &'static EventLog,
+    statistics: &'static Statistics,
+    approach: Approach,
+    red: Pins,
+    green: Pins,
+    beeper: Pins,
+    promise: Pins,
+    old_promise: AtomicBool,
+    active: AtomicBool,
+    promise_made: AtomicBool,
+    // From `go_dark()` until the heads come back with `go_flash()`.
+    dark: AtomicBool,
+}
+
+impl PedestrianLights {
+    pub const fn new(
+        lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+        events: &'static EventLog,
+        statistics: &'static Statistics,
+        approach: Approach,
+        pins: PedestrianPins,
+    ) -> Self {
+        PedestrianLights {
+            lights: lights,
+            events: events,
+            statistics: statistics,
+            approach: approach,
+            red: pins.red,
+            green: pins.green,
+            beeper: pins.beeper,
+            promise: pins.promise,
+            old_promise: AtomicBool::new(false),
+            active: AtomicBool::new(false),
+            promise_made: AtomicBool::new(false),
+            dark: AtomicBool::new(false),
+        }
+    }
+
+    pub async fn go_attention(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+
+        lights.set_on_off2(self.red, true, self.green, false);
+
+        self.active.store(true, Ordering::Relaxed);
+    }
+    pub async fn go_go(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        let promise_made: bool = self.promise_made.swap(false, Ordering::Relaxed);
+        let active_promise = self.active.load(Ordering::Relaxed) && promise_made;
+
+        lights.set_on_off2(self.red, !active_promise, self.green, active_promise);
+        lights.set_pin(self.beeper, active_promise, false, true, false);
+
+        self.old_promise.store(active_promise, Ordering::Relaxed);
+        lights.set_on_off(self.promise, false);
+        if active_promise {
+            self.statistics.promise_served(self.approach);
+        } else if promise_made {
+            self.statistics.promise_dropped(self.approach);
+        }
+    }
+    pub async fn go_flash(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+
+        lights.set_on_off3(self.red, false, self.green, false, self.beeper, false);
+
+        self.old_promise.store(false, Ordering::Relaxed);
+        self.active.store(false, Ordering::Relaxed);
+        self.dark.store(false, Ordering::Relaxed);
+        if self.promise_made.swap(false, Ordering::Relaxed) {
+            self.statistics.promise_dropped(self.approach);
+        }
+        lights.set_on_off(self.promise, false);
+    }
+    // Like the flashing phase, but nobody gets to cross until the heads come
+    // back, so the button makes no promises either.
+    pub async fn go_dark(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+
+        lights.set_on_off3(self.red, false, self.green, false, self.beeper, false);
+
+        self.old_promise.store(false, Ordering::Relaxed);
+        self.active.store(false, Ordering::Relaxed);
+        self.dark.store(true, Ordering::Relaxed);
+        if self.promise_made.swap(false, Ordering::Relaxed) {
+            self.statistics.promise_dropped(self.approach);
+        }
+        lights.set_on_off(self.promise, false);
+    }
+    pub async fn go_yield_flash(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+
+        lights.set_on_off3(self.red, false, self.green, false, self.beeper, false);
+
+        self.old_promise.store(false, Ordering::Relaxed);
+        self.active.store(false, Ordering::Relaxed);
+        if self.promise_made.swap(false, Ordering::Relaxed) {
+            self.statistics.promise_dropped(self.approach);
+        }
+        lights.set_on_off(self.promise, false);
+    }
+    pub async fn go_yield(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+        let active_old_promise =
+            self.active.load(Ordering::Relaxed) && self.old_promise.load(Ordering::Relaxed);
+
+        lights.set_pin(self.beeper, active_old_promise, true, true, false);
+        lights.set_on_off(self.red, !active_old_promise);
+        lights.set_pin(self.green, active_old_promise, true, false, false);
+    }
+    pub async fn go_clear(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+
+        lights.set_on_off2(self.red, true, self.green, false);
+        lights.set_on_off(self.beeper, false);
+        self.old_promise.store(false, Ordering::Relaxed);
+    }
+
+    // Whether someone pressed the button and waits for the next green.
+    pub fn promise_pending(&self) -> bool {
+        self.promise_made.load(Ordering::Relaxed)
+    }
+
+    // Someone pressed the button and walked off, which a puffin can tell from
+    // its kerbside detector. The promise goes, like when the crossing goes to
+    // flashing mode.
+    pub async fn cancel_promise(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+
+        if self.promise_made.swap(false, Ordering::Relaxed) {
+            self.statistics.promise_dropped(self.approach);
+        }
+        lights.set_on_off2(self.promise, false, self.beeper, false);
+    }
+
+    pub async fn make_promise(&self) {
+        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
+            self.lights.lock().await;
+
+        // While the pedestrians cross, from the go phase until the clear
+        // phase, a press is someone who can simply walk. A promise would light
+        // up next to the green and wait for the next cycle. While the
+        // crossing is dark, nobody gets to cross at all.
+        if self.old_promise.load(Ordering::Relaxed) || self.dark.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // Only the first press of a promise is worth recording, the button
+        // may bounce and people tend to press it more than once.
+        if !self.promise_made.swap(true, Ordering::Relaxed) {
+            self.events.record(EventKind::ButtonPressed(self.approach));
+            self.statistics.promise_made(self.approach);
+        }
+        lights.set_on_off(self.promise, true);
+        lights.set_pin(
+            self.beeper,
+            self.active.load(Ordering::Relaxed),
+            false,
+            false,
+            true,
+        );
+    }
+}
+
+pub async fn promise_input(
+    input: &mut impl Wait,
+    pedestrian_lights: &'static PedestrianLights,
+) -> ! {
+    loop {
+        // Making a promise is idempotent, so we don't care if the button
+        // bounces and we get called a few times for a single press. We wait
+        // for the level rather than for an edge, so that a button that is
+        // already held when we start makes its promise too.
+        let _ = input.wait_for_low().await;
+        pedestrian_lights.make_promise().await;
+        let _ = input.wait_for_high().await;
+    }
+}
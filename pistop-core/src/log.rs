@@ -0,0 +1,245 @@
+/*
+ * Logging for the controller. Tasks log records with a level and a tag that
+ * says where they come from, and a writer task prints them on the serial
+ * port, with the time since startup.
+ *
+ * Logging never waits. Records go into a queue and when the queue is full,
+ * because the serial port cannot keep up or because nobody drains it, the
+ * record is dropped and counted. The writer reports the drops once it catches
+ * up. This keeps the control tasks running on time, whatever happens on the
+ * serial port.
+ *
+ * Records below the current level are dropped before they are even formatted,
+ * and the level can be changed at run time.
+ *
+ * With the `defmt` feature, the macros hand the records to `defmt` instead,
+ * which sends them to a debug probe over RTT. `defmt` leaves the formatting to
+ * the host and only sends the arguments, so even a record for every tick of
+ * the output loop fits easily, which it does not at 115200 baud. The queue and
+ * the writer then go unused. `defmt` knows the file and the line of every
+ * record, which does the job of the tag, and the level works as before.
+ */
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use embassy_sync::channel::Channel;
+use embassy_time::Instant;
+use enum_ordinalize::Ordinalize;
+use heapless::String;
+
+use crate::ThreadModeRawMutex;
+use crate::serial::{Serial, print};
+
+const QUEUE_SIZE: usize = 16;
+
+// Longer messages are cut short.
+const MESSAGE_SIZE: usize = 64;
+
+// The longest tag, so that the messages line up.
+const TAG_WIDTH: usize = 15;
+
+#[derive(Ordinalize, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+pub struct Record {
+    pub timestamp: Instant,
+    pub level: Level,
+    pub tag: &'static str,
+    pub message: String<MESSAGE_SIZE>,
+}
+
+pub struct Log {
+    queue: Channel<ThreadModeRawMutex, Record, QUEUE_SIZE>,
+    level: AtomicU8,
+    dropped: AtomicU32,
+}
+
+impl Log {
+    pub const fn new() -> Self {
+        Log {
+            queue: Channel::new(),
+            level: AtomicU8::new(Level::Info as u8),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        Level::from_ordinal(self.level.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+
+    pub fn set_level(&self, level: Level) {
+        self.level.store(level as u8, Ordering::Relaxed);
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level()
+    }
+
+    // The number of records that were dropped because the queue was full,
+    // since startup.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Use the macros instead, they read better.
+    pub fn log(&self, level: Level, tag: &'static str, arguments: fmt::Arguments) {
+        if !self.enabled(level) {
+            return;
+        }
+        let mut message: String<MESSAGE_SIZE> = String::new();
+        let _ = Truncate(&mut message).write_fmt(arguments);
+        let record: Record = Record {
+            timestamp: Instant::now(),
+            level: level,
+            tag: tag,
+            message: message,
+        };
+        if self.queue.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub async fn next(&self) -> Record {
+        self.queue.receive().await
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A message that does not fit is cut short, which still beats no message at
+// all.
+struct Truncate<'a>(&'a mut String<MESSAGE_SIZE>);
+
+impl Write for Truncate<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for character in text.chars() {
+            if self.0.push(character).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "defmt"))]
+#[macro_export]
+macro_rules! log_at {
+    ($level:ident, $defmt:ident, $log:expr, $tag:expr, $($arguments:tt)*) => {
+        $log.log($crate::log::Level::$level, $tag, format_args!($($arguments)*))
+    };
+}
+
+// The arguments must implement `defmt::Format` as well as `Display` or
+// `Debug`, and the format string must make sense to both.
+#[doc(hidden)]
+#[cfg(feature = "defmt")]
+#[macro_export]
+macro_rules! log_at {
+    ($level:ident, $defmt:ident, $log:expr, $tag:expr, $($arguments:tt)*) => {
+        if $log.enabled($crate::log::Level::$level) {
+            ::defmt::$defmt!($($arguments)*)
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($log:expr, $tag:expr, $($arguments:tt)*) => {
+        $crate::log_at!(Error, error, $log, $tag, $($arguments)*)
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($log:expr, $tag:expr, $($arguments:tt)*) => {
+        $crate::log_at!(Warn, warn, $log, $tag, $($arguments)*)
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($log:expr, $tag:expr, $($arguments:tt)*) => {
+        $crate::log_at!(Info, info, $log, $tag, $($arguments)*)
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($log:expr, $tag:expr, $($arguments:tt)*) => {
+        $crate::log_at!(Debug, debug, $log, $tag, $($arguments)*)
+    };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($log:expr, $tag:expr, $($arguments:tt)*) => {
+        $crate::log_at!(Trace, trace, $log, $tag, $($arguments)*)
+    };
+}
+
+// Stamps the records with the time since startup, like the text lines.
+#[cfg(feature = "defmt")]
+defmt::timestamp!("{=u64:ms}", Instant::now().as_millis());
+
+// A record as a line of text, such as
+// `    12.345 INFO  mode reader     signalling Normal.`
+pub fn format(record: &Record) -> String<{ MESSAGE_SIZE + 48 }> {
+    let mut line: String<{ MESSAGE_SIZE + 48 }> = String::new();
+    let milliseconds: u64 = record.timestamp.as_millis();
+    let _ = write!(
+        line,
+        "{:>6}.{:03} {:<5} {:<TAG_WIDTH$} {}\r\n",
+        milliseconds / 1000,
+        milliseconds % 1000,
+        record.level.name(),
+        record.tag,
+        record.message
+    );
+    line
+}
+
+// Drains the queue onto the serial port. This is the only task that waits for
+// the serial port on behalf of the log.
+pub async fn log_writer(log: &'static Log, serial: &'static Serial) -> ! {
+    let mut reported_dropped: u32 = 0;
+    loop {
+        let record: Record = log.next().await;
+        print(serial, &format(&record)).await;
+
+        let dropped: u32 = log.dropped();
+        if dropped != reported_dropped {
+            let mut line: String<64> = String::new();
+            let _ = write!(
+                line,
+                "{} log records dropped.\r\n",
+                dropped.wrapping_sub(reported_dropped)
+            );
+            print(serial, &line).await;
+            reported_dropped = dropped;
+        }
+    }
+}
@@ -0,0 +1,40 @@
+/*
+ * An officer on the spot can take the crossing over, as in the manual mode of
+ * a real cabinet. The crossing then runs the turns of normal mode, but an
+ * approach only gives up its green when the officer presses the button on the
+ * police header, or when the host sends the crossing on. The amber and the
+ * clearance after the green still take their time from the settings, however
+ * fast the officer presses. See `manual_mode()` in `modes.rs`.
+ *
+ * The rotary switch has no position for the manual mode, so the host asks for
+ * it. Turning the switch takes the crossing back from the officer.
+ */
+
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+use crate::ThreadModeRawMutex;
+use crate::info;
+use crate::inputs::INPUT_SETTLE_MS;
+use crate::log::Log;
+
+// The button pulls its input low while it is pressed. One press sends the
+// crossing on once, however long the officer holds it.
+pub async fn advance_input<I: Wait + InputPin>(
+    log: &'static Log,
+    input: &mut I,
+    advance: &'static Signal<ThreadModeRawMutex, ()>,
+) -> ! {
+    loop {
+        let _ = input.wait_for_falling_edge().await;
+        Timer::after_millis(INPUT_SETTLE_MS).await;
+        if matches!(input.is_low(), Ok(true)) {
+            info!(log, "manual", "advance.");
+            advance.signal(());
+            let _ = input.wait_for_high().await;
+            Timer::after_millis(INPUT_SETTLE_MS).await;
+        }
+    }
+}
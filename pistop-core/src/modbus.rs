@@ -0,0 +1,463 @@
+/*
+ * A Modbus RTU slave, so that the crossing can be hooked up to a PLC. It takes
+ * over the serial port, which means there is no console and no host protocol
+ * in builds that use it.
+ *
+ * Frames are separated by silence on the line. A frame ends when the line is
+ * silent for 3.5 character times, and a frame with a silence of more than 1.5
+ * character times in the middle is broken and dropped. We only answer after
+ * the silence that ends the request, so the master always gets its gap.
+ *
+ * The register map, with addresses counting from zero:
+ *
+ *   coils
+ *     0-1    pedestrian request of head A and B. Reads whether a request is
+ *            waiting to be served. Writing a 1 presses the button, writing a
+ *            0 does nothing, since a promise cannot be taken back.
+ *
+ *   holding registers
+ *     0      system mode, by `SystemMode` ordinal. Writing requests the mode,
+ *            as if the rotary switch was turned, reading gives the mode that
+ *            is running. The railway mode cannot be written.
+ *     1-4    attention, go, yield and clear time of normal mode, in ms
+ *
+ *   input registers
+ *     0      system mode that is running
+ *     1      1 while locked out to change modes
+ *     2-3    phase of head A and B, by `Phase` ordinal
+ *     4-5    outputs that are on, one bit per `Pins` ordinal
+ *     6-7    lamps that are out, one bit per `Pins` ordinal
+ *     8      faults: bit 0 battery low, bit 1 battery critical, bit 2 lamp out
+ *     9      battery voltage in mV
+ *     10     frames received that were addressed to anyone
+ *     11     frames received that were broken or failed their CRC
+ *     12     exception responses sent
+ *     13-14  uptime in seconds
+ *
+ * Values that take two registers have the high word first.
+ */
+
+use crc::{CRC_16_MODBUS, Crc};
+use embassy_sync::mutex::MutexGuard;
+use embassy_time::{Duration, Instant, with_timeout};
+use enum_ordinalize::Ordinalize;
+use heapless::Vec;
+use pistop_protocol::{BatteryState, ConfigKey, Pins, Status, SystemMode};
+
+use crate::ThreadModeRawMutex;
+use crate::host_link::HostLink;
+use crate::serial::Serial;
+use crate::settings::Settings;
+
+// The largest frame the spec allows, including address and CRC.
+pub const MAX_ADU_SIZE: usize = 256;
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
+
+const READ_COILS: u8 = 0x01;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+const BROADCAST_ADDRESS: u8 = 0;
+
+const COIL_COUNT: u16 = 2;
+const HOLDING_REGISTER_COUNT: u16 = 5;
+const INPUT_REGISTER_COUNT: u16 = 15;
+
+const PROMISE_PINS: [Pins; 2] = [Pins::APromise, Pins::BPromise];
+const TIMING_KEYS: [ConfigKey; 4] = [
+    ConfigKey::NormalAttentionMs,
+    ConfigKey::NormalGoMs,
+    ConfigKey::NormalYieldMs,
+    ConfigKey::NormalClearMs,
+];
+
+pub fn crc16(bytes: &[u8]) -> u16 {
+    CRC16.checksum(bytes)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameTiming {
+    // Silence between two characters of a frame that breaks the frame.
+    pub char_gap: Duration,
+    // Silence that ends a frame.
+    pub frame_gap: Duration,
+}
+
+impl FrameTiming {
+    // The spec counts 11 bits to a character. Above 19200 baud the gaps would
+    // get too short to time reliably, so the spec fixes them at 750us and
+    // 1750us.
+    pub const fn for_baud_rate(baud_rate: u32) -> Self {
+        if baud_rate > 19_200 {
+            FrameTiming {
+                char_gap: Duration::from_micros(750),
+                frame_gap: Duration::from_micros(1_750),
+            }
+        } else {
+            let character_us: u64 = 11_000_000 / baud_rate as u64;
+            FrameTiming {
+                char_gap: Duration::from_micros(character_us * 3 / 2),
+                frame_gap: Duration::from_micros(character_us * 7 / 2),
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    // Too long a silence in the middle, or too many bytes.
+    Broken,
+    // Too short to hold an address, a function and a CRC.
+    TooShort,
+    Crc,
+}
+
+// Collects the bytes of a frame, keeping an eye on the gaps between them.
+pub struct FrameAssembler {
+    timing: FrameTiming,
+    buffer: Vec<u8, MAX_ADU_SIZE>,
+    last_byte_at: Instant,
+    broken: bool,
+}
+
+impl FrameAssembler {
+    pub const fn new(timing: FrameTiming) -> Self {
+        FrameAssembler {
+            timing: timing,
+            buffer: Vec::new(),
+            last_byte_at: Instant::MIN,
+            broken: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && !self.broken
+    }
+
+    // Take in bytes that arrived together at the given moment.
+    pub fn push(&mut self, bytes: &[u8], at: Instant) {
+        if !self.is_empty() && at - self.last_byte_at > self.timing.char_gap {
+            self.broken = true;
+        }
+        for byte in bytes {
+            if self.buffer.push(*byte).is_err() {
+                self.broken = true;
+            }
+        }
+        self.last_byte_at = at;
+    }
+
+    // Called once the line has been silent long enough to end the frame.
+    // Returns the frame without its CRC and starts on the next one.
+    pub fn finish(&mut self) -> Result<Vec<u8, MAX_ADU_SIZE>, FrameError> {
+        let mut frame: Vec<u8, MAX_ADU_SIZE> = core::mem::take(&mut self.buffer);
+        if core::mem::take(&mut self.broken) {
+            return Err(FrameError::Broken);
+        }
+        if frame.len() < 4 {
+            return Err(FrameError::TooShort);
+        }
+        // The CRC goes over the wire low byte first, unlike everything else.
+        let length: usize = frame.len() - 2;
+        let crc: u16 = u16::from_le_bytes([frame[length], frame[length + 1]]);
+        if crc16(&frame[..length]) != crc {
+            return Err(FrameError::Crc);
+        }
+        frame.truncate(length);
+        Ok(frame)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+}
+
+// The diagnostic counters of the spec. They wrap around, like the spec says.
+#[derive(Copy, Clone, Default)]
+struct Counters {
+    bus_messages: u16,
+    communication_errors: u16,
+    exceptions: u16,
+}
+
+struct Slave {
+    host_link: &'static HostLink,
+    counters: Counters,
+}
+
+impl Slave {
+    // Returns the response to send, if any.
+    async fn handle(
+        &mut self,
+        frame: Result<Vec<u8, MAX_ADU_SIZE>, FrameError>,
+    ) -> Option<Vec<u8, MAX_ADU_SIZE>> {
+        let Ok(frame) = frame else {
+            self.counters.communication_errors = self.counters.communication_errors.wrapping_add(1);
+            return None;
+        };
+        self.counters.bus_messages = self.counters.bus_messages.wrapping_add(1);
+
+        let own_address: u8 = self.host_link.settings.lock().await.modbus_address;
+        let address: u8 = frame[0];
+        if address != own_address && address != BROADCAST_ADDRESS {
+            return None;
+        }
+
+        let pdu: &[u8] = &frame[1..];
+        let mut response: Vec<u8, MAX_ADU_SIZE> = Vec::new();
+        let _ = response.push(own_address);
+        if let Err(exception) = self.execute(pdu, &mut response).await {
+            self.counters.exceptions = self.counters.exceptions.wrapping_add(1);
+            response.truncate(1);
+            let _ = response.push(pdu[0] | 0x80);
+            let _ = response.push(exception as u8);
+        }
+
+        // Broadcasts are carried out, but never answered.
+        if address == BROADCAST_ADDRESS {
+            return None;
+        }
+        let crc: u16 = crc16(&response);
+        let _ = response.extend_from_slice(&crc.to_le_bytes());
+        Some(response)
+    }
+
+    // The checks go in the order of the spec: function, quantity, address
+    // and then the values themselves.
+    async fn execute(
+        &mut self,
+        pdu: &[u8],
+        response: &mut Vec<u8, MAX_ADU_SIZE>,
+    ) -> Result<(), Exception> {
+        let function: u8 = pdu[0];
+        let data: &[u8] = &pdu[1..];
+        let _ = response.push(function);
+        match function {
+            READ_COILS => {
+                let (start, quantity) = read_request(data, 2_000)?;
+                check_range(start, quantity, COIL_COUNT)?;
+                let status: Status = self.host_link.status().await;
+                let _ = response.push(quantity.div_ceil(8) as u8);
+                let mut bits: u8 = 0;
+                for i in 0..quantity {
+                    let pin: Pins = PROMISE_PINS[(start + i) as usize];
+                    bits |= (status.is_lit(pin) as u8) << (i % 8);
+                    if i % 8 == 7 || i == quantity - 1 {
+                        let _ = response.push(bits);
+                        bits = 0;
+                    }
+                }
+            }
+            READ_HOLDING_REGISTERS => {
+                let (start, quantity) = read_request(data, 125)?;
+                check_range(start, quantity, HOLDING_REGISTER_COUNT)?;
+                let settings: Settings = *self.host_link.settings.lock().await;
+                let _ = response.push((quantity * 2) as u8);
+                for address in start..start + quantity {
+                    push_u16(response, self.holding_register(address, &settings));
+                }
+            }
+            READ_INPUT_REGISTERS => {
+                let (start, quantity) = read_request(data, 125)?;
+                check_range(start, quantity, INPUT_REGISTER_COUNT)?;
+                let status: Status = self.host_link.status().await;
+                let _ = response.push((quantity * 2) as u8);
+                for address in start..start + quantity {
+                    push_u16(response, self.input_register(address, &status));
+                }
+            }
+            WRITE_SINGLE_COIL => {
+                let (address, value) = read_pair(data)?;
+                let on: bool = match value {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(Exception::IllegalDataValue),
+                };
+                check_range(address, 1, COIL_COUNT)?;
+                self.write_coils(address, [on].into_iter()).await;
+                let _ = response.extend_from_slice(&data[..4]);
+            }
+            WRITE_SINGLE_REGISTER => {
+                let (address, value) = read_pair(data)?;
+                check_range(address, 1, HOLDING_REGISTER_COUNT)?;
+                self.write_registers(address, &[value]).await?;
+                let _ = response.extend_from_slice(&data[..4]);
+            }
+            WRITE_MULTIPLE_COILS => {
+                let (start, quantity) = read_request(data, 0x07B0)?;
+                let values: &[u8] = write_values(data, quantity.div_ceil(8))?;
+                check_range(start, quantity, COIL_COUNT)?;
+                let coils = (0..quantity).map(|i| values[i as usize / 8] & (1 << (i % 8)) != 0);
+                self.write_coils(start, coils).await;
+                let _ = response.extend_from_slice(&data[..4]);
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                let (start, quantity) = read_request(data, 0x007B)?;
+                let bytes: &[u8] = write_values(data, quantity * 2)?;
+                check_range(start, quantity, HOLDING_REGISTER_COUNT)?;
+                let values: Vec<u16, 0x007B> = bytes
+                    .chunks(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect();
+                self.write_registers(start, &values).await?;
+                let _ = response.extend_from_slice(&data[..4]);
+            }
+            _ => return Err(Exception::IllegalFunction),
+        }
+        Ok(())
+    }
+
+    fn holding_register(&self, address: u16, settings: &Settings) -> u16 {
+        match address {
+            0 => self.host_link.events.latest().mode.ordinal() as u16,
+            _ => settings
+                .get(TIMING_KEYS[address as usize - 1])
+                .min(u16::MAX as u32) as u16,
+        }
+    }
+
+    fn input_register(&self, address: u16, status: &Status) -> u16 {
+        let uptime_s: u32 = (status.uptime_ms / 1000) as u32;
+        match address {
+            0 => status.mode.ordinal() as u16,
+            1 => status.locked_out as u16,
+            2 => status.phases[0].ordinal() as u16,
+            3 => status.phases[1].ordinal() as u16,
+            4 => (status.lit >> 16) as u16,
+            5 => status.lit as u16,
+            6 => (status.lamps_out >> 16) as u16,
+            7 => status.lamps_out as u16,
+            8 => {
+                (status.battery_state == BatteryState::Low) as u16
+                    | ((status.battery_state == BatteryState::Critical) as u16) << 1
+                    | ((status.lamps_out != 0) as u16) << 2
+            }
+            9 => status.battery_millivolts.min(u16::MAX as u32) as u16,
+            10 => self.counters.bus_messages,
+            11 => self.counters.communication_errors,
+            12 => self.counters.exceptions,
+            13 => (uptime_s >> 16) as u16,
+            _ => uptime_s as u16,
+        }
+    }
+
+    async fn write_coils(&self, start: u16, coils: impl Iterator<Item = bool>) {
+        for (address, on) in (start..).zip(coils) {
+            if on {
+                self.host_link.pedestrian_lights[address as usize]
+                    .make_promise()
+                    .await;
+            }
+        }
+    }
+
+    // All the values are checked before any of them is written, so that a
+    // refused write leaves everything as it was.
+    async fn write_registers(&self, start: u16, values: &[u16]) -> Result<(), Exception> {
+        let mut settings_guard: MutexGuard<'_, ThreadModeRawMutex, Settings> =
+            self.host_link.settings.lock().await;
+        let mut settings: Settings = *settings_guard;
+        let mut mode: Option<SystemMode> = None;
+        for (address, value) in (start..).zip(values.iter().copied()) {
+            match address {
+                0 => {
+                    mode = Some(
+                        u8::try_from(value)
+                            .ok()
+                            .and_then(SystemMode::from_ordinal)
+                            .filter(|mode| *mode != SystemMode::Railway)
+                            .ok_or(Exception::IllegalDataValue)?,
+                    )
+                }
+                _ => settings
+                    .set(TIMING_KEYS[address as usize - 1], value as u32)
+                    .map_err(|_| Exception::IllegalDataValue)?,
+            }
+        }
+        *settings_guard = settings;
+        if let Some(mode) = mode {
+            self.host_link.system_mode_signal.signal(mode);
+        }
+        Ok(())
+    }
+}
+
+// The address and value that every request we support starts with.
+fn read_pair(data: &[u8]) -> Result<(u16, u16), Exception> {
+    if data.len() < 4 {
+        return Err(Exception::IllegalDataValue);
+    }
+    Ok((
+        u16::from_be_bytes([data[0], data[1]]),
+        u16::from_be_bytes([data[2], data[3]]),
+    ))
+}
+
+// The start address and quantity of the requests that cover a range.
+fn read_request(data: &[u8], max_quantity: u16) -> Result<(u16, u16), Exception> {
+    let (start, quantity) = read_pair(data)?;
+    if quantity == 0 || quantity > max_quantity {
+        return Err(Exception::IllegalDataValue);
+    }
+    Ok((start, quantity))
+}
+
+// The values of a write request, after the byte count, which has to match the
+// quantity.
+fn write_values(data: &[u8], expected_bytes: u16) -> Result<&[u8], Exception> {
+    match data.get(4) {
+        Some(count) if *count as u16 == expected_bytes && data.len() == 5 + *count as usize => {
+            Ok(&data[5..])
+        }
+        _ => Err(Exception::IllegalDataValue),
+    }
+}
+
+fn check_range(start: u16, quantity: u16, count: u16) -> Result<(), Exception> {
+    if start as u32 + quantity as u32 > count as u32 {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+fn push_u16(response: &mut Vec<u8, MAX_ADU_SIZE>, value: u16) {
+    let _ = response.extend_from_slice(&value.to_be_bytes());
+}
+
+pub async fn modbus_slave(host_link: &'static HostLink, timing: FrameTiming) -> ! {
+    let serial: &'static Serial = host_link.serial;
+    // Console text would only garble the frames.
+    serial.set_binary_mode(true);
+
+    let mut slave: Slave = Slave {
+        host_link: host_link,
+        counters: Counters::default(),
+    };
+    let mut assembler: FrameAssembler = FrameAssembler::new(timing);
+    let mut buffer: [u8; 32] = [0; 32];
+    loop {
+        let count: usize = if assembler.is_empty() {
+            serial.receive(&mut buffer).await
+        } else {
+            match with_timeout(timing.frame_gap, serial.receive(&mut buffer)).await {
+                Ok(count) => count,
+                Err(_) => {
+                    if let Some(response) = slave.handle(assembler.finish()).await {
+                        serial.write(&response).await;
+                    }
+                    continue;
+                }
+            }
+        };
+        assembler.push(&buffer[..count], Instant::now());
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * The bookkeeping of the system mode handler: the mode that the user asked
+ * for, the mode that runs and the permits that the handler has handed out.
+ * It is kept apart from `system_mode()` in `modes.rs`, which does the waiting,
+ * so that the host can explore every order in which the handler, the mode
+ * tasks and the user take their steps. See `tests/mode_switch.rs` in the
+ * simulator for that.
+ *
+ * The handler goes round in a loop. With every permit in hand, it enters the
+ * mode that was asked for and hands out the permit of that mode. When another
+ * mode is asked for, it locks out and takes the handed out permits back one by
+ * one, which waits for the mode tasks to finish their cycle. Only then does it
+ * enter the next mode.
+ *
+ * An emergency vehicle takes over the crossing in the same way, see
+ * `preemption.rs`. The mode that it needs runs instead of the mode that was
+ * asked for, until its call goes away. So does a train, for which the railway
+ * mode runs. Only the track circuit gets to run that mode, so a request for
+ * it is ignored.
+ */
+
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::SystemMode;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum PermitError {
+    // The permit was handed out already, where it should have been in hand.
+    HandedOut(SystemMode),
+    // The permit was taken back, but it was never handed out.
+    NotHandedOut(SystemMode),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ModeSwitch {
+    requested: SystemMode,
+    // The priority mode of an emergency vehicle or the railway mode, none
+    // without either.
+    preempted: Option<SystemMode>,
+    // The mode that runs, none while we are locked out.
+    running: Option<SystemMode>,
+    handed_out: [bool; SystemMode::VARIANT_COUNT],
+}
+
+impl ModeSwitch {
+    // As we start, we hold all the permits and we are locked out. This
+    // effectively blocks the mode tasks from running, as they will be waiting
+    // for a permit to become available.
+    pub const fn new(start_mode: SystemMode) -> Self {
+        ModeSwitch {
+            requested: start_mode,
+            preempted: None,
+            running: None,
+            handed_out: [false; SystemMode::VARIANT_COUNT],
+        }
+    }
+
+    pub fn request(&mut self, mode: SystemMode) {
+        if mode != SystemMode::Railway {
+            self.requested = mode;
+        }
+    }
+
+    pub fn requested(&self) -> SystemMode {
+        self.requested
+    }
+
+    pub fn preempt(&mut self, mode: Option<SystemMode>) {
+        self.preempted = mode;
+    }
+
+    pub fn running(&self) -> Option<SystemMode> {
+        self.running
+    }
+
+    // The mode that the user asked for is not necessarily the mode we run. When
+    // there is a fault, such as a battery that is about to give out, we force
+    // the crossing into flashing mode, regardless of what the rotary switch
+    // says. Short of a fault, a train or an emergency vehicle gets its way.
+    pub fn effective_mode(&self, force_flash: bool) -> SystemMode {
+        if force_flash {
+            SystemMode::Flash
+        } else {
+            self.preempted.unwrap_or(self.requested)
+        }
+    }
+
+    // Enter the mode to run and hand out its permit. This takes every permit
+    // to be in hand, or two modes would run at once.
+    pub fn enter(&mut self, force_flash: bool) -> Result<SystemMode, PermitError> {
+        if let Some(mode) = self.next_to_collect() {
+            return Err(PermitError::HandedOut(mode));
+        }
+        let mode: SystemMode = self.effective_mode(force_flash);
+        self.handed_out[mode.ordinal() as usize] = true;
+        self.running = Some(mode);
+        Ok(mode)
+    }
+
+    // Whether the running mode has to make way for another one.
+    pub fn must_leave(&self, force_flash: bool) -> bool {
+        self.running != Some(self.effective_mode(force_flash))
+    }
+
+    pub fn lock_out(&mut self) {
+        self.running = None;
+    }
+
+    // The permit to take back next, none when every permit is in hand.
+    pub fn next_to_collect(&self) -> Option<SystemMode> {
+        SystemMode::VARIANTS
+            .iter()
+            .copied()
+            .find(|mode| self.handed_out[mode.ordinal() as usize])
+    }
+
+    pub fn collected(&mut self, mode: SystemMode) -> Result<(), PermitError> {
+        let handed_out: &mut bool = &mut self.handed_out[mode.ordinal() as usize];
+        if !*handed_out {
+            return Err(PermitError::NotHandedOut(mode));
+        }
+        *handed_out = false;
+        Ok(())
+    }
+
+    // The permits that are handed out, a bit per `SystemMode` ordinal.
+    pub fn handed_out(&self) -> u16 {
+        SystemMode::VARIANTS
+            .iter()
+            .filter(|mode| self.handed_out[mode.ordinal() as usize])
+            .fold(0, |bits, mode| bits | (1 << mode.ordinal()))
+    }
+}
@@ -0,0 +1,752 @@
+/*
+ * The modes of the crossing and the switching between them. Every mode has
+ * one or more tasks that run the heads through the cycle of that mode. Which
+ * mode gets to run is decided by the system mode handler, which hands out a
+ * permit on the semaphore of the mode that should run, see `system_mode`.
+ * The bookkeeping of the handler lives in `mode_switch.rs`.
+ */
+
+#[cfg(feature = "permit-trace")]
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_sync::{mutex::Mutex, semaphore::FairSemaphore, semaphore::Semaphore, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, EventKind, SystemMode};
+
+use crate::ThreadModeRawMutex;
+use crate::event_log::EventLog;
+use crate::faults::Faults;
+use crate::green_wave::GreenWave;
+use crate::lights::{PedestrianLights, TrafficLights};
+use crate::log::Log;
+use crate::mode_switch::ModeSwitch;
+use crate::preemption::Preemption;
+use crate::settings::Settings;
+use crate::statistics::Statistics;
+use crate::timed_output_masker::{Pins, TimedOutputMasker};
+use crate::{debug, info};
+
+pub type CrossingSemaphore = FairSemaphore<ThreadModeRawMutex, 8>;
+
+pub async fn normal_mode(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: &'static TrafficLights,
+    pedestrian_lights: &'static PedestrianLights,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    statistics: &'static Statistics,
+    green_wave: Option<&'static GreenWave>,
+    preemption: &'static Preemption,
+) -> ! {
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for normal run mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        // Changes to the timings take effect from the next cycle.
+        let settings: Settings = *settings.lock().await;
+
+        // A turn that has yet to start makes way for an emergency vehicle.
+        // The system mode handler is about to collect the permit that we hand
+        // back, so we only ask again once the call or the train is over. After
+        // that, the task of the exit approach is already waiting for the
+        // permit and gets it before we do.
+        if !preemption.may_take_turn(traffic_lights.approach(), settings.preemption_exit) {
+            drop(_permit);
+            preemption.wait_for_end(traffic_lights.approach()).await;
+            continue;
+        }
+
+        // Approach A keeps to the green wave, approach B simply takes its
+        // turn after A. An emergency vehicle does not wait for the wave.
+        if let Some(green_wave) = green_wave
+            && let Either::Second(_) = select(
+                green_wave.wait_for_turn(&settings),
+                preemption.wait_to_make_way(),
+            )
+            .await
+        {
+            continue;
+        }
+
+        // Attention Phase
+        traffic_lights.go_attention().await;
+        pedestrian_lights.go_attention().await;
+
+        // Nobody got going yet when an emergency vehicle calls now, so we go
+        // straight back to red.
+        if !preemption.cut_short(settings.normal_attention_ms).await {
+            // Go Phase, with pedestrian light handling. An emergency vehicle
+            // cuts it short, but never the phases that clear the crossing.
+            traffic_lights.go_go().await;
+            pedestrian_lights.go_go().await;
+            preemption.cut_short(settings.normal_go_ms).await;
+
+            // Yield Phase
+            traffic_lights.go_yield().await;
+            pedestrian_lights.go_yield().await;
+            Timer::after_millis(settings.normal_yield_ms).await;
+        }
+
+        // Clear Crossing Phase
+        traffic_lights.go_clear().await;
+        pedestrian_lights.go_clear().await;
+        Timer::after_millis(settings.normal_clear_ms).await;
+        statistics.cycle_completed(SystemMode::Normal);
+
+        // _permit is released here...
+    }
+}
+
+pub async fn flash_mode(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights_a: &'static TrafficLights,
+    traffic_lights_b: &'static TrafficLights,
+    pedestrian_lights_a: &'static PedestrianLights,
+    pedestrian_lights_b: &'static PedestrianLights,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+) -> ! {
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for flashing run mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        // Flashing Phase
+        traffic_lights_a.go_flash().await;
+        traffic_lights_b.go_flash().await;
+        pedestrian_lights_a.go_flash().await;
+        pedestrian_lights_b.go_flash().await;
+
+        while !lockout.load(Ordering::Relaxed) {
+            Timer::after_millis(2_000).await;
+        }
+
+        leave_flash(
+            [traffic_lights_a, traffic_lights_b],
+            [pedestrian_lights_a, pedestrian_lights_b],
+        )
+        .await;
+        statistics.cycle_completed(SystemMode::Flash);
+
+        // _permit is released here...
+    }
+}
+
+// From flashing amber to all red, by way of a steady amber.
+async fn leave_flash(
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+) {
+    // Yield Phase
+    for traffic_lights in traffic_lights {
+        traffic_lights.go_yield_flash().await;
+    }
+    for pedestrian_lights in pedestrian_lights {
+        pedestrian_lights.go_yield_flash().await;
+    }
+    Timer::after_millis(3_000).await;
+
+    // Clear Crossing Phase
+    for traffic_lights in traffic_lights {
+        traffic_lights.go_clear().await;
+    }
+    for pedestrian_lights in pedestrian_lights {
+        pedestrian_lights.go_clear().await;
+    }
+    Timer::after_millis(4_000).await;
+}
+
+// The crossing comes back to life after it was closed. Drivers get to see the
+// heads flash for a while before the crossing clears as it does after flash,
+// so that the first green does not take anyone by surprise.
+async fn start_up(
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+) {
+    // Flashing Phase
+    for traffic_lights in traffic_lights {
+        traffic_lights.go_flash().await;
+    }
+    for pedestrian_lights in pedestrian_lights {
+        pedestrian_lights.go_flash().await;
+    }
+    Timer::after_millis(5_000).await;
+
+    leave_flash(traffic_lights, pedestrian_lights).await;
+}
+
+pub async fn priority_mode(
+    mode: SystemMode,
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: &'static TrafficLights,
+    pedestrian_lights: &'static PedestrianLights,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    preemption: &'static Preemption,
+) -> ! {
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for normal run mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        // no pedestrians while emergency services pass
+        pedestrian_lights.go_clear().await;
+
+        // Attention Phase
+        traffic_lights.go_attention().await;
+        Timer::after_millis(1_500).await;
+
+        // Go Phase
+        traffic_lights.go_go().await;
+        let green_since: Instant = Instant::now();
+        Timer::after_millis(4_000).await;
+
+        // crude...
+        while !lockout.load(Ordering::Relaxed) {
+            // An emergency vehicle gets the green for its dwell at least.
+            preemption
+                .hold_green(traffic_lights.approach(), green_since)
+                .await;
+            Timer::after_millis(500).await;
+        }
+
+        // Yield Phase
+        traffic_lights.go_yield().await;
+        Timer::after_millis(3_000).await;
+
+        // Clear Crossring Phase
+        traffic_lights.go_clear().await;
+        Timer::after_millis(2_000).await;
+        statistics.cycle_completed(mode);
+
+        // _permit is released here...
+    }
+}
+
+pub async fn railway_mode(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+) -> ! {
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for railway mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        let settings: Settings = *settings.lock().await;
+        let tracks: usize = settings.railway_approach.ordinal() as usize;
+        let (track_lights, other_lights) = (traffic_lights[tracks], traffic_lights[1 - tracks]);
+        let other_pedestrians: &'static PedestrianLights = pedestrian_lights[1 - tracks];
+
+        // Track Clearance Phase, so that nobody is left standing on the
+        // tracks. Nobody walks, whoever crosses would hold up the vehicles.
+        pedestrian_lights[tracks].go_clear().await;
+        other_pedestrians.go_clear().await;
+        track_lights.go_attention().await;
+        Timer::after_millis(settings.normal_attention_ms).await;
+        track_lights.go_go().await;
+        Timer::after_millis(settings.track_clearance_ms).await;
+        track_lights.go_yield().await;
+        Timer::after_millis(settings.normal_yield_ms).await;
+        track_lights.go_clear().await;
+        Timer::after_millis(settings.normal_clear_ms).await;
+
+        // Hold Phase, the approach over the tracks stays red until the train
+        // has passed. The other approach does not cross the tracks, so it
+        // goes, and the pedestrians that walk along with it. A train that has
+        // passed already leaves nothing to hold.
+        if !lockout.load(Ordering::Relaxed) {
+            other_lights.go_attention().await;
+            other_pedestrians.go_attention().await;
+            Timer::after_millis(settings.normal_attention_ms).await;
+            other_lights.go_go().await;
+            other_pedestrians.go_go().await;
+
+            while !lockout.load(Ordering::Relaxed) {
+                Timer::after_millis(500).await;
+            }
+
+            other_lights.go_yield().await;
+            other_pedestrians.go_yield().await;
+            Timer::after_millis(settings.normal_yield_ms).await;
+            other_lights.go_clear().await;
+            other_pedestrians.go_clear().await;
+            Timer::after_millis(settings.normal_clear_ms).await;
+        }
+        statistics.cycle_completed(SystemMode::Railway);
+
+        // _permit is released here...
+    }
+}
+
+pub async fn all_red_mode(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    preemption: &'static Preemption,
+) -> ! {
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for all red mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        // Hold Phase, every head at red. The mode before went through its
+        // yield and clear phases on the way out, so the crossing is clear.
+        for traffic_lights in traffic_lights {
+            traffic_lights.go_clear().await;
+        }
+        for pedestrian_lights in pedestrian_lights {
+            pedestrian_lights.go_clear().await;
+        }
+
+        while !lockout.load(Ordering::Relaxed) {
+            Timer::after_millis(500).await;
+        }
+
+        // An emergency vehicle or a train cannot wait for the start-up, and
+        // the crossing is clear already.
+        if preemption.mode().is_none() {
+            start_up(traffic_lights, pedestrian_lights).await;
+        }
+        statistics.cycle_completed(SystemMode::AllRed);
+
+        // _permit is released here...
+    }
+}
+
+// How long the heads flash before they go dark, as a real controller does
+// when it is switched off.
+const DARK_ENTRY_FLASH_MS: u64 = 10_000;
+
+pub async fn dark_mode(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+) -> ! {
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for dark mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        // Flashing Phase, so that drivers are warned before the heads go out.
+        for traffic_lights in traffic_lights {
+            traffic_lights.go_flash().await;
+        }
+        for pedestrian_lights in pedestrian_lights {
+            pedestrian_lights.go_flash().await;
+        }
+        let flash_since: Instant = Instant::now();
+        while !lockout.load(Ordering::Relaxed)
+            && Instant::now() - flash_since < Duration::from_millis(DARK_ENTRY_FLASH_MS)
+        {
+            Timer::after_millis(500).await;
+        }
+
+        // Dark Phase, every lamp off. A pip on the switching mode LED shows
+        // that the controller is still running. Releasing the lockout turned
+        // the LED off, locking out takes it back.
+        if !lockout.load(Ordering::Relaxed) {
+            for traffic_lights in traffic_lights {
+                traffic_lights.go_dark().await;
+            }
+            for pedestrian_lights in pedestrian_lights {
+                pedestrian_lights.go_dark().await;
+            }
+            lights
+                .lock()
+                .await
+                .set_pin(Pins::SwitchingMode, true, false, false, true);
+
+            while !lockout.load(Ordering::Relaxed) {
+                Timer::after_millis(500).await;
+            }
+        }
+
+        // Nobody can tell whether the crossing is clear while the heads are
+        // dark, so it always comes back with a start-up.
+        start_up(traffic_lights, pedestrian_lights).await;
+        statistics.cycle_completed(SystemMode::Dark);
+
+        // _permit is released here...
+    }
+}
+
+pub async fn pelican_mode(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    detected: &'static AtomicBool,
+) -> ! {
+    // Approach B is the road and the pedestrians of A cross it, see
+    // `pelican.rs`.
+    let [no_vehicles, road] = traffic_lights;
+    let [walkers, along_the_road] = pedestrian_lights;
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for pelican mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        // Nobody drives on approach A, nor crosses it. A press of the button
+        // of B waits for the mode after.
+        no_vehicles.go_clear().await;
+        along_the_road.go_clear().await;
+        walkers.go_attention().await;
+
+        // Attention Phase, the mode before left the crossing clear.
+        let mut settings_now: Settings = *settings.lock().await;
+        road.go_attention().await;
+        Timer::after_millis(settings_now.normal_attention_ms).await;
+
+        loop {
+            // Go Phase, the road rests on green until someone asks to cross
+            // and it had its minimum green. A puffin forgets the ask once
+            // nobody waits at the kerb any more.
+            road.go_go().await;
+            let green_since: Instant = Instant::now();
+            while !lockout.load(Ordering::Relaxed)
+                && !(walkers.promise_pending()
+                    && Instant::now() - green_since
+                        >= Duration::from_millis(settings_now.pelican_min_green_ms))
+            {
+                Timer::after_millis(100).await;
+                if settings.lock().await.puffin
+                    && walkers.promise_pending()
+                    && !detected.load(Ordering::Relaxed)
+                {
+                    walkers.cancel_promise().await;
+                }
+            }
+            settings_now = *settings.lock().await;
+
+            // Yield Phase
+            road.go_yield().await;
+            Timer::after_millis(settings_now.normal_yield_ms).await;
+
+            // Clear Crossing Phase
+            road.go_clear().await;
+            Timer::after_millis(settings_now.normal_clear_ms).await;
+            if lockout.load(Ordering::Relaxed) {
+                break;
+            }
+
+            // Walk Phase
+            walkers.go_go().await;
+            Timer::after_millis(settings_now.pelican_walk_ms).await;
+
+            if settings_now.puffin {
+                // Clear Crossing Phase, instead of the flashing, so that
+                // whoever is still on the crossing gets off it.
+                walkers.go_clear().await;
+                Timer::after_millis(settings_now.pelican_flash_ms).await;
+                statistics.cycle_completed(SystemMode::Pelican);
+                if lockout.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                // Attention Phase
+                road.go_attention().await;
+                Timer::after_millis(settings_now.normal_attention_ms).await;
+            } else {
+                // Flashing Phase, the vehicles may go once the crossing is
+                // clear and nobody should start to cross.
+                road.go_flash().await;
+                walkers.go_yield().await;
+                Timer::after_millis(settings_now.pelican_flash_ms).await;
+                walkers.go_clear().await;
+                statistics.cycle_completed(SystemMode::Pelican);
+                if lockout.load(Ordering::Relaxed) {
+                    // Yield Phase, as drivers may be moving off already.
+                    road.go_yield().await;
+                    Timer::after_millis(settings_now.normal_yield_ms).await;
+                    road.go_clear().await;
+                    Timer::after_millis(settings_now.normal_clear_ms).await;
+                    break;
+                }
+            }
+        }
+
+        // _permit is released here...
+    }
+}
+
+pub async fn manual_mode(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    advance: &'static Signal<ThreadModeRawMutex, ()>,
+) -> ! {
+    loop {
+        // we use this scope to safely hold the permit from the semaphore
+        // for manual mode.
+        let _permit = semaphore.acquire(1).await.unwrap();
+
+        for (traffic_lights, pedestrian_lights) in traffic_lights.into_iter().zip(pedestrian_lights)
+        {
+            // Another mode was asked for between the turns, and the crossing
+            // is clear already.
+            if lockout.load(Ordering::Relaxed) {
+                break;
+            }
+            let settings: Settings = *settings.lock().await;
+
+            // Attention Phase
+            traffic_lights.go_attention().await;
+            pedestrian_lights.go_attention().await;
+            Timer::after_millis(settings.normal_attention_ms).await;
+
+            // Go Phase, until the officer sends the approach on. A press from
+            // before the green does not count, the officer has to see the
+            // green first.
+            traffic_lights.go_go().await;
+            pedestrian_lights.go_go().await;
+            advance.reset();
+            while !lockout.load(Ordering::Relaxed) {
+                if let Either::First(_) = select(advance.wait(), Timer::after_millis(500)).await {
+                    break;
+                }
+            }
+
+            // Yield Phase, as long as in normal mode, however fast the
+            // officer presses.
+            traffic_lights.go_yield().await;
+            pedestrian_lights.go_yield().await;
+            Timer::after_millis(settings.normal_yield_ms).await;
+
+            // Clear Crossing Phase
+            traffic_lights.go_clear().await;
+            pedestrian_lights.go_clear().await;
+            Timer::after_millis(settings.normal_clear_ms).await;
+        }
+        statistics.cycle_completed(SystemMode::Manual);
+
+        // _permit is released here...
+    }
+}
+
+pub async fn system_mode_reader<I: Wait + InputPin>(
+    log: &'static Log,
+    mode_inputs: &mut [I; 3],
+    initial_mode: SystemMode,
+    system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
+) -> ! {
+    let mut current_mode: SystemMode = initial_mode;
+    loop {
+        debug!(log, "mode reader", "awaiting user action.");
+        #[allow(unused_assignments)]
+        let mut new_mode = current_mode;
+        'await_change: loop {
+            // Rather than polling the switch, we sleep until one of its inputs
+            // changes. We still look at the switch every few seconds, in case
+            // an edge slipped by while we were reading the inputs.
+            {
+                let [input0, input1, input2] = &mut *mode_inputs;
+                select(
+                    select3(
+                        input0.wait_for_any_edge(),
+                        input1.wait_for_any_edge(),
+                        input2.wait_for_any_edge(),
+                    ),
+                    Timer::after_millis(5_000),
+                )
+                .await;
+            }
+            new_mode = read_system_mode(mode_inputs);
+            if new_mode != current_mode {
+                debug!(log, "mode reader", "breaking await user action.");
+                break 'await_change;
+            }
+        }
+
+        // So there was a change, but we don't want to signal that change just
+        // yet. The rotary switch goes through all intermediate values and needs
+        // serious debouncing before it can be read reliably. Also, the user may
+        // have overshot the mode they want, so we want to give them a second to
+        // check the setting before it becomes file. In fact, we will use a
+        // literal second.
+
+        debug!(log, "mode reader", "awaiting debounce.");
+        'await_debounce: loop {
+            Timer::after_millis(1_000).await;
+            let debounced_mode: SystemMode = read_system_mode(mode_inputs);
+            if debounced_mode == new_mode {
+                debug!(log, "mode reader", "breaking debounce.");
+                break 'await_debounce;
+            } else {
+                new_mode = debounced_mode;
+            }
+        }
+
+        // Finally, suppress signalling if there is no actual change. This
+        // reduces the chance of glitches due to quick mode switches.
+
+        if current_mode != new_mode {
+            current_mode = new_mode;
+            info!(log, "mode reader", "signalling {:?}.", current_mode);
+            system_mode_signal.signal(current_mode);
+        }
+    }
+}
+
+// Read the raw value from the system mode rotary switch. The result of this
+// value has to be debounced before it can be used reliably.
+fn read_system_mode(mode_inputs: &mut [impl InputPin; 3]) -> SystemMode {
+    let [input0, input1, input2] = mode_inputs;
+    match (
+        matches!(input0.is_low(), Ok(true)),
+        matches!(input1.is_low(), Ok(true)),
+        matches!(input2.is_low(), Ok(true)),
+    ) {
+        (false, false, false) => SystemMode::Normal,
+        (true, _, _) => SystemMode::Flash,
+        (_, true, _) => SystemMode::PriorityA,
+        (_, _, true) => SystemMode::PriorityB,
+    }
+}
+
+// What the system mode handler works with. There is a semaphore for every
+// mode, in the order of the `SystemMode` ordinals.
+pub struct SystemModeHandler {
+    pub log: &'static Log,
+    pub system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
+    pub faults: &'static Faults,
+    pub preemption: &'static Preemption,
+    pub events: &'static EventLog,
+    pub semaphores: &'static [CrossingSemaphore; SystemMode::VARIANT_COUNT],
+    pub lockout: &'static AtomicBool,
+    pub lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    pub statistics: &'static Statistics,
+    // Which permits are handed out, a bit per `SystemMode` ordinal, for the
+    // simulator to show. The controller has no use for it.
+    #[cfg(feature = "permit-trace")]
+    pub handed_out: &'static AtomicU16,
+}
+
+pub async fn system_mode(handler: &'static SystemModeHandler, start_mode: SystemMode) -> ! {
+    let SystemModeHandler {
+        log,
+        system_mode_signal,
+        faults,
+        preemption,
+        events,
+        semaphores,
+        lockout,
+        lights,
+        statistics,
+        #[cfg(feature = "permit-trace")]
+        handed_out,
+    } = *handler;
+
+    // As we start, we hold all the permits, see `ModeSwitch`.
+    let mut switch: ModeSwitch = ModeSwitch::new(start_mode);
+    loop {
+        // When we hold every single permit we can release the lockout and then
+        // release the permit associated with the current system mode.
+        debug!(log, "sem handler", "releasing lockout.");
+        set_lockout(lockout, lights, statistics, false).await;
+
+        // Collecting semaphores can take quite a bit of time and the user may
+        // have changed the value of the system mode while we were busy. Make
+        // sure that we are entering the most recently requested mode, so we
+        // don't have to quickly cycle through an older one.
+        if system_mode_signal.signaled() {
+            switch.request(system_mode_signal.wait().await);
+        }
+        switch.preempt(preemption.mode());
+        let mode: SystemMode = match switch.enter(faults.force_flash()) {
+            Ok(mode) => mode,
+            Err(error) => panic!("double free of permit: {:?}", error),
+        };
+        events.record(EventKind::ModeChanged(mode));
+
+        info!(log, "sem handler", "releasing {:?}.", mode);
+        semaphores[mode.ordinal() as usize].release(1);
+        #[cfg(feature = "permit-trace")]
+        handed_out.store(switch.handed_out(), Ordering::Relaxed);
+
+        debug!(log, "sem handler", "awaiting new mode.");
+        'await_change: loop {
+            if let Either3::First(new_mode) = select3(
+                system_mode_signal.wait(),
+                faults.wait_changed(),
+                preemption.wait_changed(),
+            )
+            .await
+            {
+                switch.request(new_mode);
+            }
+            switch.preempt(preemption.mode());
+            if switch.must_leave(faults.force_flash()) {
+                break 'await_change;
+            }
+        }
+
+        // When there is a new pending, first signal everyone that we want to go
+        // to the lockout state, clearing traffic from the crossing. We then
+        // claim all the permits so that we know all tasks are at rest.
+        //
+        // Some tasks have a simple loop. They just need a semaphore that they
+        // release every cycle. Some tasks have a second, inner loop. They need
+        // a second trigger to be able to safely break out of the inner loop.
+        //
+        // It might be tempting to just make the system status into a global
+        // variable and use that to break out of the inner loops. Unfortunately,
+        // that may leave the semaphore handler task in a deadlocked state. The
+        // steps to reach that deadlock are that the user switches to a new
+        // state, then switches back while the permits are being collected. The
+        // tasks then see that the system mode is as they expected and will not
+        // release their permits, while the semaphore handler won't accept new
+        // states until all semaphores have been collected.
+
+        info!(log, "sem handler", "locking out.");
+        switch.lock_out();
+        set_lockout(lockout, lights, statistics, true).await;
+        events.record(EventKind::LockedOut);
+
+        debug!(log, "sem handler", "collecting semaphores...");
+        while let Some(mode) = switch.next_to_collect() {
+            semaphores[mode.ordinal() as usize]
+                .acquire(1)
+                .await
+                .unwrap()
+                .disarm();
+            if let Err(error) = switch.collected(mode) {
+                panic!("collected a permit twice: {:?}", error);
+            }
+            #[cfg(feature = "permit-trace")]
+            handed_out.store(switch.handed_out(), Ordering::Relaxed);
+        }
+    }
+}
+
+// The switching mode led blinks for as long as we are locked out.
+async fn set_lockout(
+    lockout: &'static AtomicBool,
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    statistics: &'static Statistics,
+    locked_out: bool,
+) {
+    lockout.store(locked_out, Ordering::Relaxed);
+    statistics.set_locked_out(locked_out);
+    lights
+        .lock()
+        .await
+        .set_pin(Pins::SwitchingMode, locked_out, false, true, false);
+}
@@ -0,0 +1,52 @@
+/*
+ * A crossing in the middle of a block, as a pelican or a puffin. Approach B is
+ * the road, which rests on green, and the pedestrians of A cross it when they
+ * press their button. Approach A has no vehicles of its own and stays at red.
+ * See `pelican_mode()` in `modes.rs`.
+ *
+ * Once the road has had its minimum green, it goes through its amber to red
+ * and the pedestrians walk. A pelican then flashes the amber of the road and
+ * the green of the pedestrians, during which the vehicles may go if the
+ * crossing is clear and nobody should start to cross. A puffin shows the
+ * pedestrians red instead, and holds the road at red for the time of the
+ * pelican flash. The road then gets its red and amber before the green, as
+ * after any red.
+ *
+ * A puffin also has a kerbside detector, which sees whether anyone waits at
+ * the kerb. Someone who pressed the button and then walked off, or crossed in
+ * a gap in the traffic, leaves nobody at the kerb, so the puffin cancels the
+ * promise and the road keeps its green. A real puffin would also have an
+ * on-crossing detector to hold the road at red for slow walkers. The board
+ * has no input for one, so the red simply lasts long enough for anyone.
+ *
+ * The rotary switch has no position for the pelican mode, so the host or the
+ * schedule asks for it.
+ */
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+use crate::info;
+use crate::inputs::wait_for_input_change;
+use crate::log::Log;
+
+// The kerbside detector pulls its input low while it sees someone waiting at
+// the kerb.
+pub async fn kerbside_detector<I: Wait + InputPin>(
+    log: &'static Log,
+    input: &mut I,
+    detected: &'static AtomicBool,
+) -> ! {
+    loop {
+        let someone: bool = matches!(input.is_low(), Ok(true));
+        if detected.swap(someone, Ordering::Relaxed) != someone {
+            if someone {
+                info!(log, "pelican", "someone waits at the kerb.");
+            } else {
+                info!(log, "pelican", "nobody at the kerb.");
+            }
+        }
+        wait_for_input_change(core::array::from_mut(input)).await;
+    }
+}
@@ -0,0 +1,306 @@
+/*
+ * Emergency vehicles call for the green of their approach, on an input of
+ * their own for each approach or over the host link. The system mode handler
+ * then takes the crossing out of the mode that it runs and into the priority
+ * mode of that approach, just like it goes to flashing mode for a fault. Once
+ * the call goes away, the handler goes back to the mode that was asked for,
+ * which may have changed in the meantime.
+ *
+ * Unlike a turn of the rotary switch, a call does not wait for normal mode to
+ * finish its turn. Normal mode cuts its green short, but the amber, the
+ * flashing green of the pedestrians and the all-red still last as long as the
+ * timings say, so that whoever is on the crossing gets off it safely. A turn
+ * that has yet to start is skipped altogether.
+ *
+ * A call is served once its approach had green for the dwell from the
+ * settings. Until then the call holds, so that a short call from a passing
+ * vehicle still gets it through. After a preemption, the first turn of normal
+ * mode goes to the exit approach from the settings.
+ *
+ * When both approaches call, the first call is served first and the other one
+ * once it is over.
+ *
+ * A train that comes near the railway crossing makes way in the same manner,
+ * but for the railway mode, see `railway.rs`. A train goes before any
+ * emergency vehicle, whose call waits until the train has passed.
+ */
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::Mutex, mutex::Mutex as AsyncMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, EventKind, SystemMode};
+
+use crate::ThreadModeRawMutex;
+use crate::event_log::EventLog;
+use crate::info;
+use crate::inputs::wait_for_input_change;
+use crate::log::Log;
+use crate::settings::Settings;
+
+#[derive(Ordinalize, PartialEq, Eq, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Caller {
+    Input,
+    Host,
+}
+
+#[derive(Copy, Clone)]
+struct Call {
+    approach: Approach,
+    // Whether the approach had its green for the dwell.
+    served: bool,
+}
+
+pub struct Preemption {
+    // One bit per caller and approach, see `call_bit()`.
+    calls: AtomicU8,
+    // The call that the crossing serves, none without one.
+    call: Mutex<ThreadModeRawMutex, Cell<Option<Call>>>,
+    // Whether the track circuit sees a train.
+    track_occupied: AtomicBool,
+    // Set when a preemption ends, until the exit approach takes its turn.
+    exit_pending: Mutex<ThreadModeRawMutex, Cell<bool>>,
+    // One for the system mode handler, one for normal mode and one for the
+    // priority mode, which all need to know when the call changes.
+    changed: Signal<ThreadModeRawMutex, ()>,
+    calling_changed: Signal<ThreadModeRawMutex, ()>,
+    track_changed: Signal<ThreadModeRawMutex, ()>,
+    // One for the normal mode task of each approach, which waits without its
+    // permit for the call or the train to be over, see `wait_for_end()`.
+    ended: [Signal<ThreadModeRawMutex, ()>; Approach::VARIANT_COUNT],
+    events: &'static EventLog,
+    settings: &'static AsyncMutex<ThreadModeRawMutex, Settings>,
+}
+
+impl Preemption {
+    pub const fn new(
+        events: &'static EventLog,
+        settings: &'static AsyncMutex<ThreadModeRawMutex, Settings>,
+    ) -> Self {
+        Preemption {
+            calls: AtomicU8::new(0),
+            call: Mutex::new(Cell::new(None)),
+            track_occupied: AtomicBool::new(false),
+            exit_pending: Mutex::new(Cell::new(false)),
+            changed: Signal::new(),
+            calling_changed: Signal::new(),
+            track_changed: Signal::new(),
+            ended: [const { Signal::new() }; Approach::VARIANT_COUNT],
+            events: events,
+            settings: settings,
+        }
+    }
+
+    pub fn set_call(&self, caller: Caller, approach: Approach, calling: bool) {
+        let bit: u8 = call_bit(caller, approach);
+        if calling {
+            self.calls.fetch_or(bit, Ordering::Relaxed);
+        } else {
+            self.calls.fetch_and(!bit, Ordering::Relaxed);
+        }
+        self.update();
+    }
+
+    pub fn set_track_occupied(&self, occupied: bool) {
+        if self.track_occupied.swap(occupied, Ordering::Relaxed) == occupied {
+            return;
+        }
+        if !occupied && self.called().is_none() {
+            self.exit_pending
+                .lock(|exit_pending| exit_pending.set(true));
+        }
+        self.changed.signal(());
+        self.calling_changed.signal(());
+        self.track_changed.signal(());
+        self.signal_ended();
+    }
+
+    pub fn track_occupied(&self) -> bool {
+        self.track_occupied.load(Ordering::Relaxed)
+    }
+
+    // The approach that the crossing serves, none without a call.
+    pub fn called(&self) -> Option<Approach> {
+        self.call.lock(|call| call.get()).map(|call| call.approach)
+    }
+
+    // The mode that the crossing has to run for the train or the call.
+    pub fn mode(&self) -> Option<SystemMode> {
+        if self.track_occupied() {
+            return Some(SystemMode::Railway);
+        }
+        self.called().map(|approach| match approach {
+            Approach::A => SystemMode::PriorityA,
+            Approach::B => SystemMode::PriorityB,
+        })
+    }
+
+    pub async fn wait_changed(&self) {
+        self.changed.wait().await
+    }
+
+    // Returns once normal mode has to make way, for a call or a train.
+    pub async fn wait_to_make_way(&self) {
+        while self.mode().is_none() {
+            self.calling_changed.wait().await;
+        }
+    }
+
+    // Returns once there is neither a call nor a train any more.
+    pub async fn wait_for_end(&self, approach: Approach) {
+        while self.mode().is_some() {
+            self.ended[approach.ordinal() as usize].wait().await;
+        }
+    }
+
+    fn signal_ended(&self) {
+        for ended in &self.ended {
+            ended.signal(());
+        }
+    }
+
+    // Sleep through a phase of normal mode, unless it has to make way first.
+    // Returns whether it did.
+    pub async fn cut_short(&self, duration_ms: u64) -> bool {
+        matches!(
+            select(Timer::after_millis(duration_ms), self.wait_to_make_way()).await,
+            Either::Second(_)
+        )
+    }
+
+    // Whether normal mode may start a turn of the approach. Not while there
+    // is a call or a train, and after one only the exit approach.
+    pub fn may_take_turn(&self, approach: Approach, exit: Approach) -> bool {
+        if self.mode().is_some() {
+            return false;
+        }
+        self.exit_pending.lock(|exit_pending| {
+            if exit_pending.get() && approach != exit {
+                return false;
+            }
+            exit_pending.set(false);
+            true
+        })
+    }
+
+    // Keep the green of the approach for the dwell, counted from the moment
+    // that it went green, when that is what the call needs. The priority mode
+    // calls this while it holds the green.
+    pub async fn hold_green(&self, approach: Approach, green_since: Instant) {
+        let Some(call) = self.call.lock(|call| call.get()) else {
+            return;
+        };
+        if call.approach != approach || call.served {
+            return;
+        }
+        // A train does not wait for the dwell, the call is served after the
+        // train has passed.
+        let dwell_ms: u64 = self.settings.lock().await.preemption_dwell_ms;
+        if let Either::Second(_) = select(
+            Timer::at(green_since + Duration::from_millis(dwell_ms)),
+            self.wait_for_train(),
+        )
+        .await
+        {
+            return;
+        }
+        self.call.lock(|call| {
+            if call.get().is_some_and(|call| call.approach == approach) {
+                call.set(Some(Call {
+                    approach: approach,
+                    served: true,
+                }));
+            }
+        });
+        self.update();
+    }
+
+    async fn wait_for_train(&self) {
+        while !self.track_occupied() {
+            self.track_changed.wait().await;
+        }
+    }
+
+    // Decide which call to serve. A call holds until it is served and nobody
+    // calls for its approach any longer.
+    fn update(&self) {
+        let calls: u8 = self.calls.load(Ordering::Relaxed);
+        let is_calling = |approach: Approach| {
+            Caller::VARIANTS
+                .iter()
+                .any(|caller| calls & call_bit(*caller, approach) != 0)
+        };
+        let (before, after) = self.call.lock(|call| {
+            let before: Option<Call> = call.get();
+            if let Some(current) = before
+                && (!current.served || is_calling(current.approach))
+            {
+                return (before, before);
+            }
+            let after: Option<Call> = Approach::VARIANTS
+                .iter()
+                .copied()
+                .find(|approach| is_calling(*approach))
+                .map(|approach| Call {
+                    approach: approach,
+                    served: false,
+                });
+            call.set(after);
+            (before, after)
+        });
+        let before: Option<Approach> = before.map(|call| call.approach);
+        let after: Option<Approach> = after.map(|call| call.approach);
+        if before == after {
+            return;
+        }
+        if let Some(approach) = before {
+            self.events.record(EventKind::PreemptionEnded(approach));
+        }
+        if let Some(approach) = after {
+            self.events.record(EventKind::PreemptionStarted(approach));
+        } else {
+            self.exit_pending
+                .lock(|exit_pending| exit_pending.set(true));
+        }
+        self.changed.signal(());
+        self.calling_changed.signal(());
+        self.signal_ended();
+    }
+}
+
+fn call_bit(caller: Caller, approach: Approach) -> u8 {
+    1 << (caller.ordinal() * Approach::VARIANT_COUNT as u8 + approach.ordinal())
+}
+
+// The preemption inputs, one for each approach. Like the buttons, an input is
+// pulled low by whatever calls, but for as long as the call lasts.
+pub async fn preemption_inputs<I: Wait + InputPin>(
+    log: &'static Log,
+    preemption: &'static Preemption,
+    inputs: &mut [I; Approach::VARIANT_COUNT],
+) -> ! {
+    let mut calling: [bool; Approach::VARIANT_COUNT] = [false; Approach::VARIANT_COUNT];
+    loop {
+        for (approach, input) in Approach::VARIANTS.iter().zip(inputs.iter_mut()) {
+            let now_calling: bool = matches!(input.is_low(), Ok(true));
+            let was_calling: &mut bool = &mut calling[approach.ordinal() as usize];
+            if now_calling != *was_calling {
+                *was_calling = now_calling;
+                info!(
+                    log,
+                    "preemption",
+                    "input of {:?} {}.",
+                    approach,
+                    if now_calling { "calls" } else { "released" }
+                );
+                preemption.set_call(Caller::Input, *approach, now_calling);
+            }
+        }
+        wait_for_input_change(inputs).await;
+    }
+}
@@ -0,0 +1,57 @@
+/*
+ * A railway crosses one of the approaches close to the crossing. The vehicles
+ * that queue up on that approach may stand on the tracks, so when the track
+ * circuit sees a train coming, they have to get off the tracks before it
+ * arrives.
+ *
+ * The crossing makes way for the train as it does for an emergency vehicle,
+ * see `preemption.rs`, and runs the railway mode. That mode first gives the
+ * approach that crosses the tracks a green of its own to clear them, the
+ * track clearance. It then holds that approach at red until the track circuit
+ * is clear again, while the other approach and the pedestrians that walk
+ * along with it go as usual. See `railway_mode()` in `modes.rs`.
+ *
+ * Nobody else can ask for the railway mode, not the rotary switch nor the
+ * host, see `ModeSwitch`.
+ */
+
+use embassy_futures::select::{Either, select};
+use embassy_time::Timer;
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+use crate::info;
+use crate::inputs::wait_for_input_change;
+use crate::log::Log;
+use crate::preemption::Preemption;
+
+// The track circuit may drop out for a moment as the wheels pass, so it only
+// counts as clear once it stayed clear this long.
+const TRACK_CLEAR_MS: u64 = 2_000;
+
+// The track circuit pulls its input low while a train is near.
+pub async fn track_circuit<I: Wait + InputPin>(
+    log: &'static Log,
+    preemption: &'static Preemption,
+    input: &mut I,
+) -> ! {
+    loop {
+        let occupied: bool = matches!(input.is_low(), Ok(true));
+        if occupied && !preemption.track_occupied() {
+            info!(log, "railway", "track occupied.");
+            preemption.set_track_occupied(true);
+        } else if !occupied && preemption.track_occupied() {
+            if let Either::Second(_) = select(
+                input.wait_for_any_edge(),
+                Timer::after_millis(TRACK_CLEAR_MS),
+            )
+            .await
+            {
+                info!(log, "railway", "track clear.");
+                preemption.set_track_occupied(false);
+            }
+            continue;
+        }
+        wait_for_input_change(core::array::from_mut(input)).await;
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * The schedule changes modes and timings by the time of day and the day of the
+ * week, going by the wall clock. Think of flashing mode overnight, so that
+ * nobody has to turn the rotary switch at midnight, or longer greens during
+ * the rush hour.
+ *
+ * Every entry starts at a minute of the day, on some days of the week, and
+ * holds until the next entry of the same kind starts. Mode entries and timing
+ * entries take turns independently of each other. A schedule without any mode
+ * entries leaves the mode to the rotary switch, one without timing entries
+ * leaves the timings to the settings.
+ *
+ * The schedule only acts when an entry starts. A scheduled mode goes to the
+ * system mode handler the same way as a turn of the rotary switch does, so it
+ * goes through the lockout like any other mode switch. Much like a mode that
+ * is set over the host protocol, it sticks until the switch is turned or the
+ * next entry starts. Scheduled timings simply change the settings.
+ *
+ * Holidays and other days out of the ordinary are in the calendar. A day that
+ * the calendar forces a mode or timings on starts that at midnight, and the
+ * entries of that kind leave the day alone. At the next midnight, those
+ * entries go on as if the day never was. A day that the calendar has run as
+ * another day of the week takes the entries of that day.
+ *
+ * The schedule starts out as set per box in `Schedule::new()` and can be
+ * changed over the host protocol. Unlike the settings, the changes are kept in
+ * flash, see `schedule_storage.rs`.
+ */
+
+use embassy_sync::{mutex::Mutex, signal::Signal};
+use embassy_time::Timer;
+use pistop_protocol::{
+    CalendarAction, EVERY_DAY, ErrorCode, MINUTES_PER_DAY, SCHEDULE_ENTRIES, SECONDS_PER_DAY,
+    ScheduleAction, ScheduleEntry, SystemMode, TimingPlan, WallTime,
+};
+
+use crate::ThreadModeRawMutex;
+use crate::calendar::Calendar;
+use crate::log::Log;
+use crate::settings::Settings;
+use crate::wall_clock::WallClock;
+use crate::{info, warn};
+
+#[derive(Copy, Clone)]
+pub struct Schedule {
+    entries: [Option<ScheduleEntry>; SCHEDULE_ENTRIES],
+}
+
+impl Schedule {
+    pub const fn new() -> Self {
+        Schedule {
+            entries: [None; SCHEDULE_ENTRIES],
+        }
+    }
+
+    pub fn get(&self, index: u8) -> Result<Option<ScheduleEntry>, ErrorCode> {
+        self.entries
+            .get(index as usize)
+            .copied()
+            .ok_or(ErrorCode::InvalidValue)
+    }
+
+    // Entries that make no sense are refused and leave the schedule as it was.
+    pub fn set(&mut self, index: u8, entry: Option<ScheduleEntry>) -> Result<(), ErrorCode> {
+        if entry.is_some_and(|entry| !is_valid(&entry)) {
+            return Err(ErrorCode::InvalidValue);
+        }
+        *self
+            .entries
+            .get_mut(index as usize)
+            .ok_or(ErrorCode::InvalidValue)? = entry;
+        Ok(())
+    }
+
+    // The mode that the schedule asks for at the given time, with the time at
+    // which the entry that asks for it started.
+    pub fn mode_at(&self, calendar: &Calendar, at: WallTime) -> Option<(WallTime, SystemMode)> {
+        self.latest(calendar, at, |action| match action {
+            ScheduleAction::Mode(mode) => Some(mode),
+            ScheduleAction::Timings(_) => None,
+        })
+    }
+
+    pub fn timings_at(&self, calendar: &Calendar, at: WallTime) -> Option<(WallTime, TimingPlan)> {
+        self.latest(calendar, at, |action| match action {
+            ScheduleAction::Mode(_) => None,
+            ScheduleAction::Timings(timings) => Some(timings),
+        })
+    }
+
+    // The entry of a kind that started most recently, looking back a week. On
+    // a tie, the entry that comes last in the schedule wins.
+    fn latest<T>(
+        &self,
+        calendar: &Calendar,
+        at: WallTime,
+        kind: impl Fn(ScheduleAction) -> Option<T>,
+    ) -> Option<(WallTime, T)> {
+        let today: u32 = at.0 / SECONDS_PER_DAY;
+        // Today and the seven days before, since an entry that has yet to
+        // start today may have started a week ago.
+        for day in (0..=7).filter_map(|days_ago| today.checked_sub(days_ago)) {
+            let midnight: WallTime = WallTime(day * SECONDS_PER_DAY);
+            let mut weekday: u8 = midnight.weekday();
+            match calendar.action_on(day) {
+                Some(CalendarAction::Forced(action)) => {
+                    if let Some(value) = kind(action) {
+                        // A forced day runs from midnight to midnight. After
+                        // it, the entries from before it hold again, as if
+                        // the day never was.
+                        if day == today {
+                            return Some((midnight, value));
+                        }
+                        continue;
+                    }
+                }
+                Some(CalendarAction::AsWeekday(as_weekday)) => weekday = as_weekday,
+                None => {}
+            }
+            let mut latest: Option<(WallTime, T)> = None;
+            for entry in self.entries.iter().flatten() {
+                let Some(value) = kind(entry.action) else {
+                    continue;
+                };
+                let start: WallTime = WallTime(midnight.0 + entry.start_minute as u32 * 60);
+                if start <= at
+                    && entry.weekdays & (1 << weekday) != 0
+                    && latest.as_ref().is_none_or(|(latest, _)| start >= *latest)
+                {
+                    latest = Some((start, value));
+                }
+            }
+            if latest.is_some() {
+                return latest;
+            }
+        }
+        None
+    }
+}
+
+fn is_valid(entry: &ScheduleEntry) -> bool {
+    entry.weekdays != 0
+        && entry.weekdays & !EVERY_DAY == 0
+        && entry.start_minute < MINUTES_PER_DAY
+        && is_valid_action(entry.action)
+}
+
+pub(crate) fn is_valid_action(action: ScheduleAction) -> bool {
+    match action {
+        ScheduleAction::Mode(_) => true,
+        ScheduleAction::Timings(timings) => {
+            timings.attention_ms > 0
+                && timings.go_ms > 0
+                && timings.yield_ms > 0
+                && timings.clear_ms > 0
+        }
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Longer than the mode reader takes to look at the switch and debounce it.
+const SWITCH_SETTLE_SECS: u64 = 10;
+
+// Entries start on whole minutes, so that is how often we look at the
+// schedule. Until the wall clock is set, the schedule does nothing.
+pub async fn scheduler(
+    log: &'static Log,
+    wall_clock: &'static WallClock,
+    schedule: &'static Mutex<ThreadModeRawMutex, Schedule>,
+    calendar: &'static Mutex<ThreadModeRawMutex, Calendar>,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
+) -> ! {
+    // What was applied last, with when its entry started, so that each start
+    // of an entry is applied once.
+    let mut applied_mode: Option<(WallTime, SystemMode)> = None;
+    let mut applied_timings: Option<(WallTime, TimingPlan)> = None;
+    // After a restart, the mode reader reports the rotary switch within a few
+    // seconds. Let it, so that the schedule has the last word.
+    Timer::after_secs(SWITCH_SETTLE_SECS).await;
+    loop {
+        let Some(now) = wall_clock.now() else {
+            Timer::after_secs(60).await;
+            continue;
+        };
+        let current: Schedule = *schedule.lock().await;
+        let calendar: Calendar = *calendar.lock().await;
+
+        let mode: Option<(WallTime, SystemMode)> = current.mode_at(&calendar, now);
+        if let Some((_, new_mode)) = mode
+            && mode != applied_mode
+        {
+            applied_mode = mode;
+            info!(log, "scheduler", "switching to {:?}.", new_mode);
+            system_mode_signal.signal(new_mode);
+        }
+
+        let timings: Option<(WallTime, TimingPlan)> = current.timings_at(&calendar, now);
+        if let Some((_, new_timings)) = timings
+            && timings != applied_timings
+        {
+            applied_timings = timings;
+            match settings.lock().await.set_timings(new_timings) {
+                Ok(()) => info!(log, "scheduler", "changing the timings of normal mode."),
+                Err(_) => warn!(
+                    log,
+                    "scheduler", "cannot change the timings of normal mode."
+                ),
+            }
+        }
+
+        Timer::after_secs(60 - now.0 as u64 % 60).await;
+    }
+}
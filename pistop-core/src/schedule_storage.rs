@@ -0,0 +1,140 @@
+/*
+ * The schedule and the calendar are set up per box over the host protocol,
+ * which takes a while and a laptop at the kerb. So they are kept where they
+ * survive the box being switched off, or restarting after a brown-out. On the
+ * controller that is a page of flash, in the simulator it is just memory.
+ *
+ * The schedule keeper loads both at startup. When the host changes an entry,
+ * the keeper waits for the host to be done with its changes before it stores
+ * them, since writing the flash means erasing a whole page first, and the
+ * flash only takes so many of those. See `schedule_keeper`.
+ *
+ * What is stored is the length of the entries, their CRC16, and the entries
+ * encoded with `postcard`, like the messages of the host protocol. Erased
+ * flash reads as all ones, which is too long a length, so a box that never
+ * had anything stored simply keeps the schedule and the calendar that it was
+ * built with.
+ */
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::{mutex::Mutex, signal::Signal};
+use embassy_time::Timer;
+use pistop_protocol::{CALENDAR_ENTRIES, CalendarEntry, SCHEDULE_ENTRIES, ScheduleEntry, crc16};
+
+use crate::ThreadModeRawMutex;
+use crate::calendar::Calendar;
+use crate::log::Log;
+use crate::schedule::Schedule;
+use crate::{info, warn};
+
+// Room for every entry of both, with a good margin. An even number of bytes,
+// since the flash of the controller is written in half words.
+pub const STORED_SIZE: usize = 1_024;
+
+// The length and the CRC16 of the entries, which follow.
+const HEADER_SIZE: usize = 4;
+
+// How long the host has to be quiet before we store its changes. Tools write
+// a whole schedule entry by entry, which we only want to store once.
+const STORE_DELAY_SECS: u64 = 5;
+
+type Entries = (
+    [Option<ScheduleEntry>; SCHEDULE_ENTRIES],
+    [Option<CalendarEntry>; CALENDAR_ENTRIES],
+);
+
+// Where the schedule and the calendar are kept while the power is off.
+pub trait ScheduleStorage {
+    fn read(&mut self, bytes: &mut [u8; STORED_SIZE]);
+    fn write(&mut self, bytes: &[u8; STORED_SIZE]);
+}
+
+pub async fn schedule_keeper(
+    log: &'static Log,
+    storage: &mut impl ScheduleStorage,
+    schedule: &'static Mutex<ThreadModeRawMutex, Schedule>,
+    calendar: &'static Mutex<ThreadModeRawMutex, Calendar>,
+    written: &'static Signal<ThreadModeRawMutex, ()>,
+) -> ! {
+    let mut bytes: [u8; STORED_SIZE] = [0; STORED_SIZE];
+    storage.read(&mut bytes);
+    match decode(&bytes) {
+        Some(entries) => {
+            load(log, &entries, schedule, calendar).await;
+            info!(log, "schedule", "loaded the stored schedule and calendar.");
+        }
+        None => info!(
+            log,
+            "schedule", "nothing stored, keeping the built-in schedule and calendar."
+        ),
+    }
+
+    loop {
+        written.wait().await;
+        while let Either::First(()) =
+            select(written.wait(), Timer::after_secs(STORE_DELAY_SECS)).await
+        {}
+
+        let entries: Entries = {
+            // scope for the mutex guards...
+            let schedule = schedule.lock().await;
+            let calendar = calendar.lock().await;
+            (
+                core::array::from_fn(|i| schedule.get(i as u8).ok().flatten()),
+                core::array::from_fn(|i| calendar.get(i as u8).ok().flatten()),
+            )
+        };
+        match encode(&entries, &mut bytes) {
+            Some(()) => {
+                storage.write(&bytes);
+                info!(log, "schedule", "stored the schedule and calendar.");
+            }
+            None => warn!(
+                log,
+                "schedule", "cannot store the schedule and calendar, they don't fit."
+            ),
+        }
+    }
+}
+
+// Goes through `set`, like the entries from the host, so that an entry that
+// no longer makes sense to this firmware is left out.
+async fn load(
+    log: &'static Log,
+    entries: &Entries,
+    schedule: &'static Mutex<ThreadModeRawMutex, Schedule>,
+    calendar: &'static Mutex<ThreadModeRawMutex, Calendar>,
+) {
+    let mut loaded_schedule: Schedule = Schedule::new();
+    for (i, entry) in entries.0.iter().enumerate() {
+        if loaded_schedule.set(i as u8, *entry).is_err() {
+            warn!(log, "schedule", "leaving out stored schedule entry {}.", i);
+        }
+    }
+    let mut loaded_calendar: Calendar = Calendar::new();
+    for (i, entry) in entries.1.iter().enumerate() {
+        if loaded_calendar.set(i as u8, *entry).is_err() {
+            warn!(log, "schedule", "leaving out stored calendar entry {}.", i);
+        }
+    }
+    *schedule.lock().await = loaded_schedule;
+    *calendar.lock().await = loaded_calendar;
+}
+
+fn encode(entries: &Entries, bytes: &mut [u8; STORED_SIZE]) -> Option<()> {
+    let (header, body) = bytes.split_at_mut(HEADER_SIZE);
+    let length: usize = postcard::to_slice(entries, body).ok()?.len();
+    header[..2].copy_from_slice(&(length as u16).to_le_bytes());
+    header[2..].copy_from_slice(&crc16(&body[..length]).to_le_bytes());
+    Some(())
+}
+
+fn decode(bytes: &[u8; STORED_SIZE]) -> Option<Entries> {
+    let length: usize = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+    let crc: u16 = u16::from_le_bytes([bytes[2], bytes[3]]);
+    let body: &[u8] = bytes[HEADER_SIZE..].get(..length)?;
+    if crc16(body) != crc {
+        return None;
+    }
+    postcard::from_bytes(body).ok()
+}
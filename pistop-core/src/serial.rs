@@ -0,0 +1,96 @@
+/*
+ * The serial port, as seen by the control logic. It is a pair of byte pipes:
+ * the control logic writes text and frames into the output pipe and reads
+ * requests from the input pipe. Whoever owns the actual port, the firmware or
+ * the simulator, moves the bytes between the pipes and the wire.
+ *
+ * The port starts out as a human console. As soon as a tool sends a valid
+ * frame of the host protocol, we switch over to binary mode and stop printing
+ * text, which would only get in the way of the frames. A tool can switch back
+ * to the console with `Request::Console`.
+ */
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::{mutex::Mutex, pipe::Pipe};
+use pistop_protocol::{ControllerMessage, MAX_FRAME_SIZE, encode_frame};
+
+use crate::ThreadModeRawMutex;
+
+const OUTPUT_SIZE: usize = 256;
+const INPUT_SIZE: usize = 64;
+
+pub struct Serial {
+    output: Pipe<ThreadModeRawMutex, OUTPUT_SIZE>,
+    input: Pipe<ThreadModeRawMutex, INPUT_SIZE>,
+    // Held while writing a line or a frame, so that writers don't get mixed
+    // up when the output pipe fills up halfway through.
+    writer: Mutex<ThreadModeRawMutex, ()>,
+    binary_mode: AtomicBool,
+}
+
+impl Serial {
+    pub const fn new() -> Self {
+        Serial {
+            output: Pipe::new(),
+            input: Pipe::new(),
+            writer: Mutex::new(()),
+            binary_mode: AtomicBool::new(false),
+        }
+    }
+
+    pub fn binary_mode(&self) -> bool {
+        self.binary_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn set_binary_mode(&self, binary_mode: bool) {
+        self.binary_mode.store(binary_mode, Ordering::Relaxed);
+    }
+
+    pub async fn send(&self, message: &ControllerMessage) {
+        let mut buffer: [u8; MAX_FRAME_SIZE] = [0; MAX_FRAME_SIZE];
+        // All of our messages fit in a frame, so this cannot fail in practice.
+        if let Ok(frame) = encode_frame(message, &mut buffer) {
+            let _writer = self.writer.lock().await;
+            // A zero byte first ends whatever the receiver was in the middle
+            // of, such as a line of console text, so that the frame starts
+            // afresh.
+            self.output.write_all(&[0]).await;
+            self.output.write_all(frame).await;
+        }
+    }
+
+    // Write raw bytes, for protocols that bring their own framing, such as
+    // Modbus.
+    pub async fn write(&self, bytes: &[u8]) {
+        let _writer = self.writer.lock().await;
+        self.output.write_all(bytes).await;
+    }
+
+    // Used by the control logic to take in what came over the wire.
+    pub async fn receive(&self, buffer: &mut [u8]) -> usize {
+        self.input.read(buffer).await
+    }
+
+    // Used by the owner of the port to pass on what came over the wire.
+    pub async fn received(&self, bytes: &[u8]) {
+        self.input.write_all(bytes).await;
+    }
+
+    // Used by the owner of the port to take out what has to go over the wire.
+    pub async fn transmit(&self, buffer: &mut [u8]) -> usize {
+        self.output.read(buffer).await
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn print(serial: &'static Serial, message: &str) {
+    if serial.binary_mode() {
+        return;
+    }
+    serial.write(message.as_bytes()).await;
+}
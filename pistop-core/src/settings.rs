@@ -0,0 +1,265 @@
+/*
+ * The settings are the knobs of the system that differ between builds of the
+ * box, rather than between runs of the program. Think of the resistors that
+ * happen to be in the voltage divider or the type of battery that was fitted.
+ *
+ * We keep all of these together in a single structure, so that there is one
+ * place to look when adapting the firmware to a different box. Tools can also
+ * change the settings at run time over the host protocol, which is handy while
+ * tuning a new box. So tasks get a reference to the settings behind a mutex and
+ * copy out what they need each time they use them.
+ */
+
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, ConfigKey, ErrorCode, TimingPlan};
+
+use crate::can_link::MAX_NODE;
+use crate::green_wave::Role;
+use crate::log::Level;
+
+#[derive(Copy, Clone)]
+pub struct Settings {
+    // The battery rail is measured through a resistor divider, since it is
+    // well above what the ADC can take. The ratio is expressed in thousandths,
+    // so a divider of 20k over 10k is (20 + 10) / 10 = 3.0, or 3_000.
+    pub battery_divider_ratio_milli: u32,
+
+    // Thresholds for the battery rail, after undoing the divider. The defaults
+    // are for a 2S lithium-ion pack, which is 8.4V when full.
+    pub battery_low_millivolts: u32,
+    pub battery_critical_millivolts: u32,
+    pub battery_hysteresis_millivolts: u32,
+
+    pub battery_sample_interval_ms: u64,
+    pub battery_report_interval_ms: u64,
+
+    // Light each lamp in turn at power-on, as a quick check of the wiring.
+    // This takes a few seconds on every start, including the restart after a
+    // brown-out, so it is off unless a box is being wired up or repaired.
+    pub self_test: bool,
+
+    // How long each phase of normal mode lasts. These are set per box, since
+    // what feels right depends on how far apart the heads are.
+    pub normal_attention_ms: u64,
+    pub normal_go_ms: u64,
+    pub normal_yield_ms: u64,
+    pub normal_clear_ms: u64,
+
+    // The address that the Modbus slave answers to, see `modbus.rs`.
+    pub modbus_address: u8,
+
+    // Whether this box runs in a green wave with others, how long the common
+    // cycle is and when into that cycle approach A goes green. See
+    // `green_wave.rs`.
+    pub green_wave: Role,
+    pub green_wave_cycle_ms: u64,
+    pub green_wave_offset_ms: u64,
+
+    // The node number of this box on the CAN bus, every box on the bus needs
+    // one of its own. See `can_link.rs`.
+    pub can_node_id: u8,
+
+    // The green of an emergency vehicle lasts at least this long, however
+    // short its call. Afterwards, normal mode picks up with the turn of the
+    // exit approach. See `preemption.rs`.
+    pub preemption_dwell_ms: u64,
+    pub preemption_exit: Approach,
+
+    // The approach that crosses the railway tracks, and how long its green
+    // lasts to clear them when a train comes near. See `railway.rs`.
+    pub railway_approach: Approach,
+    pub track_clearance_ms: u64,
+
+    // The road of the pelican mode rests on green for at least the minimum
+    // green, the pedestrians then walk and get a flashing green, or a puffin
+    // holds the road at red for as long as the flashing would last. See
+    // `pelican.rs`.
+    pub pelican_min_green_ms: u64,
+    pub pelican_walk_ms: u64,
+    pub pelican_flash_ms: u64,
+    pub puffin: bool,
+
+    // What gets logged on the serial port, see `log.rs`.
+    pub log_level: Level,
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Settings {
+            battery_divider_ratio_milli: 3_000,
+            battery_low_millivolts: 7_000,
+            battery_critical_millivolts: 6_600,
+            battery_hysteresis_millivolts: 100,
+            battery_sample_interval_ms: 1_000,
+            battery_report_interval_ms: 30_000,
+            self_test: false,
+            normal_attention_ms: 3_000,
+            normal_go_ms: 8_000,
+            normal_yield_ms: 6_000,
+            normal_clear_ms: 4_000,
+            modbus_address: 1,
+            green_wave: Role::Off,
+            green_wave_cycle_ms: 60_000,
+            green_wave_offset_ms: 0,
+            can_node_id: 0,
+            preemption_dwell_ms: 10_000,
+            preemption_exit: Approach::A,
+            railway_approach: Approach::A,
+            track_clearance_ms: 10_000,
+            pelican_min_green_ms: 20_000,
+            pelican_walk_ms: 6_000,
+            pelican_flash_ms: 8_000,
+            puffin: false,
+            log_level: Level::Info,
+        }
+    }
+    pub fn get(&self, key: ConfigKey) -> u32 {
+        match key {
+            ConfigKey::BatteryDividerRatioMilli => self.battery_divider_ratio_milli,
+            ConfigKey::BatteryLowMillivolts => self.battery_low_millivolts,
+            ConfigKey::BatteryCriticalMillivolts => self.battery_critical_millivolts,
+            ConfigKey::BatteryHysteresisMillivolts => self.battery_hysteresis_millivolts,
+            ConfigKey::BatterySampleIntervalMs => self.battery_sample_interval_ms as u32,
+            ConfigKey::BatteryReportIntervalMs => self.battery_report_interval_ms as u32,
+            ConfigKey::SelfTest => self.self_test as u32,
+            ConfigKey::NormalAttentionMs => self.normal_attention_ms as u32,
+            ConfigKey::NormalGoMs => self.normal_go_ms as u32,
+            ConfigKey::NormalYieldMs => self.normal_yield_ms as u32,
+            ConfigKey::NormalClearMs => self.normal_clear_ms as u32,
+            ConfigKey::ModbusAddress => self.modbus_address as u32,
+            ConfigKey::LogLevel => self.log_level.ordinal() as u32,
+            ConfigKey::GreenWave => self.green_wave.ordinal() as u32,
+            ConfigKey::GreenWaveCycleMs => self.green_wave_cycle_ms as u32,
+            ConfigKey::GreenWaveOffsetMs => self.green_wave_offset_ms as u32,
+            ConfigKey::CanNodeId => self.can_node_id as u32,
+            ConfigKey::PreemptionDwellMs => self.preemption_dwell_ms as u32,
+            ConfigKey::PreemptionExit => self.preemption_exit.ordinal() as u32,
+            ConfigKey::RailwayApproach => self.railway_approach.ordinal() as u32,
+            ConfigKey::TrackClearanceMs => self.track_clearance_ms as u32,
+            ConfigKey::PelicanMinGreenMs => self.pelican_min_green_ms as u32,
+            ConfigKey::PelicanWalkMs => self.pelican_walk_ms as u32,
+            ConfigKey::PelicanFlashMs => self.pelican_flash_ms as u32,
+            ConfigKey::Puffin => self.puffin as u32,
+        }
+    }
+
+    // Change a single setting. Values that would leave the settings in a state
+    // that makes no sense are refused and leave the settings as they were.
+    pub fn set(&mut self, key: ConfigKey, value: u32) -> Result<(), ErrorCode> {
+        let mut settings: Settings = *self;
+        match key {
+            ConfigKey::BatteryDividerRatioMilli => settings.battery_divider_ratio_milli = value,
+            ConfigKey::BatteryLowMillivolts => settings.battery_low_millivolts = value,
+            ConfigKey::BatteryCriticalMillivolts => settings.battery_critical_millivolts = value,
+            ConfigKey::BatteryHysteresisMillivolts => {
+                settings.battery_hysteresis_millivolts = value
+            }
+            ConfigKey::BatterySampleIntervalMs => {
+                settings.battery_sample_interval_ms = value as u64
+            }
+            ConfigKey::BatteryReportIntervalMs => {
+                settings.battery_report_interval_ms = value as u64
+            }
+            ConfigKey::SelfTest => match value {
+                0 => settings.self_test = false,
+                1 => settings.self_test = true,
+                _ => return Err(ErrorCode::InvalidValue),
+            },
+            ConfigKey::NormalAttentionMs => settings.normal_attention_ms = value as u64,
+            ConfigKey::NormalGoMs => settings.normal_go_ms = value as u64,
+            ConfigKey::NormalYieldMs => settings.normal_yield_ms = value as u64,
+            ConfigKey::NormalClearMs => settings.normal_clear_ms = value as u64,
+            ConfigKey::ModbusAddress => {
+                settings.modbus_address =
+                    u8::try_from(value).map_err(|_| ErrorCode::InvalidValue)?
+            }
+            ConfigKey::LogLevel => {
+                settings.log_level = u8::try_from(value)
+                    .ok()
+                    .and_then(Level::from_ordinal)
+                    .ok_or(ErrorCode::InvalidValue)?
+            }
+            ConfigKey::GreenWave => {
+                settings.green_wave = u8::try_from(value)
+                    .ok()
+                    .and_then(Role::from_ordinal)
+                    .ok_or(ErrorCode::InvalidValue)?
+            }
+            ConfigKey::GreenWaveCycleMs => settings.green_wave_cycle_ms = value as u64,
+            ConfigKey::GreenWaveOffsetMs => settings.green_wave_offset_ms = value as u64,
+            ConfigKey::CanNodeId => {
+                settings.can_node_id = u8::try_from(value).map_err(|_| ErrorCode::InvalidValue)?
+            }
+            ConfigKey::PreemptionDwellMs => settings.preemption_dwell_ms = value as u64,
+            ConfigKey::PreemptionExit => {
+                settings.preemption_exit = u8::try_from(value)
+                    .ok()
+                    .and_then(Approach::from_ordinal)
+                    .ok_or(ErrorCode::InvalidValue)?
+            }
+            ConfigKey::RailwayApproach => {
+                settings.railway_approach = u8::try_from(value)
+                    .ok()
+                    .and_then(Approach::from_ordinal)
+                    .ok_or(ErrorCode::InvalidValue)?
+            }
+            ConfigKey::TrackClearanceMs => settings.track_clearance_ms = value as u64,
+            ConfigKey::PelicanMinGreenMs => settings.pelican_min_green_ms = value as u64,
+            ConfigKey::PelicanWalkMs => settings.pelican_walk_ms = value as u64,
+            ConfigKey::PelicanFlashMs => settings.pelican_flash_ms = value as u64,
+            ConfigKey::Puffin => match value {
+                0 => settings.puffin = false,
+                1 => settings.puffin = true,
+                _ => return Err(ErrorCode::InvalidValue),
+            },
+        }
+        if !settings.is_valid() {
+            return Err(ErrorCode::InvalidValue);
+        }
+        *self = settings;
+        Ok(())
+    }
+
+    // Change all of the timings of normal mode at once, see `schedule.rs`.
+    pub fn set_timings(&mut self, timings: TimingPlan) -> Result<(), ErrorCode> {
+        let mut settings: Settings = *self;
+        settings.normal_attention_ms = timings.attention_ms as u64;
+        settings.normal_go_ms = timings.go_ms as u64;
+        settings.normal_yield_ms = timings.yield_ms as u64;
+        settings.normal_clear_ms = timings.clear_ms as u64;
+        if !settings.is_valid() {
+            return Err(ErrorCode::InvalidValue);
+        }
+        *self = settings;
+        Ok(())
+    }
+
+    fn is_valid(&self) -> bool {
+        self.battery_divider_ratio_milli > 0
+            && self.battery_critical_millivolts < self.battery_low_millivolts
+            && self.battery_sample_interval_ms > 0
+            && self.battery_report_interval_ms > 0
+            && self.normal_attention_ms > 0
+            && self.normal_go_ms > 0
+            && self.normal_yield_ms > 0
+            && self.normal_clear_ms > 0
+            && self.pelican_walk_ms > 0
+            // Address 0 is for broadcasts and 248 and up are reserved.
+            && (1..=247).contains(&self.modbus_address)
+            && self.green_wave_offset_ms < self.green_wave_cycle_ms
+            && self.can_node_id <= MAX_NODE
+            // A turn of A and a turn of B have to fit in the common cycle.
+            && (self.green_wave == Role::Off
+                || 2 * (self.normal_attention_ms
+                    + self.normal_go_ms
+                    + self.normal_yield_ms
+                    + self.normal_clear_ms)
+                    <= self.green_wave_cycle_ms)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ * Running statistics of the crossing: the cycles that the modes complete, the
+ * pedestrians that ask to cross and how long they wait for green, and the
+ * time spent switching modes. Visitors like the numbers, and they tell us
+ * when a change to the modes made things worse.
+ *
+ * The statistics count from startup until someone resets them. A reset does
+ * not forget the promises that are still open, so that their wait still comes
+ * out right once they are served. A lockout that is going on counts from the
+ * moment of the reset.
+ */
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use enum_ordinalize::Ordinalize;
+use pistop_protocol::{Approach, ApproachStatistics, SystemMode};
+
+use crate::ThreadModeRawMutex;
+
+#[derive(Copy, Clone)]
+struct ApproachCounters {
+    requests: u32,
+    served: u32,
+    dropped: u32,
+    promised_at: Option<Instant>,
+    total_wait: Duration,
+    max_wait: Duration,
+}
+
+impl ApproachCounters {
+    const fn new(promised_at: Option<Instant>) -> Self {
+        ApproachCounters {
+            requests: 0,
+            served: 0,
+            dropped: 0,
+            promised_at: promised_at,
+            total_wait: Duration::from_ticks(0),
+            max_wait: Duration::from_ticks(0),
+        }
+    }
+}
+
+struct Counters {
+    since: Instant,
+    cycles: [u32; SystemMode::VARIANT_COUNT],
+    mode_switches: u32,
+    lockout: Duration,
+    locked_out_at: Option<Instant>,
+    approaches: [ApproachCounters; Approach::VARIANT_COUNT],
+}
+
+impl Counters {
+    const fn new(since: Instant, locked_out_at: Option<Instant>) -> Self {
+        Counters {
+            since: since,
+            cycles: [0; SystemMode::VARIANT_COUNT],
+            mode_switches: 0,
+            lockout: Duration::from_ticks(0),
+            locked_out_at: locked_out_at,
+            approaches: [ApproachCounters::new(None); Approach::VARIANT_COUNT],
+        }
+    }
+}
+
+pub struct Statistics {
+    // Like the event log, the counters are only ever held for a moment.
+    counters: Mutex<ThreadModeRawMutex, RefCell<Counters>>,
+}
+
+impl Statistics {
+    // The controller starts out locked out, see `LOCKOUT` in the firmware.
+    pub const fn new() -> Self {
+        Statistics {
+            counters: Mutex::new(RefCell::new(Counters::new(
+                Instant::from_ticks(0),
+                Some(Instant::from_ticks(0)),
+            ))),
+        }
+    }
+
+    pub fn cycle_completed(&self, mode: SystemMode) {
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            let cycles: &mut u32 = &mut counters.cycles[mode.ordinal() as usize];
+            *cycles = cycles.wrapping_add(1);
+        });
+    }
+
+    // Every lockout is a switch to another mode.
+    pub fn set_locked_out(&self, locked_out: bool) {
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            let now: Instant = Instant::now();
+            match (locked_out, counters.locked_out_at) {
+                (true, None) => {
+                    counters.locked_out_at = Some(now);
+                    counters.mode_switches = counters.mode_switches.wrapping_add(1);
+                }
+                (false, Some(locked_out_at)) => {
+                    counters.locked_out_at = None;
+                    counters.lockout += now - locked_out_at;
+                }
+                _ => {}
+            }
+        });
+    }
+
+    pub fn promise_made(&self, approach: Approach) {
+        self.with_approach(approach, |counters| {
+            counters.requests = counters.requests.wrapping_add(1);
+            counters.promised_at = Some(Instant::now());
+        });
+    }
+
+    pub fn promise_served(&self, approach: Approach) {
+        self.with_approach(approach, |counters| {
+            if let Some(promised_at) = counters.promised_at.take() {
+                let wait: Duration = Instant::now() - promised_at;
+                counters.served = counters.served.wrapping_add(1);
+                counters.total_wait += wait;
+                counters.max_wait = counters.max_wait.max(wait);
+            }
+        });
+    }
+
+    pub fn promise_dropped(&self, approach: Approach) {
+        self.with_approach(approach, |counters| {
+            if counters.promised_at.take().is_some() {
+                counters.dropped = counters.dropped.wrapping_add(1);
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> pistop_protocol::Statistics {
+        self.counters.lock(|counters| {
+            let counters = counters.borrow();
+            let now: Instant = Instant::now();
+            // A lockout that is going on counts up to now.
+            let lockout: Duration = match counters.locked_out_at {
+                Some(locked_out_at) => counters.lockout + (now - locked_out_at),
+                None => counters.lockout,
+            };
+            pistop_protocol::Statistics {
+                uptime_ms: now.as_millis(),
+                counted_ms: (now - counters.since).as_millis(),
+                cycles: counters.cycles,
+                mode_switches: counters.mode_switches,
+                lockout_ms: lockout.as_millis(),
+                approaches: counters.approaches.map(|approach| ApproachStatistics {
+                    requests: approach.requests,
+                    served: approach.served,
+                    dropped: approach.dropped,
+                    pending: approach.promised_at.is_some(),
+                    total_wait_ms: approach.total_wait.as_millis(),
+                    max_wait_ms: approach.max_wait.as_millis(),
+                }),
+            }
+        })
+    }
+
+    pub fn reset(&self) {
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            let now: Instant = Instant::now();
+            let mut reset: Counters = Counters::new(now, counters.locked_out_at.map(|_| now));
+            for (approach, old) in reset.approaches.iter_mut().zip(counters.approaches) {
+                *approach = ApproachCounters::new(old.promised_at);
+            }
+            *counters = reset;
+        });
+    }
+
+    fn with_approach(&self, approach: Approach, f: impl FnOnce(&mut ApproachCounters)) {
+        self.counters
+            .lock(|counters| f(&mut counters.borrow_mut().approaches[approach.ordinal() as usize]));
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -24,42 +24,25 @@
  *
  * This module exposes a collection of output pins, each of which can be on or
  * off, but also subject to one or more timers.
+ *
+ * Since the module knows which timers each pin is subject to, it can also tell
+ * how many ticks it will be before any of the outputs changes. At night, with
+ * only the amber lights flashing, that is half a second. The caller can use
+ * that to sleep instead of waking up a hundred times per second to write the
+ * same values to the output pins. When the control logic changes a pin, the
+ * module raises a signal, so that the caller wakes up and writes the new value
+ * straight away.
  */
 
 use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::signal::Signal;
 use enum_ordinalize::Ordinalize;
 
-#[derive(Ordinalize, Clone, Copy)]
-#[repr(usize)]
-pub enum Pins {
-    // Left-right lane, lights A, pedestrian lights D, promise F and beeper.
-    ARed,
-    AAmber,
-    AGreen,
-    APedestrianRed,
-    APedestrianGreen,
-    APromise,
-    ABeeper,
-
-    // Up-down lane: lights B, pedestrian lists C and promise E.
-    BRed,
-    BAmber,
-    BGreen,
-    BPedestrianRed,
-    BPedestrianGreen,
-    BPromise,
-    // The PCB does not have a beeper for the up-down lane. We have a mock value
-    // here to keep the code orthogonal. It is simply mapped to an unused output
-    // pin.
-    BBeeper,
-
-    // common
-    OnBoardPower,
-    Power,
-    SwitchingMode,
-}
+use crate::ThreadModeRawMutex;
 
-#[derive(Copy, Clone)]
+pub use pistop_protocol::Pins;
+
+#[derive(PartialEq, Eq, Copy, Clone)]
 struct OutputStateDescriptor {
     on: bool,
     subject_to_slow_cycle: bool,
@@ -85,11 +68,15 @@ pub struct TimedOutputMasker {
     slow_cycle_value: AtomicBool,
     fast_cycle_value: AtomicBool,
     pip_timer_value: AtomicBool,
+    changed: &'static Signal<ThreadModeRawMutex, ()>,
 }
 
 static TICKS_PER_CYCLE: u8 = 100;
 impl TimedOutputMasker {
-    pub const fn new(active_lows: [bool; Pins::VARIANT_COUNT]) -> Self {
+    pub const fn new(
+        active_lows: [bool; Pins::VARIANT_COUNT],
+        changed: &'static Signal<ThreadModeRawMutex, ()>,
+    ) -> Self {
         TimedOutputMasker {
             output_descriptors: [OutputStateDescriptor::new(); Pins::VARIANT_COUNT],
             active_lows: active_lows,
@@ -97,6 +84,7 @@ impl TimedOutputMasker {
             slow_cycle_value: AtomicBool::new(false),
             fast_cycle_value: AtomicBool::new(false),
             pip_timer_value: AtomicBool::new(false),
+            changed: changed,
         }
     }
 
@@ -108,39 +96,106 @@ impl TimedOutputMasker {
      * XXX Consider exposing a task, which only calls this function at 100Hz.
      */
     pub fn call_at_100_hz(&mut self) -> [bool; Pins::VARIANT_COUNT] {
-        self.advance_timers();
+        self.call_after_ticks(1)
+    }
+
+    /*
+     * The low-power variant of `call_at_100_hz()`. The caller tells us how many
+     * 100Hz ticks have passed since the previous call, which may be zero when
+     * the caller was woken up because the control logic changed a pin.
+     */
+    pub fn call_after_ticks(&mut self, ticks: u32) -> [bool; Pins::VARIANT_COUNT] {
+        self.advance_timers(ticks);
         self.mask_output_pins()
     }
 
-    fn advance_timers(&mut self) {
-        self.tick_count = (self.tick_count + 1) % TICKS_PER_CYCLE;
+    /*
+     * Work out how many ticks from now the first output changes, given that the
+     * control logic does not change any pins in the meantime. When nothing is
+     * blinking, we still ask to be called once per cycle.
+     */
+    pub fn ticks_until_change(&self) -> u32 {
+        let current = self.mask_output_pins_with(self.cycles_in(0));
+        for ticks in 1..TICKS_PER_CYCLE as u32 {
+            if self.mask_output_pins_with(self.cycles_in(ticks)) != current {
+                return ticks;
+            }
+        }
+        TICKS_PER_CYCLE as u32
+    }
 
+    /*
+     * Which outputs are on at this tick, as seen by the control logic. That
+     * is, before the active-lows are applied.
+     */
+    pub fn lit(&self) -> [bool; Pins::VARIANT_COUNT] {
+        let mut lit = self.mask_output_pins_with(self.cycles_in(0));
+        for (on, active_low) in lit.iter_mut().zip(self.active_lows) {
+            if active_low {
+                *on = !*on;
+            }
+        }
+        lit
+    }
+
+    /*
+     * The values of the slow cycle, the fast cycle and the pip timer at this
+     * tick, the way the masks see them.
+     */
+    pub fn cycles(&self) -> (bool, bool, bool) {
+        self.cycles_in(0)
+    }
+
+    /*
+     * The values of the cycles a number of ticks from now. For a caller that
+     * sleeps between changes, and wants to know where the cycles are without
+     * moving the masker on.
+     */
+    pub fn cycles_in(&self, ticks: u32) -> (bool, bool, bool) {
+        cycle_values(((self.tick_count as u32 + ticks) % TICKS_PER_CYCLE as u32) as u8)
+    }
+
+    fn advance_timers(&mut self, ticks: u32) {
+        self.tick_count = ((self.tick_count as u32 + ticks) % TICKS_PER_CYCLE as u32) as u8;
+
+        let (slow_cycle_value, fast_cycle_value, pip_timer_value) = cycle_values(self.tick_count);
         self.slow_cycle_value
-            .store(self.tick_count < 50, Ordering::Relaxed);
+            .store(slow_cycle_value, Ordering::Relaxed);
         self.fast_cycle_value
-            .store((self.tick_count / 10) % 2 == 1, Ordering::Relaxed);
+            .store(fast_cycle_value, Ordering::Relaxed);
         self.pip_timer_value
-            .store(self.tick_count == 0, Ordering::Relaxed);
+            .store(pip_timer_value, Ordering::Relaxed);
     }
 
     fn mask_output_pins(&mut self) -> [bool; Pins::VARIANT_COUNT] {
+        self.mask_output_pins_with((
+            self.slow_cycle_value.load(Ordering::Relaxed),
+            self.fast_cycle_value.load(Ordering::Relaxed),
+            self.pip_timer_value.load(Ordering::Relaxed),
+        ))
+    }
+
+    fn mask_output_pins_with(
+        &self,
+        (slow_cycle_value, fast_cycle_value, pip_timer_value): (bool, bool, bool),
+    ) -> [bool; Pins::VARIANT_COUNT] {
         let mut outputs = [false; Pins::VARIANT_COUNT];
-        for i in 0..Pins::VARIANT_COUNT {
+        for (i, output) in outputs.iter_mut().enumerate() {
             let output_descriptor: &OutputStateDescriptor = &self.output_descriptors[i];
-            outputs[i] = output_descriptor.on;
+            *output = output_descriptor.on;
 
             if output_descriptor.subject_to_slow_cycle {
-                outputs[i] = outputs[i] & self.slow_cycle_value.load(Ordering::Relaxed);
+                *output &= slow_cycle_value;
             }
             if output_descriptor.subject_to_fast_cycle {
-                outputs[i] = outputs[i] & self.fast_cycle_value.load(Ordering::Relaxed);
+                *output &= fast_cycle_value;
             }
             if output_descriptor.subject_to_pip_timer {
-                outputs[i] = outputs[i] & self.pip_timer_value.load(Ordering::Relaxed);
+                *output &= pip_timer_value;
             }
 
             if self.active_lows[i] {
-                outputs[i] = !outputs[i];
+                *output = !*output;
             }
         }
 
@@ -178,11 +233,21 @@ impl TimedOutputMasker {
         subject_to_fast_cycle: bool,
         subject_to_pip_timer: bool,
     ) {
-        self.output_descriptors[pin.ordinal()] = OutputStateDescriptor {
+        let output_descriptor = OutputStateDescriptor {
             on: on,
             subject_to_slow_cycle: subject_to_slow_cycle,
             subject_to_fast_cycle: subject_to_fast_cycle,
             subject_to_pip_timer: subject_to_pip_timer,
+        };
+        if self.output_descriptors[pin.ordinal()] != output_descriptor {
+            self.output_descriptors[pin.ordinal()] = output_descriptor;
+            self.changed.signal(());
         }
     }
 }
+
+// The values of the slow cycle, the fast cycle and the pip timer at a given
+// tick in the cycle.
+fn cycle_values(tick_count: u8) -> (bool, bool, bool) {
+    (tick_count < 50, (tick_count / 10) % 2 == 1, tick_count == 0)
+}
@@ -0,0 +1,106 @@
+/*
+ * The wall clock tells the time of day, which the schedule goes by. The board
+ * keeps the time in its RTC, which runs from the backup battery while the box
+ * is switched off. The RTC is set over the host protocol, there is nothing on
+ * the box to set it with.
+ *
+ * Reading the RTC means going to the backup domain, so we don't do that every
+ * time someone asks for the time. The clock keeper reads the RTC at startup and
+ * from then on the wall clock goes by `Instant`. The crystal of the RTC is the
+ * better one for keeping time, so the keeper checks back with it every so
+ * often. See `clock_keeper`.
+ */
+
+use core::cell::Cell;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use embassy_time::{Instant, Timer};
+use pistop_protocol::WallTime;
+
+use crate::ThreadModeRawMutex;
+use crate::info;
+use crate::log::Log;
+
+// How often the keeper corrects the wall clock from the RTC. The two crystals
+// drift apart by a second or so a day.
+const RTC_READ_INTERVAL_SECS: u64 = 60 * 60;
+
+// Where the time is kept while the power is off. On the controller that is
+// the RTC, in the simulator it is just memory.
+pub trait Rtc {
+    // None when the RTC was never set, or when it lost its backup power.
+    fn read(&mut self) -> Option<WallTime>;
+    fn write(&mut self, time: WallTime);
+}
+
+pub struct WallClock {
+    // The wall time at `Instant` zero, in milliseconds. None until we know
+    // the time, either from the RTC or from the host.
+    start_ms: Mutex<ThreadModeRawMutex, Cell<Option<i64>>>,
+    // A time that was set, on its way to the RTC.
+    written: Signal<ThreadModeRawMutex, WallTime>,
+}
+
+impl WallClock {
+    pub const fn new() -> Self {
+        WallClock {
+            start_ms: Mutex::new(Cell::new(None)),
+            written: Signal::new(),
+        }
+    }
+
+    pub fn now(&self) -> Option<WallTime> {
+        let start_ms: i64 = self.start_ms.lock(|start_ms| start_ms.get())?;
+        let now_ms: i64 = start_ms + Instant::now().as_millis() as i64;
+        u32::try_from(now_ms / 1_000).ok().map(WallTime)
+    }
+
+    // Set the time, which also goes to the RTC.
+    pub fn set(&self, time: WallTime) {
+        self.adjust(time);
+        self.written.signal(time);
+    }
+
+    fn adjust(&self, time: WallTime) {
+        let start_ms: i64 = time.0 as i64 * 1_000 - Instant::now().as_millis() as i64;
+        self.start_ms.lock(|current| current.set(Some(start_ms)));
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn clock_keeper(
+    log: &'static Log,
+    rtc: &mut impl Rtc,
+    wall_clock: &'static WallClock,
+) -> ! {
+    loop {
+        if let Some(time) = rtc.read() {
+            wall_clock.adjust(time);
+        }
+        if let Either::First(time) = select(
+            wall_clock.written.wait(),
+            Timer::after_secs(RTC_READ_INTERVAL_SECS),
+        )
+        .await
+        {
+            rtc.write(time);
+            let (year, month, day, hour, minute, second) = time.date_time();
+            info!(
+                log,
+                "clock",
+                "set to {}-{:02}-{:02} {:02}:{:02}:{:02}.",
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second
+            );
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ * Framing turns messages into bytes on the wire and back again. A frame is the
+ * COBS encoding of the postcard-encoded message followed by its CRC16 in
+ * little-endian order, terminated by a zero byte.
+ */
+
+use crc::{CRC_16_IBM_3740, Crc};
+use serde::{Serialize, de::DeserializeOwned};
+
+pub const MAX_MESSAGE_SIZE: usize = 256;
+
+// The message, its CRC, the COBS overhead and the terminating zero.
+pub const MAX_FRAME_SIZE: usize = cobs::max_encoding_length(MAX_MESSAGE_SIZE + 2) + 1;
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FrameError {
+    // The message does not fit in a frame, or the frame does not fit in the
+    // buffer it was given.
+    TooLarge,
+    Cobs,
+    Crc,
+    Decode,
+}
+
+pub fn crc16(bytes: &[u8]) -> u16 {
+    CRC16.checksum(bytes)
+}
+
+// Encode a message into a complete frame, including the terminating zero.
+pub fn encode_frame<'a, T: Serialize>(
+    message: &T,
+    buffer: &'a mut [u8],
+) -> Result<&'a [u8], FrameError> {
+    let mut raw: [u8; MAX_MESSAGE_SIZE + 2] = [0; MAX_MESSAGE_SIZE + 2];
+    let length: usize = postcard::to_slice(message, &mut raw[..MAX_MESSAGE_SIZE])
+        .map_err(|_| FrameError::TooLarge)?
+        .len();
+    let crc: u16 = crc16(&raw[..length]);
+    raw[length..length + 2].copy_from_slice(&crc.to_le_bytes());
+
+    let encoded: usize =
+        cobs::try_encode(&raw[..length + 2], buffer).map_err(|_| FrameError::TooLarge)?;
+    if encoded >= buffer.len() {
+        return Err(FrameError::TooLarge);
+    }
+    buffer[encoded] = 0;
+    Ok(&buffer[..encoded + 1])
+}
+
+// Collects bytes from the wire until a frame is complete. Bytes are fed one at
+// a time, since that is how they trickle in over a serial port.
+pub struct FrameReader {
+    buffer: [u8; MAX_FRAME_SIZE],
+    length: usize,
+    overflowed: bool,
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        FrameReader {
+            buffer: [0; MAX_FRAME_SIZE],
+            length: 0,
+            overflowed: false,
+        }
+    }
+
+    // Returns `None` while a frame is still incomplete and the decoded message
+    // (or why it could not be decoded) once the terminating zero comes in.
+    pub fn push<T: DeserializeOwned>(&mut self, byte: u8) -> Option<Result<T, FrameError>> {
+        if byte != 0 {
+            if self.length < self.buffer.len() {
+                self.buffer[self.length] = byte;
+                self.length += 1;
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        let length: usize = self.length;
+        let overflowed: bool = self.overflowed;
+        self.length = 0;
+        self.overflowed = false;
+
+        // Senders may send zero bytes to get the receiver in sync, or to wake
+        // it up. Those show up as empty frames, which we silently skip.
+        if length == 0 {
+            return None;
+        }
+        if overflowed {
+            return Some(Err(FrameError::TooLarge));
+        }
+        Some(decode_frame(&mut self.buffer[..length]))
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn decode_frame<T: DeserializeOwned>(frame: &mut [u8]) -> Result<T, FrameError> {
+    let length: usize = cobs::decode_in_place(frame).map_err(|_| FrameError::Cobs)?;
+    if length < 2 {
+        return Err(FrameError::Crc);
+    }
+
+    let (message, crc) = frame[..length].split_at(length - 2);
+    if crc16(message).to_le_bytes() != crc {
+        return Err(FrameError::Crc);
+    }
+    postcard::from_bytes(message).map_err(|_| FrameError::Decode)
+}
@@ -0,0 +1,25 @@
+#![no_std]
+
+/*
+ * The human console on the serial port is nice for watching the controller at
+ * work, but tools have a hard time parsing it. This crate defines a binary
+ * protocol for tools to control and monitor the controller. It is shared
+ * between the firmware and the host-side tools, so that both ends always agree
+ * on what the messages look like.
+ *
+ * The host sends requests, to which the controller sends exactly one response.
+ * In between, the controller sends notifications of its own accord whenever
+ * something happens, such as a phase change or a fault.
+ *
+ * Messages are encoded with `postcard`, followed by a CRC16 over the encoded
+ * message. The result is COBS-encoded and terminated with a zero byte. COBS
+ * guarantees that there are no zero bytes inside a frame, so a receiver that
+ * comes in halfway through a frame (or that lost a byte) simply waits for the
+ * next zero byte to get back in sync.
+ */
+
+mod framing;
+mod messages;
+
+pub use framing::{FrameError, FrameReader, MAX_FRAME_SIZE, MAX_MESSAGE_SIZE, crc16, encode_frame};
+pub use messages::*;
@@ -0,0 +1,505 @@
+/*
+ * The messages of the host protocol, and the vocabulary that they are built
+ * from. The firmware uses the same vocabulary internally, so that there is no
+ * translation between what the controller does and what it reports.
+ *
+ * Messages only ever grow at the end. Postcard encodes enum variants by their
+ * index, so inserting a variant in the middle would break older tools.
+ */
+
+use enum_ordinalize::Ordinalize;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+#[derive(Ordinalize, PartialEq, Eq, Hash, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum SystemMode {
+    Normal,
+    Flash,
+    PriorityA,
+    PriorityB,
+    // Only while a train is near, see `railway.rs` in `pistop-core`.
+    Railway,
+    // An officer on the spot sends each approach on, see `manual.rs` in
+    // `pistop-core`.
+    Manual,
+    // Every head at steady red, to close the crossing.
+    AllRed,
+    // Every head off, to store the box or save the battery.
+    Dark,
+    // Approach B is a road with a pedestrian crossing on it, which the
+    // pedestrians of A cross when they ask to.
+    Pelican,
+}
+
+// The two approaches to the crossing. A is the left-right lane, B is the
+// up-down lane.
+#[derive(Ordinalize, PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Approach {
+    A,
+    B,
+}
+
+#[derive(Ordinalize, PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Phase {
+    Attention,
+    Go,
+    Yield,
+    Clear,
+    Flash,
+    Dark,
+}
+
+#[derive(Ordinalize, PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum BatteryState {
+    Ok,
+    Low,
+    Critical,
+}
+
+#[derive(Ordinalize, PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(usize)]
+pub enum Pins {
+    // Left-right lane, lights A, pedestrian lights D, promise F and beeper.
+    ARed,
+    AAmber,
+    AGreen,
+    APedestrianRed,
+    APedestrianGreen,
+    APromise,
+    ABeeper,
+
+    // Up-down lane: lights B, pedestrian lists C and promise E.
+    BRed,
+    BAmber,
+    BGreen,
+    BPedestrianRed,
+    BPedestrianGreen,
+    BPromise,
+    // The PCB does not have a beeper for the up-down lane. We have a mock value
+    // here to keep the code orthogonal. It is simply mapped to an unused output
+    // pin.
+    BBeeper,
+
+    // common
+    OnBoardPower,
+    Power,
+    SwitchingMode,
+}
+
+impl Pins {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Pins::ARed => "ARed",
+            Pins::AAmber => "AAmber",
+            Pins::AGreen => "AGreen",
+            Pins::APedestrianRed => "APedestrianRed",
+            Pins::APedestrianGreen => "APedestrianGreen",
+            Pins::APromise => "APromise",
+            Pins::ABeeper => "ABeeper",
+            Pins::BRed => "BRed",
+            Pins::BAmber => "BAmber",
+            Pins::BGreen => "BGreen",
+            Pins::BPedestrianRed => "BPedestrianRed",
+            Pins::BPedestrianGreen => "BPedestrianGreen",
+            Pins::BPromise => "BPromise",
+            Pins::BBeeper => "BBeeper",
+            Pins::OnBoardPower => "OnBoardPower",
+            Pins::Power => "Power",
+            Pins::SwitchingMode => "SwitchingMode",
+        }
+    }
+}
+
+// The settings that can be read and written over the host protocol. Values
+// are all transferred as `u32`, with `0` and `1` for booleans.
+#[derive(Ordinalize, PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum ConfigKey {
+    BatteryDividerRatioMilli,
+    BatteryLowMillivolts,
+    BatteryCriticalMillivolts,
+    BatteryHysteresisMillivolts,
+    BatterySampleIntervalMs,
+    BatteryReportIntervalMs,
+    SelfTest,
+    NormalAttentionMs,
+    NormalGoMs,
+    NormalYieldMs,
+    NormalClearMs,
+    ModbusAddress,
+    // 0 for errors only, up to 4 for everything.
+    LogLevel,
+    // 0 for off, 1 for the master and 2 for a follower.
+    GreenWave,
+    GreenWaveCycleMs,
+    GreenWaveOffsetMs,
+    // 0 to stay off the CAN bus, 1 to 15 for the node number on the bus.
+    CanNodeId,
+    // The shortest green for an emergency vehicle.
+    PreemptionDwellMs,
+    // The approach that takes the first turn of normal mode after a
+    // preemption, 0 for A and 1 for B.
+    PreemptionExit,
+    // The approach that crosses the railway tracks, 0 for A and 1 for B.
+    RailwayApproach,
+    // The green that clears the tracks before the train arrives.
+    TrackClearanceMs,
+    // The shortest green for the road of the pelican mode, however soon
+    // someone presses the button.
+    PelicanMinGreenMs,
+    // How long the pedestrians of the pelican mode see a steady green.
+    PelicanWalkMs,
+    // How long the flashing amber and green of the pelican mode last, or how
+    // long the road of a puffin stays at red after the pedestrians walked.
+    PelicanFlashMs,
+    // 0 for a pelican, 1 for a puffin that looks at the kerbside detector.
+    Puffin,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Status {
+    // The mode that is running, which is not the mode on the rotary switch
+    // when there is a fault.
+    pub mode: SystemMode,
+    pub locked_out: bool,
+    pub phases: [Phase; Approach::VARIANT_COUNT],
+    // One bit per `Pins` ordinal, for the outputs that are on right now.
+    pub lit: u32,
+    // One bit per `Pins` ordinal, for the lamps that were found to be out.
+    pub lamps_out: u32,
+    pub battery_state: BatteryState,
+    pub battery_millivolts: u32,
+    pub uptime_ms: u64,
+}
+
+impl Status {
+    pub fn is_lit(&self, pin: Pins) -> bool {
+        self.lit & (1 << pin.ordinal()) != 0
+    }
+
+    pub fn is_lamp_out(&self, pin: Pins) -> bool {
+        self.lamps_out & (1 << pin.ordinal()) != 0
+    }
+}
+
+// What the controller has been up to, counted since startup or since the
+// statistics were last reset.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Statistics {
+    // Since startup, the statistics don't reset this one.
+    pub uptime_ms: u64,
+    // How long the statistics have been counting.
+    pub counted_ms: u64,
+    // In normal mode, every turn of an approach counts as a cycle.
+    pub cycles: [u32; SystemMode::VARIANT_COUNT],
+    pub mode_switches: u32,
+    pub lockout_ms: u64,
+    pub approaches: [ApproachStatistics; Approach::VARIANT_COUNT],
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ApproachStatistics {
+    // The presses that made a promise, rather than every press of the button.
+    pub requests: u32,
+    pub served: u32,
+    // Promises that were still open when the crossing went to flashing mode,
+    // or that a puffin cancelled since nobody waited at the kerb.
+    pub dropped: u32,
+    pub pending: bool,
+    // From the press that made the promise to the green pedestrian light.
+    pub total_wait_ms: u64,
+    pub max_wait_ms: u64,
+}
+
+impl ApproachStatistics {
+    pub fn average_wait_ms(&self) -> u64 {
+        self.total_wait_ms
+            .checked_div(self.served as u64)
+            .unwrap_or(0)
+    }
+}
+
+// Wall-clock time, in seconds since the start of 2000-01-01. This is what the
+// RTC of the controller counts. It knows nothing of time zones or daylight
+// saving, so it simply runs on the local time that it was set to.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct WallTime(pub u32);
+
+// Days of the week, as the bits of `ScheduleEntry::weekdays`. Bit 0 is Monday,
+// bit 6 is Sunday, the same as `WallTime::weekday()`.
+pub const WORKDAYS: u8 = 0b0001_1111;
+pub const WEEKEND: u8 = 0b0110_0000;
+pub const EVERY_DAY: u8 = 0b0111_1111;
+
+pub const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+
+impl WallTime {
+    // The date and the time of day, or none when it falls outside of the years
+    // that the clock can count.
+    pub const fn from_date_time(
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+    ) -> Option<WallTime> {
+        if year < 2000
+            || month < 1
+            || month > 12
+            || day < 1
+            || day > days_in_month(year, month)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+        let mut days: u64 = day as u64 - 1;
+        let mut y: u16 = 2000;
+        while y < year {
+            days += if is_leap_year(y) { 366 } else { 365 };
+            y += 1;
+        }
+        let mut m: u8 = 1;
+        while m < month {
+            days += days_in_month(year, m) as u64;
+            m += 1;
+        }
+        let seconds: u64 = days * SECONDS_PER_DAY as u64
+            + hour as u64 * 3_600
+            + minute as u64 * 60
+            + second as u64;
+        if seconds > u32::MAX as u64 {
+            return None;
+        }
+        Some(WallTime(seconds as u32))
+    }
+
+    // Year, month, day, hour, minute and second.
+    pub const fn date_time(self) -> (u16, u8, u8, u8, u8, u8) {
+        let mut days: u32 = self.0 / SECONDS_PER_DAY;
+        let mut year: u16 = 2000;
+        loop {
+            let length: u32 = if is_leap_year(year) { 366 } else { 365 };
+            if days < length {
+                break;
+            }
+            days -= length;
+            year += 1;
+        }
+        let mut month: u8 = 1;
+        while days >= days_in_month(year, month) as u32 {
+            days -= days_in_month(year, month) as u32;
+            month += 1;
+        }
+        let second_of_day: u32 = self.0 % SECONDS_PER_DAY;
+        (
+            year,
+            month,
+            days as u8 + 1,
+            (second_of_day / 3_600) as u8,
+            (second_of_day / 60 % 60) as u8,
+            (second_of_day % 60) as u8,
+        )
+    }
+
+    // Monday is 0, Sunday is 6. The clock starts on a Saturday.
+    pub const fn weekday(self) -> u8 {
+        ((self.0 / SECONDS_PER_DAY + 5) % 7) as u8
+    }
+
+    pub const fn minute_of_day(self) -> u16 {
+        (self.0 % SECONDS_PER_DAY / 60) as u16
+    }
+}
+
+const fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+const fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// How long each phase of normal mode lasts, like the `Normal*Ms` settings.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct TimingPlan {
+    pub attention_ms: u32,
+    pub go_ms: u32,
+    pub yield_ms: u32,
+    pub clear_ms: u32,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum ScheduleAction {
+    // Switch modes, as if the rotary switch was turned.
+    Mode(SystemMode),
+    // Run normal mode with other timings, as if the settings were changed.
+    Timings(TimingPlan),
+}
+
+// From the given minute of the day on the given days of the week, the schedule
+// does what the entry says. The entry holds until another entry of the same
+// kind of action takes over, which may well be on another day.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    // One bit per day, see `WORKDAYS` and friends.
+    pub weekdays: u8,
+    pub start_minute: u16,
+    pub action: ScheduleAction,
+}
+
+// The number of entries that the schedule of the controller has room for.
+pub const SCHEDULE_ENTRIES: usize = 8;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum CalendarAction {
+    // All day long, instead of the entries of the schedule of the same kind
+    // of action.
+    Forced(ScheduleAction),
+    // Follow the schedule as if it were another day of the week, Monday being
+    // 0. Think of running the Sunday plan on a holiday.
+    AsWeekday(u8),
+}
+
+// Days that differ from what the schedule says for their day of the week, from
+// the first day up to and including the last. Holidays come back every year,
+// in which case the years of the dates are ignored and the days may run over
+// new year.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct CalendarEntry {
+    pub first: Date,
+    pub last: Date,
+    pub every_year: bool,
+    pub action: CalendarAction,
+}
+
+pub const CALENDAR_ENTRIES: usize = 16;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum EventKind {
+    ModeChanged(SystemMode),
+    LockedOut,
+    PhaseChanged(Approach, Phase),
+    ButtonPressed(Approach),
+    BatteryStateChanged(BatteryState),
+    LampOut(Pins),
+    // Another box on the CAN bus, by its node number.
+    NodeFound(u8),
+    NodeLost(u8),
+    // An emergency vehicle called for the green of an approach, and the call
+    // was served.
+    PreemptionStarted(Approach),
+    PreemptionEnded(Approach),
+    // The crystal of the RTC did not start, so the RTC runs from the internal
+    // oscillator, which may be off by a few minutes a day.
+    ClockCrystalFailed,
+}
+
+// Events are numbered, so that a tool can tell whether it missed any and can
+// read the event log from where it left off.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    pub sequence: u32,
+    pub timestamp_ms: u64,
+    pub kind: EventKind,
+}
+
+// The number of events that fit in a single response.
+pub const EVENTS_PER_RESPONSE: usize = 8;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Request {
+    GetStatus,
+    SetMode(SystemMode),
+    PressButton(Approach),
+    ReadConfig(ConfigKey),
+    // Changes the running configuration. The settings go back to their
+    // defaults when the controller restarts.
+    WriteConfig(ConfigKey, u32),
+    // Read the events starting from the given sequence number.
+    ReadEventLog(u32),
+    // Switch the serial port back to the human console.
+    Console,
+    ReadStatistics,
+    ResetStatistics,
+    ReadClock,
+    // Sets the wall clock, which the controller keeps in its RTC.
+    SetClock(WallTime),
+    ReadSchedule(u8),
+    // Changes an entry of the schedule, or clears it with none. Like the
+    // settings, the schedule goes back to its default on a restart.
+    WriteSchedule(u8, Option<ScheduleEntry>),
+    ReadCalendar(u8),
+    // Changes an entry of the calendar, or clears it with none.
+    WriteCalendar(u8, Option<CalendarEntry>),
+    // Calls for the green of an approach, as an emergency vehicle does, or
+    // takes the call back with none. See `preemption.rs` in `pistop-core`.
+    Preempt(Option<Approach>),
+    // Sends the manual mode on to the next approach, as the button of the
+    // officer does.
+    Advance,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum ErrorCode {
+    InvalidValue,
+}
+
+// There is no allocator on the controller, so we cannot box the larger
+// variants. Messages are short-lived, so the size does not hurt.
+#[allow(clippy::large_enum_variant)]
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok,
+    Error(ErrorCode),
+    Status(Status),
+    Config(ConfigKey, u32),
+    // The events that were found, and the sequence number to continue reading
+    // from. Events that dropped out of the log are skipped.
+    Events(Vec<Event, EVENTS_PER_RESPONSE>, u32),
+    Statistics(Statistics),
+    // None while the clock was never set.
+    Clock(Option<WallTime>),
+    Schedule(u8, Option<ScheduleEntry>),
+    Calendar(u8, Option<CalendarEntry>),
+}
+
+// What the host sends. The sequence number is copied into the response, so
+// that the host can match responses to requests.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct HostMessage {
+    pub sequence: u16,
+    pub request: Request,
+}
+
+// What the controller sends.
+#[allow(clippy::large_enum_variant)]
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub enum ControllerMessage {
+    Response { sequence: u16, response: Response },
+    Notification(Event),
+}
@@ -0,0 +1,478 @@
+/*
+ * Between events, the executor puts the core to sleep with `WFE`. That stops
+ * the core, but the clocks keep running, and so does the timer behind
+ * `embassy_time`. The F103 also has STOP mode, which stops all of the clocks
+ * and draws a few tens of microamps instead of a few milliamps. Only the RTC
+ * keeps counting, and the EXTI lines can still wake the core up.
+ *
+ * Embassy has a low-power executor that goes into STOP, but only on parts
+ * with the newer RTC, which has a wake-up timer to take over from the timer of
+ * `embassy_time`. The RTC of the F103 counts seconds, see `BackupRtc`, and its
+ * alarm can only go off on the second. So we do what embassy does, our own
+ * way:
+ *
+ * - Our time driver for `embassy_time` counts on TIM2, the way embassy's does.
+ *   Once the RTC runs from the crystal, the time follows the RTC: after a
+ *   STOP, it moves on by as much as the RTC counted, and its whole seconds are
+ *   the seconds of the RTC.
+ * - Our executor goes into STOP when the next timer is more than the next
+ *   second of the RTC away, with the alarm of the RTC set for that second. It
+ *   wakes up there and sleeps the rest of the way with `WFE`, or goes into
+ *   STOP again. So a STOP never lasts longer than a second.
+ * - The timers that don't need to be exact go off on the whole second, see
+ *   `coarse_timer` in `pistop-core`, and the cycles of the output masker start
+ *   just after it. That way, the core stops for most of the half second that
+ *   the ambers are off in flash mode, and for most of the time in dark mode.
+ *
+ * A few things keep the core out of STOP:
+ *
+ * - The RTC running from the internal oscillator, which is too far off to
+ *   keep time with, and the RTC not running yet, see `BackupRtc::start()`.
+ * - Bytes that the UART is still sending, and a conversion of the ADC.
+ * - Bytes that came in over the serial port not long ago, see
+ *   `stay_awake_for()`, and bytes that the DMA is still receiving. The UART
+ *   cannot wake the core up, so whatever comes in during a STOP is lost. After
+ *   each STOP, the core listens for a little while before it stops again. The
+ *   tools send a second and a bit of zero bytes before their first request,
+ *   which some of gets through while it listens, see `link.rs` in `pistopctl`.
+ * - The CAN link and the Modbus slave, which cannot afford to lose frames
+ *   like that. With either, the core never goes into STOP.
+ */
+
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering, compiler_fence};
+use critical_section::CriticalSection;
+use embassy_executor::{Spawner, raw};
+// The module of the interrupts, and the attribute for their handlers.
+use embassy_stm32::interrupt;
+use embassy_stm32::{
+    interrupt::InterruptExt,
+    pac::{
+        self, ADC1, DMA1, EXTI, PWR, RCC, RTC, USART1,
+        pwr::vals::Pdds,
+        rcc::vals::Rtcsel,
+        rtc::vals::Rtoff,
+        timer::{regs, vals},
+    },
+    peripherals, rcc,
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Duration;
+use embassy_time_driver::{Driver, TICK_HZ};
+use embassy_time_queue_utils::Queue;
+
+// The driver counts in microseconds, which the RTC is converted to.
+const _: () = assert!(TICK_HZ == 1_000_000);
+const MICROS_PER_SECOND: u64 = 1_000_000;
+
+// The prescaler that makes the RTC count seconds from the crystal, see
+// `BackupRtc::write()`. Its divider counts down from this to zero every second.
+const LSE_PRESCALER: u32 = 32_767;
+
+// The alarm of the RTC is on EXTI line 17.
+const RTC_ALARM_LINE: usize = 17;
+
+// The next second of the RTC has to be at least this far away to go into
+// STOP, so that it does not go by while we set the alarm.
+const MIN_STOP: u64 = 1_000;
+
+// Waking up takes the regulator, the internal oscillator and the RTC a few
+// tens of microseconds. The next timer has to be at least this far after the
+// next second of the RTC.
+const WAKE_UP_MARGIN: u64 = 1_000;
+
+// After a STOP, the core stays awake for at least this long, to listen for
+// bytes on the serial port.
+const LISTEN_AFTER_STOP: u64 = 20_000;
+
+// The DMA channel that receives from the serial port, channel 5 of DMA1.
+const SERIAL_RX_CHANNEL: usize = 4;
+
+/*
+ * The time driver. Like embassy's, it runs a 16-bit timer at 1MHz, and counts
+ * half periods of the timer in `period` on the overflow and the compare of
+ * channel 1, which leaves channel 2 for the alarm. The time is then the
+ * periods and the counter, and whatever the timer missed during STOP.
+ */
+struct StopTimeDriver {
+    period: AtomicU32,
+    // How far the time is ahead of the timer, since the timer stands still
+    // during STOP.
+    stopped: Mutex<CriticalSectionRawMutex, Cell<u64>>,
+    // The time of the RTC in microseconds subtracted from the time, once it
+    // runs from the crystal. A whole number of seconds.
+    rtc_offset: Mutex<CriticalSectionRawMutex, Cell<Option<i64>>>,
+    alarm: Mutex<CriticalSectionRawMutex, Cell<u64>>,
+    queue: Mutex<CriticalSectionRawMutex, RefCell<Queue>>,
+    // Until when the core stays out of STOP, see `stay_awake_for()`.
+    awake_until: Mutex<CriticalSectionRawMutex, Cell<u64>>,
+    // Where the DMA was in the receive buffer of the serial port, the last
+    // time we looked.
+    serial_rx_remaining: Mutex<CriticalSectionRawMutex, Cell<u16>>,
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: StopTimeDriver = StopTimeDriver {
+    period: AtomicU32::new(0),
+    stopped: Mutex::const_new(CriticalSectionRawMutex::new(), Cell::new(0)),
+    rtc_offset: Mutex::const_new(CriticalSectionRawMutex::new(), Cell::new(None)),
+    alarm: Mutex::const_new(CriticalSectionRawMutex::new(), Cell::new(u64::MAX)),
+    queue: Mutex::new(RefCell::new(Queue::new())),
+    awake_until: Mutex::const_new(CriticalSectionRawMutex::new(), Cell::new(0)),
+    serial_rx_remaining: Mutex::const_new(CriticalSectionRawMutex::new(), Cell::new(0)),
+});
+
+#[interrupt]
+fn TIM2() {
+    DRIVER.on_interrupt()
+}
+
+fn calc_now(period: u32, counter: u16) -> u64 {
+    ((period as u64) << 15) + ((counter as u32 ^ ((period & 1) << 15)) as u64)
+}
+
+impl StopTimeDriver {
+    fn init(&self, cs: CriticalSection) {
+        rcc::enable_and_reset_with_cs::<peripherals::TIM2>(cs);
+        pac::TIM2.cr1().modify(|w| w.set_cen(false));
+        pac::TIM2.cnt().write(|w| w.set_cnt(0));
+
+        let prescaler: u32 = rcc::frequency::<peripherals::TIM2>().0 / TICK_HZ as u32 - 1;
+        pac::TIM2.psc().write_value(prescaler as u16);
+        pac::TIM2.arr().write(|w| w.set_arr(u16::MAX));
+
+        // Load the prescaler without raising the update interrupt.
+        pac::TIM2
+            .cr1()
+            .modify(|w| w.set_urs(vals::Urs::COUNTER_ONLY));
+        pac::TIM2.egr().write(|w| w.set_ug(true));
+        pac::TIM2.cr1().modify(|w| w.set_urs(vals::Urs::ANY_EVENT));
+
+        // Half way round, for the half periods.
+        pac::TIM2.ccr(0).write(|w| w.set_ccr(0x8000));
+        pac::TIM2.dier().write(|w| {
+            w.set_uie(true);
+            w.set_ccie(0, true);
+        });
+
+        interrupt::TIM2.unpend();
+        // SAFETY: the handler above only takes the critical section.
+        unsafe { interrupt::TIM2.enable() };
+        pac::TIM2.cr1().modify(|w| w.set_cen(true));
+    }
+
+    fn timer_now(&self) -> u64 {
+        let period: u32 = self.period.load(Ordering::Relaxed);
+        compiler_fence(Ordering::Acquire);
+        let counter: u16 = pac::TIM2.cnt().read().cnt();
+        calc_now(period, counter)
+    }
+
+    fn on_interrupt(&self) {
+        critical_section::with(|cs| {
+            let sr: regs::SrGp16 = pac::TIM2.sr().read();
+            let dier: regs::DierGp16 = pac::TIM2.dier().read();
+            // Clear the flags that we are about to handle, and only those.
+            pac::TIM2.sr().write_value(regs::SrGp16(!sr.0));
+
+            if sr.uif() {
+                self.next_period(cs);
+            }
+            if sr.ccif(0) {
+                self.next_period(cs);
+            }
+            if sr.ccif(1) && dier.ccie(1) {
+                self.trigger_alarm(cs);
+            }
+        })
+    }
+
+    fn next_period(&self, cs: CriticalSection) {
+        let period: u32 = self.period.load(Ordering::Relaxed) + 1;
+        self.period.store(period, Ordering::Relaxed);
+        let t: u64 = (period as u64) << 15;
+
+        // The compare of the alarm only looks at the counter, so we only
+        // enable it in the period before the alarm.
+        let at: u64 = self
+            .alarm
+            .borrow(cs)
+            .get()
+            .saturating_sub(self.stopped.borrow(cs).get());
+        if at < t + 0xc000 {
+            pac::TIM2.dier().modify(|w| w.set_ccie(1, true));
+        }
+    }
+
+    fn trigger_alarm(&self, cs: CriticalSection) {
+        let mut next: u64 = self
+            .queue
+            .borrow(cs)
+            .borrow_mut()
+            .next_expiration(self.now());
+        while !self.set_alarm(cs, next) {
+            next = self
+                .queue
+                .borrow(cs)
+                .borrow_mut()
+                .next_expiration(self.now());
+        }
+    }
+
+    // Returns false when the time is already past, in which case the alarm
+    // may or may not have gone off, and the caller has to look at the queue
+    // again.
+    fn set_alarm(&self, cs: CriticalSection, timestamp: u64) -> bool {
+        self.alarm.borrow(cs).set(timestamp);
+
+        let t: u64 = self.now();
+        if timestamp <= t {
+            pac::TIM2.dier().modify(|w| w.set_ccie(1, false));
+            self.alarm.borrow(cs).set(u64::MAX);
+            return false;
+        }
+
+        let timer_timestamp: u64 = timestamp - self.stopped.borrow(cs).get();
+        pac::TIM2
+            .ccr(1)
+            .write(|w| w.set_ccr(timer_timestamp as u16));
+        // Otherwise, `next_period()` enables it when it's time.
+        pac::TIM2
+            .dier()
+            .modify(|w| w.set_ccie(1, timestamp - t < 0xc000));
+
+        let t: u64 = self.now();
+        if timestamp <= t {
+            pac::TIM2.dier().modify(|w| w.set_ccie(1, false));
+            self.alarm.borrow(cs).set(u64::MAX);
+            return false;
+        }
+        true
+    }
+
+    // Moves the time on, for a time that the timer stood still.
+    fn add_time(&self, cs: CriticalSection, ticks: u64) {
+        let stopped: &Cell<u64> = self.stopped.borrow(cs);
+        stopped.set(stopped.get() + ticks);
+        if !self.set_alarm(cs, self.alarm.borrow(cs).get()) {
+            self.trigger_alarm(cs);
+        }
+    }
+
+    // Catches up with the RTC, if it is ahead. When the timer ran fast since
+    // the last time, the RTC catches up with us instead.
+    fn follow_rtc(&self, cs: CriticalSection) {
+        if let Some(rtc_offset) = self.rtc_offset.borrow(cs).get() {
+            let rtc: i64 = rtc_micros() as i64 + rtc_offset;
+            let now: i64 = self.now() as i64;
+            if rtc > now {
+                self.add_time(cs, (rtc - now) as u64);
+            }
+        }
+    }
+}
+
+impl Driver for StopTimeDriver {
+    fn now(&self) -> u64 {
+        critical_section::with(|cs| self.timer_now() + self.stopped.borrow(cs).get())
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &core::task::Waker) {
+        critical_section::with(|cs| {
+            let mut queue = self.queue.borrow(cs).borrow_mut();
+            if queue.schedule_wake(at, waker) {
+                let mut next: u64 = queue.next_expiration(self.now());
+                while !self.set_alarm(cs, next) {
+                    next = queue.next_expiration(self.now());
+                }
+            }
+        })
+    }
+}
+
+// The time of the RTC in microseconds, from its counter and the divider of its
+// prescaler. Only for an RTC that runs from the crystal.
+fn rtc_micros() -> u64 {
+    // The counter may carry while we read the divider.
+    loop {
+        let high: u16 = RTC.cnth().read().cnth();
+        let low: u16 = RTC.cntl().read().cntl();
+        let divider: u32 =
+            (RTC.divh().read().divh() as u32) << 16 | RTC.divl().read().divl() as u32;
+        if RTC.cntl().read().cntl() == low && RTC.cnth().read().cnth() == high {
+            let seconds: u64 = (high as u64) << 16 | low as u64;
+            let ticks: u64 = (LSE_PRESCALER - divider.min(LSE_PRESCALER)) as u64;
+            return seconds * MICROS_PER_SECOND
+                + ticks * MICROS_PER_SECOND / (LSE_PRESCALER as u64 + 1);
+        }
+    }
+}
+
+fn wait_for_rtc_write() {
+    while RTC.crl().read().rtoff() == Rtoff::ONGOING {}
+}
+
+// Starts the time driver. Before this, the time stands at zero.
+pub fn init(_tim2: peripherals::TIM2) {
+    critical_section::with(|cs| DRIVER.init(cs));
+    // For the regulator settings of STOP.
+    RCC.apb1enr().modify(|w| w.set_pwren(true));
+    // The debug probe loses the core in STOP, unless we keep its clock going.
+    #[cfg(feature = "defmt")]
+    pac::DBGMCU.cr().modify(|w| w.set_dbg_stop(true));
+}
+
+// Call once the RTC runs, and whenever its counter was set. From then on, the
+// time follows the RTC, which may move it on by up to a second. Until the RTC
+// runs from the crystal, the core stays out of STOP.
+pub fn follow_rtc() {
+    let rtc_running: bool = {
+        let bdcr = RCC.bdcr().read();
+        bdcr.rtcen() && bdcr.rtcsel() == Rtcsel::LSE
+    };
+    critical_section::with(|cs| {
+        if !rtc_running {
+            DRIVER.rtc_offset.borrow(cs).set(None);
+            return;
+        }
+        // A whole number of seconds, so that the seconds line up, and such
+        // that the time only ever moves on.
+        let ahead: i64 = DRIVER.now() as i64 - rtc_micros() as i64;
+        let rtc_offset: i64 =
+            -(-ahead).div_euclid(MICROS_PER_SECOND as i64) * MICROS_PER_SECOND as i64;
+        DRIVER.rtc_offset.borrow(cs).set(Some(rtc_offset));
+        DRIVER.follow_rtc(cs);
+    });
+}
+
+// Keeps the core out of STOP for a while, for the serial port to be able to
+// receive.
+pub fn stay_awake_for(duration: Duration) {
+    critical_section::with(|cs| {
+        let awake_until: &Cell<u64> = DRIVER.awake_until.borrow(cs);
+        awake_until.set(awake_until.get().max(DRIVER.now() + duration.as_ticks()));
+    });
+}
+
+// Whether the core may go into STOP until the next second of the RTC, and if
+// so, sets everything up for it. The `WFE` that follows then goes into STOP.
+fn prepare_stop(cs: CriticalSection) -> bool {
+    if cfg!(any(feature = "can-link", feature = "modbus")) {
+        return false;
+    }
+    let Some(_) = DRIVER.rtc_offset.borrow(cs).get() else {
+        return false;
+    };
+    // The DMA only tells the receiving task about the bytes once the line
+    // goes idle, or the buffer is half full.
+    let serial_rx_remaining: u16 = DMA1.ch(SERIAL_RX_CHANNEL).ndtr().read().ndt();
+    if DRIVER
+        .serial_rx_remaining
+        .borrow(cs)
+        .replace(serial_rx_remaining)
+        != serial_rx_remaining
+    {
+        return false;
+    }
+    let now: u64 = DRIVER.now();
+    if now < DRIVER.awake_until.borrow(cs).get()
+        || !USART1.sr().read().tc()
+        || ADC1.cr1().read().eocie()
+    {
+        return false;
+    }
+
+    let rtc: u64 = rtc_micros();
+    let until_second: u64 = MICROS_PER_SECOND - rtc % MICROS_PER_SECOND;
+    if until_second < MIN_STOP
+        || now + until_second + WAKE_UP_MARGIN > DRIVER.alarm.borrow(cs).get()
+    {
+        return false;
+    }
+
+    let second: u32 = (rtc / MICROS_PER_SECOND) as u32 + 1;
+    wait_for_rtc_write();
+    RTC.crl().modify(|w| w.set_cnf(true));
+    RTC.alrh().write(|w| w.set_alrh((second >> 16) as u16));
+    RTC.alrl().write(|w| w.set_alrl(second as u16));
+    RTC.crl().modify(|w| w.set_cnf(false));
+    wait_for_rtc_write();
+    // The alarm only goes off as the counter gets to it.
+    if rtc_micros() / MICROS_PER_SECOND >= second as u64 {
+        return false;
+    }
+
+    // The alarm interrupt never gets past the NVIC, but ST's own examples
+    // enable it for the alarm to wake the core, so we do the same.
+    RTC.crl().modify(|w| w.set_alrf(false));
+    RTC.crh().modify(|w| w.set_alrie(true));
+    EXTI.pr(0).write(|w| w.set_line(RTC_ALARM_LINE, true));
+    EXTI.rtsr(0).modify(|w| w.set_line(RTC_ALARM_LINE, true));
+    EXTI.emr(0).modify(|w| w.set_line(RTC_ALARM_LINE, true));
+
+    // STOP rather than standby, with the regulator in low-power mode.
+    PWR.cr().modify(|w| {
+        w.set_pdds(Pdds::STOP_MODE);
+        w.set_lpds(true);
+    });
+    // SAFETY: only the executor touches SLEEPDEEP.
+    unsafe { cortex_m::Peripherals::steal() }
+        .SCB
+        .set_sleepdeep();
+    true
+}
+
+// After the `WFE`, whether it went into STOP or not. The core runs from the
+// internal oscillator again, which it does all the time anyway.
+fn resume_from_stop() {
+    // SAFETY: only the executor touches SLEEPDEEP.
+    unsafe { cortex_m::Peripherals::steal() }
+        .SCB
+        .clear_sleepdeep();
+    // The registers of the RTC are only valid to read again once they have
+    // been synchronised with the bus.
+    RTC.crl().modify(|w| w.set_rsf(false));
+    while !RTC.crl().read().rsf() {}
+    RTC.crl().modify(|w| w.set_alrf(false));
+    EXTI.pr(0).write(|w| w.set_line(RTC_ALARM_LINE, true));
+    critical_section::with(|cs| {
+        DRIVER.follow_rtc(cs);
+        let awake_until: &Cell<u64> = DRIVER.awake_until.borrow(cs);
+        awake_until.set(awake_until.get().max(DRIVER.now() + LISTEN_AFTER_STOP));
+    });
+}
+
+/*
+ * The thread mode executor of embassy, with STOP. It polls its tasks until
+ * they have nothing left to do, and then waits for an event. A task that gets
+ * woken up sends one, since embassy's pender does so for the context of
+ * `usize::MAX`.
+ */
+pub struct Executor {
+    inner: raw::Executor,
+    not_send: PhantomData<*mut ()>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            inner: raw::Executor::new(usize::MAX as *mut ()),
+            not_send: PhantomData,
+        }
+    }
+
+    pub fn run(&'static mut self, init: impl FnOnce(Spawner)) -> ! {
+        init(self.inner.spawner());
+        loop {
+            // SAFETY: the executor lives forever, in thread mode only.
+            unsafe { self.inner.poll() };
+            // An interrupt that wakes a task after this sends an event, and
+            // then the `WFE` returns straight away.
+            let stopping: bool = critical_section::with(prepare_stop);
+            cortex_m::asm::wfe();
+            if stopping {
+                resume_from_stop();
+            }
+        }
+    }
+}
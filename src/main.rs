@@ -4,240 +4,120 @@
 // https://dev.to/theembeddedrustacean/embedded-rust-embassy-gpio-button-controlled-blinking-3ee6
 // https://www.youtube.com/watch?v=dab_vzVDr_M
 
-use core::sync::atomic::{AtomicBool, Ordering};
+/*
+ * The firmware wires the controller logic in `pistop-core` up to the board.
+ * It owns the peripherals, hands them to the tasks of the controller and runs
+ * the output loop that drives the pins.
+ */
+
+mod low_power;
+
+use core::sync::atomic::{AtomicBool, AtomicU32};
 use embassy_executor::Spawner;
+use embassy_futures::select::select;
 use embassy_stm32::{
+    adc::{self, Adc, AdcChannel, AnyAdcChannel, SampleTime, Vref},
     bind_interrupts,
-    gpio::{Input, Level, Output, Pin, Pull, Speed},
+    exti::ExtiInput,
+    flash::{Blocking, Flash},
+    gpio::{Level, Output, Pin, Pull, Speed},
     mode::Async,
-    peripherals::USART1,
-    usart::{Config, InterruptHandler, Uart},
+    pac::{BKP, RCC, RTC, rcc::vals::Rtcsel, rtc::vals::Rtoff},
+    peripherals::{ADC1, USART1},
+    rcc::LsConfig,
+    usart::{self, Config, RingBufferedUartRx, Uart, UartTx},
 };
 use embassy_sync::{
     blocking_mutex::raw::ThreadModeRawMutex,
     mutex::{Mutex, MutexGuard},
-    semaphore::{FairSemaphore, Semaphore},
     signal::Signal,
 };
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
 use enum_ordinalize::Ordinalize;
+use pistop_core::{
+    battery_monitor::{self, BatterySensor},
+    calendar::Calendar,
+    event_log::EventLog,
+    faults::Faults,
+    green_wave::{self, GreenWave},
+    host_link::HostLink,
+    lights::{self, PedestrianLights, PedestrianPins, TrafficLights, TrafficPins},
+    log::Log,
+    manual,
+    modes::{self, CrossingSemaphore, SystemModeHandler},
+    pelican,
+    preemption::{self, Preemption},
+    railway,
+    schedule::{self, Schedule},
+    schedule_storage::{self, STORED_SIZE, ScheduleStorage},
+    serial::Serial,
+    settings::Settings,
+    statistics::Statistics,
+    timed_output_masker::{Pins, TimedOutputMasker},
+    trace,
+    wall_clock::{self, Rtc, WallClock},
+    warn,
+};
+use pistop_protocol::{Approach, SystemMode, WallTime};
+
+#[cfg(not(feature = "defmt"))]
 use panic_halt as _;
+#[cfg(feature = "defmt")]
+use {defmt_rtt as _, panic_probe as _};
 
-mod timed_output_masker;
-use timed_output_masker::{Pins, TimedOutputMasker};
+#[cfg(feature = "lamp-monitor")]
+use embassy_stm32::gpio::Input;
+#[cfg(feature = "can-link")]
+use embassy_stm32::{
+    can::{self, Can, Fifo, Frame, Id, filter::Mask32},
+    pac::AFIO,
+    peripherals::CAN,
+};
+#[cfg(feature = "can-link")]
+use pistop_core::can_link::{self, CanBus, CanFrame, CanLink};
+#[cfg(not(feature = "modbus"))]
+use pistop_core::host_link;
+#[cfg(feature = "lamp-monitor")]
+use pistop_core::lamp_monitor;
+#[cfg(not(feature = "defmt"))]
+use pistop_core::log;
+#[cfg(feature = "modbus")]
+use pistop_core::modbus::{self, FrameTiming};
 
 const IO_INIT_ERROR: &str = "I/O init error";
 
-#[derive(PartialEq, Eq, Copy, Clone)]
-pub enum SystemMode {
-    Normal,
-    Flash,
-    PriorityA,
-    PriorityB,
-}
-
-struct TrafficLights {
-    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
-    red: Pins,
-    amber: Pins,
-    green: Pins,
-}
-
-impl TrafficLights {
-    const fn new(
-        lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
-        red: Pins,
-        amber: Pins,
-        green: Pins,
-    ) -> Self {
-        TrafficLights {
-            lights: lights,
-            red: red,
-            amber: amber,
-            green: green,
-        }
-    }
-
-    async fn go_attention(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-        lights.set_on_off3(self.red, true, self.amber, true, self.green, false);
-    }
-    async fn go_go(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-        lights.set_on_off3(self.red, false, self.amber, false, self.green, true);
-    }
-    async fn go_flash(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-        lights.set_on_off2(self.red, false, self.green, false);
-        lights.set_pin(self.amber, true, true, false, false);
-    }
-    async fn go_yield(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-        lights.set_on_off3(self.red, false, self.amber, true, self.green, false);
-    }
-    async fn go_yield_flash(&self) {
-        self.go_yield().await;
-    }
-    async fn go_clear(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-        lights.set_on_off3(self.red, true, self.amber, false, self.green, false);
-    }
-}
-
-struct PedestrianLights {
-    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
-    red: Pins,
-    green: Pins,
-    beeper: Pins,
-    promise: Pins,
-    old_promise: AtomicBool,
-    active: AtomicBool,
-    promise_made: AtomicBool,
-}
-
-impl PedestrianLights {
-    const fn new(
-        lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
-        red: Pins,
-        green: Pins,
-        beeper: Pins,
-        promise: Pins,
-    ) -> Self {
-        PedestrianLights {
-            lights: lights,
-            red: red,
-            green: green,
-            beeper: beeper,
-            promise: promise,
-            old_promise: AtomicBool::new(false),
-            active: AtomicBool::new(false),
-            promise_made: AtomicBool::new(false),
-        }
-    }
-
-    async fn go_attention(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-
-        lights.set_on_off2(self.red, true, self.green, false);
-
-        self.active.store(true, Ordering::Relaxed);
-    }
-    async fn go_go(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-        let active_promise =
-            self.active.load(Ordering::Relaxed) && self.promise_made.load(Ordering::Relaxed);
-
-        lights.set_on_off2(self.red, !active_promise, self.green, active_promise);
-        lights.set_pin(self.beeper, active_promise, false, true, false);
-
-        self.old_promise.store(active_promise, Ordering::Relaxed);
-        self.promise_made.store(false, Ordering::Relaxed);
-        lights.set_on_off(self.promise, false);
-    }
-    async fn go_flash(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-
-        lights.set_on_off3(self.red, false, self.green, false, self.beeper, false);
-
-        self.old_promise.store(false, Ordering::Relaxed);
-        self.active.store(false, Ordering::Relaxed);
-        self.promise_made.store(false, Ordering::Relaxed);
-        lights.set_on_off(self.promise, false);
-    }
-    async fn go_yield_flash(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-
-        lights.set_on_off3(self.red, false, self.green, false, self.beeper, false);
-
-        self.old_promise.store(false, Ordering::Relaxed);
-        self.active.store(false, Ordering::Relaxed);
-        self.promise_made.store(false, Ordering::Relaxed);
-        lights.set_on_off(self.promise, false);
-    }
-    async fn go_yield(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-        let active_old_promise =
-            self.active.load(Ordering::Relaxed) && self.old_promise.load(Ordering::Relaxed);
-
-        lights.set_pin(self.beeper, active_old_promise, true, true, false);
-        lights.set_on_off(self.red, !active_old_promise);
-        lights.set_pin(self.green, active_old_promise, true, false, false);
-    }
-    async fn go_clear(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-
-        lights.set_on_off2(self.red, true, self.green, false);
-        lights.set_on_off(self.beeper, false);
-    }
-
-    async fn make_promise(&self) {
-        let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
-            self.lights.lock().await;
-
-        self.promise_made.store(true, Ordering::Relaxed);
-        lights.set_on_off(self.promise, true);
-        lights.set_pin(
-            self.beeper,
-            self.active.load(Ordering::Relaxed),
-            false,
-            false,
-            true,
-        );
-    }
-}
-
-type CrossingSemaphore = FairSemaphore<ThreadModeRawMutex, 8>;
-
 // When the system starts, we don't know what happened before the shutdown. We
 // cannot trust the mode input, since it may be in debounce. Thus, we start in
 // lockout mode, so that all traffic on the crossing is cleared and barred from
 // entering. Maybe not efficient, but certainly safe.
 static LOCKOUT: AtomicBool = AtomicBool::new(true);
 
+/*
+ * The tasks of the controller. Embassy tasks cannot be generic, so each task
+ * here is a thin wrapper that takes its peripherals and runs the matching
+ * function from `pistop-core`.
+ */
+
 #[embassy_executor::task(pool_size = 2)]
 async fn normal_mode_task(
     semaphore: &'static CrossingSemaphore,
     traffic_lights: &'static TrafficLights,
     pedestrian_lights: &'static PedestrianLights,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    statistics: &'static Statistics,
+    green_wave: Option<&'static GreenWave>,
+    preemption: &'static Preemption,
 ) -> ! {
-    loop {
-        // we use this scope to safely hold the permit from the semaphore
-        // for normal run mode.
-        let _permit = semaphore.acquire(1).await.unwrap();
-
-        // Attention Phase
-        traffic_lights.go_attention().await;
-        pedestrian_lights.go_attention().await;
-        Timer::after_millis(3_000).await;
-
-        // Go Phase, with pedestrian light handling
-        traffic_lights.go_go().await;
-        pedestrian_lights.go_go().await;
-        Timer::after_millis(8_000).await;
-
-        // Yield Phase
-        traffic_lights.go_yield().await;
-        pedestrian_lights.go_yield().await;
-        Timer::after_millis(6_000).await;
-
-        // Clear Crossing Phase
-        traffic_lights.go_clear().await;
-        pedestrian_lights.go_clear().await;
-        Timer::after_millis(4_000).await;
-
-        // _permit is released here...
-    }
+    modes::normal_mode(
+        semaphore,
+        traffic_lights,
+        pedestrian_lights,
+        settings,
+        statistics,
+        green_wave,
+        preemption,
+    )
+    .await
 }
 
 #[embassy_executor::task(pool_size = 1)]
@@ -248,324 +128,652 @@ async fn flash_mode_task(
     pedestrian_lights_a: &'static PedestrianLights,
     pedestrian_lights_b: &'static PedestrianLights,
     lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
 ) -> ! {
-    loop {
-        // we use this scope to safely hold the permit from the semaphore
-        // for flashing run mode.
-        let _permit = semaphore.acquire(1).await.unwrap();
-
-        // Flashing Phase
-        traffic_lights_a.go_flash().await;
-        traffic_lights_b.go_flash().await;
-        pedestrian_lights_a.go_flash().await;
-        pedestrian_lights_b.go_flash().await;
-
-        while !lockout.load(Ordering::Relaxed) {
-            Timer::after_millis(2_000).await;
-        }
-
-        // Yield Phase
-        traffic_lights_a.go_yield_flash().await;
-        traffic_lights_b.go_yield_flash().await;
-        pedestrian_lights_a.go_yield_flash().await;
-        pedestrian_lights_b.go_yield_flash().await;
-        Timer::after_millis(3_000).await;
-
-        // Clear Crossing Phase
-        traffic_lights_a.go_clear().await;
-        traffic_lights_b.go_clear().await;
-        pedestrian_lights_a.go_clear().await;
-        pedestrian_lights_b.go_clear().await;
-        Timer::after_millis(4_000).await;
-
-        // _permit is released here...
-    }
+    modes::flash_mode(
+        semaphore,
+        traffic_lights_a,
+        traffic_lights_b,
+        pedestrian_lights_a,
+        pedestrian_lights_b,
+        lockout,
+        statistics,
+    )
+    .await
 }
 
 #[embassy_executor::task(pool_size = 2)]
 async fn priority_mode_task(
+    mode: SystemMode,
     semaphore: &'static CrossingSemaphore,
     traffic_lights: &'static TrafficLights,
     pedestrian_lights: &'static PedestrianLights,
     lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    preemption: &'static Preemption,
 ) -> ! {
-    loop {
-        // we use this scope to safely hold the permit from the semaphore
-        // for normal run mode.
-        let _permit = semaphore.acquire(1).await.unwrap();
-
-        // no pedestrians while emergency services pass
-        pedestrian_lights.go_clear().await;
-
-        // Attention Phase
-        traffic_lights.go_attention().await;
-        Timer::after_millis(1_500).await;
+    modes::priority_mode(
+        mode,
+        semaphore,
+        traffic_lights,
+        pedestrian_lights,
+        lockout,
+        statistics,
+        preemption,
+    )
+    .await
+}
 
-        // Go Phase
-        traffic_lights.go_go().await;
-        Timer::after_millis(4_000).await;
+#[embassy_executor::task(pool_size = 1)]
+async fn railway_mode_task(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+) -> ! {
+    modes::railway_mode(
+        semaphore,
+        traffic_lights,
+        pedestrian_lights,
+        settings,
+        lockout,
+        statistics,
+    )
+    .await
+}
 
-        // crude...
-        while !lockout.load(Ordering::Relaxed) {
-            Timer::after_millis(500).await;
-        }
+#[embassy_executor::task(pool_size = 1)]
+async fn all_red_mode_task(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    preemption: &'static Preemption,
+) -> ! {
+    modes::all_red_mode(
+        semaphore,
+        traffic_lights,
+        pedestrian_lights,
+        lockout,
+        statistics,
+        preemption,
+    )
+    .await
+}
 
-        // Yield Phase
-        traffic_lights.go_yield().await;
-        Timer::after_millis(3_000).await;
+#[embassy_executor::task(pool_size = 1)]
+async fn dark_mode_task(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+) -> ! {
+    modes::dark_mode(
+        semaphore,
+        traffic_lights,
+        pedestrian_lights,
+        lights,
+        lockout,
+        statistics,
+    )
+    .await
+}
 
-        // Clear Crossring Phase
-        traffic_lights.go_clear().await;
-        Timer::after_millis(2_000).await;
+#[embassy_executor::task(pool_size = 1)]
+async fn pelican_mode_task(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    detected: &'static AtomicBool,
+) -> ! {
+    modes::pelican_mode(
+        semaphore,
+        traffic_lights,
+        pedestrian_lights,
+        settings,
+        lockout,
+        statistics,
+        detected,
+    )
+    .await
+}
 
-        // _permit is released here...
-    }
+#[embassy_executor::task(pool_size = 1)]
+async fn manual_mode_task(
+    semaphore: &'static CrossingSemaphore,
+    traffic_lights: [&'static TrafficLights; Approach::VARIANT_COUNT],
+    pedestrian_lights: [&'static PedestrianLights; Approach::VARIANT_COUNT],
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lockout: &'static AtomicBool,
+    statistics: &'static Statistics,
+    advance: &'static Signal<ThreadModeRawMutex, ()>,
+) -> ! {
+    modes::manual_mode(
+        semaphore,
+        traffic_lights,
+        pedestrian_lights,
+        settings,
+        lockout,
+        statistics,
+        advance,
+    )
+    .await
 }
 
 #[embassy_executor::task(pool_size = 1)]
 async fn system_mode_reader_task(
-    serial: &'static Mutex<ThreadModeRawMutex, Option<Uart<'static, Async>>>,
-    mode_inputs_option: &'static Mutex<ThreadModeRawMutex, Option<[Input<'static>; 3]>>,
+    log: &'static Log,
+    mode_inputs_option: &'static Mutex<ThreadModeRawMutex, Option<[ExtiInput<'static>; 3]>>,
     initial_mode: SystemMode,
     system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
 ) -> ! {
-    let mode_inputs: [Input<'_>; 3] = mode_inputs_option.lock().await.take().expect(IO_INIT_ERROR);
-    let mut current_mode: SystemMode = initial_mode;
-    loop {
-        print(
-            serial,
-            "mode reader:                     awaiting user action.\r\n",
-        )
-        .await;
-        #[allow(unused_assignments)]
-        let mut new_mode = current_mode;
-        'await_change: loop {
-            Timer::after_millis(200).await;
-            new_mode = read_system_mode(&mode_inputs);
-            if new_mode != current_mode {
-                print(
-                    serial,
-                    "mode reader:                     breaking await user action.\r\n",
-                )
-                .await;
-                break 'await_change;
-            }
-        }
+    let mut mode_inputs: [ExtiInput<'_>; 3] =
+        mode_inputs_option.lock().await.take().expect(IO_INIT_ERROR);
+    modes::system_mode_reader(log, &mut mode_inputs, initial_mode, system_mode_signal).await
+}
 
-        // So there was a change, but we don't want to signal that change just
-        // yet. The rotary switch goes through all intermediate values and needs
-        // serious debouncing before it can be read reliably. Also, the user may
-        // have overshot the mode they want, so we want to give them a second to
-        // check the setting before it becomes file. In fact, we will use a
-        // literal second.
+#[embassy_executor::task(pool_size = 1)]
+async fn system_mode_task(handler: &'static SystemModeHandler, start_mode: SystemMode) -> ! {
+    modes::system_mode(handler, start_mode).await
+}
 
-        print(
-            serial,
-            "mode reader:                     awaiting debounce.\r\n",
-        )
-        .await;
-        'await_debounce: loop {
-            Timer::after_millis(1_000).await;
-            let debounced_mode: SystemMode = read_system_mode(&mode_inputs);
-            if debounced_mode == new_mode {
-                print(
-                    serial,
-                    "mode reader:                     breaking debounce.\r\n",
-                )
-                .await;
-                break 'await_debounce;
-            } else {
-                new_mode = debounced_mode;
-            }
+#[embassy_executor::task(pool_size = 2)]
+async fn promise_input_task(
+    input_option: &'static Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>>,
+    pedestrian_lights: &'static PedestrianLights,
+) -> ! {
+    let mut input: ExtiInput = input_option.lock().await.take().expect(IO_INIT_ERROR);
+    lights::promise_input(&mut input, pedestrian_lights).await
+}
+
+#[embassy_executor::task(pool_size = 1)]
+async fn preemption_inputs_task(
+    log: &'static Log,
+    preemption: &'static Preemption,
+    inputs_option: &'static Mutex<ThreadModeRawMutex, Option<[ExtiInput<'static>; 2]>>,
+) -> ! {
+    let mut inputs: [ExtiInput<'_>; 2] = inputs_option.lock().await.take().expect(IO_INIT_ERROR);
+    preemption::preemption_inputs(log, preemption, &mut inputs).await
+}
+
+#[embassy_executor::task(pool_size = 1)]
+async fn track_circuit_task(
+    log: &'static Log,
+    preemption: &'static Preemption,
+    input_option: &'static Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>>,
+) -> ! {
+    let mut input: ExtiInput = input_option.lock().await.take().expect(IO_INIT_ERROR);
+    railway::track_circuit(log, preemption, &mut input).await
+}
+
+#[embassy_executor::task(pool_size = 1)]
+async fn advance_input_task(
+    log: &'static Log,
+    input_option: &'static Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>>,
+    advance: &'static Signal<ThreadModeRawMutex, ()>,
+) -> ! {
+    let mut input: ExtiInput = input_option.lock().await.take().expect(IO_INIT_ERROR);
+    manual::advance_input(log, &mut input, advance).await
+}
+
+#[embassy_executor::task(pool_size = 1)]
+async fn kerbside_detector_task(
+    log: &'static Log,
+    input_option: &'static Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>>,
+    detected: &'static AtomicBool,
+) -> ! {
+    let mut input: ExtiInput = input_option.lock().await.take().expect(IO_INIT_ERROR);
+    pelican::kerbside_detector(log, &mut input, detected).await
+}
+
+#[embassy_executor::task(pool_size = 1)]
+async fn sync_pulses_task(
+    log: &'static Log,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    green_wave: &'static GreenWave,
+    sync_input_option: &'static Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>>,
+    sync_output_option: &'static Mutex<ThreadModeRawMutex, Option<Output<'static>>>,
+) -> ! {
+    let mut sync_input: ExtiInput = sync_input_option.lock().await.take().expect(IO_INIT_ERROR);
+    let mut sync_output: Output = sync_output_option.lock().await.take().expect(IO_INIT_ERROR);
+    green_wave::sync_pulses(log, settings, green_wave, &mut sync_input, &mut sync_output).await
+}
+
+// The bxCAN controller, which takes frames with standard identifiers only.
+#[cfg(feature = "can-link")]
+struct CanPort(Can<'static>);
+
+#[cfg(feature = "can-link")]
+impl CanBus for CanPort {
+    fn send(&mut self, frame: &CanFrame) {
+        if let Ok(frame) = Frame::new_standard(frame.id, &frame.data) {
+            // Full when nobody acknowledges our frames, see `CanBus`.
+            let _ = self.0.try_write(&frame);
         }
+    }
 
-        // Finally, suppress signalling if there is no actual change. This
-        // reduces the chance of glitches due to quick mode switches.
-
-        if current_mode != new_mode {
-            current_mode = new_mode;
-            match current_mode {
-                SystemMode::Normal => {
-                    print(
-                        serial,
-                        "mode reader:                     signalling SystemMode::Normal.\r\n",
-                    )
-                    .await
-                }
-                SystemMode::Flash => {
-                    print(
-                        serial,
-                        "mode reader:                     signalling SystemMode::Flash.\r\n",
-                    )
-                    .await
-                }
-                SystemMode::PriorityA => {
-                    print(
-                        serial,
-                        "mode reader:                     signalling SystemMode::PriorityA.\r\n",
-                    )
-                    .await
-                }
-                SystemMode::PriorityB => {
-                    print(
-                        serial,
-                        "mode reader:                     signalling SystemMode::PriorityB.\r\n",
-                    )
-                    .await
-                }
+    async fn receive(&mut self) -> CanFrame {
+        loop {
+            // Errors on the bus are the business of the controller, which
+            // retransmits by itself.
+            let Ok(envelope) = self.0.read().await else {
+                continue;
+            };
+            let (frame, _) = envelope.parts();
+            if let Id::Standard(id) = frame.id()
+                && let Ok(data) = frame.data().try_into()
+            {
+                return CanFrame {
+                    id: id.as_raw(),
+                    data: data,
+                };
             }
-            system_mode_signal.signal(current_mode);
         }
     }
 }
 
-// Read the raw value from the system mode rotary switch. The result of this
-// value has to be debounced before it can be used reliably.
-fn read_system_mode(mode_inputs: &[Input; 3]) -> SystemMode {
-    match (
-        mode_inputs[0].is_low(),
-        mode_inputs[1].is_low(),
-        mode_inputs[2].is_low(),
-    ) {
-        (false, false, false) => SystemMode::Normal,
-        (true, _, _) => SystemMode::Flash,
-        (_, true, _) => SystemMode::PriorityA,
-        (_, _, true) => SystemMode::PriorityB,
+#[cfg(feature = "can-link")]
+#[embassy_executor::task(pool_size = 1)]
+async fn can_link_task(can_link: &'static CanLink, mut can: Can<'static>) -> ! {
+    // Waits for the bus to go quiet, which it never does without a
+    // transceiver. So we do that here rather than in `main()`.
+    can.enable().await;
+    can_link::can_link(can_link, &mut CanPort(can)).await
+}
+
+// The ADC, the input channel for the battery divider and the internal
+// reference travel together.
+struct BatteryAdc {
+    adc: Adc<'static, ADC1>,
+    battery_input: AnyAdcChannel<ADC1>,
+    vref: Vref,
+}
+
+impl BatteryAdc {
+    fn new(mut adc: Adc<'static, ADC1>, battery_input: AnyAdcChannel<ADC1>) -> Self {
+        // The internal reference needs a long sample time, the datasheet asks
+        // for at least 17.1us. The divider has a high impedance too, so we
+        // simply use the longest sample time for both.
+        adc.set_sample_time(SampleTime::CYCLES239_5);
+        let vref: Vref = adc.enable_vref();
+        BatteryAdc {
+            adc: adc,
+            battery_input: battery_input,
+            vref: vref,
+        }
+    }
+}
+
+impl BatterySensor for BatteryAdc {
+    async fn read(&mut self) -> (u16, u16) {
+        let vref_raw: u16 = self.adc.read(&mut self.vref).await;
+        let raw: u16 = self.adc.read(&mut self.battery_input).await;
+        (raw, vref_raw)
     }
 }
 
 #[embassy_executor::task(pool_size = 1)]
-async fn system_mode_task(
-    serial: &'static Mutex<ThreadModeRawMutex, Option<Uart<'static, Async>>>,
-    start_mode: SystemMode,
-    system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
-    normal_mode_semaphore: &'static CrossingSemaphore,
-    flash_mode_semaphore: &'static CrossingSemaphore,
-    priority_a_semaphore: &'static CrossingSemaphore,
-    priority_b_semaphore: &'static CrossingSemaphore,
-    lockout: &'static AtomicBool,
+async fn battery_monitor_task(
+    log: &'static Log,
+    adc_option: &'static Mutex<ThreadModeRawMutex, Option<BatteryAdc>>,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    faults: &'static Faults,
+    events: &'static EventLog,
+    battery_millivolts: &'static AtomicU32,
 ) -> ! {
-    // As we start, we hold all the permits. This effectively blocks the traffic
-    // light tasks from running, as they will be waiting for a permit to become
-    // available. Permits are represented as boolean values, since we can only
-    // ever have or have not one.
-    let mut have_normal_permit: bool = true;
-    let mut have_flash_permit: bool = true;
-    let mut have_priority_a_permit: bool = true;
-    let mut have_priority_b_permit: bool = true;
-
-    let mut mode: SystemMode = start_mode;
-    loop {
-        // When we hold every single permit we can release the lockout and then
-        // release the permit associated with the current system mode.
-        print(serial, "sem handler: releasing lockout.\r\n").await;
-        lockout.store(false, Ordering::Relaxed);
-
-        // Collecting semaphores can take quite a bit of time and the user may
-        // have changed the value of the system mode while we were busy. Make
-        // sure that we are entering the most recently requested mode, so we
-        // don't have to quickly cycle through an older one.
-        if system_mode_signal.signaled() {
-            mode = system_mode_signal.wait().await;
+    let mut adc: BatteryAdc = adc_option.lock().await.take().expect(IO_INIT_ERROR);
+    battery_monitor::battery_monitor(
+        log,
+        &mut adc,
+        settings,
+        lights,
+        faults,
+        events,
+        battery_millivolts,
+    )
+    .await
+}
+
+// The RTC of the F103 is the simple kind, a counter of seconds in the backup
+// domain, which embassy has no driver for. So we go to its registers ourselves.
+// The counter keeps running from the backup battery while the power is off.
+//
+// The counter counts the 32.768kHz crystal on the board. Should the crystal
+// not start, it counts the internal oscillator instead, which is far less
+// accurate but keeps the schedule going. Which of the two it counts is kept in
+// the backup domain, like the counter.
+struct BackupRtc;
+
+// A backup register holds this once the RTC was set. The backup registers are
+// cleared together with the counter when the backup domain loses its power.
+const RTC_SET_MARKER: u16 = 0x5e7c;
+
+// The crystal takes a few seconds to start, see tSU(LSE) in the datasheet.
+const LSE_STARTUP_MS: u64 = 5_000;
+
+impl BackupRtc {
+    // Embassy waits for the crystal to start for as long as it takes, which is
+    // forever when the crystal is broken. So we only have it keep the clock
+    // that the backup domain already runs from, and start the crystal
+    // ourselves when there is none yet, see `start()`. Anything else and
+    // embassy resets the backup domain, along with the time.
+    fn ls_config() -> LsConfig {
+        let bdcr = RCC.bdcr().read();
+        match (bdcr.rtcen(), bdcr.rtcsel()) {
+            (true, Rtcsel::LSE) => LsConfig::default_lse(),
+            (true, Rtcsel::LSI) => LsConfig::default_lsi(),
+            _ => LsConfig::off(),
         }
+    }
 
-        match mode {
-            SystemMode::Normal => {
-                print(serial, "sem handler: releasing SystemMode::Normal.\r\n").await;
-                ensure_released(&mut have_normal_permit, normal_mode_semaphore);
-            }
-            SystemMode::Flash => {
-                print(serial, "sem handler: releasing SystemMode::Flash.\r\n").await;
-                ensure_released(&mut have_flash_permit, flash_mode_semaphore);
-            }
-            SystemMode::PriorityA => {
-                print(serial, "sem handler: releasing SystemMode::PriorityA.\r\n").await;
-                ensure_released(&mut have_priority_a_permit, priority_a_semaphore);
+    fn new(_rtc: embassy_stm32::peripherals::RTC) -> Self {
+        RCC.apb1enr().modify(|w| w.set_bkpen(true));
+        BackupRtc
+    }
+
+    // Give the RTC a clock, unless it already has one from before the reset.
+    async fn start(&mut self, log: &'static Log, faults: &'static Faults) {
+        if !RCC.bdcr().read().rtcen() {
+            RCC.bdcr().modify(|w| w.set_lseon(true));
+            let started_at: Instant = Instant::now();
+            while !RCC.bdcr().read().lserdy()
+                && Instant::now() - started_at < Duration::from_millis(LSE_STARTUP_MS)
+            {
+                Timer::after_millis(100).await;
             }
-            SystemMode::PriorityB => {
-                print(serial, "sem handler: releasing SystemMode::PriorityB.\r\n").await;
-                ensure_released(&mut have_priority_b_permit, priority_b_semaphore);
+            let source: Rtcsel = if RCC.bdcr().read().lserdy() {
+                Rtcsel::LSE
+            } else {
+                RCC.bdcr().modify(|w| w.set_lseon(false));
+                RCC.csr().modify(|w| w.set_lsion(true));
+                while !RCC.csr().read().lsirdy() {}
+                Rtcsel::LSI
+            };
+            RCC.bdcr().modify(|w| {
+                w.set_rtcsel(source);
+                w.set_rtcen(true);
+            });
+        }
+        if RCC.bdcr().read().rtcsel() == Rtcsel::LSI {
+            faults.set_clock_crystal_failed();
+            warn!(log, "clock", "crystal failed, running from LSI.");
+        }
+        // After a reset, the counter is only valid to read once it has been
+        // synchronised with the bus.
+        RTC.crl().modify(|w| w.set_rsf(false));
+        while !RTC.crl().read().rsf() {}
+        low_power::follow_rtc();
+    }
+
+    fn wait_for_write(&self) {
+        while RTC.crl().read().rtoff() == Rtoff::ONGOING {}
+    }
+}
+
+impl Rtc for BackupRtc {
+    fn read(&mut self) -> Option<WallTime> {
+        if BKP.dr(0).read().d() != RTC_SET_MARKER {
+            return None;
+        }
+        // The counter may carry into the high half between the two reads.
+        loop {
+            let high: u16 = RTC.cnth().read().cnth();
+            let low: u16 = RTC.cntl().read().cntl();
+            if RTC.cnth().read().cnth() == high {
+                return Some(WallTime((high as u32) << 16 | low as u32));
             }
         }
+    }
 
-        print(serial, "sem handler: awaiting new mode.\r\n").await;
-        mode = system_mode_signal.wait().await;
+    fn write(&mut self, time: WallTime) {
+        self.wait_for_write();
+        RTC.crl().modify(|w| w.set_cnf(true));
+        // The prescaler divides the 32.768kHz of the LSE crystal, or the
+        // 40kHz of the LSI oscillator, by one more than this, which makes the
+        // counter count seconds.
+        let prescaler: u32 = if RCC.bdcr().read().rtcsel() == Rtcsel::LSI {
+            39_999
+        } else {
+            32_767
+        };
+        RTC.prlh().write(|w| w.set_prlh((prescaler >> 16) as u8));
+        RTC.prll().write(|w| w.set_prll(prescaler as u16));
+        RTC.cnth().write(|w| w.set_cnth((time.0 >> 16) as u16));
+        RTC.cntl().write(|w| w.set_cntl(time.0 as u16));
+        RTC.crl().modify(|w| w.set_cnf(false));
+        self.wait_for_write();
+        BKP.dr(0).write(|w| w.set_d(RTC_SET_MARKER));
+        low_power::follow_rtc();
+    }
+}
 
-        // When there is a new pending, first signal everyone that we want to go
-        // to the lockout state, clearing traffic from the crossing. We then
-        // claim all the permits so that we know all tasks are at rest.
-        //
-        // Some tasks have a simple loop. They just need a semaphore that they
-        // release every cycle. Some tasks have a second, inner loop. They need
-        // a second trigger to be able to safely break out of the inner loop.
-        //
-        // It might be tempting to just make the system status into a global
-        // variable and use that to break out of the inner loops. Unfortunately,
-        // that may leave the semaphore handler task in a deadlocked state. The
-        // steps to reach that deadlock are that the user switches to a new
-        // state, then switches back while the permits are being collected. The
-        // tasks then see that the system mode is as they expected and will not
-        // release their permits, while the semaphore handler won't accept new
-        // states until all semaphores have been collected.
+#[embassy_executor::task(pool_size = 1)]
+async fn clock_keeper_task(
+    log: &'static Log,
+    rtc_option: &'static Mutex<ThreadModeRawMutex, Option<BackupRtc>>,
+    wall_clock: &'static WallClock,
+    faults: &'static Faults,
+) -> ! {
+    let mut rtc: BackupRtc = rtc_option.lock().await.take().expect(IO_INIT_ERROR);
+    rtc.start(log, faults).await;
+    wall_clock::clock_keeper(log, &mut rtc, wall_clock).await
+}
+
+// The schedule and the calendar go in the last page of the flash, which the
+// firmware is nowhere near growing into.
+struct FlashStorage {
+    flash: Flash<'static, Blocking>,
+}
 
-        print(serial, "sem handler: locking out.\r\n").await;
-        lockout.store(true, Ordering::Relaxed);
+// The F103VE has 512 kilobytes of flash, in pages of two kilobytes.
+const STORAGE_OFFSET: u32 = 510 * 1_024;
+const STORAGE_PAGE_SIZE: u32 = 2 * 1_024;
 
-        print(serial, "sem handler: collecting semaphores...\r\n").await;
-        ensure_aquired(&mut have_normal_permit, normal_mode_semaphore).await;
-        ensure_aquired(&mut have_flash_permit, flash_mode_semaphore).await;
-        ensure_aquired(&mut have_priority_a_permit, priority_a_semaphore).await;
-        ensure_aquired(&mut have_priority_b_permit, priority_b_semaphore).await;
+impl ScheduleStorage for FlashStorage {
+    fn read(&mut self, bytes: &mut [u8; STORED_SIZE]) {
+        // Reading the flash cannot fail at an offset that is in the flash.
+        let _ = self.flash.blocking_read(STORAGE_OFFSET, bytes);
     }
-}
 
-async fn ensure_aquired(permit: &mut bool, semaphore: &'static CrossingSemaphore) {
-    if !*permit {
-        semaphore.acquire(1).await.unwrap().disarm();
-        *permit = true;
+    // Erasing the page stalls the CPU for a few dozen milliseconds, which the
+    // lights ride out on their own.
+    fn write(&mut self, bytes: &[u8; STORED_SIZE]) {
+        let _ = self
+            .flash
+            .blocking_erase(STORAGE_OFFSET, STORAGE_OFFSET + STORAGE_PAGE_SIZE);
+        let _ = self.flash.blocking_write(STORAGE_OFFSET, bytes);
     }
 }
-fn ensure_released(permit: &mut bool, semaphore: &'static CrossingSemaphore) {
-    if !*permit {
-        panic!("double free of permit");
+
+#[embassy_executor::task(pool_size = 1)]
+async fn schedule_keeper_task(
+    log: &'static Log,
+    storage_option: &'static Mutex<ThreadModeRawMutex, Option<FlashStorage>>,
+    schedule: &'static Mutex<ThreadModeRawMutex, Schedule>,
+    calendar: &'static Mutex<ThreadModeRawMutex, Calendar>,
+    schedule_written: &'static Signal<ThreadModeRawMutex, ()>,
+) -> ! {
+    let mut storage: FlashStorage = storage_option.lock().await.take().expect(IO_INIT_ERROR);
+    schedule_storage::schedule_keeper(log, &mut storage, schedule, calendar, schedule_written).await
+}
+
+#[embassy_executor::task(pool_size = 1)]
+async fn scheduler_task(
+    log: &'static Log,
+    wall_clock: &'static WallClock,
+    schedule: &'static Mutex<ThreadModeRawMutex, Schedule>,
+    calendar: &'static Mutex<ThreadModeRawMutex, Calendar>,
+    settings: &'static Mutex<ThreadModeRawMutex, Settings>,
+    system_mode_signal: &'static Signal<ThreadModeRawMutex, SystemMode>,
+) -> ! {
+    schedule::scheduler(
+        log,
+        wall_clock,
+        schedule,
+        calendar,
+        settings,
+        system_mode_signal,
+    )
+    .await
+}
+
+// The current sensor inputs, one for every lamp.
+#[cfg(feature = "lamp-monitor")]
+type LampSenseInputs = [(Pins, Input<'static>); 10];
+
+#[cfg(feature = "lamp-monitor")]
+#[embassy_executor::task(pool_size = 1)]
+async fn lamp_monitor_task(
+    log: &'static Log,
+    sense_inputs_option: &'static Mutex<ThreadModeRawMutex, Option<LampSenseInputs>>,
+    lights: &'static Mutex<ThreadModeRawMutex, TimedOutputMasker>,
+    faults: &'static Faults,
+) -> ! {
+    let mut sense_inputs: LampSenseInputs = sense_inputs_option
+        .lock()
+        .await
+        .take()
+        .expect(IO_INIT_ERROR);
+    lamp_monitor::lamp_monitor(log, &mut sense_inputs, lights, faults).await
+}
+
+#[cfg(not(feature = "modbus"))]
+#[embassy_executor::task(pool_size = 1)]
+async fn host_link_task(host_link: &'static HostLink) -> ! {
+    host_link::host_link(host_link).await
+}
+
+#[cfg(not(feature = "modbus"))]
+#[embassy_executor::task(pool_size = 1)]
+async fn host_notification_task(host_link: &'static HostLink) -> ! {
+    host_link::host_notifications(host_link).await
+}
+
+#[cfg(feature = "modbus")]
+#[embassy_executor::task(pool_size = 1)]
+async fn modbus_slave_task(host_link: &'static HostLink) -> ! {
+    // Matches the baud rate of `Config::default()` in `main()`.
+    modbus::modbus_slave(host_link, FrameTiming::for_baud_rate(115_200)).await
+}
+
+#[cfg(not(feature = "defmt"))]
+#[embassy_executor::task(pool_size = 1)]
+async fn log_writer_task(log: &'static Log, serial: &'static Serial) -> ! {
+    log::log_writer(log, serial).await
+}
+
+// Moves the bytes that the controller writes to the serial port onto the wire.
+#[embassy_executor::task(pool_size = 1)]
+async fn serial_writer_task(
+    serial: &'static Serial,
+    uart_tx_option: &'static Mutex<ThreadModeRawMutex, Option<UartTx<'static, Async>>>,
+) -> ! {
+    let mut uart_tx: UartTx<'static, Async> =
+        uart_tx_option.lock().await.take().expect(IO_INIT_ERROR);
+    let mut buffer: [u8; 64] = [0; 64];
+    loop {
+        let count: usize = serial.transmit(&mut buffer).await;
+        uart_tx.write(&buffer[..count]).await.unwrap();
     }
-    semaphore.release(1);
-    *permit = false;
 }
 
-#[embassy_executor::task(pool_size = 2)]
-async fn promise_input_task(
-    input_option: &'static Mutex<ThreadModeRawMutex, Option<Input<'static>>>,
-    pedestrian_lights: &'static PedestrianLights,
+// Moves the bytes that come in over the wire to the controller. The receive
+// side runs from a DMA ring buffer, so that no bytes get lost while the host
+// link is busy answering a request. The UART cannot wake the core from STOP,
+// so once bytes come in, we stay awake for the rest of the conversation.
+const SERIAL_AWAKE_SECS: u64 = 10;
+
+#[embassy_executor::task(pool_size = 1)]
+async fn serial_reader_task(
+    serial: &'static Serial,
+    uart_rx_option: &'static Mutex<ThreadModeRawMutex, Option<RingBufferedUartRx<'static>>>,
 ) -> ! {
-    let input: Input = input_option.lock().await.take().expect(IO_INIT_ERROR);
+    let mut uart_rx: RingBufferedUartRx<'static> =
+        uart_rx_option.lock().await.take().expect(IO_INIT_ERROR);
+    let mut buffer: [u8; 32] = [0; 32];
     loop {
-        Timer::after_millis(10).await;
-        if input.is_low() {
-            pedestrian_lights.make_promise().await;
+        // After an overrun or a framing error, the frame that we were in the
+        // middle of will fail its CRC. The host will time out and ask again.
+        if let Ok(count) = uart_rx.read(&mut buffer).await {
+            low_power::stay_awake_for(Duration::from_secs(SERIAL_AWAKE_SECS));
+            serial.received(&buffer[..count]).await;
         }
     }
 }
 
-pub async fn print(
-    uart: &'static Mutex<ThreadModeRawMutex, Option<Uart<'static, Async>>>,
-    message: &str,
+/*
+ * After opening the box it is easy to knock a connector loose or to swap two
+ * wires. The self-test lights each lamp in turn and chirps each beeper, so
+ * that a quick look at the box tells whether the wiring is right. Where a lamp
+ * has a current sensor, we also check that it actually draws current.
+ *
+ * The self-test runs before the output loop starts, so it drives the outputs
+ * directly rather than through the output masker.
+ */
+async fn self_test(
+    log: &'static Log,
+    outputs: &mut [Output<'_>; Pins::VARIANT_COUNT],
+    active_lows: &[bool; Pins::VARIANT_COUNT],
+    sense_lamp: impl Fn(Pins) -> Option<bool>,
+    faults: &'static Faults,
 ) {
-    uart.lock()
-        .await
-        .as_mut()
-        .expect(IO_INIT_ERROR)
-        .write(message.as_bytes())
-        .await
-        .unwrap();
+    let set_output = |output: &mut Output<'_>, active_low: bool, on: bool| {
+        output.set_level(if on != active_low {
+            Level::High
+        } else {
+            Level::Low
+        });
+    };
+
+    for (output, active_low) in outputs.iter_mut().zip(active_lows) {
+        set_output(output, *active_low, false);
+    }
+
+    for pin in Pins::VARIANTS {
+        let chirp: bool = matches!(pin, Pins::ABeeper | Pins::BBeeper);
+        let i: usize = pin.ordinal();
+
+        set_output(&mut outputs[i], active_lows[i], true);
+        Timer::after_millis(if chirp { 50 } else { 150 }).await;
+
+        // By now the lamp has warmed up, so it should draw current.
+        if sense_lamp(*pin) == Some(false) {
+            faults.set_lamp_out(*pin);
+
+            warn!(log, "self-test", "{} is out.", pin.name());
+        }
+
+        if !chirp {
+            Timer::after_millis(100).await;
+        }
+        set_output(&mut outputs[i], active_lows[i], false);
+    }
+}
+
+// Our own executor, which goes into STOP between events, see `low_power.rs`.
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let executor: &'static mut low_power::Executor =
+        cortex_m::singleton!(: low_power::Executor = low_power::Executor::new())
+            .expect(IO_INIT_ERROR);
+    executor.run(|spawner| spawner.must_spawn(main_task(spawner)))
 }
 
 /*
  * The main task defines all of the semaphores and global state, then spawns all
  * of the tasks and finally runs the primary output loop.
  */
-#[embassy_executor::main]
-async fn main(spawner: Spawner) -> ! {
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) -> ! {
     // The power led is active-high and `LED4` is active-low.
     static ACTIVE_LOWS: [bool; Pins::VARIANT_COUNT] = {
         let mut active_lows = [false; Pins::VARIANT_COUNT];
@@ -576,43 +784,92 @@ async fn main(spawner: Spawner) -> ! {
         active_lows[16 /* Pins::SwitchingMode.ordinal() */] = true;
         active_lows
     };
+    static OUTPUTS_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
     static LIGHTS: Mutex<ThreadModeRawMutex, TimedOutputMasker> =
-        Mutex::new(TimedOutputMasker::new(ACTIVE_LOWS));
+        Mutex::new(TimedOutputMasker::new(ACTIVE_LOWS, &OUTPUTS_CHANGED));
 
-    static TRAFFIC_LIGHTS_A: TrafficLights =
-        TrafficLights::new(&LIGHTS, Pins::ARed, Pins::AAmber, Pins::AGreen);
-    static TRAFFIC_LIGHTS_B: TrafficLights =
-        TrafficLights::new(&LIGHTS, Pins::BRed, Pins::BAmber, Pins::BGreen);
+    const START_MODE: SystemMode = SystemMode::Flash;
+    static EVENTS: EventLog = EventLog::new(START_MODE);
+    static STATISTICS: Statistics = Statistics::new();
+
+    static TRAFFIC_LIGHTS_A: TrafficLights = TrafficLights::new(
+        &LIGHTS,
+        &EVENTS,
+        Approach::A,
+        TrafficPins {
+            red: Pins::ARed,
+            amber: Pins::AAmber,
+            green: Pins::AGreen,
+        },
+    );
+    static TRAFFIC_LIGHTS_B: TrafficLights = TrafficLights::new(
+        &LIGHTS,
+        &EVENTS,
+        Approach::B,
+        TrafficPins {
+            red: Pins::BRed,
+            amber: Pins::BAmber,
+            green: Pins::BGreen,
+        },
+    );
 
     static PEDESTRIAN_LIGHTS_A: PedestrianLights = PedestrianLights::new(
         &LIGHTS,
-        Pins::APedestrianRed,
-        Pins::APedestrianGreen,
-        Pins::ABeeper,
-        Pins::APromise,
+        &EVENTS,
+        &STATISTICS,
+        Approach::A,
+        PedestrianPins {
+            red: Pins::APedestrianRed,
+            green: Pins::APedestrianGreen,
+            beeper: Pins::ABeeper,
+            promise: Pins::APromise,
+        },
     );
     static PEDESTRIAN_LIGHTS_B: PedestrianLights = PedestrianLights::new(
         &LIGHTS,
-        Pins::BPedestrianRed,
-        Pins::BPedestrianGreen,
-        Pins::BBeeper,
-        Pins::BPromise,
+        &EVENTS,
+        &STATISTICS,
+        Approach::B,
+        PedestrianPins {
+            red: Pins::BPedestrianRed,
+            green: Pins::BPedestrianGreen,
+            beeper: Pins::BBeeper,
+            promise: Pins::BPromise,
+        },
     );
 
-    const START_MODE: SystemMode = SystemMode::Flash;
-    static SYSTEM_MODE_SIGNAL: Signal<ThreadModeRawMutex, SystemMode> = Signal::new();
-
-    static NORMAL_MODE_SEMAPHORE: CrossingSemaphore = CrossingSemaphore::new(0);
-    static FLASH_MODE_SEMAPHORE: CrossingSemaphore = CrossingSemaphore::new(0);
-    static PRIORITY_A_SEMAPHORE: CrossingSemaphore = CrossingSemaphore::new(0);
-    static PRIORITY_B_SEMAPHORE: CrossingSemaphore = CrossingSemaphore::new(0);
-
-    let peripherals = embassy_stm32::init(Default::default());
+    static SETTINGS: Mutex<ThreadModeRawMutex, Settings> = Mutex::new(Settings::new());
 
-    static SERIAL: Mutex<ThreadModeRawMutex, Option<Uart<'static, Async>>> =
+    static SYSTEM_MODE_SIGNAL: Signal<ThreadModeRawMutex, SystemMode> = Signal::new();
+    static MANUAL_ADVANCE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+    static KERBSIDE_DETECTED: AtomicBool = AtomicBool::new(false);
+    static FAULTS: Faults = Faults::new(&EVENTS);
+    static PREEMPTION: Preemption = Preemption::new(&EVENTS, &SETTINGS);
+    static BATTERY_MILLIVOLTS: AtomicU32 = AtomicU32::new(0);
+
+    // A semaphore for every mode, in the order of the `SystemMode` ordinals.
+    static SEMAPHORES: [CrossingSemaphore; SystemMode::VARIANT_COUNT] =
+        [const { CrossingSemaphore::new(0) }; SystemMode::VARIANT_COUNT];
+    let semaphore =
+        |mode: SystemMode| -> &'static CrossingSemaphore { &SEMAPHORES[mode.ordinal() as usize] };
+
+    // The RTC runs from the 32.768kHz crystal on the board, see `BackupRtc`.
+    let mut config: embassy_stm32::Config = Default::default();
+    config.rcc.ls = BackupRtc::ls_config();
+    let peripherals = embassy_stm32::init(config);
+    low_power::init(peripherals.TIM2);
+
+    static SERIAL: Serial = Serial::new();
+    // The Modbus slave has the serial port to itself, there is no console.
+    #[cfg(feature = "modbus")]
+    SERIAL.set_binary_mode(true);
+    static UART_TX: Mutex<ThreadModeRawMutex, Option<UartTx<'static, Async>>> =
+        Mutex::new(Option::None);
+    static UART_RX: Mutex<ThreadModeRawMutex, Option<RingBufferedUartRx<'static>>> =
         Mutex::new(Option::None);
     bind_interrupts!(struct Irqs {
-        USART1 => InterruptHandler<USART1>;
+        USART1 => usart::InterruptHandler<USART1>;
+        ADC1_2 => adc::InterruptHandler<ADC1>;
     });
     let uart: Uart<'static, Async> = Uart::new(
         peripherals.USART1,
@@ -624,12 +881,32 @@ async fn main(spawner: Spawner) -> ! {
         Config::default(), // 115200 baud
     )
     .unwrap();
-    SERIAL.lock().await.replace(uart);
+    let (uart_tx, uart_rx) = uart.split();
+    let uart_rx_buffer: &'static mut [u8; 128] =
+        cortex_m::singleton!(: [u8; 128] = [0; 128]).expect(IO_INIT_ERROR);
+    {
+        // scope for the mutex guard...
+        UART_TX.lock().await.replace(uart_tx);
+        UART_RX
+            .lock()
+            .await
+            .replace(uart_rx.into_ring_buffered(uart_rx_buffer));
+    }
+    // These go first, so that the self-test can already report. With `defmt`
+    // the log goes to the debug probe, so there is nothing to write it out.
+    static LOG: Log = Log::new();
+    LOG.set_level(SETTINGS.lock().await.log_level);
+    spawner.must_spawn(serial_writer_task(&SERIAL, &UART_TX));
+    spawner.must_spawn(serial_reader_task(&SERIAL, &UART_RX));
+    #[cfg(not(feature = "defmt"))]
+    spawner.must_spawn(log_writer_task(&LOG, &SERIAL));
 
     // The USB serial port takes about 3 seconds to connect when there is
-    // traffic. To troubleshoot startup problems it is a good idea to `print()`
-    // some messages at startup. We don't do that so that the control loop
-    // starts quickly, which makes the system feel fast and reliable.
+    // traffic. Logging never waits for the serial port, so what is logged at
+    // startup may well be gone before anyone gets to see it. We want the
+    // control loop to start quickly, which makes the system feel fast and
+    // reliable. For the same reason, the power-on self-test is off unless it is
+    // switched on in the settings.
 
     let mut outputs: [Output<'_>; Pins::VARIANT_COUNT] = [
         // Left-right lane outputs.
@@ -680,6 +957,78 @@ async fn main(spawner: Spawner) -> ! {
         Output::new(peripherals.PE3.degrade(), Level::Low, Speed::Low),
     ];
 
+    // The comparators pull their input high while the lamp draws current.
+    #[cfg(feature = "lamp-monitor")]
+    let lamp_sense_inputs: LampSenseInputs = [
+        // lamp sense ribbon / white
+        (
+            Pins::ARed,
+            Input::new(peripherals.PC2.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / grey
+        (
+            Pins::AAmber,
+            Input::new(peripherals.PC3.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / purple
+        (
+            Pins::AGreen,
+            Input::new(peripherals.PC4.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / blue
+        (
+            Pins::APedestrianRed,
+            Input::new(peripherals.PC5.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / green
+        (
+            Pins::APedestrianGreen,
+            Input::new(peripherals.PC6.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / yellow
+        (
+            Pins::BRed,
+            Input::new(peripherals.PC7.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / orange
+        (
+            Pins::BAmber,
+            Input::new(peripherals.PA0.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / red
+        (
+            Pins::BGreen,
+            Input::new(peripherals.PA1.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / brown
+        (
+            Pins::BPedestrianRed,
+            Input::new(peripherals.PA2.degrade(), Pull::Down),
+        ),
+        // lamp sense ribbon / black
+        (
+            Pins::BPedestrianGreen,
+            Input::new(peripherals.PA3.degrade(), Pull::Down),
+        ),
+    ];
+
+    // Tell the self-test whether a lamp draws current, for the lamps that have
+    // a sensor.
+    #[cfg(feature = "lamp-monitor")]
+    let sense_lamp = |pin: Pins| {
+        lamp_sense_inputs
+            .iter()
+            .find(|(sensed_pin, _)| sensed_pin.ordinal() == pin.ordinal())
+            .map(|(_, input)| input.is_high())
+    };
+    #[cfg(not(feature = "lamp-monitor"))]
+    let sense_lamp = |_: Pins| None;
+
+    let run_self_test: bool = SETTINGS.lock().await.self_test;
+    if run_self_test {
+        self_test(&LOG, &mut outputs, &ACTIVE_LOWS, sense_lamp, &FAULTS).await;
+    }
+
     {
         // scope for the mutex guard...
         let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> = LIGHTS.lock().await;
@@ -689,102 +1038,399 @@ async fn main(spawner: Spawner) -> ! {
         lights.set_on_off2(Pins::APedestrianRed, true, Pins::APedestrianGreen, false);
         lights.set_on_off2(Pins::BPedestrianRed, true, Pins::BPedestrianGreen, false);
 
-        // Make the power leds blink with short bips
+        // Make the power leds blink with short bips. The on-board led keeps
+        // doing that, the other one is taken over by the battery monitor once
+        // it has taken its first reading.
         lights.set_pin(Pins::OnBoardPower, true, false, false, true);
         lights.set_pin(Pins::Power, true, false, false, true);
+
+        // We start out locked out, see `LOCKOUT`.
+        lights.set_pin(Pins::SwitchingMode, true, false, true, false);
+    }
+
+    static BATTERY_ADC: Mutex<ThreadModeRawMutex, Option<BatteryAdc>> = Mutex::new(None);
+    // battery divider / PC0, ADC123_IN10
+    let battery_input: AnyAdcChannel<ADC1> = peripherals.PC0.degrade_adc();
+    {
+        // scope for the mutex guard...
+        BATTERY_ADC
+            .lock()
+            .await
+            .replace(BatteryAdc::new(Adc::new(peripherals.ADC1), battery_input));
     }
 
-    static SYSTEM_MODE_INPUTS: Mutex<ThreadModeRawMutex, Option<[Input<'static>; 3]>> =
+    // The inputs all use EXTI, so that we can sleep until something happens
+    // instead of polling them.
+    static SYSTEM_MODE_INPUTS: Mutex<ThreadModeRawMutex, Option<[ExtiInput<'static>; 3]>> =
         Mutex::new(Option::None);
-    let system_mode_inputs: [Input; 3] = [
+    let system_mode_inputs: [ExtiInput; 3] = [
         // status rotary ribbon / blue
-        Input::new(peripherals.PB14.degrade(), Pull::Up),
+        ExtiInput::new(peripherals.PB14, peripherals.EXTI14, Pull::Up),
         // status rotary ribbon / green
-        Input::new(peripherals.PB12.degrade(), Pull::Up),
+        ExtiInput::new(peripherals.PB12, peripherals.EXTI12, Pull::Up),
         // status rotary ribbon / yellow
-        Input::new(peripherals.PB10.degrade(), Pull::Up),
+        ExtiInput::new(peripherals.PB10, peripherals.EXTI10, Pull::Up),
     ];
     {
         // scope for the mutex guard...
         SYSTEM_MODE_INPUTS.lock().await.replace(system_mode_inputs);
     }
 
-    static PROMISE_INPUT_A: Mutex<ThreadModeRawMutex, Option<Input<'static>>> = Mutex::new(None);
-    static PROMISE_INPUT_B: Mutex<ThreadModeRawMutex, Option<Input<'static>>> = Mutex::new(None);
+    static PROMISE_INPUT_A: Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>> =
+        Mutex::new(None);
+    static PROMISE_INPUT_B: Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>> =
+        Mutex::new(None);
     // crossing ribbon / gray
-    let promise_input_a: Input = Input::new(peripherals.PD3.degrade(), Pull::Up);
+    let promise_input_a: ExtiInput = ExtiInput::new(peripherals.PD3, peripherals.EXTI3, Pull::Up);
     // crossing ribbon / white
-    let promise_input_b: Input = Input::new(peripherals.PD4.degrade(), Pull::Up);
+    let promise_input_b: ExtiInput = ExtiInput::new(peripherals.PD4, peripherals.EXTI4, Pull::Up);
     {
         // scope for the mutex guard...
         PROMISE_INPUT_A.lock().await.replace(promise_input_a);
         PROMISE_INPUT_B.lock().await.replace(promise_input_b);
     }
 
+    // Whatever calls for a preemption pulls its input low, like the buttons.
+    static PREEMPTION_INPUTS: Mutex<ThreadModeRawMutex, Option<[ExtiInput<'static>; 2]>> =
+        Mutex::new(None);
+    let preemption_inputs: [ExtiInput; 2] = [
+        // preemption header / A
+        ExtiInput::new(peripherals.PD11, peripherals.EXTI11, Pull::Up),
+        // preemption header / B
+        ExtiInput::new(peripherals.PD13, peripherals.EXTI13, Pull::Up),
+    ];
+    {
+        // scope for the mutex guard...
+        PREEMPTION_INPUTS.lock().await.replace(preemption_inputs);
+    }
+    spawner.must_spawn(preemption_inputs_task(
+        &LOG,
+        &PREEMPTION,
+        &PREEMPTION_INPUTS,
+    ));
+
+    // The track circuit pulls its input low while a train is near.
+    static TRACK_CIRCUIT_INPUT: Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>> =
+        Mutex::new(None);
+    // railway header / track circuit
+    let track_circuit_input: ExtiInput =
+        ExtiInput::new(peripherals.PD15, peripherals.EXTI15, Pull::Up);
+    {
+        // scope for the mutex guard...
+        TRACK_CIRCUIT_INPUT
+            .lock()
+            .await
+            .replace(track_circuit_input);
+    }
+    spawner.must_spawn(track_circuit_task(&LOG, &PREEMPTION, &TRACK_CIRCUIT_INPUT));
+
+    // The button of the officer pulls its input low while pressed.
+    static ADVANCE_INPUT: Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>> = Mutex::new(None);
+    // police header / advance
+    let advance_input: ExtiInput = ExtiInput::new(peripherals.PE7, peripherals.EXTI7, Pull::Up);
+    {
+        // scope for the mutex guard...
+        ADVANCE_INPUT.lock().await.replace(advance_input);
+    }
+    spawner.must_spawn(advance_input_task(&LOG, &ADVANCE_INPUT, &MANUAL_ADVANCE));
+
+    // The kerbside detector of a puffin pulls its input low while it sees
+    // someone waiting at the kerb.
+    static KERBSIDE_INPUT: Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>> = Mutex::new(None);
+    // kerbside header / detector
+    let kerbside_input: ExtiInput = ExtiInput::new(peripherals.PE9, peripherals.EXTI9, Pull::Up);
+    {
+        // scope for the mutex guard...
+        KERBSIDE_INPUT.lock().await.replace(kerbside_input);
+    }
+    spawner.must_spawn(kerbside_detector_task(
+        &LOG,
+        &KERBSIDE_INPUT,
+        &KERBSIDE_DETECTED,
+    ));
+
+    // The sync line of the green wave idles high, like the buttons.
+    static GREEN_WAVE: GreenWave = GreenWave::new();
+    static SYNC_INPUT: Mutex<ThreadModeRawMutex, Option<ExtiInput<'static>>> = Mutex::new(None);
+    static SYNC_OUTPUT: Mutex<ThreadModeRawMutex, Option<Output<'static>>> = Mutex::new(None);
+    // green wave header / sync in
+    let sync_input: ExtiInput = ExtiInput::new(peripherals.PD8, peripherals.EXTI8, Pull::Up);
+    // green wave header / sync out
+    let sync_output: Output = Output::new(peripherals.PD9, Level::High, Speed::Low);
+    {
+        // scope for the mutex guard...
+        SYNC_INPUT.lock().await.replace(sync_input);
+        SYNC_OUTPUT.lock().await.replace(sync_output);
+    }
+    spawner.must_spawn(sync_pulses_task(
+        &LOG,
+        &SETTINGS,
+        &GREEN_WAVE,
+        &SYNC_INPUT,
+        &SYNC_OUTPUT,
+    ));
+
     spawner.must_spawn(normal_mode_task(
-        &NORMAL_MODE_SEMAPHORE,
+        semaphore(SystemMode::Normal),
         &TRAFFIC_LIGHTS_A,
         &PEDESTRIAN_LIGHTS_A,
+        &SETTINGS,
+        &STATISTICS,
+        Some(&GREEN_WAVE),
+        &PREEMPTION,
     ));
     spawner.must_spawn(normal_mode_task(
-        &NORMAL_MODE_SEMAPHORE,
+        semaphore(SystemMode::Normal),
         &TRAFFIC_LIGHTS_B,
         &PEDESTRIAN_LIGHTS_B,
+        &SETTINGS,
+        &STATISTICS,
+        None,
+        &PREEMPTION,
     ));
     spawner.must_spawn(flash_mode_task(
-        &FLASH_MODE_SEMAPHORE,
+        semaphore(SystemMode::Flash),
         &TRAFFIC_LIGHTS_A,
         &TRAFFIC_LIGHTS_B,
         &PEDESTRIAN_LIGHTS_A,
         &PEDESTRIAN_LIGHTS_B,
         &LOCKOUT,
+        &STATISTICS,
     ));
     spawner.must_spawn(priority_mode_task(
-        &PRIORITY_A_SEMAPHORE,
+        SystemMode::PriorityA,
+        semaphore(SystemMode::PriorityA),
         &TRAFFIC_LIGHTS_A,
         &PEDESTRIAN_LIGHTS_A,
         &LOCKOUT,
+        &STATISTICS,
+        &PREEMPTION,
     ));
     spawner.must_spawn(priority_mode_task(
-        &PRIORITY_B_SEMAPHORE,
+        SystemMode::PriorityB,
+        semaphore(SystemMode::PriorityB),
         &TRAFFIC_LIGHTS_B,
         &PEDESTRIAN_LIGHTS_B,
         &LOCKOUT,
+        &STATISTICS,
+        &PREEMPTION,
     ));
-    spawner.must_spawn(system_mode_task(
-        &SERIAL,
-        START_MODE,
-        &SYSTEM_MODE_SIGNAL,
-        &NORMAL_MODE_SEMAPHORE,
-        &FLASH_MODE_SEMAPHORE,
-        &PRIORITY_A_SEMAPHORE,
-        &PRIORITY_B_SEMAPHORE,
+    spawner.must_spawn(railway_mode_task(
+        semaphore(SystemMode::Railway),
+        [&TRAFFIC_LIGHTS_A, &TRAFFIC_LIGHTS_B],
+        [&PEDESTRIAN_LIGHTS_A, &PEDESTRIAN_LIGHTS_B],
+        &SETTINGS,
+        &LOCKOUT,
+        &STATISTICS,
+    ));
+    spawner.must_spawn(manual_mode_task(
+        semaphore(SystemMode::Manual),
+        [&TRAFFIC_LIGHTS_A, &TRAFFIC_LIGHTS_B],
+        [&PEDESTRIAN_LIGHTS_A, &PEDESTRIAN_LIGHTS_B],
+        &SETTINGS,
+        &LOCKOUT,
+        &STATISTICS,
+        &MANUAL_ADVANCE,
+    ));
+    spawner.must_spawn(all_red_mode_task(
+        semaphore(SystemMode::AllRed),
+        [&TRAFFIC_LIGHTS_A, &TRAFFIC_LIGHTS_B],
+        [&PEDESTRIAN_LIGHTS_A, &PEDESTRIAN_LIGHTS_B],
+        &LOCKOUT,
+        &STATISTICS,
+        &PREEMPTION,
+    ));
+    spawner.must_spawn(pelican_mode_task(
+        semaphore(SystemMode::Pelican),
+        [&TRAFFIC_LIGHTS_A, &TRAFFIC_LIGHTS_B],
+        [&PEDESTRIAN_LIGHTS_A, &PEDESTRIAN_LIGHTS_B],
+        &SETTINGS,
+        &LOCKOUT,
+        &STATISTICS,
+        &KERBSIDE_DETECTED,
+    ));
+    spawner.must_spawn(dark_mode_task(
+        semaphore(SystemMode::Dark),
+        [&TRAFFIC_LIGHTS_A, &TRAFFIC_LIGHTS_B],
+        [&PEDESTRIAN_LIGHTS_A, &PEDESTRIAN_LIGHTS_B],
+        &LIGHTS,
         &LOCKOUT,
+        &STATISTICS,
     ));
+    static SYSTEM_MODE_HANDLER: SystemModeHandler = SystemModeHandler {
+        log: &LOG,
+        system_mode_signal: &SYSTEM_MODE_SIGNAL,
+        faults: &FAULTS,
+        preemption: &PREEMPTION,
+        events: &EVENTS,
+        semaphores: &SEMAPHORES,
+        lockout: &LOCKOUT,
+        lights: &LIGHTS,
+        statistics: &STATISTICS,
+    };
+    spawner.must_spawn(system_mode_task(&SYSTEM_MODE_HANDLER, START_MODE));
     spawner.must_spawn(system_mode_reader_task(
-        &SERIAL,
+        &LOG,
         &SYSTEM_MODE_INPUTS,
         START_MODE,
         &SYSTEM_MODE_SIGNAL,
     ));
     spawner.must_spawn(promise_input_task(&PROMISE_INPUT_A, &PEDESTRIAN_LIGHTS_A));
     spawner.must_spawn(promise_input_task(&PROMISE_INPUT_B, &PEDESTRIAN_LIGHTS_B));
+    spawner.must_spawn(battery_monitor_task(
+        &LOG,
+        &BATTERY_ADC,
+        &SETTINGS,
+        &LIGHTS,
+        &FAULTS,
+        &EVENTS,
+        &BATTERY_MILLIVOLTS,
+    ));
 
+    static WALL_CLOCK: WallClock = WallClock::new();
+    static SCHEDULE: Mutex<ThreadModeRawMutex, Schedule> = Mutex::new(Schedule::new());
+    static CALENDAR: Mutex<ThreadModeRawMutex, Calendar> = Mutex::new(Calendar::new());
+    static BACKUP_RTC: Mutex<ThreadModeRawMutex, Option<BackupRtc>> = Mutex::new(None);
+    {
+        // scope for the mutex guard...
+        BACKUP_RTC
+            .lock()
+            .await
+            .replace(BackupRtc::new(peripherals.RTC));
+    }
+    spawner.must_spawn(clock_keeper_task(&LOG, &BACKUP_RTC, &WALL_CLOCK, &FAULTS));
+    static SCHEDULE_WRITTEN: Signal<ThreadModeRawMutex, ()> = Signal::new();
+    static FLASH_STORAGE: Mutex<ThreadModeRawMutex, Option<FlashStorage>> = Mutex::new(None);
+    {
+        // scope for the mutex guard...
+        FLASH_STORAGE.lock().await.replace(FlashStorage {
+            flash: Flash::new_blocking(peripherals.FLASH),
+        });
+    }
+    spawner.must_spawn(schedule_keeper_task(
+        &LOG,
+        &FLASH_STORAGE,
+        &SCHEDULE,
+        &CALENDAR,
+        &SCHEDULE_WRITTEN,
+    ));
+    spawner.must_spawn(scheduler_task(
+        &LOG,
+        &WALL_CLOCK,
+        &SCHEDULE,
+        &CALENDAR,
+        &SETTINGS,
+        &SYSTEM_MODE_SIGNAL,
+    ));
+
+    static HOST_LINK: HostLink = HostLink {
+        serial: &SERIAL,
+        log: &LOG,
+        events: &EVENTS,
+        statistics: &STATISTICS,
+        faults: &FAULTS,
+        preemption: &PREEMPTION,
+        settings: &SETTINGS,
+        lights: &LIGHTS,
+        lockout: &LOCKOUT,
+        battery_millivolts: &BATTERY_MILLIVOLTS,
+        system_mode_signal: &SYSTEM_MODE_SIGNAL,
+        manual_advance: &MANUAL_ADVANCE,
+        pedestrian_lights: [&PEDESTRIAN_LIGHTS_A, &PEDESTRIAN_LIGHTS_B],
+        wall_clock: &WALL_CLOCK,
+        schedule: &SCHEDULE,
+        calendar: &CALENDAR,
+        schedule_written: &SCHEDULE_WRITTEN,
+    };
+    #[cfg(not(feature = "modbus"))]
+    {
+        spawner.must_spawn(host_link_task(&HOST_LINK));
+        spawner.must_spawn(host_notification_task(&HOST_LINK));
+    }
+    #[cfg(feature = "modbus")]
+    spawner.must_spawn(modbus_slave_task(&HOST_LINK));
+
+    #[cfg(feature = "can-link")]
+    {
+        static CAN_LINK: CanLink = CanLink {
+            log: &LOG,
+            settings: &SETTINGS,
+            events: &EVENTS,
+            system_mode_signal: &SYSTEM_MODE_SIGNAL,
+            pedestrian_lights: [&PEDESTRIAN_LIGHTS_A, &PEDESTRIAN_LIGHTS_B],
+            green_wave: &GREEN_WAVE,
+        };
+        bind_interrupts!(struct CanIrqs {
+            USB_HP_CAN1_TX => can::TxInterruptHandler<CAN>;
+            USB_LP_CAN1_RX0 => can::Rx0InterruptHandler<CAN>;
+            CAN1_RX1 => can::Rx1InterruptHandler<CAN>;
+            CAN1_SCE => can::SceInterruptHandler<CAN>;
+        });
+        // PD0 and PD1 are the third place that CAN can go to on the F103, see
+        // AFIO_MAPR in the reference manual. Embassy leaves the remap to us.
+        AFIO.mapr().modify(|w| w.set_can1_remap(0b11));
+        // can transceiver / rx on PD0, tx on PD1
+        let mut can: Can<'static> =
+            Can::new(peripherals.CAN, peripherals.PD0, peripherals.PD1, CanIrqs);
+        // Everything on the bus is for us, `can_link` sorts it out.
+        can.modify_filters()
+            .enable_bank(0, Fifo::Fifo0, Mask32::accept_all());
+        can.set_bitrate(125_000);
+        // The driver keeps its state in statics that may not be shared across
+        // threads, so it cannot go in a mutex. We hand it over directly.
+        spawner.must_spawn(can_link_task(&CAN_LINK, can));
+    }
+
+    #[cfg(feature = "lamp-monitor")]
+    {
+        static LAMP_SENSE_INPUTS: Mutex<ThreadModeRawMutex, Option<LampSenseInputs>> =
+            Mutex::new(None);
+        {
+            // scope for the mutex guard...
+            LAMP_SENSE_INPUTS.lock().await.replace(lamp_sense_inputs);
+        }
+        spawner.must_spawn(lamp_monitor_task(
+            &LOG,
+            &LAMP_SENSE_INPUTS,
+            &LIGHTS,
+            &FAULTS,
+        ));
+    }
+
+    // We don't actually run the output loop at 100Hz. Most of the time the
+    // outputs don't change for many ticks on end, so we sleep until the next
+    // tick at which they do, or until the control logic changes a pin. Between
+    // wake-ups the executor waits for events, which puts the core to sleep.
+    //
+    // When the next change is more than the next whole second away, the
+    // executor goes into the much deeper STOP mode, see `low_power.rs`. The
+    // cycles of the masker start just after the whole second, so that in flash
+    // mode the core stops for most of the time that the ambers are off.
+    const TICK: Duration = Duration::from_millis(10);
+    const CYCLE_OFFSET: Duration = Duration::from_millis(20);
+    // The masker starts at the last tick of its cycle.
+    let mut last_tick: Instant = Instant::from_ticks(0) + CYCLE_OFFSET - TICK;
     loop {
-        let output_values: [bool; Pins::VARIANT_COUNT] = {
+        let elapsed_ticks: u64 = Instant::now()
+            .saturating_duration_since(last_tick)
+            .as_ticks()
+            / TICK.as_ticks();
+        last_tick += TICK * elapsed_ticks as u32;
+
+        let (output_values, ticks_until_change): ([bool; Pins::VARIANT_COUNT], u32) = {
             // scope for the mutex guard...
             let mut lights: MutexGuard<'_, ThreadModeRawMutex, TimedOutputMasker> =
                 LIGHTS.lock().await;
 
-            lights.set_pin(
-                Pins::SwitchingMode,
-                LOCKOUT.load(Ordering::Relaxed),
-                false,
-                true,
-                false,
-            );
-            lights.call_at_100_hz()
+            (
+                lights.call_after_ticks(elapsed_ticks as u32),
+                lights.ticks_until_change(),
+            )
         };
+        trace!(
+            LOG,
+            "output loop", "{} ticks, next change in {} ticks.", elapsed_ticks, ticks_until_change
+        );
 
         for i in 0..Pins::VARIANT_COUNT {
             outputs[i].set_level(if output_values[i] {
@@ -794,6 +1440,10 @@ async fn main(spawner: Spawner) -> ! {
             });
         }
 
-        Timer::after_millis(10).await;
+        select(
+            Timer::at(last_tick + TICK * ticks_until_change),
+            OUTPUTS_CHANGED.wait(),
+        )
+        .await;
     }
 }
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+
+/*
+ * Tests that run on the board itself, for what the simulator on the desktop
+ * cannot tell us: the timer, the tick rate and the atomics of the STM32. They
+ * need a debug probe, see the readme.
+ */
+
+use defmt_rtt as _;
+use panic_probe as _;
+
+// The time driver of the firmware. Its executor and STOP are not tested here.
+#[allow(dead_code)]
+#[path = "../src/low_power.rs"]
+mod low_power;
+
+#[defmt_test::tests]
+mod tests {
+    use embassy_time::{Duration, Instant, block_for};
+    use pistop_core::log::{Level, Log};
+    use pistop_core::modbus::{self, FrameTiming};
+
+    #[init]
+    fn init() {
+        // Starts the timer that drives `embassy_time`.
+        let peripherals = embassy_stm32::init(Default::default());
+        crate::low_power::init(peripherals.TIM2);
+    }
+
+    #[test]
+    fn time_moves_on() {
+        let start: Instant = Instant::now();
+        block_for(Duration::from_millis(10));
+        let elapsed: Duration = Instant::now() - start;
+        defmt::assert!(elapsed >= Duration::from_millis(10));
+        defmt::assert!(elapsed < Duration::from_millis(11));
+    }
+
+    // The output loop counts in 10ms ticks, which must come out exact.
+    #[test]
+    fn output_loop_ticks_are_exact() {
+        let tick: Duration = Duration::from_millis(10);
+        defmt::assert_eq!((tick * 100).as_millis(), 1_000);
+        defmt::assert_eq!(Duration::from_secs(1).as_ticks() % tick.as_ticks(), 0);
+    }
+
+    #[test]
+    fn frame_timing_fits_the_tick_rate() {
+        let timing: FrameTiming = FrameTiming::for_baud_rate(115_200);
+        defmt::assert_eq!(timing.char_gap.as_micros(), 750);
+        defmt::assert_eq!(timing.frame_gap.as_micros(), 1_750);
+    }
+
+    #[test]
+    fn crc_matches_the_example_from_the_spec() {
+        defmt::assert_eq!(modbus::crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xCDC5);
+    }
+
+    #[test]
+    fn log_level_changes_at_run_time() {
+        static LOG: Log = Log::new();
+        defmt::assert!(LOG.enabled(Level::Info));
+        defmt::assert!(!LOG.enabled(Level::Trace));
+        LOG.set_level(Level::Trace);
+        defmt::assert!(LOG.enabled(Level::Trace));
+        LOG.set_level(Level::Error);
+        defmt::assert!(!LOG.enabled(Level::Warn));
+    }
+}